@@ -0,0 +1,117 @@
+use std::path::PathBuf;
+
+use clap::Clap;
+
+const DESCRIPTION: &str = r#"
+The Bindle Signing Agent
+
+Bindle is a technology for storing and retrieving aggregate applications.
+This program holds secret keys and signs with them for other programs, so that
+they never need to read the keys themselves. Set $BINDLE_AGENT_SOCK to the
+socket it listens on to sign with the agent's keys.
+"#;
+
+/// The environment variable the passphrase for an encrypted secret key file is read from. This is
+/// the same one the client uses
+const PASSPHRASE_ENV: &str = "BINDLE_KEY_PASSPHRASE";
+
+#[derive(Clap)]
+#[clap(name = "bindle-agent", version = clap::crate_version!(), author = "DeisLabs at Microsoft Azure", about = DESCRIPTION)]
+struct Opts {
+    #[clap(
+        short = 'f',
+        long = "secrets-file",
+        about = "the path to the file where the secret keys to hold are stored. Defaults to $XDG_CONFIG/bindle/secret_keys.toml"
+    )]
+    secret_file: Option<PathBuf>,
+    #[clap(
+        long = "passphrase-file",
+        env = "BINDLE_KEY_PASSPHRASE_FILE",
+        about = "The path to a file containing the passphrase for an encrypted secret key file. If not set, the passphrase is read from $BINDLE_KEY_PASSPHRASE if it is set, or prompted for otherwise"
+    )]
+    passphrase_file: Option<PathBuf>,
+    #[clap(
+        short = 'a',
+        long = "socket",
+        about = "the path of the socket to listen on. Defaults to $XDG_RUNTIME_DIR/bindle/agent.sock, or a new directory in the temp directory if that isn't set"
+    )]
+    socket: Option<PathBuf>,
+}
+
+#[cfg(target_family = "unix")]
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    use bindle::signature::{PassphraseSource, SecretKeyFile};
+    use std::os::unix::fs::DirBuilderExt;
+
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let opts = Opts::parse();
+
+    let secret_file = opts.secret_file.unwrap_or_else(|| {
+        dirs::config_dir()
+            .map(|v| v.join("bindle/"))
+            .unwrap_or_else(|| "./bindle".into())
+            .join("secret_keys.toml")
+    });
+    let passphrase = PassphraseSource::detect(opts.passphrase_file, PASSPHRASE_ENV);
+    let keys = SecretKeyFile::load_file_from_source(&secret_file, &passphrase)
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to load secret key file from {}: {}",
+                secret_file.display(),
+                e
+            )
+        })?;
+
+    // A directory in the temp directory is only used if there is nowhere better to put the socket.
+    // It gets a random name and must not exist yet, so nobody else can have created it first
+    let mut temp_dir = None;
+    let socket = match opts
+        .socket
+        .or_else(|| dirs::runtime_dir().map(|d| d.join("bindle/agent.sock")))
+    {
+        Some(s) => {
+            if let Some(parent) = s.parent().filter(|p| !p.as_os_str().is_empty()) {
+                // Keep other users from being able to get to the socket at all. An existing
+                // directory is left as is, and binding fails if anyone else can access it
+                std::fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(parent)?;
+            }
+            s
+        }
+        None => {
+            let dir =
+                std::env::temp_dir().join(format!("bindle-agent-{:016x}", rand::random::<u64>()));
+            std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+            let socket = dir.join("agent.sock");
+            temp_dir = Some(dir);
+            socket
+        }
+    };
+    let listener = bindle::agent::bind(&socket).await?;
+    println!("{}={}", bindle::agent::AGENT_SOCKET_ENV, socket.display());
+
+    let res = tokio::select! {
+        res = bindle::agent::serve(listener, keys) => res,
+        res = tokio::signal::ctrl_c() => res.map_err(anyhow::Error::from),
+    };
+    tokio::fs::remove_file(&socket).await?;
+    if let Some(dir) = temp_dir {
+        tokio::fs::remove_dir(dir).await?;
+    }
+    res
+}
+
+#[cfg(not(target_family = "unix"))]
+fn main() -> anyhow::Result<()> {
+    // Parse anyway so that --help and --version still work
+    let _ = Opts::parse();
+    anyhow::bail!("The signing agent is only supported on Unix platforms")
+}
@@ -3,16 +3,18 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use bindle::client::{
-    tokens::{HttpBasic, NoToken, OidcToken, TokenManager},
-    Client, ClientError, Result,
+    tokens::{HttpBasic, LongLivedToken, NoToken, OidcToken, TokenManager},
+    Client, ClientBuilder, ClientError, Result,
 };
 use bindle::invoice::signature::{
-    KeyRing, SecretKeyEntry, SecretKeyFile, SecretKeyStorage, SignatureRole,
+    KeyRing, PassphraseSource, SecretKeyEntry, SecretKeyFile, SecretKeyStorage, SignatureRole,
+    SigningKey,
 };
 use bindle::invoice::Invoice;
 use bindle::provider::ProviderError;
 use bindle::signature::KeyEntry;
 use bindle::standalone::{StandaloneRead, StandaloneWrite};
+use bindle::verification::VerificationReport;
 use bindle::{
     cache::{Cache, DumbCache},
     provider::Provider,
@@ -20,7 +22,7 @@ use bindle::{
 
 use clap::Clap;
 use sha2::Digest;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 use tokio_stream::StreamExt;
 use tokio_util::io::StreamReader;
@@ -30,6 +32,11 @@ mod opts;
 
 use opts::*;
 
+/// The environment variable the passphrase for encrypted secret key files is read from
+const PASSPHRASE_ENV: &str = "BINDLE_KEY_PASSPHRASE";
+/// The environment variable a new passphrase is read from when encrypting secret key files
+const NEW_PASSPHRASE_ENV: &str = "BINDLE_NEW_KEY_PASSPHRASE";
+
 #[tokio::main]
 async fn main() {
     // Trap and format error messages using the proper value
@@ -56,6 +63,7 @@ enum PickYourAuth {
     None(NoToken),
     Http(HttpBasic),
     Oidc(OidcToken),
+    LongLived(LongLivedToken),
 }
 
 #[async_trait::async_trait]
@@ -68,6 +76,7 @@ impl TokenManager for PickYourAuth {
             PickYourAuth::None(nt) => nt.apply_auth_header(builder).await,
             PickYourAuth::Http(h) => h.apply_auth_header(builder).await,
             PickYourAuth::Oidc(oidc) => oidc.apply_auth_header(builder).await,
+            PickYourAuth::LongLived(t) => t.apply_auth_header(builder).await,
         }
     }
 }
@@ -94,7 +103,13 @@ async fn run() -> std::result::Result<(), ClientError> {
         tokio::fs::create_dir_all(p).await?;
     }
 
-    let token = if !matches!(opts.subcmd, SubCommand::Login(_)) {
+    let token = if matches!(opts.subcmd, SubCommand::Login(_)) {
+        PickYourAuth::None(NoToken)
+    } else if let Some(api_token) = opts.api_token {
+        // An API token was explicitly given, so it takes precedence over any saved login
+        tracing::debug!("Using API token");
+        PickYourAuth::LongLived(LongLivedToken::new(&api_token))
+    } else {
         match OidcToken::new_from_file(&token_file).await {
             Ok(t) => {
                 tracing::debug!("Found and loaded token file");
@@ -118,11 +133,20 @@ async fn run() -> std::result::Result<(), ClientError> {
                 return Err(ClientError::InvalidConfig(message));
             }
         }
-    } else {
-        PickYourAuth::None(NoToken)
     };
 
-    let bindle_client = Client::new(&opts.server_url, token)?;
+    let keyring_file = keyring_path(opts.keyring);
+    let keyring = load_keyring(&keyring_file)
+        .await
+        .unwrap_or_else(|_| KeyRing::default());
+
+    let builder = match opts.verification_strategy {
+        Some(strategy) => ClientBuilder::default()
+            .keyring(keyring.clone())
+            .verification_strategy(strategy),
+        None => ClientBuilder::default(),
+    };
+    let bindle_client = builder.build(&opts.server_url, token.clone())?;
 
     let local = bindle::provider::file::FileProvider::new(
         bindle_dir,
@@ -130,11 +154,8 @@ async fn run() -> std::result::Result<(), ClientError> {
     )
     .await;
     let cache = DumbCache::new(bindle_client.clone(), local);
-
-    // We don't verify locally yet, but we will need the keyring to do so
-    let _keyring = load_keyring(opts.keyring)
-        .await
-        .unwrap_or_else(|_| KeyRing::default());
+    let passphrase = PassphraseSource::detect(opts.passphrase_file, PASSPHRASE_ENV);
+    let agent_socket = opts.agent_socket;
 
     match opts.subcmd {
         SubCommand::Info(info_opts) => {
@@ -231,11 +252,11 @@ async fn run() -> std::result::Result<(), ClientError> {
             };
 
             // Signing key
-            let key = first_matching_key(keyfile, &role).await?;
+            let key = first_matching_key(keyfile, &role, &passphrase, &agent_socket).await?;
 
             // Load the invoice and sign it.
             let mut inv: Invoice = bindle::client::load::toml(sign_opts.invoice.as_str()).await?;
-            inv.sign(role.clone(), &key)?;
+            inv.sign(role.clone(), key.as_ref())?;
 
             // Write the signed invoice to a file.
             let outfile = sign_opts
@@ -248,6 +269,30 @@ async fn run() -> std::result::Result<(), ClientError> {
             );
             tokio::fs::write(outfile, toml::to_string(&inv)?).await?;
         }
+        SubCommand::SignRemote(sign_opts) => {
+            let role = match sign_opts.role {
+                Some(r) => role_from_name(r)?,
+                None => SignatureRole::Approver,
+            };
+            let keyfile = match sign_opts.secret_file {
+                Some(dir) => dir,
+                None => ensure_config_dir().await?.join("secret_keys.toml"),
+            };
+            let key = first_matching_key(keyfile, &role, &passphrase, &agent_socket).await?;
+
+            // Sign the invoice as the server has it and only send back the new signature
+            let mut inv = bindle_client.get_invoice(&sign_opts.bindle_id).await?;
+            inv.sign(role.clone(), key.as_ref())?;
+            let signature = inv
+                .signature
+                .and_then(|mut s| s.pop())
+                .ok_or_else(|| ClientError::Other("Signing did not add a signature".to_owned()))?;
+            bindle_client
+                .add_signatures(&sign_opts.bindle_id, vec![signature])
+                .await?;
+            println!("Signed {} with role {}", sign_opts.bindle_id, role);
+        }
+        SubCommand::Verify(verify_opts) => verify(cache, &keyring, verify_opts).await?,
         SubCommand::PushFile(push_opts) => {
             let label =
                 generate_label(&push_opts.path, push_opts.name, push_opts.media_type).await?;
@@ -271,7 +316,7 @@ async fn run() -> std::result::Result<(), ClientError> {
                 Some(dir) => dir,
                 None => ensure_config_dir().await?.join("secret_keys.toml"),
             };
-            let keyfile = SecretKeyFile::load_file(dir)
+            let keyfile = SecretKeyFile::load_file_from_source(dir, &passphrase)
                 .await
                 .map_err(|e| ClientError::Other(e.to_string()))?;
 
@@ -339,22 +384,78 @@ async fn run() -> std::result::Result<(), ClientError> {
                             "Keyfile cannot be directory or symlink".to_owned(),
                         ));
                     }
-                    let mut keyfile = SecretKeyFile::load_file(&dir)
+                    // Keep an encrypted file encrypted with the same passphrase
+                    let existing_passphrase = if SecretKeyFile::is_encrypted(&dir)
                         .await
-                        .map_err(|e| ClientError::Other(e.to_string()))?;
+                        .map_err(|e| ClientError::Other(e.to_string()))?
+                    {
+                        Some(read_passphrase(&passphrase, &dir).await?)
+                    } else {
+                        None
+                    };
+                    let mut keyfile = SecretKeyFile::load_file_with_passphrase(
+                        &dir,
+                        existing_passphrase.as_deref(),
+                    )
+                    .await
+                    .map_err(|e| ClientError::Other(e.to_string()))?;
                     let newkey = SecretKeyEntry::new(
                         create_opts.label,
                         vec![bindle::SignatureRole::Creator],
                     );
                     keyfile.key.push(newkey);
-                    keyfile
-                        .save_file(dir)
-                        .await
-                        .map_err(|e| ClientError::Other(e.to_string()))?;
+                    match existing_passphrase {
+                        Some(p) => keyfile.save_encrypted_file(dir, &p).await,
+                        None => keyfile.save_file(dir).await,
+                    }
+                    .map_err(|e| ClientError::Other(e.to_string()))?;
                 }
                 Err(e) => return Err(e.into()),
             }
         }
+        SubCommand::EncryptKeys(encrypt_opts) => {
+            let dir = match encrypt_opts.secret_file {
+                Some(dir) => dir,
+                None => ensure_config_dir().await?.join("secret_keys.toml"),
+            };
+            if SecretKeyFile::is_encrypted(&dir)
+                .await
+                .map_err(|e| ClientError::Other(e.to_string()))?
+            {
+                return Err(ClientError::Other(format!(
+                    "{} is already encrypted. Use change-passphrase to change its passphrase",
+                    dir.display()
+                )));
+            }
+            let keyfile = SecretKeyFile::load_file(&dir)
+                .await
+                .map_err(|e| ClientError::Other(e.to_string()))?;
+            let new_passphrase = read_new_passphrase(encrypt_opts.new_passphrase_file).await?;
+            keyfile
+                .save_encrypted_file(&dir, &new_passphrase)
+                .await
+                .map_err(|e| ClientError::Other(e.to_string()))?;
+            println!("Encrypted secret keys in {}", dir.display());
+        }
+        SubCommand::ChangePassphrase(change_opts) => {
+            let dir = match change_opts.secret_file {
+                Some(dir) => dir,
+                None => ensure_config_dir().await?.join("secret_keys.toml"),
+            };
+            let old_passphrase = read_passphrase(&passphrase, &dir).await?;
+            let keyfile = SecretKeyFile::load_file_with_passphrase(&dir, Some(&old_passphrase))
+                .await
+                .map_err(|e| ClientError::Other(e.to_string()))?;
+            let new_passphrase = read_new_passphrase(change_opts.new_passphrase_file).await?;
+            keyfile
+                .save_encrypted_file(&dir, &new_passphrase)
+                .await
+                .map_err(|e| ClientError::Other(e.to_string()))?;
+            println!("Changed passphrase for {}", dir.display());
+        }
+        SubCommand::Keys(keys_opts) => {
+            manage_keys(keys_opts.subcmd, &keyring_file, &bindle_client, token).await?
+        }
         SubCommand::Login(_login_opts) => {
             // TODO: We'll use login opts when we enable additional login providers
             OidcToken::login(&opts.server_url, token_file).await?;
@@ -512,13 +613,355 @@ async fn get_all<C: Cache + Send + Sync + Clone>(cache: C, opts: Get) -> Result<
     Ok(())
 }
 
-async fn load_keyring(keyring: Option<PathBuf>) -> anyhow::Result<KeyRing> {
-    // This takes an Option<PathBuf> because we want to wrap all of the flag handling in this
-    // function, including setting the default if the kyering is None.
-    let dir = keyring
-        .unwrap_or_else(default_config_dir)
-        .join("keyring.toml");
-    let kr = bindle::client::load::toml(dir).await?;
+/// The output of the verify command
+#[derive(serde::Serialize)]
+struct VerifyOutput {
+    report: VerificationReport,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    parcels: Vec<ParcelCheck>,
+}
+
+/// The result of checking a parcel against its label
+#[derive(serde::Serialize)]
+struct ParcelCheck {
+    sha256: String,
+    name: String,
+    problem: Option<String>,
+}
+
+async fn verify<C: Cache + Send + Sync + Clone>(
+    cache: C,
+    keyring: &KeyRing,
+    opts: Verify,
+) -> Result<()> {
+    let standalone = match &opts.path {
+        Some(p) => Some(StandaloneRead::new(p, &opts.bindle_id).await?),
+        None => None,
+    };
+    let inv = match &standalone {
+        Some(s) => s.get_invoice().await?,
+        None => match opts.yanked {
+            true => cache.get_yanked_invoice(&opts.bindle_id),
+            false => cache.get_invoice(&opts.bindle_id),
+        }
+        .await
+        .map_err(map_storage_error)?,
+    };
+    let report = opts.strategy.unwrap_or_default().report(&inv, keyring);
+
+    let mut parcels = Vec::new();
+    if opts.parcels {
+        for label in inv.parcel.iter().flatten().map(|p| &p.label) {
+            let res = match &standalone {
+                Some(s) => match s.get_parcel_stream(&label.sha256).await {
+                    Ok(stream) => hash_parcel(stream).await.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                },
+                None => match cache.get_parcel(&inv.bindle.id, &label.sha256).await {
+                    Ok(stream) => hash_parcel(stream).await.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                },
+            };
+            let problem = match res {
+                Err(e) => Some(format!("Unable to read parcel: {}", e)),
+                Ok((sha, _)) if sha != label.sha256 => {
+                    Some(format!("Parcel data hashes to {}", sha))
+                }
+                Ok((_, size)) if size != label.size => Some(format!(
+                    "Parcel is {} bytes, but its label says it should be {} bytes",
+                    size, label.size
+                )),
+                Ok(_) => None,
+            };
+            parcels.push(ParcelCheck {
+                sha256: label.sha256.clone(),
+                name: label.name.clone(),
+                problem,
+            });
+        }
+    }
+
+    let passed = report.verified && parcels.iter().all(|p| p.problem.is_none());
+    let output = VerifyOutput { report, parcels };
+    match opts.output {
+        Some(format) if &format == "toml" => {
+            tokio::io::stdout()
+                .write_all(&toml::to_vec(&output)?)
+                .await?
+        }
+        Some(format) if &format == "json" => {
+            tokio::io::stdout()
+                .write_all(&serde_json::to_vec_pretty(&output)?)
+                .await?
+        }
+        Some(format) if &format == "table" => print_verify_output(&output),
+        Some(format) => return Err(ClientError::Other(format!("Unknown format: {}", format))),
+        None => print_verify_output(&output),
+    }
+    if !passed {
+        return Err(ClientError::Other(format!(
+            "Bindle {} failed verification",
+            inv.bindle.id
+        )));
+    }
+    Ok(())
+}
+
+/// Hashes all of the parcel data in the stream, returning the hex encoded SHA-256 and the size
+async fn hash_parcel<S, B, E>(stream: S) -> std::io::Result<(String, u64)>
+where
+    S: tokio_stream::Stream<Item = std::result::Result<B, E>> + Unpin,
+    B: bytes::Buf,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut reader = StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)));
+    let mut sha = bindle::async_util::AsyncSha256::new();
+    let size = tokio::io::copy(&mut reader, &mut sha).await?;
+    let result = sha.into_inner().expect("data lock error").finalize();
+    Ok((format!("{:x}", result), size))
+}
+
+fn print_verify_output(output: &VerifyOutput) {
+    let report = &output.report;
+    println!("Strategy: {}", report.strategy);
+    println!("Signatures:");
+    let signatures = report
+        .signatures
+        .iter()
+        .map(|s| ("signed", s))
+        .chain(report.yank_signatures.iter().map(|s| ("yanked", s)));
+    for (kind, sig) in signatures {
+        let key = match (&sig.key_label, &sig.revoked) {
+            (_, Some(reason)) => format!("revoked ({:?})", reason),
+            (Some(label), None) => format!("known as {}", label),
+            (None, None) => "not in keyring".to_owned(),
+        };
+        let validity = match &sig.problem {
+            Some(p) => format!("INVALID: {}", p),
+            None => "valid".to_owned(),
+        };
+        let checked = if sig.checked { "" } else { " (not checked)" };
+        println!(
+            "  {} as {} by {} at {}: key {}, {}{}",
+            kind, sig.role, sig.by, sig.at, key, validity, checked
+        );
+    }
+    println!("Rules:");
+    for rule in report.rules.iter() {
+        let result = if rule.passed { "PASS" } else { "FAIL" };
+        println!("  [{}] {}", result, rule.rule);
+    }
+    if !output.parcels.is_empty() {
+        println!("Parcels:");
+        for parcel in output.parcels.iter() {
+            match &parcel.problem {
+                Some(p) => println!("  [FAIL] {} ({}): {}", parcel.name, parcel.sha256, p),
+                None => println!("  [OK] {} ({})", parcel.name, parcel.sha256),
+            }
+        }
+    }
+    match &report.error {
+        Some(e) => println!("Verification failed: {}", e),
+        None => println!("Verification passed"),
+    }
+}
+
+async fn manage_keys(
+    cmd: KeysCommand,
+    keyring_file: &Path,
+    bindle_client: &Client<PickYourAuth>,
+    token: PickYourAuth,
+) -> Result<()> {
+    // Unlike when verifying, a keyring that fails to load is an error here so that it is never
+    // overwritten
+    let mut keyring = match tokio::fs::metadata(keyring_file).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => KeyRing::default(),
+        _ => load_keyring(keyring_file).await.map_err(|e| {
+            ClientError::Other(format!(
+                "Unable to load keyring {}: {}",
+                keyring_file.display(),
+                e
+            ))
+        })?,
+    };
+
+    match cmd {
+        KeysCommand::List(_) => {
+            if keyring.key.is_empty() {
+                println!("No keys in {}", keyring_file.display());
+            }
+            for entry in keyring.key.iter() {
+                print_key_entry(entry, &keyring);
+            }
+            return Ok(());
+        }
+        KeysCommand::Verify(_) => {
+            let mut failed = 0;
+            for entry in keyring.key.iter() {
+                match check_key_entry(entry) {
+                    Ok(status) => println!("OK      {} ({})", entry.label, status),
+                    Err(e) => {
+                        failed += 1;
+                        println!("FAILED  {}: {}", entry.label, e);
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(ClientError::Other(format!(
+                    "{} of {} keys failed verification",
+                    failed,
+                    keyring.key.len()
+                )));
+            }
+            println!("Verified {} keys", keyring.key.len());
+            return Ok(());
+        }
+        KeysCommand::Add(add_opts) => {
+            let roles = add_opts
+                .roles
+                .into_iter()
+                .map(role_from_name)
+                .collect::<Result<Vec<_>>>()?;
+            let entry = KeyEntry {
+                label: add_opts.label,
+                roles,
+                key: add_opts.key,
+                label_signature: add_opts.label_signature,
+            };
+            check_key_entry(&entry).map_err(ClientError::Other)?;
+            if let Some(existing) = keyring.key.iter().find(|k| k.key == entry.key) {
+                return Err(ClientError::Other(format!(
+                    "The key is already in the keyring with the label {}",
+                    existing.label
+                )));
+            }
+            println!("Added {}", entry.label);
+            keyring.key.push(entry);
+        }
+        KeysCommand::Remove(remove_opts) => {
+            let before = keyring.key.len();
+            keyring
+                .key
+                .retain(|k| k.key != remove_opts.key && k.label != remove_opts.key);
+            let removed = before - keyring.key.len();
+            if removed == 0 {
+                return Err(ClientError::Other(format!(
+                    "No key with the public key or label {} is in the keyring",
+                    remove_opts.key
+                )));
+            }
+            println!("Removed {} keys", removed);
+        }
+        KeysCommand::Fetch(fetch_opts) => {
+            let host_keys = match fetch_opts.server_url {
+                Some(url) => {
+                    ClientBuilder::default()
+                        .build(&url, token)?
+                        .get_host_keys()
+                        .await?
+                }
+                None => bindle_client.get_host_keys().await?,
+            };
+            if host_keys.key.is_empty() {
+                return Err(ClientError::Other(
+                    "The server did not return any host keys".to_owned(),
+                ));
+            }
+            let mut stdin = tokio::io::BufReader::new(tokio::io::stdin());
+            let mut added = 0;
+            for mut entry in host_keys.key {
+                check_key_entry(&entry).map_err(|e| {
+                    ClientError::Other(format!(
+                        "Key {} from the server is invalid: {}",
+                        entry.label, e
+                    ))
+                })?;
+                if keyring.key.iter().any(|k| k.key == entry.key) {
+                    println!("{} is already in the keyring", entry.label);
+                    continue;
+                }
+                // Only trust the key to do what a host does, no matter what the server says
+                entry.roles = vec![SignatureRole::Host];
+                print_key_entry(&entry, &keyring);
+                if !fetch_opts.yes
+                    && !confirm(&mut stdin, "Add this key to the keyring as a host key?").await?
+                {
+                    continue;
+                }
+                keyring.key.push(entry);
+                added += 1;
+            }
+            if added == 0 {
+                return Ok(());
+            }
+            println!("Added {} keys", added);
+        }
+    }
+
+    if let Some(parent) = keyring_file.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(keyring_file, toml::to_string(&keyring)?).await?;
+    println!("Wrote keyring to {}", keyring_file.display());
+    Ok(())
+}
+
+/// Checks that the key in the entry is valid and that the label was signed by it, if it was signed
+/// at all. Returns a description of the label's status
+fn check_key_entry(entry: &KeyEntry) -> std::result::Result<&'static str, String> {
+    let key = entry.public_key().map_err(|e| e.to_string())?;
+    if entry.label_signature.is_none() {
+        return Ok("label not signed");
+    }
+    entry
+        .clone()
+        .verify_label(key)
+        .map_err(|e| format!("label signature is invalid: {}", e))?;
+    Ok("label signed by key")
+}
+
+fn print_key_entry(entry: &KeyEntry, keyring: &KeyRing) {
+    println!("{}", entry.label);
+    let roles: Vec<String> = entry.roles.iter().map(|r| r.to_string()).collect();
+    println!("  roles:       {}", roles.join(", "));
+    println!("  key:         {}", entry.key);
+    match entry.fingerprint() {
+        Ok(f) => println!("  fingerprint: {}", f),
+        Err(e) => println!("  fingerprint: invalid key ({})", e),
+    }
+    match check_key_entry(entry) {
+        Ok(status) => println!("  label:       {}", status),
+        Err(e) => println!("  label:       {}", e),
+    }
+    if let Some(revocation) = keyring.revoked.iter().find(|r| r.key == entry.key) {
+        println!("  revoked:     {:?}", revocation.reason);
+    }
+}
+
+/// Asks a yes or no question on the terminal, returning true only if the answer was yes
+async fn confirm<R>(input: &mut R, question: &str) -> Result<bool>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    print!("{} [y/N] ", question);
+    std::io::Write::flush(&mut std::io::stdout())?;
+    let mut answer = String::new();
+    input.read_line(&mut answer).await?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Returns the path to the keyring file, using the default if it isn't set. A directory can also be
+/// given, in which case the keyring is the `keyring.toml` file inside of it
+fn keyring_path(keyring: Option<PathBuf>) -> PathBuf {
+    match keyring {
+        Some(path) if !path.is_dir() => path,
+        Some(dir) => dir.join("keyring.toml"),
+        None => default_config_dir().join("keyring.toml"),
+    }
+}
+
+async fn load_keyring(keyring_file: &Path) -> anyhow::Result<KeyRing> {
+    let kr = bindle::client::load::toml(keyring_file).await?;
     Ok(kr)
 }
 
@@ -562,20 +1005,63 @@ fn role_from_name(name: String) -> Result<SignatureRole> {
     }
 }
 
-async fn first_matching_key(fpath: PathBuf, role: &SignatureRole) -> Result<SecretKeyEntry> {
-    let keys = SecretKeyFile::load_file(&fpath).await.map_err(|e| {
-        ClientError::Other(format!(
-            "Error loading file {}: {}",
-            fpath.display(),
-            e.to_string()
-        ))
-    })?;
+async fn read_passphrase(source: &PassphraseSource, path: &Path) -> Result<String> {
+    source
+        .read(&format!("Passphrase for {}: ", path.display()), false)
+        .await
+        .map_err(|e| ClientError::Other(e.to_string()))
+}
+
+async fn read_new_passphrase(file: Option<PathBuf>) -> Result<String> {
+    PassphraseSource::detect(file, NEW_PASSPHRASE_ENV)
+        .read("New passphrase: ", true)
+        .await
+        .map_err(|e| ClientError::Other(e.to_string()))
+}
+
+/// Finds the first key with the role, using the signing agent if a socket was given and the secret
+/// key file otherwise
+async fn first_matching_key(
+    fpath: PathBuf,
+    role: &SignatureRole,
+    passphrase: &PassphraseSource,
+    agent_socket: &Option<PathBuf>,
+) -> Result<Box<dyn SigningKey>> {
+    if let Some(socket) = agent_socket {
+        return agent_key(socket, role);
+    }
+    let keys = SecretKeyFile::load_file_from_source(&fpath, passphrase)
+        .await
+        .map_err(|e| {
+            ClientError::Other(format!(
+                "Error loading file {}: {}",
+                fpath.display(),
+                e.to_string()
+            ))
+        })?;
 
     keys.get_first_matching(role)
-        .map(|k| k.to_owned())
+        .map(|k| Box::new(k.to_owned()) as Box<dyn SigningKey>)
         .ok_or_else(|| ClientError::Other("No satisfactory key found".to_owned()))
 }
 
+#[cfg(target_family = "unix")]
+fn agent_key(socket: &Path, role: &SignatureRole) -> Result<Box<dyn SigningKey>> {
+    let keys = bindle::agent::AgentKeyStore::connect(socket)?;
+    keys.get_first_matching(role)
+        .map(|k| Box::new(k.to_owned()) as Box<dyn SigningKey>)
+        .ok_or_else(|| {
+            ClientError::Other("No satisfactory key found in the signing agent".to_owned())
+        })
+}
+
+#[cfg(not(target_family = "unix"))]
+fn agent_key(_socket: &Path, _role: &SignatureRole) -> Result<Box<dyn SigningKey>> {
+    Err(ClientError::Other(
+        "Signing agents are only supported on Unix platforms".to_owned(),
+    ))
+}
+
 fn tablify(matches: &bindle::search::Matches) {
     let last = matches.offset + matches.invoices.len() as u64;
     let trailer = if matches.more {
@@ -34,6 +34,27 @@ pub struct Opts {
     )]
     pub keyring: Option<PathBuf>,
 
+    #[clap(
+        long = "verification-strategy",
+        env = "BINDLE_VERIFICATION_STRATEGY",
+        about = "Verify every invoice fetched from the server against the keyring with the given strategy (e.g. GreedyVerification), refusing any that fail. Fetched invoices are not verified if this is not set"
+    )]
+    pub verification_strategy: Option<bindle::VerificationStrategy>,
+
+    #[clap(
+        long = "passphrase-file",
+        env = "BINDLE_KEY_PASSPHRASE_FILE",
+        about = "The path to a file containing the passphrase for an encrypted secret key file. If not set, the passphrase is read from $BINDLE_KEY_PASSPHRASE if it is set, or prompted for otherwise"
+    )]
+    pub passphrase_file: Option<PathBuf>,
+
+    #[clap(
+        long = "agent-socket",
+        env = "BINDLE_AGENT_SOCK",
+        about = "The path to the socket of a running bindle-agent. If set, invoices are signed with the keys held by the agent instead of the ones in the secret key file"
+    )]
+    pub agent_socket: Option<PathBuf>,
+
     #[clap(
         short = 't',
         long = "token-file",
@@ -59,6 +80,14 @@ pub struct Opts {
     )]
     pub http_password: Option<String>,
 
+    #[clap(
+        long = "api-token",
+        about = "An API token issued by the server, such as for use in CI. Takes precedence over the login token file",
+        env = "BINDLE_API_TOKEN",
+        hide_env_values = true
+    )]
+    pub api_token: Option<String>,
+
     #[clap(subcommand)]
     pub subcmd: SubCommand,
 }
@@ -110,11 +139,36 @@ pub enum SubCommand {
         about = "Sign an invoice with one of your secret keys"
     )]
     SignInvoice(SignInvoice),
+    #[clap(
+        name = "sign-remote",
+        about = "Sign a bindle that already exists on the server with one of your secret keys. The key must be in the server's keyring"
+    )]
+    SignRemote(SignRemote),
+    #[clap(
+        name = "verify",
+        about = "Verify the signatures on a bindle from the server or a standalone bindle and print a report on each signature"
+    )]
+    Verify(Verify),
     #[clap(
         name = "print-key",
         about = "Print the public key entries for keys from the secret key file. If no '--label' is supplied, public keys for all secret keys are returned."
     )]
     PrintKey(PrintKey),
+    #[clap(
+        name = "encrypt-keys",
+        about = "Encrypts a plaintext secret key file with a new passphrase. If no secret file is provided, the one in the default config directory for Bindle is used"
+    )]
+    EncryptKeys(EncryptKeys),
+    #[clap(
+        name = "change-passphrase",
+        about = "Changes the passphrase of an encrypted secret key file. If no secret file is provided, the one in the default config directory for Bindle is used"
+    )]
+    ChangePassphrase(ChangePassphrase),
+    #[clap(
+        name = "keys",
+        about = "Manage the public keys in the keyring that are trusted for verifying signatures"
+    )]
+    Keys(Keys),
     #[clap(
         name = "login",
         about = "Logs in to a bindle server, saving the token locally"
@@ -359,6 +413,129 @@ pub struct PrintKey {
     pub label: Option<String>,
 }
 
+#[derive(Clap)]
+pub struct Keys {
+    #[clap(subcommand)]
+    pub subcmd: KeysCommand,
+}
+
+#[derive(Clap)]
+pub enum KeysCommand {
+    #[clap(name = "add", about = "Add a public key to the keyring")]
+    Add(KeysAdd),
+    #[clap(
+        name = "remove",
+        about = "Remove every key from the keyring that has the given public key or label"
+    )]
+    Remove(KeysRemove),
+    #[clap(
+        name = "list",
+        about = "List the keys in the keyring along with their fingerprints"
+    )]
+    List(KeysList),
+    #[clap(
+        name = "verify",
+        about = "Check that every key in the keyring is valid and that signed labels were signed by their keys. Exits with an error if any are not"
+    )]
+    Verify(KeysVerify),
+    #[clap(
+        name = "fetch",
+        about = "Fetch the host keys a server signs with and add them to the keyring after showing their fingerprints. The keys are only trusted with the host role"
+    )]
+    Fetch(KeysFetch),
+}
+
+#[derive(Clap)]
+pub struct KeysAdd {
+    #[clap(
+        index = 1,
+        value_name = "LABEL",
+        about = "the label of the key, typically a name and email address of the form 'name <email>'"
+    )]
+    pub label: String,
+    #[clap(index = 2, value_name = "KEY", about = "the base64 encoded public key")]
+    pub key: String,
+    #[clap(
+        short = 'r',
+        long = "roles",
+        use_delimiter = true,
+        required = true,
+        about = "a comma separated list of the roles the key is trusted for. Values are: c[reator], a[pprover], h[ost], p[roxy]"
+    )]
+    pub roles: Vec<String>,
+    #[clap(
+        long = "label-signature",
+        about = "the base64 encoded signature of the label made by the key. If set, it must be valid"
+    )]
+    pub label_signature: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct KeysRemove {
+    #[clap(
+        index = 1,
+        value_name = "KEY_OR_LABEL",
+        about = "the base64 encoded public key or the exact label of the keys to remove"
+    )]
+    pub key: String,
+}
+
+#[derive(Clap)]
+pub struct KeysList {}
+
+#[derive(Clap)]
+pub struct KeysVerify {}
+
+#[derive(Clap)]
+pub struct KeysFetch {
+    #[clap(
+        index = 1,
+        value_name = "SERVER",
+        about = "the address of the bindle server to fetch the keys from, e.g. https://bindle.example.com/v1. Defaults to the server set with --server"
+    )]
+    pub server_url: Option<String>,
+    #[clap(
+        short = 'y',
+        long = "yes",
+        about = "add the keys without asking for confirmation. Only use this if the connection to the server can be trusted"
+    )]
+    pub yes: bool,
+}
+
+#[derive(Clap)]
+pub struct EncryptKeys {
+    #[clap(
+        short = 'f',
+        long = "secrets-file",
+        value_name = "KEYFILE_PATH",
+        about = "The path to the private key file. If not set, the default location will be used."
+    )]
+    pub secret_file: Option<PathBuf>,
+    #[clap(
+        long = "new-passphrase-file",
+        env = "BINDLE_NEW_KEY_PASSPHRASE_FILE",
+        about = "The path to a file containing the new passphrase. If not set, the passphrase is read from $BINDLE_NEW_KEY_PASSPHRASE if it is set, or prompted for otherwise"
+    )]
+    pub new_passphrase_file: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct ChangePassphrase {
+    #[clap(
+        short = 'f',
+        long = "secrets-file",
+        value_name = "KEYFILE_PATH",
+        about = "The path to the private key file. If not set, the default location will be used."
+    )]
+    pub secret_file: Option<PathBuf>,
+    #[clap(
+        long = "new-passphrase-file",
+        env = "BINDLE_NEW_KEY_PASSPHRASE_FILE",
+        about = "The path to a file containing the new passphrase. If not set, the passphrase is read from $BINDLE_NEW_KEY_PASSPHRASE if it is set, or prompted for otherwise"
+    )]
+    pub new_passphrase_file: Option<PathBuf>,
+}
+
 #[derive(Clap)]
 pub struct SignInvoice {
     #[clap(
@@ -387,6 +564,67 @@ pub struct SignInvoice {
     pub destination: Option<String>,
 }
 
+#[derive(Clap)]
+pub struct SignRemote {
+    #[clap(
+        index = 1,
+        value_name = "BINDLE",
+        about = "The name of the bindle, e.g. example.com/mybindle/1.2.3"
+    )]
+    pub bindle_id: String,
+    #[clap(
+        short = 'f',
+        long = "secrets-file",
+        about = "the path to the file where secret keys are stored. Use 'create-key' to create a new key"
+    )]
+    pub secret_file: Option<PathBuf>,
+    #[clap(
+        short = 'r',
+        long = "role",
+        about = "the role to sign with. Values are: a[pprover], p[roxy]. If no role is specified, 'approver' is used"
+    )]
+    pub role: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct Verify {
+    #[clap(
+        index = 1,
+        value_name = "BINDLE",
+        about = "The name of the bindle, e.g. example.com/mybindle/1.2.3"
+    )]
+    pub bindle_id: String,
+    #[clap(
+        short = 'p',
+        long = "path",
+        about = "A path where a standalone bindle directory is located. If set, the bindle is read from there instead of the server"
+    )]
+    pub path: Option<PathBuf>,
+    #[clap(
+        short = 's',
+        long = "strategy",
+        about = "The verification strategy to use, e.g. CreativeIntegrity or MultipleAttestation[Creator, Approver]. Defaults to GreedyVerification"
+    )]
+    pub strategy: Option<bindle::VerificationStrategy>,
+    #[clap(
+        long = "parcels",
+        about = "Also check that every parcel matches the SHA and size in its label. This downloads all of the parcels"
+    )]
+    pub parcels: bool,
+    #[clap(
+        short = 'y',
+        long = "yanked",
+        about = "Whether or not to verify a yanked bindle. If you attempt to verify a yanked bindle from the server without this set, it will error"
+    )]
+    pub yanked: bool,
+    #[clap(
+        short = 'o',
+        long = "output",
+        about = "the format to output the report in. Allowed values: table, toml, json. Defaults to table"
+    )]
+    pub output: Option<String>,
+}
+
 #[derive(Clap)]
 pub struct PushInvoice {
     #[clap(
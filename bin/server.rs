@@ -1,15 +1,27 @@
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::Clap;
 use tracing::{info, warn};
 
 use bindle::{
+    authn::token::{Scope, TokenAuthenticator, TokenFile, TOKEN_FILE_NAME},
+    authz::{
+        always::AlwaysAuthorize, anonymous_get::AnonymousGet, policy::PolicyAuthorizer,
+        Authorizable, Authorizer, Operation, Request,
+    },
     invoice::signature::{KeyRing, SignatureRole},
-    provider, search,
+    provider::{
+        self,
+        compromise::CompromiseOptions,
+        gc::GcOptions,
+        scrub::{Scrub, ScrubOptions},
+    },
+    search,
     server::{server, TlsConfig},
-    signature::SecretKeyFile,
-    SecretKeyEntry,
+    signature::{PassphraseSource, SecretKeyFile, SecretKeyStorage},
+    SecretKeyEntry, SigningKey, VerificationStrategy,
 };
 
 enum AuthType {
@@ -21,6 +33,110 @@ enum AuthType {
     None,
 }
 
+/// The keys the server signs with
+#[derive(Clone)]
+enum HostKeys {
+    /// Keys loaded from a signing keys file
+    File(SecretKeyFile),
+    /// Keys held by a signing agent
+    #[cfg(target_family = "unix")]
+    Agent(bindle::agent::AgentKeyStore),
+}
+
+impl HostKeys {
+    /// Returns the key with the given label, or the first one with the host role if no label is
+    /// given
+    fn host_key(&self, label: Option<&str>) -> Option<Box<dyn SigningKey>> {
+        match self {
+            HostKeys::File(f) => match label {
+                Some(label) => f.key.iter().find(|k| k.label == label),
+                None => f.get_first_matching(&SignatureRole::Host),
+            }
+            .map(|k| Box::new(k.clone()) as Box<dyn SigningKey>),
+            #[cfg(target_family = "unix")]
+            HostKeys::Agent(a) => match label {
+                Some(label) => a.keys().iter().find(|k| k.label() == label),
+                None => a.get_first_matching(&SignatureRole::Host),
+            }
+            .map(|k| Box::new(k.clone()) as Box<dyn SigningKey>),
+        }
+    }
+}
+
+impl SecretKeyStorage for HostKeys {
+    type Key = dyn SigningKey;
+
+    fn get_first_matching(&self, role: &SignatureRole) -> Option<&Self::Key> {
+        match self {
+            HostKeys::File(f) => f.get_first_matching(role).map(|k| k as &dyn SigningKey),
+            #[cfg(target_family = "unix")]
+            HostKeys::Agent(a) => a.get_first_matching(role).map(|k| k as &dyn SigningKey),
+        }
+    }
+
+    fn get_all_matching(&self, role: &SignatureRole) -> Vec<&Self::Key> {
+        match self {
+            HostKeys::File(f) => f
+                .get_all_matching(role)
+                .into_iter()
+                .map(|k| k as &dyn SigningKey)
+                .collect(),
+            #[cfg(target_family = "unix")]
+            HostKeys::Agent(a) => a
+                .get_all_matching(role)
+                .into_iter()
+                .map(|k| k as &dyn SigningKey)
+                .collect(),
+        }
+    }
+}
+
+/// The authorizer the server uses
+#[derive(Clone)]
+enum ServerAuthz {
+    Always(AlwaysAuthorize),
+    AnonymousGet(AnonymousGet),
+    Policy(PolicyAuthorizer),
+}
+
+impl ServerAuthz {
+    /// Returns the policy authorizer if one was loaded, and the given default for the
+    /// authentication type otherwise
+    fn or_policy(self, policy: &Option<PolicyAuthorizer>) -> Self {
+        match policy {
+            Some(p) => ServerAuthz::Policy(p.clone()),
+            None => self,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Authorizer for ServerAuthz {
+    async fn authorize<A>(&self, item: &A, request: &Request<'_>) -> anyhow::Result<()>
+    where
+        A: Authorizable + Sync,
+    {
+        match self {
+            ServerAuthz::Always(a) => a.authorize(item, request).await,
+            ServerAuthz::AnonymousGet(a) => a.authorize(item, request).await,
+            ServerAuthz::Policy(p) => p.authorize(item, request).await,
+        }
+    }
+
+    fn needs_invoice(&self, operation: Operation) -> bool {
+        match self {
+            ServerAuthz::Always(a) => a.needs_invoice(operation),
+            ServerAuthz::AnonymousGet(a) => a.needs_invoice(operation),
+            ServerAuthz::Policy(p) => p.needs_invoice(operation),
+        }
+    }
+}
+
+/// The default read rate for background scrubs of 10 MiB per second
+const DEFAULT_SCRUB_RATE: u64 = 10 * 1024 * 1024;
+/// The environment variable the passphrase for an encrypted signing keys file is read from
+const SIGNING_KEYS_PASSPHRASE_ENV: &str = "BINDLE_SIGNING_KEYS_PASSPHRASE";
+
 const DESCRIPTION: &str = r#"
 The Bindle Server
 
@@ -86,6 +202,23 @@ struct Opts {
     )]
     signing_file: Option<PathBuf>,
 
+    #[clap(
+        name = "signing_keys_passphrase_file",
+        long = "signing-keys-passphrase-file",
+        env = "BINDLE_SIGNING_KEYS_PASSPHRASE_FILE",
+        about = "location of a file containing the passphrase for the signing keys file, if it is encrypted. If not set, the passphrase is read from $BINDLE_SIGNING_KEYS_PASSPHRASE if it is set, or prompted for otherwise"
+    )]
+    signing_keys_passphrase_file: Option<PathBuf>,
+
+    #[clap(
+        name = "signing_agent",
+        long = "signing-agent",
+        env = "BINDLE_SIGNING_AGENT",
+        conflicts_with = "signing_keys",
+        about = "the path to the socket of a running bindle-agent that holds the host key. If set, the server signs with the agent instead of loading a signing keys file"
+    )]
+    signing_agent: Option<PathBuf>,
+
     #[clap(
         name = "verification_strategy",
         long = "strategy",
@@ -104,6 +237,22 @@ struct Opts {
     #[serde(default)]
     use_embedded_db: bool,
 
+    #[clap(
+        name = "index_directory",
+        long = "index-directory",
+        env = "BINDLE_INDEX_DIRECTORY",
+        about = "the path to the directory in which the search index will be stored [default: $BINDLE_DIRECTORY/index]"
+    )]
+    index_directory: Option<PathBuf>,
+
+    #[clap(
+        name = "rebuild_index",
+        long = "rebuild-index",
+        about = "Throw away the stored search index and rebuild it from all of the bindles on startup. Use this if the index gets out of sync (for example, after restoring bindles from a backup)"
+    )]
+    #[serde(default)]
+    rebuild_index: bool,
+
     #[clap(
         name = "htpasswd-file",
         long = "htpasswd-file",
@@ -142,6 +291,14 @@ struct Opts {
     )]
     oidc_issuer_url: Option<String>,
 
+    #[clap(
+        name = "policy_file",
+        long = "policy-file",
+        env = "BINDLE_POLICY_FILE",
+        about = "If set, requests are authorized using the rules in the given policy file, which grant users and groups permissions on bindle name prefixes. Anything not granted by a rule is denied"
+    )]
+    policy_file: Option<PathBuf>,
+
     #[clap(
         name = "unauthenticated",
         long = "unauthenticated",
@@ -149,6 +306,232 @@ struct Opts {
     )]
     #[serde(default)]
     unauthenticated: bool,
+
+    #[clap(
+        name = "scrub_interval",
+        long = "scrub-interval",
+        env = "BINDLE_SCRUB_INTERVAL",
+        about = "If set, check the integrity of all stored invoices and parcels in the background every this many seconds. Problems are logged, but nothing is quarantined. Use the scrub command to quarantine bad items"
+    )]
+    scrub_interval: Option<u64>,
+
+    #[clap(
+        name = "scrub_rate",
+        long = "scrub-rate",
+        env = "BINDLE_SCRUB_RATE",
+        about = "The maximum number of bytes per second read by background scrubs [default: 10485760]"
+    )]
+    scrub_rate: Option<u64>,
+
+    #[clap(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    #[clap(
+        name = "gc",
+        about = "Remove parcels that are no longer referenced by any invoice and exit. Fails if a server is running on the same directory, unless this is a dry run"
+    )]
+    Gc(GcOpts),
+    #[clap(
+        name = "migrate",
+        about = "Copy all invoices and parcels from one storage backend to another and exit. Neither server may be running while this runs. Running it again resumes an interrupted migration"
+    )]
+    Migrate(MigrateOpts),
+    #[clap(
+        name = "scrub",
+        about = "Check the integrity of all stored invoices and parcels and exit. Signatures are verified using the keyring and verification strategy. Exits with an error if any problems are found"
+    )]
+    Scrub(ScrubOpts),
+    #[clap(
+        name = "resign",
+        about = "Sign all stored invoices with a host key from the signing keys file and exit, keeping all existing signatures. Invoices that fail verification using the keyring and verification strategy are not signed. Use this after rotating the host key"
+    )]
+    Resign(ResignOpts),
+    #[clap(
+        name = "compromised-key",
+        about = "Respond to a compromised key and exit. Bindles the key created are yanked, its approver and proxy signatures are removed, and bindles it signed or yanked as the host are listed for review. The server must not be running while this runs"
+    )]
+    CompromisedKey(CompromisedKeyOpts),
+    #[clap(
+        name = "token",
+        about = "Manage the API tokens the server accepts and exit. Changes are picked up by a running server"
+    )]
+    Token(TokenOpts),
+}
+
+#[derive(Clap)]
+struct GcOpts {
+    #[clap(
+        long = "dry-run",
+        about = "Only report which parcels would be removed without removing them"
+    )]
+    dry_run: bool,
+    #[clap(
+        long = "grace-period",
+        default_value = "86400",
+        about = "Unreferenced parcels newer than this many seconds will not be removed"
+    )]
+    grace_period: u64,
+}
+
+#[derive(Clap)]
+struct ScrubOpts {
+    #[clap(
+        long = "quarantine",
+        about = "Move bad items and leftover part files into the quarantine directory instead of only reporting them. The server should not be running when this is set"
+    )]
+    quarantine: bool,
+    #[clap(
+        long = "max-rate",
+        about = "The maximum number of bytes of parcel data to read per second. Unlimited if not set"
+    )]
+    max_rate: Option<u64>,
+    #[clap(
+        long = "part-grace-period",
+        default_value = "3600",
+        about = "Part files that haven't been written to for this many seconds are considered left over from an interrupted upload"
+    )]
+    part_grace_period: u64,
+}
+
+#[derive(Clap)]
+struct ResignOpts {
+    #[clap(
+        long = "label",
+        about = "The label of the key in the signing keys file to sign with. Defaults to the first key with the host role"
+    )]
+    label: Option<String>,
+}
+
+#[derive(Clap)]
+struct CompromisedKeyOpts {
+    #[clap(
+        index = 1,
+        value_name = "KEY",
+        about = "The compromised public key, base64 encoded as it is in a keyring"
+    )]
+    key: String,
+    #[clap(
+        long = "from",
+        about = "Only handle signatures made at or after this UNIX timestamp. Defaults to all signatures before --until"
+    )]
+    from: Option<u64>,
+    #[clap(
+        long = "until",
+        about = "Only handle signatures made before this UNIX timestamp. Defaults to all signatures after --from"
+    )]
+    until: Option<u64>,
+    #[clap(
+        long = "dry-run",
+        about = "Only report which bindles would be changed without changing them"
+    )]
+    dry_run: bool,
+    #[clap(
+        long = "label",
+        about = "The label of the key in the signing keys file to sign yanks with. Defaults to the first key with the host role"
+    )]
+    label: Option<String>,
+}
+
+#[derive(Clap)]
+struct TokenOpts {
+    #[clap(subcommand)]
+    command: TokenCommand,
+}
+
+#[derive(Clap)]
+enum TokenCommand {
+    #[clap(
+        name = "create",
+        about = "Create a token and print it. The token is not stored, so it can't be shown again"
+    )]
+    Create(CreateTokenOpts),
+    #[clap(
+        name = "list",
+        about = "List all tokens, including expired and revoked ones"
+    )]
+    List,
+    #[clap(name = "revoke", about = "Revoke a token")]
+    Revoke(RevokeTokenOpts),
+}
+
+#[derive(Clap)]
+struct CreateTokenOpts {
+    #[clap(
+        long = "principal",
+        about = "The principal requests made with the token are authenticated as"
+    )]
+    principal: String,
+    #[clap(
+        long = "scopes",
+        default_value = "read",
+        use_delimiter = true,
+        about = "A comma separated list of what the token can be used for: read, push, or yank"
+    )]
+    scopes: Vec<Scope>,
+    #[clap(
+        long = "prefix",
+        multiple_occurrences = true,
+        about = "A bindle name prefix the token can be used on. Can be given more than once. If not set, the token can be used on any bindle. Tokens limited to prefixes can't be used to query"
+    )]
+    prefixes: Vec<String>,
+    #[clap(
+        long = "expires-in-days",
+        default_value = "90",
+        about = "The number of days until the token expires"
+    )]
+    expires_in_days: u64,
+    #[clap(
+        long = "no-expiry",
+        conflicts_with = "expires-in-days",
+        about = "Create a token that never expires"
+    )]
+    no_expiry: bool,
+}
+
+#[derive(Clap)]
+struct RevokeTokenOpts {
+    #[clap(index = 1, value_name = "ID", about = "The ID of the token to revoke")]
+    id: String,
+}
+
+#[derive(Clap)]
+struct MigrateOpts {
+    #[clap(
+        long = "from",
+        about = "The storage to copy from, given as file:<directory> or embedded:<directory>"
+    )]
+    from: StorageSpec,
+    #[clap(
+        long = "to",
+        about = "The storage to copy to, given as file:<directory> or embedded:<directory>. If the migrated server uses a custom --index-directory, start it with --rebuild-index"
+    )]
+    to: StorageSpec,
+}
+
+/// A storage backend and the directory it is stored in
+#[derive(PartialEq)]
+enum StorageSpec {
+    File(PathBuf),
+    Embedded(PathBuf),
+}
+
+impl std::str::FromStr for StorageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", dir)) if !dir.is_empty() => Ok(StorageSpec::File(dir.into())),
+            Some(("embedded", dir)) if !dir.is_empty() => Ok(StorageSpec::Embedded(dir.into())),
+            _ => Err(format!(
+                "Invalid storage {}, expected file:<directory> or embedded:<directory>",
+                s
+            )),
+        }
+    }
 }
 
 #[tokio::main]
@@ -172,37 +555,69 @@ async fn main() -> anyhow::Result<()> {
             .join("bindle")
     });
 
-    // TODO: Should we ensure a keyring?
-    let keyring_file: PathBuf = config
-        .keyring_file
-        .unwrap_or_else(|| default_config_dir().join("keyring.toml"));
-
-    // We might want to do something different in the future. But what we do here is
-    // load the file if we can find it. If the file just doesn't exist, we print a
-    // warning and load a placeholder. This prevents the program from failing when
-    // a keyring does not exist.
-    //
-    // All other cases are considered errors worthy of failing.
-    let keyring: KeyRing = match std::fs::metadata(&keyring_file) {
-        Ok(md) if md.is_file() => load_toml(keyring_file).await?,
-        Ok(_) => {
-            anyhow::bail!("Expected {} to be a regular file", keyring_file.display());
+    match config.command {
+        Some(Command::Gc(opts)) => {
+            return collect_garbage(&bindle_directory, config.use_embedded_db, opts).await
         }
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            warn!("No keyring.toml found. Using default keyring.");
-            KeyRing::default()
+        Some(Command::Migrate(opts)) => return migrate(opts).await,
+        Some(Command::Scrub(opts)) => {
+            let options = ScrubOptions {
+                keyring: Some(load_keyring(config.keyring_file).await?),
+                strategy: config.verification_strategy.unwrap_or_default(),
+                quarantine: opts.quarantine,
+                max_bytes_per_sec: opts.max_rate,
+                part_grace_period: Duration::from_secs(opts.part_grace_period),
+            };
+            return scrub(&bindle_directory, config.use_embedded_db, options).await;
         }
-        Err(e) => anyhow::bail!("failed to read file {}: {}", keyring_file.display(), e),
-    };
+        Some(Command::Resign(opts)) => {
+            let keyring = load_keyring(config.keyring_file).await?;
+            let strategy = config.verification_strategy.unwrap_or_default();
+            let key = load_host_key(
+                config.signing_file,
+                config.signing_keys_passphrase_file,
+                config.signing_agent,
+                opts.label,
+            )
+            .await?;
+            return resign(
+                &bindle_directory,
+                config.use_embedded_db,
+                key.as_ref(),
+                &strategy,
+                &keyring,
+            )
+            .await;
+        }
+        Some(Command::CompromisedKey(opts)) => {
+            let host_key = load_host_key(
+                config.signing_file,
+                config.signing_keys_passphrase_file,
+                config.signing_agent,
+                opts.label,
+            )
+            .await?;
+            let options = CompromiseOptions {
+                key: opts.key,
+                from: opts.from,
+                until: opts.until,
+                dry_run: opts.dry_run,
+            };
+            return respond_to_compromise(
+                &bindle_directory,
+                config.use_embedded_db,
+                &options,
+                host_key.as_ref(),
+            )
+            .await;
+        }
+        Some(Command::Token(opts)) => {
+            return manage_tokens(&bindle_directory.join(TOKEN_FILE_NAME), opts.command).await
+        }
+        None => (),
+    }
 
-    // Load the signing keys from...
-    // - --signing-keys filename
-    // - or config file signing-keys entry
-    // - or $XDG_DATA/bindle/signing-keys.toml
-    let signing_keys: PathBuf = match config.signing_file {
-        Some(keypath) => keypath,
-        None => ensure_signing_keys().await?,
-    };
+    let keyring = load_keyring(config.keyring_file).await?;
 
     // Map doesn't work here because we've already moved data out of opts
     #[allow(clippy::manual_map)]
@@ -220,14 +635,73 @@ async fn main() -> anyhow::Result<()> {
 
     tracing::info!("Using verification strategy of {:?}", strategy);
 
-    let index = search::StrictEngine::default();
-    let secret_store = SecretKeyFile::load_file(&signing_keys).await.map_err(|e| {
-        anyhow::anyhow!(
-            "Failed to load secret key file from {}: {} HINT: Try the flag --signing-keys",
-            signing_keys.display(),
-            e
-        )
-    })?;
+    // Background scrubs only report problems, as quarantining items could race with uploads
+    let scrub_rate = config.scrub_rate.unwrap_or(DEFAULT_SCRUB_RATE);
+    let background_scrub = config.scrub_interval.map(|interval| {
+        info!(interval, "Enabling background scrubs");
+        let options = ScrubOptions {
+            keyring: Some(keyring.clone()),
+            strategy: strategy.clone(),
+            max_bytes_per_sec: Some(scrub_rate),
+            ..Default::default()
+        };
+        (options, Duration::from_secs(interval))
+    });
+
+    let index_directory = config
+        .index_directory
+        .unwrap_or_else(|| bindle_directory.join("index"));
+    info!(path = %index_directory.display(), "Opening search index");
+    let index = search::EmbeddedEngine::new(&index_directory)
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to open search index at {}: {}",
+                index_directory.display(),
+                e
+            )
+        })?;
+    if config.rebuild_index {
+        info!("Clearing search index so it is fully rebuilt");
+        index.clear().await?;
+    }
+    let secret_store = match config.signing_agent {
+        Some(socket) => {
+            info!(socket = %socket.display(), "Using signing agent for host keys");
+            connect_agent(&socket)?
+        }
+        None => {
+            // Load the signing keys from...
+            // - --signing-keys filename
+            // - or config file signing-keys entry
+            // - or $XDG_DATA/bindle/signing-keys.toml
+            let signing_keys: PathBuf = match config.signing_file {
+                Some(keypath) => keypath,
+                None => ensure_signing_keys().await?,
+            };
+            let passphrase = PassphraseSource::detect(
+                config.signing_keys_passphrase_file,
+                SIGNING_KEYS_PASSPHRASE_ENV,
+            );
+            HostKeys::File(
+                SecretKeyFile::load_file_from_source(&signing_keys, &passphrase)
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!(
+                            "Failed to load secret key file from {}: {} HINT: Try the flag --signing-keys",
+                            signing_keys.display(),
+                            e
+                        )
+                    })?,
+            )
+        }
+    };
+    if secret_store
+        .get_first_matching(&SignatureRole::Host)
+        .is_none()
+    {
+        warn!("No key with the host role was found, so creating and yanking invoices will fail");
+    }
 
     tracing::log::info!(
         "Starting server at {}, and serving bindles from {}",
@@ -253,6 +727,22 @@ async fn main() -> anyhow::Result<()> {
         );
     };
 
+    let policy = match config.policy_file {
+        Some(path) => {
+            info!(
+                "Authorizing requests using the policy file {}",
+                path.display()
+            );
+            Some(PolicyAuthorizer::from_file(&path).await.map_err(|e| {
+                anyhow::anyhow!("Failed to load policy file from {}: {}", path.display(), e)
+            })?)
+        }
+        None => None,
+    };
+    // Tokens are only accepted when authentication is enabled, as they can't do anything that an
+    // unauthenticated request can't
+    let token_file = bindle_directory.join(TOKEN_FILE_NAME);
+
     // TODO: This is really gnarly, but the associated type on `Authenticator` makes turning it into
     // a Boxed dynner really difficult. I also tried rolling our own type erasure and ran into
     // similar issues (though I think it could be fixed, it would be a lot of code). So we might
@@ -265,15 +755,17 @@ async fn main() -> anyhow::Result<()> {
             info!("Using OIDC token authentication");
             let store =
                 provider::embedded::EmbeddedProvider::new(&bindle_directory, index.clone()).await?;
+            start_background_scrub(&store, &background_scrub);
 
             let authn =
                 bindle::authn::oidc::OidcAuthenticator::new(&issuer, &token_url, &client_id)
                     .await?;
+            let authn = TokenAuthenticator::new(&token_file, authn).await?;
             server(
                 store,
                 index,
                 authn,
-                bindle::authz::anonymous_get::AnonymousGet,
+                ServerAuthz::AnonymousGet(AnonymousGet).or_policy(&policy),
                 addr,
                 tls,
                 secret_store,
@@ -287,11 +779,12 @@ async fn main() -> anyhow::Result<()> {
             warn!("Using EmbeddedProvider. This is currently experimental");
             let store =
                 provider::embedded::EmbeddedProvider::new(&bindle_directory, index.clone()).await?;
+            start_background_scrub(&store, &background_scrub);
             server(
                 store,
                 index,
                 bindle::authn::always::AlwaysAuthenticate,
-                bindle::authz::always::AlwaysAuthorize,
+                ServerAuthz::Always(AlwaysAuthorize).or_policy(&policy),
                 addr,
                 tls,
                 secret_store,
@@ -305,15 +798,18 @@ async fn main() -> anyhow::Result<()> {
             info!("Using FileProvider");
             info!("Using OIDC token authentication");
             let store = provider::file::FileProvider::new(&bindle_directory, index.clone()).await;
+            let _lock = store.lock_shared().await?;
+            start_background_scrub(&store, &background_scrub);
 
             let authn =
                 bindle::authn::oidc::OidcAuthenticator::new(&issuer, &token_url, &client_id)
                     .await?;
+            let authn = TokenAuthenticator::new(&token_file, authn).await?;
             server(
                 store,
                 index,
                 authn,
-                bindle::authz::anonymous_get::AnonymousGet,
+                ServerAuthz::AnonymousGet(AnonymousGet).or_policy(&policy),
                 addr,
                 tls,
                 secret_store,
@@ -326,11 +822,13 @@ async fn main() -> anyhow::Result<()> {
         (false, AuthType::None) => {
             info!("Using FileProvider");
             let store = provider::file::FileProvider::new(&bindle_directory, index.clone()).await;
+            let _lock = store.lock_shared().await?;
+            start_background_scrub(&store, &background_scrub);
             server(
                 store,
                 index,
                 bindle::authn::always::AlwaysAuthenticate,
-                bindle::authz::always::AlwaysAuthorize,
+                ServerAuthz::Always(AlwaysAuthorize).or_policy(&policy),
                 addr,
                 tls,
                 secret_store,
@@ -345,12 +843,14 @@ async fn main() -> anyhow::Result<()> {
             info!("Auth mode: HTTP Basic Auth");
             let store =
                 provider::embedded::EmbeddedProvider::new(&bindle_directory, index.clone()).await?;
+            start_background_scrub(&store, &background_scrub);
             let authn = bindle::authn::http_basic::HttpBasic::from_file(filename).await?;
+            let authn = TokenAuthenticator::new(&token_file, authn).await?;
             server(
                 store,
                 index,
                 authn,
-                bindle::authz::anonymous_get::AnonymousGet,
+                ServerAuthz::AnonymousGet(AnonymousGet).or_policy(&policy),
                 addr,
                 tls,
                 secret_store,
@@ -364,12 +864,15 @@ async fn main() -> anyhow::Result<()> {
             info!("Using FileProvider");
             info!("Auth mode: HTTP Basic Auth");
             let authn = bindle::authn::http_basic::HttpBasic::from_file(filename).await?;
+            let authn = TokenAuthenticator::new(&token_file, authn).await?;
             let store = provider::file::FileProvider::new(&bindle_directory, index.clone()).await;
+            let _lock = store.lock_shared().await?;
+            start_background_scrub(&store, &background_scrub);
             server(
                 store,
                 index,
                 authn,
-                bindle::authz::anonymous_get::AnonymousGet,
+                ServerAuthz::AnonymousGet(AnonymousGet).or_policy(&policy),
                 addr,
                 tls,
                 secret_store,
@@ -381,6 +884,409 @@ async fn main() -> anyhow::Result<()> {
     }
 }
 
+async fn collect_garbage(
+    bindle_directory: &Path,
+    use_embedded_db: bool,
+    opts: GcOpts,
+) -> anyhow::Result<()> {
+    let options = GcOptions {
+        grace_period: Duration::from_secs(opts.grace_period),
+        dry_run: opts.dry_run,
+    };
+    // The search index isn't needed to collect garbage, so don't bother building one
+    let report = if use_embedded_db {
+        provider::embedded::EmbeddedProvider::new(bindle_directory, search::NoopEngine::default())
+            .await?
+            .collect_garbage(&options)
+            .await?
+    } else {
+        provider::file::FileProvider::new(bindle_directory, search::NoopEngine::default())
+            .await
+            .collect_garbage(&options)
+            .await?
+    };
+
+    let verb = if report.dry_run {
+        "Would remove"
+    } else {
+        "Removed"
+    };
+    for sha in report.removed.iter() {
+        println!("{} parcel {}", verb, sha);
+    }
+    println!(
+        "{} {} unreferenced parcels ({} bytes). Kept {} unreferenced parcels within the grace period and {} live parcels",
+        verb,
+        report.removed.len(),
+        report.removed_bytes,
+        report.retained.len(),
+        report.live_parcels
+    );
+    Ok(())
+}
+
+async fn scrub(
+    bindle_directory: &Path,
+    use_embedded_db: bool,
+    options: ScrubOptions,
+) -> anyhow::Result<()> {
+    // The search index isn't needed for a scrub, so don't bother building one
+    let report = if use_embedded_db {
+        provider::embedded::EmbeddedProvider::new(bindle_directory, search::NoopEngine::default())
+            .await?
+            .scrub(&options)
+            .await?
+    } else {
+        provider::file::FileProvider::new(bindle_directory, search::NoopEngine::default())
+            .await
+            .scrub(&options)
+            .await?
+    };
+
+    for issue in report.bad_invoices.iter() {
+        println!("Bad invoice {}: {}", issue.name, issue.problem);
+    }
+    for issue in report.bad_parcels.iter() {
+        println!("Bad parcel {}: {}", issue.name, issue.problem);
+    }
+    for path in report.part_files.iter() {
+        println!("Leftover part file {}", path.display());
+    }
+    println!(
+        "Checked {} invoices and {} parcels ({} bytes)",
+        report.invoices_checked, report.parcels_checked, report.bytes_checked
+    );
+    if report.is_clean() {
+        return Ok(());
+    }
+    let verb = if report.quarantined {
+        "quarantined"
+    } else {
+        "found"
+    };
+    anyhow::bail!(
+        "Scrub {} {} bad invoices, {} bad parcels, and {} leftover part files",
+        verb,
+        report.bad_invoices.len(),
+        report.bad_parcels.len(),
+        report.part_files.len()
+    )
+}
+
+async fn resign(
+    bindle_directory: &Path,
+    use_embedded_db: bool,
+    key: &dyn SigningKey,
+    strategy: &VerificationStrategy,
+    keyring: &KeyRing,
+) -> anyhow::Result<()> {
+    // Re-signed invoices are reindexed by the server the next time it starts, so don't bother
+    // building a search index here
+    let report = if use_embedded_db {
+        provider::resign::resign_all(
+            &provider::embedded::EmbeddedProvider::new(
+                bindle_directory,
+                search::NoopEngine::default(),
+            )
+            .await?,
+            key,
+            SignatureRole::Host,
+            strategy,
+            keyring,
+        )
+        .await?
+    } else {
+        provider::resign::resign_all(
+            &provider::file::FileProvider::new(bindle_directory, search::NoopEngine::default())
+                .await,
+            key,
+            SignatureRole::Host,
+            strategy,
+            keyring,
+        )
+        .await?
+    };
+
+    for (id, reason) in report.unverified.iter() {
+        println!("Did not sign {} as it failed verification: {}", id, reason);
+    }
+    println!(
+        "Signed {} invoices with key {}. Skipped {} that were already signed by it and {} that failed verification",
+        report.signed,
+        key.label(),
+        report.already_signed,
+        report.unverified.len()
+    );
+    Ok(())
+}
+
+async fn manage_tokens(path: &Path, command: TokenCommand) -> anyhow::Result<()> {
+    let mut tokens = TokenFile::load_file(path).await?;
+    match command {
+        TokenCommand::Create(opts) => {
+            let valid_for = if opts.no_expiry {
+                None
+            } else {
+                Some(Duration::from_secs(opts.expires_in_days * 24 * 60 * 60))
+            };
+            let (token, entry) =
+                tokens.create(&opts.principal, opts.scopes, opts.prefixes, valid_for)?;
+            eprintln!(
+                "Created token {}. Store it somewhere safe, as it can't be shown again",
+                entry.id
+            );
+            println!("{}", token);
+            tokens.save_file(path).await?;
+        }
+        TokenCommand::List => {
+            for entry in tokens.token.iter() {
+                let prefixes = if entry.prefixes.is_empty() {
+                    "any bindle".to_owned()
+                } else {
+                    entry.prefixes.join(", ")
+                };
+                let scopes: Vec<String> = entry.scopes.iter().map(|s| s.to_string()).collect();
+                let status = if tokens.is_revoked(&entry.id) {
+                    "revoked"
+                } else if entry.is_expired() {
+                    "expired"
+                } else {
+                    "active"
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\tcreated {}\texpires {}\t{}",
+                    entry.id,
+                    entry.principal,
+                    scopes.join(","),
+                    prefixes,
+                    format_timestamp(entry.created),
+                    entry
+                        .expires
+                        .map(format_timestamp)
+                        .unwrap_or_else(|| "never".to_owned()),
+                    status
+                );
+            }
+        }
+        TokenCommand::Revoke(opts) => {
+            tokens.revoke(&opts.id)?;
+            tokens.save_file(path).await?;
+            println!("Revoked token {}", opts.id);
+        }
+    }
+    Ok(())
+}
+
+fn format_timestamp(secs: u64) -> String {
+    chrono::DateTime::<chrono::Utc>::from(std::time::UNIX_EPOCH + Duration::from_secs(secs))
+        .to_rfc3339()
+}
+
+async fn respond_to_compromise(
+    bindle_directory: &Path,
+    use_embedded_db: bool,
+    options: &CompromiseOptions,
+    host_key: &dyn SigningKey,
+) -> anyhow::Result<()> {
+    // Changed invoices are reindexed by the server the next time it starts, so don't bother
+    // building a search index here
+    let report = if use_embedded_db {
+        provider::compromise::respond_to_compromise(
+            &provider::embedded::EmbeddedProvider::new(
+                bindle_directory,
+                search::NoopEngine::default(),
+            )
+            .await?,
+            options,
+            host_key,
+        )
+        .await?
+    } else {
+        provider::compromise::respond_to_compromise(
+            &provider::file::FileProvider::new(bindle_directory, search::NoopEngine::default())
+                .await,
+            options,
+            host_key,
+        )
+        .await?
+    };
+
+    let (yank_verb, remove_verb) = if report.dry_run {
+        ("Would yank", "Would remove")
+    } else {
+        ("Yanked", "Removed")
+    };
+    for affected in report.affected.iter() {
+        if affected.yanked {
+            println!("{} {}", yank_verb, affected.id);
+        }
+        for sig in affected.removed_signatures.iter() {
+            println!(
+                "{} {} signature by {} made at {} from {}",
+                remove_verb, sig.role, sig.by, sig.at, affected.id
+            );
+        }
+        if affected.unsigned {
+            println!(
+                "{} has no signatures left and should no longer be trusted",
+                affected.id
+            );
+        }
+        if affected.needs_review {
+            println!(
+                "{} was signed or yanked by the key as the host and needs to be reviewed",
+                affected.id
+            );
+        }
+    }
+    println!(
+        "Checked {} invoices and found {} signed by the key. Remember to revoke the key in your keyrings",
+        report.invoices_checked,
+        report.affected.len()
+    );
+    Ok(())
+}
+
+/// Loads a host key from the signing agent if a socket was given or the signing keys file otherwise,
+/// either by label or the first one with the host role
+async fn load_host_key(
+    signing_file: Option<PathBuf>,
+    passphrase_file: Option<PathBuf>,
+    signing_agent: Option<PathBuf>,
+    label: Option<String>,
+) -> anyhow::Result<Box<dyn SigningKey>> {
+    if let Some(socket) = signing_agent {
+        return connect_agent(&socket)?
+            .host_key(label.as_deref())
+            .ok_or_else(|| anyhow::anyhow!("No matching host key found in the signing agent"));
+    }
+    // Don't create a new key here, as signing anything with a key nobody knows about isn't useful
+    let signing_keys =
+        signing_file.unwrap_or_else(|| default_config_dir().join("signing-keys.toml"));
+    let passphrase = PassphraseSource::detect(passphrase_file, SIGNING_KEYS_PASSPHRASE_ENV);
+    let secret_store = SecretKeyFile::load_file_from_source(&signing_keys, &passphrase)
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to load secret key file from {}: {} HINT: Try the flag --signing-keys",
+                signing_keys.display(),
+                e
+            )
+        })?;
+    HostKeys::File(secret_store)
+        .host_key(label.as_deref())
+        .ok_or_else(|| anyhow::anyhow!("No matching host key found in {}", signing_keys.display()))
+}
+
+/// Connects to the signing agent listening on the socket and fetches its keys
+#[cfg(target_family = "unix")]
+fn connect_agent(socket: &Path) -> anyhow::Result<HostKeys> {
+    bindle::agent::AgentKeyStore::connect(socket)
+        .map(HostKeys::Agent)
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to connect to the signing agent at {}: {} HINT: Try the flag --signing-agent",
+                socket.display(),
+                e
+            )
+        })
+}
+
+#[cfg(not(target_family = "unix"))]
+fn connect_agent(_socket: &Path) -> anyhow::Result<HostKeys> {
+    anyhow::bail!("Signing agents are only supported on Unix platforms")
+}
+
+/// Starts periodically scrubbing the store in the background if it was configured
+fn start_background_scrub<S>(store: &S, config: &Option<(ScrubOptions, Duration)>)
+where
+    S: Scrub + Clone + Send + Sync + 'static,
+{
+    if let Some((options, interval)) = config {
+        provider::scrub::run_periodically(store.clone(), options.clone(), *interval);
+    }
+}
+
+async fn migrate(opts: MigrateOpts) -> anyhow::Result<()> {
+    if opts.from == opts.to {
+        anyhow::bail!("Cannot migrate storage to itself");
+    }
+    // Imported invoices are indexed by the server the next time it starts, so neither side needs
+    // a search index here
+    let report = match (opts.from, opts.to) {
+        (StorageSpec::File(from), StorageSpec::File(to)) => {
+            provider::migrate::migrate(
+                &provider::file::FileProvider::new(from, search::NoopEngine::default()).await,
+                &provider::file::FileProvider::new(to, search::NoopEngine::default()).await,
+            )
+            .await?
+        }
+        (StorageSpec::File(from), StorageSpec::Embedded(to)) => {
+            provider::migrate::migrate(
+                &provider::file::FileProvider::new(from, search::NoopEngine::default()).await,
+                &provider::embedded::EmbeddedProvider::new(to, search::NoopEngine::default())
+                    .await?,
+            )
+            .await?
+        }
+        (StorageSpec::Embedded(from), StorageSpec::File(to)) => {
+            provider::migrate::migrate(
+                &provider::embedded::EmbeddedProvider::new(from, search::NoopEngine::default())
+                    .await?,
+                &provider::file::FileProvider::new(to, search::NoopEngine::default()).await,
+            )
+            .await?
+        }
+        (StorageSpec::Embedded(from), StorageSpec::Embedded(to)) => {
+            provider::migrate::migrate(
+                &provider::embedded::EmbeddedProvider::new(from, search::NoopEngine::default())
+                    .await?,
+                &provider::embedded::EmbeddedProvider::new(to, search::NoopEngine::default())
+                    .await?,
+            )
+            .await?
+        }
+    };
+
+    for sha in report.parcels_missing.iter() {
+        println!(
+            "Parcel {} does not exist in the source and was not copied",
+            sha
+        );
+    }
+    println!(
+        "Copied {} invoices and skipped {} that were already present. Copied {} parcels and skipped {} that were already present",
+        report.invoices_copied,
+        report.invoices_skipped,
+        report.parcels_copied,
+        report.parcels_skipped
+    );
+    Ok(())
+}
+
+async fn load_keyring(keyring_file: Option<PathBuf>) -> anyhow::Result<KeyRing> {
+    // TODO: Should we ensure a keyring?
+    let keyring_file = keyring_file.unwrap_or_else(|| default_config_dir().join("keyring.toml"));
+
+    // We might want to do something different in the future. But what we do here is
+    // load the file if we can find it. If the file just doesn't exist, we print a
+    // warning and load a placeholder. This prevents the program from failing when
+    // a keyring does not exist.
+    //
+    // All other cases are considered errors worthy of failing.
+    match std::fs::metadata(&keyring_file) {
+        Ok(md) if md.is_file() => load_toml(keyring_file).await,
+        Ok(_) => {
+            anyhow::bail!("Expected {} to be a regular file", keyring_file.display());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("No keyring.toml found. Using default keyring.");
+            Ok(KeyRing::default())
+        }
+        Err(e) => anyhow::bail!("failed to read file {}: {}", keyring_file.display(), e),
+    }
+}
+
 fn default_config_file() -> Option<PathBuf> {
     dirs::config_dir().map(|v| v.join("bindle/server.toml"))
 }
@@ -450,21 +1356,37 @@ async fn merged_opts() -> anyhow::Result<Opts> {
         Opts::default()
     });
 
+    // A signing keys file given on the command line takes precedence over an agent in the config
+    let signing_agent = match opts.signing_file {
+        Some(_) => opts.signing_agent,
+        None => opts.signing_agent.or(config.signing_agent),
+    };
+
     Ok(Opts {
         address: opts.address.or(config.address),
         bindle_directory: opts.bindle_directory.or(config.bindle_directory),
         cert_path: opts.cert_path.or(config.cert_path),
         config_file: opts.config_file,
         htpasswd_file: opts.htpasswd_file.or(config.htpasswd_file),
+        index_directory: opts.index_directory.or(config.index_directory),
+        rebuild_index: opts.rebuild_index || config.rebuild_index,
+        scrub_interval: opts.scrub_interval.or(config.scrub_interval),
+        scrub_rate: opts.scrub_rate.or(config.scrub_rate),
         unauthenticated: opts.unauthenticated || config.unauthenticated,
         key_path: opts.key_path.or(config.key_path),
         keyring_file: opts.keyring_file.or(config.keyring_file),
         oidc_client_id: opts.oidc_client_id.or(config.oidc_client_id),
         oidc_device_url: opts.oidc_device_url.or(config.oidc_device_url),
         oidc_issuer_url: opts.oidc_issuer_url.or(config.oidc_issuer_url),
+        policy_file: opts.policy_file.or(config.policy_file),
         signing_file: opts.signing_file.or(config.signing_file),
+        signing_agent,
+        signing_keys_passphrase_file: opts
+            .signing_keys_passphrase_file
+            .or(config.signing_keys_passphrase_file),
         use_embedded_db: opts.use_embedded_db || config.use_embedded_db,
         verification_strategy: opts.verification_strategy.or(config.verification_strategy),
+        command: opts.command,
     })
 }
 
@@ -0,0 +1,466 @@
+//! A signing agent that holds secret keys and signs data for other processes, similar to
+//! `ssh-agent`.
+//!
+//! The agent loads the keys once and serves them over a Unix domain socket, so the processes that
+//! sign invoices (such as CI runners or the server) never read key material themselves. Only the
+//! public keys ever leave the agent. Anything with access to the socket can ask the agent to sign
+//! with its keys, so the socket must be in a directory that only the user running the agent can
+//! access.
+//!
+//! The protocol is newline delimited JSON. Each request gets exactly one response, and multiple
+//! requests can be sent over the same connection. Data to sign and the returned signatures are
+//! base64 encoded.
+//!
+//! Use [`serve`] to run an agent and [`AgentKeyStore`] to sign with the keys it holds
+
+use std::convert::{TryFrom, TryInto};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, instrument, warn};
+
+use crate::invoice::signature::{
+    EdSignature, KeyEntry, PublicKey, SecretKeyFile, SecretKeyStorage,
+};
+use crate::{SecretKeyEntry, SignatureError, SignatureRole, SigningKey};
+
+/// The environment variable that holds the path to the socket of the signing agent to use
+pub const AGENT_SOCKET_ENV: &str = "BINDLE_AGENT_SOCK";
+
+/// The largest message that will be read from the socket, which leaves plenty of room for the
+/// signed data of large invoices
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+/// How long clients wait for the agent before giving up
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Request {
+    /// List the public keys held by the agent
+    ListKeys,
+    /// Sign the data with the key that has the given public key
+    Sign { key: String, data: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Response {
+    Keys { keys: Vec<KeyEntry> },
+    Signature { signature: String },
+    Error { message: String },
+}
+
+/// A key held by the agent, along with its public keyring entry
+struct HeldKey {
+    entry: KeyEntry,
+    secret: SecretKeyEntry,
+}
+
+/// Binds a listener to the socket at the given path that only the current user can connect to.
+///
+/// The socket is protected by the directory it is in rather than by its own permissions, as there
+/// would otherwise be a window between creating the socket and restricting it where anyone could
+/// connect. This returns an error unless the directory is owned by the current user and nobody
+/// else can access it (mode 0700).
+///
+/// A socket left over from an agent that is no longer running is removed, but this returns an
+/// error if another agent is still listening on it
+pub async fn bind(path: impl AsRef<Path>) -> anyhow::Result<UnixListener> {
+    let path = path.as_ref();
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    check_private_dir(parent).await?;
+    if tokio::fs::symlink_metadata(path).await.is_ok() {
+        if UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!("A signing agent is already listening on {}", path.display());
+        }
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// Returns an error if the directory isn't owned by the current user or if anyone else has any
+/// access to it
+pub async fn check_private_dir(dir: impl AsRef<Path>) -> anyhow::Result<()> {
+    let dir = dir.as_ref();
+    let metadata = tokio::fs::symlink_metadata(dir).await?;
+    if !metadata.is_dir() {
+        anyhow::bail!("{} is not a directory", dir.display());
+    }
+    // SAFETY: getuid has no preconditions and always succeeds
+    let uid = unsafe { libc::getuid() };
+    if metadata.uid() != uid {
+        anyhow::bail!("{} is not owned by the current user", dir.display());
+    }
+    if metadata.mode() & 0o077 != 0 {
+        anyhow::bail!(
+            "{} can be accessed by other users. Its mode must be 0700",
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Serves the keys in the given key file on the listener until an error occurs accepting a
+/// connection. Errors on individual connections are logged and only close that connection.
+///
+/// The keys are checked before anything is served, so this returns an error right away if any of
+/// them are corrupt
+pub async fn serve(listener: UnixListener, keys: SecretKeyFile) -> anyhow::Result<()> {
+    let keys = keys
+        .key
+        .into_iter()
+        .map(|secret| {
+            let entry = KeyEntry::try_from(&secret).map_err(|e| {
+                anyhow::anyhow!("Unable to load key with label {}: {}", secret.label, e)
+            })?;
+            Ok(HeldKey { entry, secret })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let keys = Arc::new(keys);
+    info!(keys = keys.len(), "Signing agent is ready");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let keys = keys.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &keys).await {
+                warn!(error = %e, "Error handling signing agent connection");
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: UnixStream, keys: &[HeldKey]) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut reader = tokio::io::BufReader::new(read);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_MESSAGE_SIZE)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+        // A line without a newline is either too large or was cut off, and there is no way to
+        // find the start of the next message after it
+        let too_large = !line.ends_with('\n');
+        let response = if too_large {
+            Response::Error {
+                message: "Message is too large".to_owned(),
+            }
+        } else {
+            match serde_json::from_str(&line) {
+                Ok(req) => respond(req, keys),
+                Err(e) => Response::Error {
+                    message: format!("Invalid request: {}", e),
+                },
+            }
+        };
+        let mut out = serde_json::to_vec(&response)?;
+        out.push(b'\n');
+        write.write_all(&out).await?;
+        if too_large {
+            return Ok(());
+        }
+    }
+}
+
+fn respond(req: Request, keys: &[HeldKey]) -> Response {
+    match req {
+        Request::ListKeys => Response::Keys {
+            keys: keys.iter().map(|k| k.entry.clone()).collect(),
+        },
+        Request::Sign { key, data } => {
+            let held = match keys.iter().find(|k| k.entry.key == key) {
+                Some(k) => k,
+                None => {
+                    return Response::Error {
+                        message: format!("The agent does not hold the key {}", key),
+                    }
+                }
+            };
+            let data = match base64::decode(&data) {
+                Ok(d) => d,
+                Err(_) => {
+                    return Response::Error {
+                        message: "Data to sign is not valid base64".to_owned(),
+                    }
+                }
+            };
+            debug!(label = %held.entry.label, "Signing data");
+            match SigningKey::sign(&held.secret, &data) {
+                Ok(signature) => Response::Signature {
+                    signature: base64::encode(signature.to_bytes()),
+                },
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            }
+        }
+    }
+}
+
+/// A client for the signing agent listening on a socket.
+///
+/// The client uses blocking IO, as signing with a [`SigningKey`] is synchronous. Async code that
+/// signs with agent keys, such as the server handlers, should do so with
+/// [`spawn_blocking`](tokio::task::spawn_blocking) so the runtime isn't stalled waiting on the
+/// agent
+#[derive(Debug, Clone)]
+pub struct AgentClient {
+    socket: PathBuf,
+}
+
+impl AgentClient {
+    /// Creates a client for the agent listening on the socket at the given path
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        AgentClient {
+            socket: socket.into(),
+        }
+    }
+
+    /// Creates a client for the agent set in the `BINDLE_AGENT_SOCK` environment variable, if it
+    /// is set
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os(AGENT_SOCKET_ENV).map(Self::new)
+    }
+
+    /// Returns all of the keys held by the agent
+    #[instrument(level = "trace", skip(self), fields(socket = %self.socket.display()))]
+    pub fn list_keys(&self) -> Result<Vec<AgentKey>, SignatureError> {
+        let keys = match self.request(&Request::ListKeys)? {
+            Response::Keys { keys } => keys,
+            _ => return Err(unexpected_response()),
+        };
+        keys.into_iter()
+            .map(|entry| {
+                Ok(AgentKey {
+                    public_key: entry.public_key()?,
+                    entry,
+                    client: self.clone(),
+                })
+            })
+            .collect()
+    }
+
+    fn request(&self, req: &Request) -> Result<Response, SignatureError> {
+        let agent_err = |e: std::io::Error| {
+            SignatureError::Agent(format!(
+                "unable to talk to the agent at {}: {}",
+                self.socket.display(),
+                e
+            ))
+        };
+        let stream = std::os::unix::net::UnixStream::connect(&self.socket).map_err(agent_err)?;
+        stream
+            .set_read_timeout(Some(CLIENT_TIMEOUT))
+            .map_err(agent_err)?;
+        stream
+            .set_write_timeout(Some(CLIENT_TIMEOUT))
+            .map_err(agent_err)?;
+
+        let mut msg = serde_json::to_vec(req).map_err(|e| SignatureError::Agent(e.to_string()))?;
+        msg.push(b'\n');
+        (&stream).write_all(&msg).map_err(agent_err)?;
+
+        let mut line = String::new();
+        BufReader::new((&stream).take(MAX_MESSAGE_SIZE))
+            .read_line(&mut line)
+            .map_err(agent_err)?;
+        match serde_json::from_str(&line) {
+            Ok(Response::Error { message }) => Err(SignatureError::Agent(message)),
+            Ok(resp) => Ok(resp),
+            Err(_) => Err(unexpected_response()),
+        }
+    }
+}
+
+fn unexpected_response() -> SignatureError {
+    SignatureError::Agent("unexpected response from the agent".to_owned())
+}
+
+/// A key held by a signing agent. Signing with it sends the data to the agent
+#[derive(Debug, Clone)]
+pub struct AgentKey {
+    entry: KeyEntry,
+    public_key: PublicKey,
+    client: AgentClient,
+}
+
+impl AgentKey {
+    /// The keyring entry for this key, with the label signed by the key
+    pub fn key_entry(&self) -> &KeyEntry {
+        &self.entry
+    }
+}
+
+impl SigningKey for AgentKey {
+    fn label(&self) -> &str {
+        &self.entry.label
+    }
+
+    fn roles(&self) -> &[SignatureRole] {
+        &self.entry.roles
+    }
+
+    fn public_key(&self) -> Result<PublicKey, SignatureError> {
+        Ok(self.public_key)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<EdSignature, SignatureError> {
+        let req = Request::Sign {
+            key: self.entry.key.clone(),
+            data: base64::encode(data),
+        };
+        let signature = match self.client.request(&req)? {
+            Response::Signature { signature } => signature,
+            _ => return Err(unexpected_response()),
+        };
+        let raw = base64::decode(&signature).map_err(|_| unexpected_response())?;
+        let signature = EdSignature::new(
+            raw.as_slice()
+                .try_into()
+                .map_err(|_| unexpected_response())?,
+        );
+        // Make sure the agent actually signed with the key we asked for, as an invalid signature
+        // wouldn't be noticed until something tried to verify it
+        self.public_key
+            .verify_strict(data, &signature)
+            .map_err(|_| SignatureError::Agent("the agent returned an invalid signature".into()))?;
+        Ok(signature)
+    }
+}
+
+/// Secret key storage backed by a signing agent. The list of keys is fetched from the agent when
+/// the store is created
+#[derive(Debug, Clone)]
+pub struct AgentKeyStore {
+    keys: Vec<AgentKey>,
+}
+
+impl AgentKeyStore {
+    /// Connects to the agent listening on the socket at the given path and fetches its keys
+    pub fn connect(socket: impl Into<PathBuf>) -> Result<Self, SignatureError> {
+        Ok(AgentKeyStore {
+            keys: AgentClient::new(socket).list_keys()?,
+        })
+    }
+
+    /// All of the keys held by the agent
+    pub fn keys(&self) -> &[AgentKey] {
+        &self.keys
+    }
+}
+
+impl SecretKeyStorage for AgentKeyStore {
+    type Key = AgentKey;
+
+    fn get_first_matching(&self, role: &SignatureRole) -> Option<&AgentKey> {
+        self.keys.iter().find(|k| k.entry.roles.contains(role))
+    }
+
+    fn get_all_matching(&self, role: &SignatureRole) -> Vec<&AgentKey> {
+        self.keys
+            .iter()
+            .filter(|k| k.entry.roles.contains(role))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::invoice::signature::KeyRing;
+    use crate::testing;
+    use crate::VerificationStrategy;
+
+    #[tokio::test]
+    async fn test_should_sign_with_agent() {
+        let dir = testing::private_tempdir();
+        let socket = dir.path().join("agent.sock");
+        let creator = SecretKeyEntry::new("Creator".to_owned(), vec![SignatureRole::Creator]);
+        let host = SecretKeyEntry::new("Host".to_owned(), vec![SignatureRole::Host]);
+        let keyring = KeyRing::new(vec![
+            (&creator).try_into().unwrap(),
+            (&host).try_into().unwrap(),
+        ]);
+        let keys = SecretKeyFile {
+            key: vec![creator, host],
+            ..Default::default()
+        };
+
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let shared = tempfile::tempdir().unwrap();
+            std::fs::set_permissions(shared.path(), std::fs::Permissions::from_mode(0o755))
+                .unwrap();
+            bind(shared.path().join("agent.sock"))
+                .await
+                .expect_err("Should not bind in a directory other users can access");
+            assert!(!shared.path().join("agent.sock").exists());
+        }
+
+        let listener = bind(&socket).await.expect("should be able to bind");
+        bind(&socket)
+            .await
+            .expect_err("Should not bind over a running agent");
+        tokio::spawn(serve(listener, keys));
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        // The client blocks, so it can't run on the same thread as the agent
+        let (signed, yank) = tokio::task::spawn_blocking(move || {
+            let store = AgentKeyStore::connect(&socket).expect("should connect to agent");
+            assert_eq!(2, store.keys().len());
+            let creator = store.get_first_matching(&SignatureRole::Creator).unwrap();
+            let host = store.get_first_matching(&SignatureRole::Host).unwrap();
+            assert!(store.get_first_matching(&SignatureRole::Proxy).is_none());
+
+            let mut inv = scaffold.invoice;
+            inv.sign(SignatureRole::Creator, creator).unwrap();
+            inv.sign(SignatureRole::Host, host).unwrap();
+            assert!(matches!(
+                inv.sign(SignatureRole::Host, host),
+                Err(SignatureError::DuplicateSignature)
+            ));
+            assert!(matches!(
+                crate::sign_yank(&inv.bindle.id, creator),
+                Err(SignatureError::NoSuitableKey)
+            ));
+            let yank = crate::sign_yank(&inv.bindle.id, host).expect("should sign yank");
+
+            // Unknown keys should be rejected by the agent
+            let mut unknown = creator.clone();
+            unknown.public_key = SecretKeyEntry::new("Unknown".to_owned(), vec![])
+                .public_key()
+                .unwrap();
+            unknown.entry.key = base64::encode(unknown.public_key.to_bytes());
+            assert!(matches!(
+                SigningKey::sign(&unknown, b"data"),
+                Err(SignatureError::Agent(_))
+            ));
+            (inv, yank)
+        })
+        .await
+        .unwrap();
+
+        VerificationStrategy::ExhaustiveVerification
+            .verify(signed.clone(), &keyring)
+            .expect("Signatures made by the agent should be valid");
+        let mut yanked = signed;
+        yanked.yank(Some(yank));
+        VerificationStrategy::ExhaustiveVerification
+            .verify(yanked, &keyring)
+            .expect("Yank signed by the agent should be valid");
+    }
+}
@@ -4,6 +4,7 @@
 pub mod always;
 pub mod http_basic;
 pub mod oidc;
+pub mod token;
 
 use crate::authz::Authorizable;
 
@@ -11,7 +12,7 @@ use crate::authz::Authorizable;
 #[async_trait::async_trait]
 pub trait Authenticator {
     /// The authorizable item type that is returned from the `authenticate` method
-    type Item: Authorizable + Send + 'static;
+    type Item: Authorizable + Send + Sync + 'static;
 
     /// Authenticate the request given the arbitrary `auth_data`, returning an arbitrary error in
     /// case of a failure. This data will likely be the value of the Authorization header. Anonymous
@@ -0,0 +1,547 @@
+//! Long lived API tokens issued by the server, for clients such as CI systems that can't log in
+//! interactively.
+//!
+//! Tokens are limited to a set of [`Scope`](Scope)s and, optionally, to bindle name prefixes. They
+//! can expire and can be revoked. Only a SHA-256 hash of each token is stored, in a
+//! [`TokenFile`](TokenFile) in the server's data directory, so the token itself is only ever shown
+//! when it is created. Tokens look like `bindle_<id>_<secret>` and are sent as bearer tokens, which
+//! is what the client's [`LongLivedToken`](crate::client::tokens::LongLivedToken) does
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use either::Either;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{RwLock, RwLockReadGuard};
+use tracing::debug;
+
+use super::Authenticator;
+use crate::authz::{name_has_prefix, Authorizable, Operation, Request};
+
+/// The prefix all tokens start with, used to tell them apart from other bearer tokens
+pub const TOKEN_PREFIX: &str = "bindle_";
+/// The name of the token file in the server's data directory
+pub const TOKEN_FILE_NAME: &str = "tokens.toml";
+
+const BEARER_PREFIX: &str = "Bearer ";
+const ID_LEN: usize = 8;
+const SECRET_LEN: usize = 32;
+
+/// What a token can be used for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Fetch invoices and parcels, query, and list missing parcels
+    Read,
+    /// Create invoices, upload parcels and add signatures
+    Push,
+    /// Yank invoices
+    Yank,
+}
+
+impl Scope {
+    fn allows(&self, operation: Operation) -> bool {
+        match self {
+            Scope::Read => operation.is_read(),
+            Scope::Push => matches!(
+                operation,
+                Operation::CreateInvoice | Operation::CreateParcel | Operation::AddSignatures
+            ),
+            Scope::Yank => operation == Operation::YankInvoice,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Read => "read",
+            Scope::Push => "push",
+            Scope::Yank => "yank",
+        })
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "push" => Ok(Scope::Push),
+            "yank" => Ok(Scope::Yank),
+            _ => Err(format!(
+                "Invalid scope {}, expected one of read, push, or yank",
+                s
+            )),
+        }
+    }
+}
+
+/// A token that has been issued. The token itself is not stored, only its hash
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenEntry {
+    /// The unique ID of the token, which is also part of the token
+    pub id: String,
+    /// The principal requests made with this token are authenticated as
+    pub principal: String,
+    /// The hex encoded SHA-256 hash of the token
+    pub hash: String,
+    /// What the token can be used for
+    pub scopes: Vec<Scope>,
+    /// The bindle name prefixes the token can be used on, matched on `/` boundaries. If empty, it
+    /// can be used on any bindle. Tokens limited to prefixes can't be used to query, as query
+    /// results aren't limited to a single bindle
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    /// When the token was created, in seconds since the Unix epoch
+    pub created: u64,
+    /// When the token expires, in seconds since the Unix epoch. If not set, it never expires
+    #[serde(default)]
+    pub expires: Option<u64>,
+}
+
+impl TokenEntry {
+    /// Returns whether the token has expired
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires, Some(expires) if now() >= expires)
+    }
+}
+
+/// The tokens the server has issued and the IDs of the ones that have been revoked
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TokenFile {
+    // This has to come before the tokens, as plain values can't follow tables in TOML
+    /// The IDs of revoked tokens
+    #[serde(default)]
+    pub revoked: Vec<String>,
+    #[serde(default)]
+    pub token: Vec<TokenEntry>,
+}
+
+impl TokenFile {
+    /// Loads the token file at the given path. If it doesn't exist, an empty token file is returned
+    pub async fn load_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(raw) => Ok(toml::from_slice(&raw)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TokenFile::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Saves the token file to the given path. The file is replaced atomically, so a running server
+    /// never sees a partially written file
+    pub async fn save_file(&self, dest: impl AsRef<Path>) -> anyhow::Result<()> {
+        let dest = dest.as_ref();
+        let tmp = dest.with_extension("toml.tmp");
+        let mut opts = tokio::fs::OpenOptions::new();
+        opts.create(true).write(true).truncate(true);
+        #[cfg(target_family = "unix")]
+        opts.mode(0o600);
+        let mut file = opts.open(&tmp).await?;
+        file.write_all(&toml::to_vec(self)?).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, dest).await?;
+        Ok(())
+    }
+
+    /// Creates a new token for the principal with the given scopes and prefixes, returning the
+    /// token. If `valid_for` is set, the token expires after that long
+    pub fn create(
+        &mut self,
+        principal: &str,
+        scopes: Vec<Scope>,
+        prefixes: Vec<String>,
+        valid_for: Option<Duration>,
+    ) -> anyhow::Result<(String, &TokenEntry)> {
+        if principal.is_empty() {
+            anyhow::bail!("The principal of a token cannot be empty");
+        }
+        if scopes.is_empty() {
+            anyhow::bail!("A token must have at least one scope");
+        }
+        let id = random_hex(ID_LEN);
+        let token = format!("{}{}_{}", TOKEN_PREFIX, id, random_hex(SECRET_LEN));
+        let created = now();
+        self.token.push(TokenEntry {
+            id,
+            principal: principal.to_owned(),
+            hash: hash_token(&token),
+            scopes,
+            prefixes,
+            created,
+            expires: valid_for.map(|d| created + d.as_secs()),
+        });
+        // Safe to unwrap because we just pushed it
+        Ok((token, self.token.last().unwrap()))
+    }
+
+    /// Revokes the token with the given ID
+    pub fn revoke(&mut self, id: &str) -> anyhow::Result<()> {
+        if !self.token.iter().any(|t| t.id == id) {
+            anyhow::bail!("No token with the ID {} exists", id);
+        }
+        if self.is_revoked(id) {
+            anyhow::bail!("The token with the ID {} is already revoked", id);
+        }
+        self.revoked.push(id.to_owned());
+        Ok(())
+    }
+
+    /// Returns whether the token with the given ID has been revoked
+    pub fn is_revoked(&self, id: &str) -> bool {
+        self.revoked.iter().any(|r| r == id)
+    }
+
+    /// Returns the entry for the given token if it is valid, meaning it was issued, has not expired
+    /// and has not been revoked
+    pub fn validate(&self, token: &str) -> anyhow::Result<&TokenEntry> {
+        let id = token
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|t| t.split_once('_'))
+            .map(|(id, _)| id)
+            .ok_or_else(|| anyhow::anyhow!("Token is malformed"))?;
+        let entry = self
+            .token
+            .iter()
+            .find(|t| t.id == id)
+            .filter(|t| {
+                ring::constant_time::verify_slices_are_equal(
+                    t.hash.as_bytes(),
+                    hash_token(token).as_bytes(),
+                )
+                .is_ok()
+            })
+            .ok_or_else(|| anyhow::anyhow!("Token is not valid"))?;
+        if self.is_revoked(&entry.id) {
+            anyhow::bail!("Token {} has been revoked", entry.id);
+        }
+        if entry.is_expired() {
+            anyhow::bail!("Token {} has expired", entry.id);
+        }
+        Ok(entry)
+    }
+}
+
+/// A user authenticated with a token, limited to what the token's scopes allow
+#[derive(Debug, Clone)]
+pub struct TokenUser {
+    principal: String,
+    scopes: Vec<Scope>,
+    prefixes: Vec<String>,
+}
+
+impl From<&TokenEntry> for TokenUser {
+    fn from(entry: &TokenEntry) -> Self {
+        TokenUser {
+            principal: entry.principal.clone(),
+            scopes: entry.scopes.clone(),
+            prefixes: entry.prefixes.clone(),
+        }
+    }
+}
+
+impl Authorizable for TokenUser {
+    fn principal(&self) -> String {
+        self.principal.clone()
+    }
+
+    fn groups(&self) -> Vec<String> {
+        Vec::with_capacity(0)
+    }
+
+    fn check_scope(&self, request: &Request<'_>) -> anyhow::Result<()> {
+        // The host keys are public, so there is no reason to limit them
+        if request.operation == Operation::GetHostKeys {
+            return Ok(());
+        }
+        if !self.scopes.iter().any(|s| s.allows(request.operation)) {
+            anyhow::bail!(
+                "Token does not have a scope that allows {:?}",
+                request.operation
+            );
+        }
+        if self.prefixes.is_empty() {
+            return Ok(());
+        }
+        match request.bindle_id {
+            Some(id) if !self.prefixes.iter().any(|p| name_has_prefix(id.name(), p)) => {
+                anyhow::bail!("Token cannot be used on {}", id)
+            }
+            Some(_) => Ok(()),
+            // Operations that aren't on a single bindle, like queries, could return bindles
+            // outside of the prefixes, so they need a token that covers every bindle
+            None if !self.prefixes.iter().any(|p| p.is_empty()) => anyhow::bail!(
+                "Token is limited to bindle prefixes, so it cannot be used for {:?}",
+                request.operation
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The loaded token file, along with what the file looked like when it was loaded
+#[derive(Default)]
+struct Loaded {
+    tokens: TokenFile,
+    stamp: Option<(SystemTime, u64)>,
+}
+
+/// An authenticator that validates tokens from a [`TokenFile`](TokenFile), passing anything that
+/// isn't a token on to another authenticator. The file is reloaded whenever it changes, so tokens
+/// can be created and revoked while the server is running
+#[derive(Clone)]
+pub struct TokenAuthenticator<A> {
+    inner: A,
+    path: PathBuf,
+    loaded: Arc<RwLock<Loaded>>,
+}
+
+impl<A: Authenticator> TokenAuthenticator<A> {
+    /// Returns a new authenticator that validates tokens from the token file at the given path and
+    /// uses the inner authenticator for everything else
+    pub async fn new(path: impl AsRef<Path>, inner: A) -> anyhow::Result<Self> {
+        let me = TokenAuthenticator {
+            inner,
+            path: path.as_ref().to_owned(),
+            loaded: Arc::new(RwLock::new(Loaded::default())),
+        };
+        // Load the file now so that a bad file is caught at startup
+        me.tokens().await?;
+        Ok(me)
+    }
+
+    /// Returns the current tokens, reloading them first if the file has changed
+    async fn tokens(&self) -> anyhow::Result<RwLockReadGuard<'_, TokenFile>> {
+        let stamp = match tokio::fs::metadata(&self.path).await {
+            Ok(m) => Some((m.modified()?, m.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if self.loaded.read().await.stamp != stamp {
+            let mut loaded = self.loaded.write().await;
+            // Another request may have reloaded the file while we waited for the lock
+            if loaded.stamp != stamp {
+                debug!(path = %self.path.display(), "Reloading token file");
+                loaded.tokens = TokenFile::load_file(&self.path).await?;
+                loaded.stamp = stamp;
+            }
+        }
+        Ok(RwLockReadGuard::map(self.loaded.read().await, |l| {
+            &l.tokens
+        }))
+    }
+}
+
+#[async_trait::async_trait]
+impl<A: Authenticator + Send + Sync> Authenticator for TokenAuthenticator<A> {
+    type Item = Either<TokenUser, A::Item>;
+
+    async fn authenticate(&self, auth_data: &str) -> anyhow::Result<Self::Item> {
+        match auth_data
+            .strip_prefix(BEARER_PREFIX)
+            .filter(|t| t.starts_with(TOKEN_PREFIX))
+        {
+            Some(token) => {
+                let tokens = self.tokens().await?;
+                Ok(Either::Left(tokens.validate(token)?.into()))
+            }
+            None => self.inner.authenticate(auth_data).await.map(Either::Right),
+        }
+    }
+
+    fn client_id(&self) -> &str {
+        self.inner.client_id()
+    }
+
+    fn auth_url(&self) -> &str {
+        self.inner.auth_url()
+    }
+
+    fn token_url(&self) -> &str {
+        self.inner.token_url()
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::rngs::OsRng {}.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::authn::always::AlwaysAuthenticate;
+    use crate::Id;
+
+    fn request(operation: Operation, id: Option<&Id>) -> Request<'_> {
+        Request {
+            operation,
+            bindle_id: id,
+            parcel_sha: None,
+            invoice: None,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut tokens = TokenFile::default();
+        let (token, entry) = tokens
+            .create("ci", vec![Scope::Read], vec![], None)
+            .expect("token should be created");
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert!(
+            !entry.hash.contains(&token) && !token.contains(&entry.hash),
+            "The token should not be stored"
+        );
+        let id = entry.id.clone();
+
+        assert_eq!(
+            tokens.validate(&token).expect("token should be valid").id,
+            id
+        );
+        // Change the last character of the secret
+        let mut tampered = token.clone();
+        let last = if tampered.pop() == Some('0') {
+            '1'
+        } else {
+            '0'
+        };
+        tampered.push(last);
+        tokens
+            .validate(&tampered)
+            .expect_err("a token with the wrong secret should not be valid");
+        tokens
+            .validate("bindle_nope")
+            .expect_err("a malformed token should not be valid");
+
+        tokens.revoke(&id).expect("token should be revoked");
+        tokens
+            .revoke(&id)
+            .expect_err("a token should not be revoked twice");
+        tokens
+            .validate(&token)
+            .expect_err("a revoked token should not be valid");
+
+        let (expired, _) = tokens
+            .create(
+                "ci",
+                vec![Scope::Read],
+                vec![],
+                Some(Duration::from_secs(0)),
+            )
+            .unwrap();
+        tokens
+            .validate(&expired)
+            .expect_err("an expired token should not be valid");
+    }
+
+    #[test]
+    fn test_scopes() {
+        let mut tokens = TokenFile::default();
+        let (_, entry) = tokens
+            .create(
+                "ci",
+                vec![Scope::Read, Scope::Push],
+                vec!["example.com/team-a/".to_owned()],
+                None,
+            )
+            .unwrap();
+        let user = TokenUser::from(entry);
+        let team_a = Id::try_from("example.com/team-a/foo/1.0.0").unwrap();
+        let team_b = Id::try_from("example.com/team-b/foo/1.0.0").unwrap();
+
+        user.check_scope(&request(Operation::CreateParcel, Some(&team_a)))
+            .expect("push should be allowed in the prefix");
+        user.check_scope(&request(Operation::GetInvoice, Some(&team_a)))
+            .expect("read should be allowed in the prefix");
+        user.check_scope(&request(Operation::Query, None))
+            .expect_err("query should not be allowed for a token limited to prefixes");
+        user.check_scope(&request(Operation::GetHostKeys, None))
+            .expect("host keys should always be allowed");
+        user.check_scope(&request(Operation::YankInvoice, Some(&team_a)))
+            .expect_err("yank should not be allowed without the yank scope");
+        user.check_scope(&request(Operation::GetInvoice, Some(&team_b)))
+            .expect_err("nothing should be allowed outside of the prefix");
+
+        let (_, entry) = tokens
+            .create(
+                "ci",
+                vec![Scope::Read],
+                vec!["example.com/team".to_owned()],
+                None,
+            )
+            .unwrap();
+        let user = TokenUser::from(entry);
+        user.check_scope(&request(Operation::GetInvoice, Some(&team_a)))
+            .expect_err("prefix should only match on a segment boundary");
+
+        let (_, entry) = tokens
+            .create("ci", vec![Scope::Read], vec![], None)
+            .unwrap();
+        let user = TokenUser::from(entry);
+        user.check_scope(&request(Operation::Query, None))
+            .expect("query should be allowed with the read scope on every bindle");
+        user.check_scope(&request(Operation::GetInvoice, Some(&team_b)))
+            .expect("read should be allowed anywhere without prefixes");
+    }
+
+    #[tokio::test]
+    async fn test_authenticator() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(TOKEN_FILE_NAME);
+        let authn = TokenAuthenticator::new(&path, AlwaysAuthenticate)
+            .await
+            .expect("a missing token file should be allowed");
+
+        let mut tokens = TokenFile::default();
+        let (token, entry) = tokens
+            .create("ci", vec![Scope::Read], vec![], None)
+            .unwrap();
+        let id = entry.id.clone();
+        tokens.save_file(&path).await.unwrap();
+
+        let header = format!("Bearer {}", token);
+        let user = authn
+            .authenticate(&header)
+            .await
+            .expect("token should authenticate once the file is written");
+        assert_eq!(user.principal(), "ci");
+
+        assert!(
+            authn
+                .authenticate("Basic Zm9vOmJhcg==")
+                .await
+                .expect("other credentials should go to the inner authenticator")
+                .is_right(),
+            "other credentials should not be treated as a token"
+        );
+
+        tokens.revoke(&id).unwrap();
+        tokens.save_file(&path).await.unwrap();
+        authn
+            .authenticate(&header)
+            .await
+            .expect_err("token should not authenticate once revoked");
+    }
+}
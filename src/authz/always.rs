@@ -1,6 +1,6 @@
 //! A simple noop authorizer that does nothing for use when authorization is not desired or for
 //! development environments
-use super::{Authorizable, Authorizer};
+use super::{Authorizable, Authorizer, Request};
 
 /// An anonymous user
 #[derive(Debug, Clone)]
@@ -20,13 +20,12 @@ impl Authorizable for Anonymous {
 #[derive(Debug, Clone)]
 pub struct AlwaysAuthorize;
 
+#[async_trait::async_trait]
 impl Authorizer for AlwaysAuthorize {
-    fn authorize<A: Authorizable>(
-        &self,
-        _: A,
-        _: &str,
-        _: &warp::http::Method,
-    ) -> anyhow::Result<()> {
+    async fn authorize<A>(&self, _: &A, _: &Request<'_>) -> anyhow::Result<()>
+    where
+        A: Authorizable + Sync,
+    {
         Ok(())
     }
 }
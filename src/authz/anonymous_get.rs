@@ -1,26 +1,25 @@
-//! An authorizer that authorizes anonymous access for GET requests and denies all other
+//! An authorizer that authorizes anonymous access for read operations and denies all other
 //! unauthenticated requests
 
-use warp::http::Method;
+use super::Request;
 
 #[derive(Clone)]
 pub struct AnonymousGet;
 
+#[async_trait::async_trait]
 impl super::Authorizer for AnonymousGet {
-    fn authorize<A: super::Authorizable>(
-        &self,
-        item: A,
-        _path: &str,
-        method: &Method,
-    ) -> anyhow::Result<()> {
-        // Any get request should succeed, no matter the path
-        if matches!(method, &Method::GET) {
+    async fn authorize<A>(&self, item: &A, request: &Request<'_>) -> anyhow::Result<()>
+    where
+        A: super::Authorizable + Sync,
+    {
+        // Any read should succeed, no matter what it is for
+        if request.operation.is_read() {
             return Ok(());
         }
 
         // An empty principal would mean this is anonymous
         if item.principal().is_empty() {
-            anyhow::bail!("Anonymous authorization is not allowed for non-GET endpoints")
+            anyhow::bail!("Anonymous authorization is not allowed for operations that modify data")
         } else {
             Ok(())
         }
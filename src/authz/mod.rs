@@ -3,6 +3,11 @@
 
 pub mod always;
 pub mod anonymous_get;
+pub mod policy;
+
+use either::Either;
+
+use crate::{Id, Invoice};
 
 /// A trait that can be implemented on any type (such as a custom `User` or `Token` type) so that it
 /// can be authorized by an [`Authorizer`](Authorizer)
@@ -13,18 +18,112 @@ pub trait Authorizable {
     /// Returns the groups the authenticated user is a member of, generally embedded on something
     /// like a JWT or fetched from an upstream server
     fn groups(&self) -> Vec<String>;
+
+    /// Returns an error if the credentials the user authenticated with don't allow the request, no
+    /// matter what the [`Authorizer`](Authorizer) decides. This is for credentials that are limited
+    /// to a subset of what their principal can do, such as scoped API tokens. By default, nothing
+    /// is restricted
+    fn check_scope(&self, _request: &Request<'_>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl<L: Authorizable, R: Authorizable> Authorizable for Either<L, R> {
+    fn principal(&self) -> String {
+        self.as_ref().either(|l| l.principal(), |r| r.principal())
+    }
+
+    fn groups(&self) -> Vec<String> {
+        self.as_ref().either(|l| l.groups(), |r| r.groups())
+    }
+
+    fn check_scope(&self, request: &Request<'_>) -> anyhow::Result<()> {
+        self.as_ref()
+            .either(|l| l.check_scope(request), |r| r.check_scope(request))
+    }
+}
+
+/// An operation on the API that can be authorized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Querying for invoices
+    Query,
+    /// Fetching an invoice, or just its headers
+    GetInvoice,
+    /// Creating an invoice
+    CreateInvoice,
+    /// Yanking an invoice
+    YankInvoice,
+    /// Adding signatures to an existing invoice
+    AddSignatures,
+    /// Fetching a parcel, or just its headers
+    GetParcel,
+    /// Uploading a parcel
+    CreateParcel,
+    /// Listing the parcels of a bindle that haven't been uploaded
+    GetMissingParcels,
+    /// Fetching the server's host keys
+    GetHostKeys,
+}
+
+impl Operation {
+    /// Returns whether the operation only reads data
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Operation::Query
+                | Operation::GetInvoice
+                | Operation::GetParcel
+                | Operation::GetMissingParcels
+                | Operation::GetHostKeys
+        )
+    }
+}
+
+/// A request to perform an operation, as parsed from the HTTP request
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    /// The operation being performed
+    pub operation: Operation,
+    /// The ID of the bindle the operation is on. This is `None` for operations that aren't on a
+    /// single bindle, such as queries
+    pub bindle_id: Option<&'a Id>,
+    /// The SHA of the parcel the operation is on, if any
+    pub parcel_sha: Option<&'a str>,
+    /// The invoice the operation is on, once loaded. For
+    /// [`CreateInvoice`](Operation::CreateInvoice), this is always the invoice being created. For
+    /// other operations on an existing bindle, this is the stored invoice (even if it is yanked)
+    /// if [`Authorizer::needs_invoice`](Authorizer::needs_invoice) returns `true` and the invoice
+    /// exists
+    pub invoice: Option<&'a Invoice>,
+}
+
+/// Returns whether the bindle name is under the given prefix. Prefixes only match on `/`
+/// boundaries, so `example.com/team-a` matches `example.com/team-a` and `example.com/team-a/foo`
+/// but not `example.com/team-ab`. An empty prefix matches every name
+pub(crate) fn name_has_prefix(name: &str, prefix: &str) -> bool {
+    match name.strip_prefix(prefix) {
+        Some(rest) => {
+            prefix.is_empty() || prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+        }
+        None => false,
+    }
 }
 
 /// A trait for any system that can authorize any [`Authorizable`](Authorizable) type
-// TODO: Will this need to be async?
+#[async_trait::async_trait]
 pub trait Authorizer {
-    /// Checks whether or not the given item is authorized to access provided path and method,
+    /// Checks whether or not the given item is authorized to perform the requested operation,
     /// returning a failure reason in the case where the item is not authorized
     // TODO: We might want to have a custom error enum down the line
-    fn authorize<A: Authorizable>(
-        &self,
-        item: A,
-        path: &str,
-        method: &warp::http::Method,
-    ) -> anyhow::Result<()>;
+    async fn authorize<A>(&self, item: &A, request: &Request<'_>) -> anyhow::Result<()>
+    where
+        A: Authorizable + Sync;
+
+    /// Returns whether the stored invoice should be loaded and passed to
+    /// [`authorize`](Authorizer::authorize) for the given operation on an existing bindle. Loading
+    /// it costs an extra read from the provider, so this returns `false` by default
+    fn needs_invoice(&self, _operation: Operation) -> bool {
+        false
+    }
 }
@@ -0,0 +1,388 @@
+//! An authorizer that grants permissions on bindle name prefixes to users and groups according to
+//! a policy file.
+//!
+//! A policy file is a TOML file made up of rules. Each rule lists who it applies to and which
+//! permissions it grants on which bindle name prefixes:
+//!
+//! ```toml
+//! [[rule]]
+//! principals = ["alice"]
+//! groups = ["team-a"]
+//! prefixes = ["example.com/team-a/"]
+//! permissions = ["read", "create", "upload", "yank"]
+//!
+//! [[rule]]
+//! principals = ["*"]
+//! anonymous = true
+//! prefixes = ["example.com/public/"]
+//! permissions = ["read"]
+//! ```
+//!
+//! A principal of `*` matches any authenticated user, and anonymous users are only matched by rules
+//! that set `anonymous = true`. Anything not granted by a rule is denied.
+//!
+//! Prefixes match on `/` boundaries, so `example.com/team-a` covers `example.com/team-a/foo` but not
+//! `example.com/team-ab`. Query results (`/_q`) aren't limited to a single bindle, so querying is
+//! only allowed for users with the read permission on every bindle, which is granted by an empty
+//! prefix. Fetching the server's host keys is always allowed
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use super::{name_has_prefix, Authorizable, Authorizer, Operation, Request};
+use crate::Id;
+
+/// The principal that matches any authenticated user
+pub const ANY_PRINCIPAL: &str = "*";
+
+/// A permission that can be granted on a bindle name prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Fetch invoices and parcels, query, and list missing parcels
+    Read,
+    /// Create invoices and add signatures to them
+    Create,
+    /// Upload parcels
+    Upload,
+    /// Yank invoices
+    Yank,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Read => "read",
+            Permission::Create => "create",
+            Permission::Upload => "upload",
+            Permission::Yank => "yank",
+        })
+    }
+}
+
+/// A single rule in a policy, granting permissions on bindle name prefixes to the principals and
+/// groups it lists
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    /// The principals this rule applies to. `*` matches any authenticated user
+    #[serde(default)]
+    pub principals: Vec<String>,
+    /// The groups this rule applies to. A user in any of these groups matches
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Whether this rule applies to anonymous users
+    #[serde(default)]
+    pub anonymous: bool,
+    /// The bindle name prefixes this rule grants permissions on, such as `example.com/team-a/`.
+    /// Prefixes match on `/` boundaries, and an empty prefix matches every bindle
+    pub prefixes: Vec<String>,
+    /// The permissions this rule grants
+    pub permissions: Vec<Permission>,
+}
+
+impl Rule {
+    fn applies_to(&self, principal: &str, groups: &[String]) -> bool {
+        if principal.is_empty() {
+            return self.anonymous;
+        }
+        self.principals
+            .iter()
+            .any(|p| p == ANY_PRINCIPAL || p == principal)
+            || self.groups.iter().any(|g| groups.contains(g))
+    }
+
+    fn grants(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    fn covers(&self, id: &Id) -> bool {
+        self.prefixes.iter().any(|p| name_has_prefix(id.name(), p))
+    }
+
+    fn covers_all(&self) -> bool {
+        self.prefixes.iter().any(|p| p.is_empty())
+    }
+}
+
+/// The set of rules loaded from a policy file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub rule: Vec<Rule>,
+}
+
+/// What a request needs to be allowed
+#[derive(Debug, PartialEq)]
+enum Access<'a> {
+    /// The permission is needed on the given bindle
+    Bindle(Permission, &'a Id),
+    /// The request isn't limited to a single bindle, so the permission is needed on every bindle
+    All(Permission),
+    /// Anyone can make the request
+    Public,
+}
+
+/// An authorizer that checks each request against the rules in a [`Policy`](Policy)
+#[derive(Debug, Clone)]
+pub struct PolicyAuthorizer {
+    policy: Arc<Policy>,
+}
+
+impl PolicyAuthorizer {
+    /// Returns a new authorizer that uses the given policy
+    pub fn new(policy: Policy) -> Self {
+        PolicyAuthorizer {
+            policy: Arc::new(policy),
+        }
+    }
+
+    /// Loads the policy from the TOML file at the given path
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read(&path).await?;
+        let policy: Policy = toml::from_slice(&raw)?;
+        Ok(PolicyAuthorizer::new(policy))
+    }
+}
+
+#[async_trait::async_trait]
+impl Authorizer for PolicyAuthorizer {
+    async fn authorize<A>(&self, item: &A, request: &Request<'_>) -> anyhow::Result<()>
+    where
+        A: Authorizable + Sync,
+    {
+        let principal = item.principal();
+        let groups = item.groups();
+        let mut rules = self
+            .policy
+            .rule
+            .iter()
+            .filter(|r| r.applies_to(&principal, &groups));
+        let (allowed, permission, target) = match access(request)? {
+            Access::Public => return Ok(()),
+            Access::All(permission) => (
+                rules.any(|r| r.grants(permission) && r.covers_all()),
+                permission,
+                "all bindles".to_owned(),
+            ),
+            Access::Bindle(permission, id) => (
+                rules.any(|r| r.grants(permission) && r.covers(id)),
+                permission,
+                id.to_string(),
+            ),
+        };
+        if !allowed {
+            let who = if principal.is_empty() {
+                "anonymous user"
+            } else {
+                principal.as_str()
+            };
+            anyhow::bail!(
+                "{} does not have {} permission on {}",
+                who,
+                permission,
+                target
+            );
+        }
+        Ok(())
+    }
+}
+
+fn access<'a>(request: &Request<'a>) -> anyhow::Result<Access<'a>> {
+    let permission = match request.operation {
+        Operation::GetHostKeys => return Ok(Access::Public),
+        Operation::Query => return Ok(Access::All(Permission::Read)),
+        Operation::GetInvoice | Operation::GetParcel | Operation::GetMissingParcels => {
+            Permission::Read
+        }
+        Operation::CreateInvoice | Operation::AddSignatures => Permission::Create,
+        Operation::CreateParcel => Permission::Upload,
+        Operation::YankInvoice => Permission::Yank,
+    };
+    let id = request
+        .bindle_id
+        .ok_or_else(|| anyhow::anyhow!("No bindle ID given for {:?}", request.operation))?;
+    Ok(Access::Bindle(permission, id))
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    struct User(&'static str, Vec<String>);
+
+    impl Authorizable for User {
+        fn principal(&self) -> String {
+            self.0.to_owned()
+        }
+
+        fn groups(&self) -> Vec<String> {
+            self.1.clone()
+        }
+    }
+
+    fn authorizer() -> PolicyAuthorizer {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[rule]]
+            principals = ["alice"]
+            groups = ["team-a"]
+            prefixes = ["example.com/team-a/"]
+            permissions = ["read", "create", "upload", "yank"]
+
+            [[rule]]
+            principals = ["*"]
+            anonymous = true
+            prefixes = ["example.com/public/"]
+            permissions = ["read"]
+            "#,
+        )
+        .expect("policy should parse");
+        PolicyAuthorizer::new(policy)
+    }
+
+    fn request(operation: Operation, id: Option<&Id>) -> Request<'_> {
+        Request {
+            operation,
+            bindle_id: id,
+            parcel_sha: None,
+            invoice: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_authorizer() {
+        let authz = authorizer();
+        let team_a = Id::try_from("example.com/team-a/foo/1.0.0").unwrap();
+        let public = Id::try_from("example.com/public/foo/1.0.0").unwrap();
+
+        authz
+            .authorize(
+                &User("alice", vec![]),
+                &request(Operation::YankInvoice, Some(&team_a)),
+            )
+            .await
+            .expect("principal should be able to yank in its prefix");
+        authz
+            .authorize(
+                &User("bob", vec!["team-a".into()]),
+                &request(Operation::YankInvoice, Some(&team_a)),
+            )
+            .await
+            .expect("group member should be able to yank in its prefix");
+        authz
+            .authorize(
+                &User("bob", vec![]),
+                &request(Operation::GetInvoice, Some(&team_a)),
+            )
+            .await
+            .expect_err("other users should not be able to read outside of their prefixes");
+        authz
+            .authorize(
+                &User("bob", vec![]),
+                &request(Operation::GetParcel, Some(&public)),
+            )
+            .await
+            .expect("any authenticated user should be able to read public bindles");
+        authz
+            .authorize(
+                &User("bob", vec![]),
+                &request(Operation::YankInvoice, Some(&public)),
+            )
+            .await
+            .expect_err("public bindles should not be yankable by other users");
+        authz
+            .authorize(
+                &User("alice", vec![]),
+                &request(Operation::CreateInvoice, Some(&team_a)),
+            )
+            .await
+            .expect("principal should be able to create invoices in its prefix");
+        authz
+            .authorize(
+                &User("alice", vec![]),
+                &request(Operation::CreateParcel, Some(&public)),
+            )
+            .await
+            .expect_err("users should not be able to upload parcels outside of their prefixes");
+        authz
+            .authorize(
+                &User("alice", vec![]),
+                &request(Operation::YankInvoice, Some(&public)),
+            )
+            .await
+            .expect_err("users should not be able to yank outside of their prefixes");
+        authz
+            .authorize(
+                &User("", vec![]),
+                &request(Operation::GetInvoice, Some(&public)),
+            )
+            .await
+            .expect("anonymous users should be able to read public bindles");
+        authz
+            .authorize(&User("", vec![]), &request(Operation::Query, None))
+            .await
+            .expect_err("users who can't read every bindle should not be able to query");
+        authz
+            .authorize(&User("alice", vec![]), &request(Operation::Query, None))
+            .await
+            .expect_err("users who can't read every bindle should not be able to query");
+        authz
+            .authorize(&User("bob", vec![]), &request(Operation::GetHostKeys, None))
+            .await
+            .expect("host keys should be public");
+        authz
+            .authorize(
+                &User("alice", vec![]),
+                &request(Operation::CreateInvoice, None),
+            )
+            .await
+            .expect_err("an operation on a bindle without an ID should not be allowed");
+    }
+
+    #[tokio::test]
+    async fn test_policy_prefixes() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[rule]]
+            principals = ["alice"]
+            prefixes = ["example.com/team-a"]
+            permissions = ["read"]
+
+            [[rule]]
+            principals = ["reader"]
+            prefixes = [""]
+            permissions = ["read"]
+            "#,
+        )
+        .expect("policy should parse");
+        let authz = PolicyAuthorizer::new(policy);
+        let exact = Id::try_from("example.com/team-a/1.0.0").unwrap();
+        let nested = Id::try_from("example.com/team-a/foo/1.0.0").unwrap();
+        let sibling = Id::try_from("example.com/team-ab/foo/1.0.0").unwrap();
+
+        for id in &[exact, nested] {
+            authz
+                .authorize(
+                    &User("alice", vec![]),
+                    &request(Operation::GetInvoice, Some(id)),
+                )
+                .await
+                .expect("prefix should match names under it");
+        }
+        authz
+            .authorize(
+                &User("alice", vec![]),
+                &request(Operation::GetInvoice, Some(&sibling)),
+            )
+            .await
+            .expect_err("prefix should only match on a segment boundary");
+        authz
+            .authorize(&User("reader", vec![]), &request(Operation::Query, None))
+            .await
+            .expect("users who can read every bindle should be able to query");
+    }
+}
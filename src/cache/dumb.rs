@@ -8,7 +8,7 @@ use tracing_futures::Instrument;
 use super::{into_cache_result, Cache};
 use crate::provider::{Provider, ProviderError, Result};
 use crate::verification::Verified;
-use crate::{Id, Signed};
+use crate::{Id, Signature, Signed};
 
 /// A cache that doesn't ever expire entries. It fills the cache by requesting bindles from a bindle
 /// server using the configured client and stores them in the given storage implementation
@@ -85,14 +85,14 @@ where
         }
     }
 
-    #[instrument(level = "trace", skip(self, id))]
-    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    #[instrument(level = "trace", skip(self, id, signature))]
+    async fn yank_invoice<I>(&self, id: I, signature: Option<Signature>) -> Result<()>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>,
     {
         // This is just an update of the local cache
-        self.local.yank_invoice(id).await
+        self.local.yank_invoice(id, signature).await
     }
 
     async fn create_parcel<I, R, B>(&self, _: I, _: &str, _: R) -> Result<()>
@@ -171,4 +171,18 @@ where
     {
         self.local.parcel_exists(bindle_id, parcel_id).await
     }
+
+    // Like `parcel_exists`, this only lists what the local provider has. Use the remote provider
+    // directly to list everything available
+    async fn list_invoices(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<crate::Invoice>> + Unpin + Send + Sync>> {
+        self.local.list_invoices().await
+    }
+
+    async fn list_parcels(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<String>> + Unpin + Send + Sync>> {
+        self.local.list_parcels().await
+    }
 }
@@ -16,8 +16,8 @@ use tracing::{debug, instrument, trace};
 use tracing_futures::Instrument;
 
 use super::*;
-use crate::provider::{Provider, ProviderError, Result};
-use crate::{Id, Invoice};
+use crate::provider::{read_range, Provider, ProviderError, Result};
+use crate::{Id, Invoice, Signature};
 
 // Type alias for shorthanding a locked cache
 type LockedCache<K, V> = Arc<Mutex<Lru<K, V>>>;
@@ -48,6 +48,59 @@ impl<Remote: Provider + Clone> LruCache<Remote> {
     }
 }
 
+impl<Remote> LruCache<Remote>
+where
+    Remote: Provider + Send + Sync + Clone,
+{
+    /// Returns an open handle to the cached parcel file, fetching the parcel from the remote and
+    /// caching it first if needed. The returned file is positioned at the start of the parcel
+    async fn cached_parcel(&self, parsed_id: &Id, parcel_id: &str) -> Result<File> {
+        let mut parcels = self.parcels.lock().await;
+        let parcel_id_owned = parcel_id.to_owned();
+        trace!("Checking for parcel {}@{} in cache", parsed_id, parcel_id);
+        match parcels.get(&parcel_id_owned) {
+            Some(f) => {
+                // This forces a requirement of a multithreaded runtime, but avoids weird borrowing
+                // issues required by the static bound on `spawn_blocking`. If this is going to be a
+                // problem, we can try something else
+                Ok(File::from_std(tokio::task::block_in_place(move || {
+                    f.reopen()
+                })?))
+            }
+            None => {
+                async {
+                    debug!("Cache miss for getting parcel. Attempting to fetch from server");
+                    let stream = self.remote.get_parcel(parsed_id, parcel_id).await?;
+                    trace!("Attempting to insert parcel data into cache");
+                    let tempfile = tokio::task::spawn_blocking(NamedTempFile::new)
+                        .await
+                        .map_err(|e| ProviderError::Other(e.to_string()))??;
+                    let handle = tempfile.as_file().try_clone()?;
+                    let mut file = File::from_std(handle);
+                    tokio::io::copy(
+                        &mut StreamReader::new(stream.map(|res| {
+                            res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                        })),
+                        &mut file,
+                    )
+                    .await?;
+
+                    // Insert the file in the cache
+                    parcels.put(parcel_id_owned, tempfile);
+                    trace!("Parcel caching successful");
+
+                    // Seek back to the beginning of the file before returning
+                    trace!("Resetting file cursor to start");
+                    file.seek(std::io::SeekFrom::Start(0)).await?;
+                    Ok::<_, ProviderError>(file)
+                }
+                .instrument(tracing::trace_span!("get_parcel_cache_miss", invoice_id = %parsed_id, parcel_id))
+                .await
+            }
+        }
+    }
+}
+
 impl<Remote> Cache for LruCache<Remote> where Remote: Provider + Send + Sync + Clone {}
 
 #[async_trait::async_trait]
@@ -91,8 +144,8 @@ where
         }
     }
 
-    #[instrument(level = "trace", skip(self, id), fields(invoice_id))]
-    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    #[instrument(level = "trace", skip(self, id, signature), fields(invoice_id))]
+    async fn yank_invoice<I>(&self, id: I, signature: Option<Signature>) -> Result<()>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>,
@@ -102,7 +155,7 @@ where
         tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
         debug!("Removing local cache entry for yanked invoice");
         self.invoices.lock().await.pop(&parsed_id);
-        self.remote.yank_invoice(parsed_id).await
+        self.remote.yank_invoice(parsed_id, signature).await
     }
 
     #[instrument(level = "trace", skip(self, bindle_id, data), fields(invoice_id))]
@@ -139,54 +192,37 @@ where
         trace!("Validating that parcel exists in invoice");
         self.validate_parcel(&parsed_id, parcel_id).await?;
 
-        let mut parcels = self.parcels.lock().await;
-        let parcel_id_owned = parcel_id.to_owned();
-        trace!("Checking for parcel {}@{} in cache", parsed_id, parcel_id);
-        let file = match parcels.get(&parcel_id_owned) {
-            Some(f) => {
-                // This forces a requirement of a multithreaded runtime, but avoids weird borrowing
-                // issues required by the static bound on `spawn_blocking`. If this is going to be a
-                // problem, we can try something else
-                File::from_std(tokio::task::block_in_place(move || f.reopen())?)
-            }
-            None => {
-                async {
-                    debug!("Cache miss for getting parcel. Attempting to fetch from server");
-                    let stream = self.remote.get_parcel(&parsed_id, parcel_id).await?;
-                    trace!("Attempting to insert parcel data into cache");
-                    let tempfile = tokio::task::spawn_blocking(NamedTempFile::new)
-                        .await
-                        .map_err(|e| ProviderError::Other(e.to_string()))??;
-                    let handle = tempfile.as_file().try_clone()?;
-                    let mut file = File::from_std(handle);
-                    tokio::io::copy(
-                        &mut StreamReader::new(stream.map(|res| {
-                            res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-                        })),
-                        &mut file,
-                    )
-                    .await?;
-
-                    // Insert the file in the cache
-                    parcels.put(parcel_id_owned, tempfile);
-                    trace!("Parcel caching successful");
-
-                    // Seek back to the beginning of the file before returning
-                    trace!("Resetting file cursor to start");
-                    file.seek(std::io::SeekFrom::Start(0)).await?;
-                    Ok::<_, ProviderError>(file)
-                }
-                .instrument(tracing::trace_span!("get_parcel_cache_miss", invoice_id = %parsed_id, parcel_id))
-                .await?
-            }
-        };
-
+        let file = self.cached_parcel(&parsed_id, parcel_id).await?;
         Ok::<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>, _>(Box::new(
             FramedRead::new(file, BytesCodec::default())
                 .map(|res| res.map(|b| b.freeze()).map_err(ProviderError::from)),
         ))
     }
 
+    #[instrument(level = "trace", skip(self, bindle_id), fields(invoice_id))]
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        trace!("Validating that parcel exists in invoice");
+        self.validate_parcel(&parsed_id, parcel_id).await?;
+
+        // The whole parcel is cached on a miss, so later ranges can be read straight from disk
+        let file = self.cached_parcel(&parsed_id, parcel_id).await?;
+        let stream = read_range(file, range).await?;
+        Ok(Box::new(stream.map(|res| {
+            res.map(|b| b.freeze()).map_err(ProviderError::from)
+        })))
+    }
+
     #[instrument(level = "trace", skip(self, bindle_id), fields(invoice_id))]
     async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
     where
@@ -207,6 +243,20 @@ where
             self.remote.parcel_exists(&parsed_id, parcel_id).instrument(tracing::trace_span!("parcel_exists_cache_miss", invoice_id = %parsed_id, parcel_id)).await
         }
     }
+
+    // The cache only ever holds a subset of what the remote has, so listing always passes through
+    // to the remote
+    async fn list_invoices(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<Invoice>> + Unpin + Send + Sync>> {
+        self.remote.list_invoices().await
+    }
+
+    async fn list_parcels(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<String>> + Unpin + Send + Sync>> {
+        self.remote.list_parcels().await
+    }
 }
 
 #[cfg(test)]
@@ -262,7 +312,7 @@ mod test {
             Ok(scaffold.invoice)
         }
 
-        async fn yank_invoice<I>(&self, _id: I) -> Result<()>
+        async fn yank_invoice<I>(&self, _id: I, _signature: Option<Signature>) -> Result<()>
         where
             I: TryInto<Id> + Send,
             I::Error: Into<ProviderError>,
@@ -322,6 +372,26 @@ mod test {
             *count += 1;
             Ok(true)
         }
+
+        async fn list_invoices(
+            &self,
+        ) -> Result<Box<dyn Stream<Item = Result<Invoice>> + Unpin + Send + Sync>> {
+            let scaffold = testing::Scaffold::load("valid_v1").await;
+            Ok(Box::new(tokio_stream::iter(vec![Ok(scaffold.invoice)])))
+        }
+
+        async fn list_parcels(
+            &self,
+        ) -> Result<Box<dyn Stream<Item = Result<String>> + Unpin + Send + Sync>> {
+            let scaffold = testing::Scaffold::load("valid_v1").await;
+            Ok(Box::new(tokio_stream::iter(
+                scaffold
+                    .parcel_files
+                    .into_values()
+                    .map(|info| Ok(info.sha))
+                    .collect::<Vec<_>>(),
+            )))
+        }
     }
 
     #[tokio::test]
@@ -372,6 +442,33 @@ mod test {
         )
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_parcel_range() {
+        // Ranges should be served from the cached parcel after the first fetch
+        let provider = TestProvider::default();
+        let cache = LruCache::new(10, provider.clone());
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        for range in [2..6, 1..3] {
+            let mut stream = cache
+                .get_parcel_range(&scaffold.invoice.bindle.id, &parcel.sha, range.clone())
+                .await
+                .expect("Should be able to get parcel range");
+            let mut data = Vec::new();
+            while let Some(chunk) = stream.next().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+            assert_eq!(&parcel.data[range.start as usize..range.end as usize], data);
+        }
+
+        let num_called = provider.get_parcel_count.lock().await;
+        assert_eq!(
+            1, *num_called,
+            "Remote store should have only been called once"
+        )
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_parcel_exists() {
         let provider = TestProvider::default();
@@ -423,7 +520,7 @@ mod test {
             .await
             .expect("Should be able to create invoice");
         cache
-            .yank_invoice("enterprise.com/warpcore/1.0.0")
+            .yank_invoice("enterprise.com/warpcore/1.0.0", None)
             .await
             .expect("Should be able to yank invoice");
         let parcel_info = scaffold.parcel_files.get("parcel").unwrap();
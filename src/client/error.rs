@@ -68,6 +68,14 @@ pub enum ClientError {
     /// There was an error with the signature on an invoice
     #[error("Signature error")]
     SignatureError(#[from] crate::invoice::signature::SignatureError),
+    /// An invoice fetched from the server failed verification with the keyring and strategy the
+    /// client was configured with. Contains the reason it failed
+    #[error("Invoice failed verification: {0}")]
+    VerificationFailed(#[source] crate::invoice::signature::SignatureError),
+    /// The server returned a different invoice than the one that was requested, or a yanked invoice
+    /// when yanked invoices weren't asked for. Contains a description of what was wrong
+    #[error("Server returned an unexpected invoice: {0}")]
+    UnexpectedInvoice(String),
 
     /// A catch-all for uncategorized errors. Contains an error message describing the underlying
     /// issue
@@ -5,8 +5,10 @@ mod error;
 pub mod load;
 pub mod tokens;
 
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::path::Path;
+use std::sync::Arc;
 
 use reqwest::header::{self, HeaderMap};
 use reqwest::Client as HttpClient;
@@ -15,9 +17,10 @@ use tokio_stream::{Stream, StreamExt};
 use tracing::{debug, info, instrument, trace};
 use url::Url;
 
-use crate::provider::{Provider, ProviderError};
+use crate::invoice::signature::KeyRing;
+use crate::provider::{Provider, ProviderError, LIST_BUFFER_SIZE};
 use crate::verification::Verified;
-use crate::{Id, Signed};
+use crate::{Id, Signature, Signed, VerificationStrategy};
 
 pub use error::ClientError;
 
@@ -27,21 +30,46 @@ pub type Result<T> = std::result::Result<T, ClientError>;
 pub const INVOICE_ENDPOINT: &str = "_i";
 pub const QUERY_ENDPOINT: &str = "_q";
 pub const RELATIONSHIP_ENDPOINT: &str = "_r";
+pub const KEYS_ENDPOINT: &str = "_k";
 pub const LOGIN_ENDPOINT: &str = "login";
+/// The path segment after an invoice ID for adding signatures to it
+pub const SIGNATURES_PATH: &str = "signatures";
+/// The number of invoices to request per query when listing all invoices
+const LIST_PAGE_SIZE: u8 = 100;
 const TOML_MIME_TYPE: &str = "application/toml";
 
 /// A client type for interacting with a Bindle server
-#[derive(Clone)]
 pub struct Client<T> {
     client: HttpClient,
     base_url: Url,
-    token_manager: T,
+    // This is an Arc so the client can be cloned (e.g. into a spawned task) even if the token
+    // manager can't be
+    token_manager: Arc<T>,
+    verification: Option<Arc<Verification>>,
+}
+
+impl<T> Clone for Client<T> {
+    fn clone(&self) -> Self {
+        Client {
+            client: self.client.clone(),
+            base_url: self.base_url.clone(),
+            token_manager: Arc::clone(&self.token_manager),
+            verification: self.verification.clone(),
+        }
+    }
+}
+
+/// The keyring and strategy used to verify fetched invoices
+struct Verification {
+    keyring: KeyRing,
+    strategy: VerificationStrategy,
 }
 
 /// The operation being performed against a Bindle server.
 enum Operation {
     Create,
     Yank,
+    Sign,
     Get,
     Query,
     Login,
@@ -51,6 +79,8 @@ enum Operation {
 pub struct ClientBuilder {
     http2_prior_knowledge: bool,
     danger_accept_invalid_certs: bool,
+    keyring: Option<KeyRing>,
+    verification_strategy: Option<VerificationStrategy>,
 }
 
 impl Default for ClientBuilder {
@@ -58,6 +88,8 @@ impl Default for ClientBuilder {
         Self {
             http2_prior_knowledge: false,
             danger_accept_invalid_certs: false,
+            keyring: None,
+            verification_strategy: None,
         }
     }
 }
@@ -77,6 +109,23 @@ impl ClientBuilder {
         self
     }
 
+    /// Sets the keyring used to verify every invoice fetched from the server before it is returned,
+    /// including the ones returned by queries. Invoices that fail verification are returned as a
+    /// [`VerificationFailed`](ClientError::VerificationFailed) error. Invoices are not verified
+    /// unless a keyring is set
+    pub fn keyring(mut self, keyring: KeyRing) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Sets the strategy used to verify fetched invoices. This has no effect unless a
+    /// [`keyring`](ClientBuilder::keyring) is also set. Defaults to the default
+    /// [`VerificationStrategy`]
+    pub fn verification_strategy(mut self, strategy: VerificationStrategy) -> Self {
+        self.verification_strategy = Some(strategy);
+        self
+    }
+
     /// Returns a new Client with the given URL and token manager, configured using the set options.
     ///
     /// This URL should be the FQDN plus any namespacing (like `v1`). So if you were running a
@@ -93,10 +142,16 @@ impl ClientBuilder {
             .build()
             .map_err(|e| ClientError::Other(e.to_string()))?;
 
+        let strategy = self.verification_strategy.unwrap_or_default();
+        let verification = self
+            .keyring
+            .map(|keyring| Arc::new(Verification { keyring, strategy }));
+
         Ok(Client {
             client,
             base_url: base_parsed,
-            token_manager,
+            token_manager: Arc::new(token_manager),
+            verification,
         })
     }
 }
@@ -211,7 +266,11 @@ impl<T: tokens::TokenManager> Client<T> {
     /// Returns the requested invoice from the bindle server if it exists. This can take any form
     /// that can convert into the `Id` type, but generally speaking, this is the canonical name of
     /// the bindle (e.g. `example.com/foo/1.0.0`). If you want to fetch a yanked invoice, use the
-    /// [`get_yanked_invoice`](Client::get_yanked_invoice) function
+    /// [`get_yanked_invoice`](Client::get_yanked_invoice) function.
+    ///
+    /// If the client was built with a [`keyring`](ClientBuilder::keyring), the invoice is verified
+    /// before it is returned. An invoice that isn't the one requested, or is yanked, is always
+    /// rejected with [`ClientError::UnexpectedInvoice`]
     #[instrument(level = "trace", skip(self, id), fields(invoice_id))]
     pub async fn get_invoice<I>(&self, id: I) -> Result<crate::Invoice>
     where
@@ -220,11 +279,10 @@ impl<T: tokens::TokenManager> Client<T> {
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
         tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
-        self.get_invoice_request(
-            self.base_url
-                .join(&format!("{}/{}", INVOICE_ENDPOINT, parsed_id))?,
-        )
-        .await
+        let url = self
+            .base_url
+            .join(&format!("{}/{}", INVOICE_ENDPOINT, parsed_id))?;
+        self.get_invoice_request(url, &parsed_id, false).await
     }
 
     /// Same as `get_invoice` but allows you to fetch a yanked invoice
@@ -240,21 +298,57 @@ impl<T: tokens::TokenManager> Client<T> {
             .base_url
             .join(&format!("{}/{}", INVOICE_ENDPOINT, parsed_id))?;
         url.set_query(Some("yanked=true"));
-        self.get_invoice_request(url).await
+        self.get_invoice_request(url, &parsed_id, true).await
     }
 
-    async fn get_invoice_request(&self, url: Url) -> Result<crate::Invoice> {
+    async fn get_invoice_request(
+        &self,
+        url: Url,
+        id: &Id,
+        allow_yanked: bool,
+    ) -> Result<crate::Invoice> {
         let req = self.client.get(url);
         let req = self.token_manager.apply_auth_header(req).await?;
         trace!(?req);
         let resp = req.send().await?;
         let resp = unwrap_status(resp, Endpoint::Invoice, Operation::Get).await?;
-        Ok(toml::from_slice(&resp.bytes().await?)?)
+        let inv: crate::Invoice = toml::from_slice(&resp.bytes().await?)?;
+        // A valid signature only means the invoice is genuine, not that it is the one we asked for
+        if inv.bindle.id != *id {
+            info!(requested = %id, returned = %inv.bindle.id, "Server returned a different invoice");
+            return Err(ClientError::UnexpectedInvoice(format!(
+                "requested {} but got {}",
+                id, inv.bindle.id
+            )));
+        }
+        if !allow_yanked && inv.yanked.unwrap_or_default() {
+            return Err(ClientError::UnexpectedInvoice(format!("{} is yanked", id)));
+        }
+        self.verify_invoice(inv)
+    }
+
+    /// Verifies the invoice if the client is configured to do so
+    fn verify_invoice(&self, inv: crate::Invoice) -> Result<crate::Invoice> {
+        let verification = match self.verification.as_ref() {
+            Some(v) => v,
+            None => return Ok(inv),
+        };
+        let id = inv.bindle.id.clone();
+        match verification.strategy.verify(inv, &verification.keyring) {
+            Ok(verified) => Ok(verified.into()),
+            Err(e) => {
+                info!(%id, error = %e, "Fetched invoice failed verification");
+                Err(ClientError::VerificationFailed(e))
+            }
+        }
     }
 
     //////////////// Query Invoice ////////////////
 
-    /// Queries the bindle server for matching invoices as specified by the given query options
+    /// Queries the bindle server for matching invoices as specified by the given query options.
+    ///
+    /// If the client was built with a [`keyring`](ClientBuilder::keyring), every returned invoice is
+    /// verified, and the query fails if any of them don't pass
     #[instrument(level = "trace", skip(self))]
     pub async fn query_invoices(
         &self,
@@ -268,7 +362,13 @@ impl<T: tokens::TokenManager> Client<T> {
         trace!(?req);
         let resp = req.send().await?;
         let resp = unwrap_status(resp, Endpoint::Query, Operation::Query).await?;
-        Ok(toml::from_slice(&resp.bytes().await?)?)
+        let mut matches: crate::search::Matches = toml::from_slice(&resp.bytes().await?)?;
+        matches.invoices = matches
+            .invoices
+            .into_iter()
+            .map(|inv| self.verify_invoice(inv))
+            .collect::<Result<_>>()?;
+        Ok(matches)
     }
 
     //////////////// Yank Invoice ////////////////
@@ -296,6 +396,43 @@ impl<T: tokens::TokenManager> Client<T> {
         Ok(())
     }
 
+    //////////////// Add Signatures ////////////////
+
+    /// Adds the given signatures to an invoice that already exists on the bindle server, returning
+    /// the updated invoice. This is how an approver can sign a bindle after it was uploaded.
+    ///
+    /// Each signature must be made over the invoice as it is stored on the server (for example, by
+    /// signing the result of [`get_invoice`](Client::get_invoice)) by a key in the server's keyring
+    #[instrument(level = "trace", skip(self, id, signatures), fields(invoice_id, signatures = signatures.len()))]
+    pub async fn add_signatures<I>(
+        &self,
+        id: I,
+        signatures: Vec<Signature>,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        let body = crate::AddSignaturesRequest {
+            signature: signatures,
+        };
+        let req = self
+            .client
+            .post(self.base_url.join(&format!(
+                "{}/{}/{}",
+                INVOICE_ENDPOINT, parsed_id, SIGNATURES_PATH
+            ))?)
+            .header(header::CONTENT_TYPE, TOML_MIME_TYPE)
+            .body(toml::to_vec(&body)?);
+        let req = self.token_manager.apply_auth_header(req).await?;
+        trace!(?req);
+        let resp = req.send().await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice, Operation::Sign).await?;
+        Ok(toml::from_slice(&resp.bytes().await?)?)
+    }
+
     //////////////// Create Parcel ////////////////
 
     /// Creates the given parcel using the SHA and the raw parcel data to upload to the server.
@@ -439,7 +576,66 @@ impl<T: tokens::TokenManager> Client<T> {
         Ok(resp.bytes_stream().map(|r| r.map_err(|e| e.into())))
     }
 
+    /// Returns the requested parcel as a stream of bytes starting at the given offset. This is
+    /// useful for resuming an interrupted download without fetching the data that was already
+    /// received. If the server does not support range requests, the skipped data is still
+    /// downloaded but is not returned
+    #[instrument(level = "trace", skip(self, bindle_id), fields(invoice_id))]
+    pub async fn get_parcel_stream_from<I>(
+        &self,
+        bindle_id: I,
+        sha: &str,
+        offset: u64,
+    ) -> Result<impl Stream<Item = Result<bytes::Bytes>>>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        self.get_parcel_range_stream(&parsed_id, sha, offset..u64::MAX)
+            .await
+    }
+
+    /// Fetches the given range of a parcel. An end of `u64::MAX` reads to the end of the parcel
+    async fn get_parcel_range_stream(
+        &self,
+        bindle_id: &Id,
+        sha: &str,
+        range: std::ops::Range<u64>,
+    ) -> Result<impl Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync> {
+        let range_header = match range.end {
+            u64::MAX => format!("bytes={}-", range.start),
+            end => format!("bytes={}-{}", range.start, end.saturating_sub(1)),
+        };
+        let resp = self
+            .parcel_request(bindle_id, sha, |req| {
+                req.header(header::RANGE, range_header)
+            })
+            .await?;
+        // A server that doesn't support ranges sends the whole parcel, so we have to skip to the
+        // start of the range ourselves
+        let local_range = if resp.status() == StatusCode::PARTIAL_CONTENT {
+            0..range.end.saturating_sub(range.start)
+        } else {
+            range
+        };
+        Ok(crate::provider::slice_stream(
+            resp.bytes_stream().map(|r| r.map_err(ClientError::from)),
+            local_range,
+        ))
+    }
+
     async fn get_parcel_request(&self, bindle_id: &Id, sha: &str) -> Result<reqwest::Response> {
+        self.parcel_request(bindle_id, sha, |req| req).await
+    }
+
+    async fn parcel_request(
+        &self,
+        bindle_id: &Id,
+        sha: &str,
+        customize: impl FnOnce(RequestBuilder) -> RequestBuilder,
+    ) -> Result<reqwest::Response> {
         // Override the default accept header
         let req = self
             .client
@@ -449,7 +645,7 @@ impl<T: tokens::TokenManager> Client<T> {
                     .unwrap(),
             )
             .header(header::ACCEPT, "*/*");
-        let req = self.token_manager.apply_auth_header(req).await?;
+        let req = self.token_manager.apply_auth_header(customize(req)).await?;
         trace!(?req);
         let resp = req.send().await?;
         unwrap_status(resp, Endpoint::Parcel, Operation::Get).await
@@ -479,6 +675,21 @@ impl<T: tokens::TokenManager> Client<T> {
         let resp = unwrap_status(resp, Endpoint::Invoice, Operation::Get).await?;
         Ok(toml::from_slice::<crate::MissingParcelsResponse>(&resp.bytes().await?)?.missing)
     }
+
+    //////////////// Keys ////////////////
+
+    /// Fetches the public keys the server signs with as the host, with their labels signed by the
+    /// keys. The keys are returned as a keyring, but nothing has been done to establish whether
+    /// they should be trusted
+    #[instrument(level = "trace", skip(self))]
+    pub async fn get_host_keys(&self) -> Result<KeyRing> {
+        let req = self.client.get(self.base_url.join(KEYS_ENDPOINT)?);
+        let req = self.token_manager.apply_auth_header(req).await?;
+        trace!(?req);
+        let resp = req.send().await?;
+        let resp = unwrap_status(resp, Endpoint::Keys, Operation::Get).await?;
+        Ok(toml::from_slice::<KeyRing>(&resp.bytes().await?)?)
+    }
 }
 
 // We implement provider for client because often times (such as in the CLI) we are composing the
@@ -508,11 +719,16 @@ impl<T: tokens::TokenManager + Send + Sync + 'static> Provider for Client<T> {
             .map_err(|e| e.into())
     }
 
-    async fn yank_invoice<I>(&self, id: I) -> crate::provider::Result<()>
+    async fn yank_invoice<I>(
+        &self,
+        id: I,
+        _signature: Option<Signature>,
+    ) -> crate::provider::Result<()>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>,
     {
+        // The server generates its own yank signature, so there is no need to send one
         // Parse the ID now because the error type constraint doesn't match that of the client
         let parsed_id = id.try_into().map_err(|e| e.into())?;
         self.yank_invoice(parsed_id).await.map_err(|e| e.into())
@@ -554,6 +770,29 @@ impl<T: tokens::TokenManager + Send + Sync + 'static> Provider for Client<T> {
         Ok(Box::new(stream.map(|res| res.map_err(|e| e.into()))))
     }
 
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: std::ops::Range<u64>,
+    ) -> crate::provider::Result<
+        Box<dyn Stream<Item = crate::provider::Result<bytes::Bytes>> + Unpin + Send + Sync>,
+    >
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        // An empty range can't be expressed as a range header, so there is nothing to fetch
+        if range.start >= range.end {
+            return Ok(Box::new(tokio_stream::empty()));
+        }
+        let stream = self
+            .get_parcel_range_stream(&parsed_id, parcel_id, range)
+            .await?;
+        Ok(Box::new(stream.map(|res| res.map_err(|e| e.into()))))
+    }
+
     async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> crate::provider::Result<bool>
     where
         I: TryInto<Id> + Send,
@@ -582,6 +821,88 @@ impl<T: tokens::TokenManager + Send + Sync + 'static> Provider for Client<T> {
             })),
         }
     }
+
+    /// Lists all invoices by paging through an empty query that includes yanked invoices. This
+    /// means the results depend on the search engine used by the server. Invoices created while the
+    /// listing is in progress may be skipped or returned twice
+    async fn list_invoices(
+        &self,
+    ) -> crate::provider::Result<
+        Box<dyn Stream<Item = crate::provider::Result<crate::Invoice>> + Unpin + Send + Sync>,
+    > {
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER_SIZE);
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut offset = 0;
+            loop {
+                let matches = match client.query_invoices(list_query(offset)).await {
+                    Ok(m) => m,
+                    Err(e) => {
+                        let _ = tx.send(Err(e.into())).await;
+                        break;
+                    }
+                };
+                offset += matches.invoices.len() as u64;
+                for inv in matches.invoices {
+                    if tx.send(Ok(inv)).await.is_err() {
+                        return;
+                    }
+                }
+                if !matches.more {
+                    break;
+                }
+            }
+        });
+        Ok(Box::new(tokio_stream::wrappers::ReceiverStream::new(rx)))
+    }
+
+    /// The server has no way to list parcels directly, so this lists the parcels referenced by
+    /// every invoice from [`list_invoices`](Provider::list_invoices) that exist on the server
+    async fn list_parcels(
+        &self,
+    ) -> crate::provider::Result<
+        Box<dyn Stream<Item = crate::provider::Result<String>> + Unpin + Send + Sync>,
+    > {
+        let mut invoices = Provider::list_invoices(self).await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER_SIZE);
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut seen = HashSet::new();
+            while let Some(res) = invoices.next().await {
+                let inv = match res {
+                    Ok(inv) => inv,
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        continue;
+                    }
+                };
+                for label in inv.parcel.into_iter().flatten().map(|p| p.label) {
+                    if !seen.insert(label.sha256.clone()) {
+                        continue;
+                    }
+                    let res = match client.parcel_exists(&inv.bindle.id, &label.sha256).await {
+                        Ok(true) => Ok(label.sha256),
+                        Ok(false) => continue,
+                        Err(e) => Err(e),
+                    };
+                    if tx.send(res).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Box::new(tokio_stream::wrappers::ReceiverStream::new(rx)))
+    }
+}
+
+/// Returns the query options for fetching the page of all invoices at the given offset
+fn list_query(offset: u64) -> crate::QueryOptions {
+    crate::QueryOptions {
+        offset: Some(offset),
+        limit: Some(LIST_PAGE_SIZE),
+        yanked: Some(true),
+        ..Default::default()
+    }
 }
 
 // A helper function and related enum to make some reusable code for unwrapping a status code and returning the right error
@@ -590,6 +911,7 @@ enum Endpoint {
     Invoice,
     Parcel,
     Query,
+    Keys,
     // NOTE: This endpoint currently does nothing, but if we need more specific errors, we can use
     // this down the line
     Login,
@@ -602,6 +924,7 @@ async fn unwrap_status(
 ) -> Result<reqwest::Response> {
     match (resp.status(), endpoint) {
         (StatusCode::OK, _) => Ok(resp),
+        (StatusCode::PARTIAL_CONTENT, Endpoint::Parcel) => Ok(resp),
         (StatusCode::ACCEPTED, Endpoint::Invoice) => Ok(resp),
         (StatusCode::CREATED, Endpoint::Invoice) => Ok(resp),
         (StatusCode::NOT_FOUND, Endpoint::Invoice) | (StatusCode::FORBIDDEN, Endpoint::Invoice) => {
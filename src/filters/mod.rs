@@ -55,7 +55,7 @@
 //!    "#;
 //! let inv: bindle::Invoice = toml::from_str(toml).expect("test invoice parsed");
 //!
-//! let filter = BindleFilter::new(&inv).filter();
+//! let filter = BindleFilter::new(&inv).filter().expect("parcels resolved");
 //! assert_eq!(1, filter.len());
 //! ```
 //!
@@ -100,12 +100,75 @@
 //! let inv: bindle::Invoice = toml::from_str(toml).expect("test invoice parsed");
 //! let filter = BindleFilter::new(&inv)
 //!     .activate_feature("testing", "animal", "narwhal")
-//!     .filter();
+//!     .filter()
+//!     .expect("parcels resolved");
 //! assert_eq!(2, filter.len());
 //! ```
-use std::collections::HashSet;
-
-use crate::{Invoice, Parcel};
+//!
+//! Groups with a `satisfiedBy` of `oneOf` only need one of their parcels. By default, the first
+//! parcel in the group is chosen, but a specific parcel can be selected instead:
+//!
+//! ```
+//! use bindle::filters::BindleFilter;
+//! let toml = r#"
+//! bindleVersion = "1.0.0"
+//! [bindle]
+//! name = "test/one-of"
+//! version = "0.1.0"
+//!
+//! [[group]]
+//! name = "cli"
+//! satisfiedBy = "oneOf"
+//! required = true
+//!
+//! [[parcel]]
+//! [parcel.label]
+//! name = "cli-linux"
+//! sha256 = "12345"
+//! mediaType = "application/octet-stream"
+//! size = 123
+//! [parcel.conditions]
+//! memberOf = ["cli"]
+//!
+//! [[parcel]]
+//! [parcel.label]
+//! name = "cli-windows"
+//! sha256 = "5432"
+//! mediaType = "application/octet-stream"
+//! size = 321
+//! [parcel.conditions]
+//! memberOf = ["cli"]
+//! "#;
+//! let inv: bindle::Invoice = toml::from_str(toml).expect("test invoice parsed");
+//! let filter = BindleFilter::new(&inv)
+//!     .select_parcel("cli", "cli-windows")
+//!     .filter()
+//!     .expect("parcels resolved");
+//! assert_eq!(1, filter.len());
+//! assert_eq!("cli-windows", filter[0].label.name);
+//! ```
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use thiserror::Error;
+
+use crate::{Invoice, Parcel, SatisfiedBy};
+
+/// Errors that can occur when resolving the parcels for a bindle
+#[derive(Error, Debug)]
+pub enum FilterError {
+    /// A group has a `satisfiedBy` value that is not defined in the spec
+    #[error("group `{0}` has an invalid satisfiedBy value `{1}`")]
+    InvalidSatisfiedBy(String, String),
+    /// A parcel was selected for a group that does not exist in the invoice
+    #[error("group `{0}` does not exist")]
+    UnknownGroup(String),
+    /// A selected parcel is not an enabled member of the group it was selected for
+    #[error("parcel `{1}` is not an enabled member of group `{0}`")]
+    InvalidSelection(String, String),
+    /// A required group has no enabled parcels that can satisfy it
+    #[error("the requirements for group `{0}` cannot be satisfied")]
+    Unsatisfiable(String),
+}
 
 /// A convenience representation of a feature as a member of a group with a name/value
 /// pair attached.
@@ -133,6 +196,7 @@ pub struct BindleFilter<'a> {
     exclude_groups: HashSet<String>,
     features: Vec<FeatureReference>,
     exclude_features: Vec<FeatureReference>,
+    selections: HashMap<String, Vec<String>>,
 }
 
 impl<'a> BindleFilter<'a> {
@@ -143,6 +207,7 @@ impl<'a> BindleFilter<'a> {
             exclude_groups: HashSet::new(),
             features: vec![],
             exclude_features: vec![],
+            selections: HashMap::new(),
         }
     }
     /// Explicitly enable the given group.
@@ -207,6 +272,20 @@ impl<'a> BindleFilter<'a> {
         self
     }
 
+    /// Select a parcel (by its label name) to use for the given group.
+    ///
+    /// For a `oneOf` group, the selected parcels are used to satisfy the group instead of the
+    /// default choice. For an `optional` group, only selected parcels are included. This may be
+    /// called multiple times to select more than one parcel. Selections have no effect on `allOf`
+    /// groups, as all of their parcels are always included.
+    pub fn select_parcel(&mut self, group_name: &str, parcel_name: &str) -> &mut Self {
+        self.selections
+            .entry(group_name.to_owned())
+            .or_default()
+            .push(parcel_name.to_owned());
+        self
+    }
+
     /// Determine whether a given parcel should be disabled according to the filter.
     fn is_disabled(&self, parcel: &Parcel) -> bool {
         match &parcel.label.feature {
@@ -239,148 +318,154 @@ impl<'a> BindleFilter<'a> {
         }
     }
 
+    /// Resolve the list of parcels for the invoice, returned in the order they appear in the
+    /// invoice.
+    ///
+    /// Groups are resolved according to their `satisfiedBy` field:
+    ///
+    /// - `allOf` (the default): every enabled parcel in the group is included
+    /// - `oneOf`: the parcels selected with [`select_parcel`](Self::select_parcel) are included.
+    ///   If there is no selection, a parcel that is already included for another reason satisfies
+    ///   the group. Otherwise, the first enabled parcel in the group (in invoice order) is chosen
+    /// - `optional`: only the parcels selected with [`select_parcel`](Self::select_parcel) are
+    ///   included
+    ///
+    /// An error is returned if a group has an unknown `satisfiedBy` value, if a selection does not
+    /// match an enabled member of its group, or if a `oneOf` group has no enabled parcels to choose
+    /// from.
     // Do we filter media types, too?
     // Do we filter by size?
-    pub fn filter(&self) -> Vec<Parcel> {
+    pub fn filter(&self) -> Result<Vec<Parcel>, FilterError> {
+        let invoice: &'a Invoice = self.invoice;
+        let parcels = invoice.parcel.as_deref().unwrap_or_default();
+        let group_list = invoice.group.as_deref().unwrap_or_default();
+
+        let mut satisfaction: HashMap<&str, SatisfiedBy> = HashMap::new();
+        for g in group_list {
+            let satisfied_by = g.satisfaction().map_err(|_| {
+                FilterError::InvalidSatisfiedBy(
+                    g.name.clone(),
+                    g.satisfied_by.clone().unwrap_or_default(),
+                )
+            })?;
+            satisfaction.insert(g.name.as_str(), satisfied_by);
+        }
+
+        // Make sure all selections point at a valid group before doing anything else
+        if let Some(group) = self.selections.keys().find(|g| !self.invoice.has_group(g)) {
+            return Err(FilterError::UnknownGroup(group.clone()));
+        }
+
+        let mut included = vec![false; parcels.len()];
+        // Groups that are waiting to be resolved. `allOf` groups are resolved before any others
+        // so that a parcel they include can be used to satisfy a `oneOf` group.
+        let mut pending: VecDeque<&str> = VecDeque::new();
+        let mut deferred: VecDeque<&str> = VecDeque::new();
+        // Groups that have already been queued. This is what keeps us from recursing infinitely
+        // on circular dependencies, since all relationships are proxied through a layer of group
+        // indirection.
+        let mut seen: HashSet<&str> = HashSet::new();
+
+        let mut enqueue =
+            |name: &'a str, pending: &mut VecDeque<&'a str>, deferred: &mut VecDeque<&'a str>| {
+                if !seen.insert(name) {
+                    return;
+                }
+                match satisfaction.get(name).copied().unwrap_or_default() {
+                    SatisfiedBy::AllOf => pending.push_back(name),
+                    _ => deferred.push_back(name),
+                }
+            };
+
         // First we need to find all of the groups that should be enabled. These can be
         // enabled because of their 'required' flag or because they are in the the
         // 'groups' set on this struct. This is a special pass over groups because it
         // must take into account the 'required' flag. Subsequent passes do not.
-        let mut groups: HashSet<String> = match &self.invoice.group {
-            Some(group) => {
-                group
-                    .iter()
-                    .filter(|&i| {
-                        // Skip any group explicitly in the exclude list
-                        // FIXME: Do we really want to allow this as an override to
-                        // a required group?
-                        if self.exclude_groups.contains(&i.name) {
-                            return false;
-                        }
-                        i.required.unwrap_or(false) || self.groups.contains(&i.name)
-                    })
-                    .map(|g| g.name.clone())
-                    .collect()
+        for g in group_list {
+            // Skip any group explicitly in the exclude list
+            // FIXME: Do we really want to allow this as an override to
+            // a required group?
+            if self.exclude_groups.contains(&g.name) {
+                continue;
             }
-            // If there are no groups, then our current list of parcels is complete.
-            None => HashSet::new(),
-        };
-
-        // Build a list of parcels that are enabled by default, or enabled because a group
-        // is enabled.
-        let zero_vec = Vec::with_capacity(0);
-        let mut parcels: HashSet<Parcel> = self
-            .invoice
-            .parcel
-            .as_ref()
-            .unwrap_or(&zero_vec)
-            .iter()
-            .filter(|p| {
-                // Filter out any parcels that are not part of the global group or one
-                // of the enabled groups.
-                // If conditions is None or conditions.member_of is None, then this parcel
-                // is a member of the global group.
-                p.conditions
-                    .as_ref()
-                    .map(|c| {
-                        match &c.member_of {
-                            // In the global group
-                            None => true,
-                            // In an enabled group
-                            Some(gnames) => gnames.iter().any(|n| groups.contains(n)),
-                        }
-                    })
-                    .unwrap_or(true) // No conditions means parcel is in global group
-            })
-            .filter(|p| !self.is_disabled(p))
-            .cloned()
-            .collect();
-
-        // Loop through the parcels and see if any of them require in more groups.
-        // If so, descend down that tree and add parcels.
-        let dependencies: HashSet<Parcel> = parcels.iter().fold(HashSet::new(), |mut deps, p| {
-            if let Some(extras) = self.walk_parcel_requires(p, &mut groups) {
-                deps.extend(extras)
+            if g.required.unwrap_or(false) || self.groups.contains(&g.name) {
+                enqueue(g.name.as_str(), &mut pending, &mut deferred);
             }
-            deps
-        });
-
-        // Add all of the dependencies to the main parcel list.
-        parcels.extend(dependencies);
-
-        // Collect it into a Vec
-        parcels.into_iter().collect()
-    }
+        }
 
-    /// Given a parcel, get a list of all of the parcels that are required via groups.
-    ///
-    /// A parcel can require zero or more groups. And a group can container zero or more
-    /// parcels. This function takes a parcel and a list of already-resolved groups. Then
-    /// for every group this parcel requires, it will find all the parcels that are members
-    /// of that group and return them.
-    ///
-    /// This is recursive. If Parcel A requires Group 1, Group 1 requires Parcel B, and Parcel B
-    /// requires Group 2, this function will walk down the tree and return a combined list
-    /// of all of the parcels that are required to satisfy the top-level parcel's requirements.
-    fn walk_parcel_requires(
-        &self,
-        p: &Parcel,
-        groupset: &mut HashSet<String>,
-    ) -> Option<HashSet<Parcel>> {
-        // By passing in the group set, we should be able to prevent direct or indirect
-        // infinite recursion, since all relationships are proxied through the a layer
-        // of group indirection.
-
-        let mut ret: HashSet<Parcel> = HashSet::new();
-        // For each parcel in this set, see if it has required groups.
-        if let Some(c) = p.conditions.as_ref() {
-            if let Some(req) = &c.requires {
-                // Loop through the list of required groups and add any that are not present.
-                req.iter().for_each(|r| {
-                    // If the groupset does not already have this group, then we can skip
-                    // it.
-                    if groupset.contains(r) {
-                        return;
-                    }
-                    // Otherwise, we mark this group as processed before we recurse down
-                    // to children. This prevents infinite recursion.
-                    groupset.insert(r.to_owned());
-                    // Loop through parcels to find ones in this group.
-                    if let Some(pvec) = self.invoice.parcel.as_ref() {
-                        pvec.iter().for_each(|p| {
-                            // Loop through the member_of data and see if this
-                            // parcel is a member of the r group.
-                            if let Some(c) = p.conditions.as_ref() {
-                                if let Some(groups) = &c.member_of {
-                                    if groups.iter().any(|g| g == r) {
-                                        // Check to see if this parcel should be disabled.
-                                        // If so, skip it and all of its children.
-                                        if self.is_disabled(p) {
-                                            return;
-                                        }
-
-                                        ret.insert(p.clone());
-
-                                        // Now recurse on this parcel
-                                        let sub = self.walk_parcel_requires(p, groupset);
-                                        if let Some(extras) = sub {
-                                            ret.extend(extras)
-                                        }
-                                    }
-                                }
-                            }
-                        });
-                    }
-                })
+        // All parcels in the global group are included unless they are disabled
+        for (i, p) in parcels.iter().enumerate() {
+            if p.is_global_group() && !self.is_disabled(p) {
+                included[i] = true;
+                requires_of(p).for_each(|r| enqueue(r, &mut pending, &mut deferred));
             }
         }
-        if ret.is_empty() {
-            return None;
+
+        while let Some(name) = pending.pop_front().or_else(|| deferred.pop_front()) {
+            let members: Vec<usize> = parcels
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| p.member_of(name) && !self.is_disabled(p))
+                .map(|(i, _)| i)
+                .collect();
+
+            let chosen: Vec<usize> = match satisfaction.get(name).copied().unwrap_or_default() {
+                SatisfiedBy::AllOf => members,
+                mode => match self.selections.get(name) {
+                    Some(selected) => selected
+                        .iter()
+                        .map(|parcel_name| {
+                            members
+                                .iter()
+                                .copied()
+                                .find(|i| parcels[*i].label.name == *parcel_name)
+                                .ok_or_else(|| {
+                                    FilterError::InvalidSelection(
+                                        name.to_owned(),
+                                        parcel_name.clone(),
+                                    )
+                                })
+                        })
+                        .collect::<Result<_, _>>()?,
+                    None if mode == SatisfiedBy::Optional => Vec::new(),
+                    // A oneOf group is already satisfied if one of its members was included
+                    None if members.iter().any(|i| included[*i]) => Vec::new(),
+                    None => match members.first() {
+                        Some(i) => vec![*i],
+                        None => return Err(FilterError::Unsatisfiable(name.to_owned())),
+                    },
+                },
+            };
+
+            for i in chosen {
+                if included[i] {
+                    continue;
+                }
+                included[i] = true;
+                requires_of(&parcels[i]).for_each(|r| enqueue(r, &mut pending, &mut deferred));
+            }
         }
-        Some(ret)
+
+        // Collect the parcels in invoice order, skipping any duplicate entries
+        let mut unique: HashSet<&Parcel> = HashSet::new();
+        Ok(parcels
+            .iter()
+            .zip(included)
+            .filter(|(p, inc)| *inc && unique.insert(p))
+            .map(|(p, _)| p.clone())
+            .collect())
     }
 }
 
+/// Returns an iterator over the names of all groups required by the given parcel
+fn requires_of(p: &Parcel) -> impl Iterator<Item = &str> {
+    p.conditions
+        .iter()
+        .filter_map(|c| c.requires.as_ref())
+        .flatten()
+        .map(|s| s.as_str())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -443,7 +528,7 @@ mod test {
         let inv: crate::Invoice = toml::from_str(toml).expect("test invoice parsed");
         // If we leave everything on, we should get two bindles. The two should be members
         // of the global group.
-        let filter = BindleFilter::new(&inv).filter();
+        let filter = BindleFilter::new(&inv).filter().expect("parcels resolved");
         assert_eq!(2, filter.len());
     }
 
@@ -477,7 +562,7 @@ mod test {
         let inv: crate::Invoice = toml::from_str(toml).expect("test invoice parsed");
         // If we leave everything on, we should get two bindles.
         {
-            let filter = BindleFilter::new(&inv).filter();
+            let filter = BindleFilter::new(&inv).filter().expect("parcels resolved");
             assert_eq!(2, filter.len());
         }
 
@@ -485,7 +570,8 @@ mod test {
         {
             let filter = BindleFilter::new(&inv)
                 .deactivate_feature("testing", "disabled", "true")
-                .filter();
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(1, filter.len());
         }
 
@@ -494,7 +580,8 @@ mod test {
             let filter = BindleFilter::new(&inv)
                 .deactivate_feature("testing", "disabled", "true")
                 .activate_feature("testing", "disabled", "true")
-                .filter();
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(1, filter.len());
         }
 
@@ -502,7 +589,8 @@ mod test {
         {
             let filter = BindleFilter::new(&inv)
                 .deactivate_feature("testing", "disabled", "false")
-                .filter();
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(2, filter.len());
         }
     }
@@ -549,7 +637,7 @@ mod test {
 
         // By default, we should get all three bindles, since they are all in global.
         {
-            let filter = BindleFilter::new(&inv).filter();
+            let filter = BindleFilter::new(&inv).filter().expect("parcels resolved");
             assert_eq!(3, filter.len());
         }
 
@@ -558,7 +646,8 @@ mod test {
         {
             let filter = BindleFilter::new(&inv)
                 .activate_feature("testing", "animal", "narwhal")
-                .filter();
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(2, filter.len());
 
             // We need to make sure that we got the narwhal.
@@ -572,7 +661,8 @@ mod test {
             let filter = BindleFilter::new(&inv)
                 .activate_feature("testing", "animal", "narwhal")
                 .deactivate_feature("testing", "animal", "narwhal")
-                .filter();
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(1, filter.len());
         }
 
@@ -582,7 +672,8 @@ mod test {
             let filter = BindleFilter::new(&inv)
                 .activate_feature("testing", "animal", "narwhal")
                 .activate_feature("testing", "animal", "unicorn")
-                .filter();
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(2, filter.len());
             assert!(filter.iter().any(|p| p.label.name == "unicorn_handler"));
         }
@@ -638,13 +729,16 @@ mod test {
 
         // Check that by default we have one parcel, because group is required.
         {
-            let filter = BindleFilter::new(&inv).filter();
+            let filter = BindleFilter::new(&inv).filter().expect("parcels resolved");
             assert_eq!(1, filter.len());
         }
 
         // Activating one group should get us an additional parcel
         {
-            let filter = BindleFilter::new(&inv).with_group("is_optional").filter();
+            let filter = BindleFilter::new(&inv)
+                .with_group("is_optional")
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(2, filter.len());
         }
 
@@ -654,7 +748,8 @@ mod test {
             let filter = BindleFilter::new(&inv)
                 .with_group("is_optional")
                 .with_group("also_optional")
-                .filter();
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(3, filter.len());
         }
     }
@@ -711,7 +806,7 @@ mod test {
         let inv: crate::Invoice = toml::from_str(toml).expect("test invoice parsed");
 
         // Should have three. More importantly, should not get stuck in an infinite loop.
-        let filter = BindleFilter::new(&inv).filter();
+        let filter = BindleFilter::new(&inv).filter().expect("parcels resolved");
         assert_eq!(3, filter.len());
     }
 
@@ -767,13 +862,16 @@ mod test {
 
         // By default, we should get all three because "first" is required
         {
-            let filter = BindleFilter::new(&inv).filter();
+            let filter = BindleFilter::new(&inv).filter().expect("parcels resolved");
             assert_eq!(3, filter.len());
         }
 
         // Disabling "first" should disable all
         {
-            let filter = BindleFilter::new(&inv).without_group("first").filter();
+            let filter = BindleFilter::new(&inv)
+                .without_group("first")
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(0, filter.len());
         }
     }
@@ -870,17 +968,199 @@ mod test {
 
         // The parcels should be processed like this:
         // - One global parcel
-        // - Two parcels included as "entrypoint"
+        // - One parcel chosen to satisfy "entrypoint", which is the first one by default
         // - Three more parcels added by the `requires` directive on weather-ui.wasm
         {
-            let filter = BindleFilter::new(&inv).filter();
-            assert_eq!(6, filter.len());
+            let filter = BindleFilter::new(&inv).filter().expect("parcels resolved");
+            assert_eq!(5, filter.len());
+            assert!(filter.iter().any(|p| p.label.name == "weather-ui.wasm"));
+            assert!(!filter.iter().any(|p| p.label.name == "weather-cli.wasm"));
+        }
+
+        // Selecting the CLI entrypoint should skip the UI support parcels
+        {
+            let filter = BindleFilter::new(&inv)
+                .select_parcel("entrypoint", "weather-cli.wasm")
+                .filter()
+                .expect("parcels resolved");
+            assert_eq!(2, filter.len());
+            assert!(filter.iter().any(|p| p.label.name == "weather-cli.wasm"));
+        }
+
+        // Disabling the default choice should fall back to the next parcel in the group
+        {
+            let filter = BindleFilter::new(&inv)
+                .deactivate_feature("wasm", "ui-kit", "electron+sgu")
+                .filter()
+                .expect("parcels resolved");
+            assert_eq!(2, filter.len());
+            assert!(filter.iter().any(|p| p.label.name == "weather-cli.wasm"));
         }
 
         // We can disable the "entrypoint" group, and then we should have only one group.
         {
-            let filter = BindleFilter::new(&inv).without_group("entrypoint").filter();
+            let filter = BindleFilter::new(&inv)
+                .without_group("entrypoint")
+                .filter()
+                .expect("parcels resolved");
             assert_eq!(1, filter.len());
         }
     }
+
+    #[test]
+    fn test_satisfied_by() {
+        // This is the example from the invoice spec
+        let toml = r#"
+        bindleVersion = "1.0.0"
+
+        [bindle]
+        name = "test/satisfied-by"
+        version = "0.1.0"
+
+        [[group]]
+        name = "server"
+        satisfiedBy = "allOf"
+
+        [[group]]
+        name = "cli"
+        satisfiedBy = "oneOf"
+        required = true
+
+        [[group]]
+        name = "utility"
+        satisfiedBy = "optional"
+
+        [[parcel]]
+        [parcel.label]
+        sha256 = "e1706ab0a39ac88094b6d54a3f5cdba41fe5a901"
+        mediaType = "application/bin"
+        name = "daemon"
+        size = 123
+        [parcel.conditions]
+        memberOf = ["server"]
+        requires = ["utility"]
+
+        [[parcel]]
+        [parcel.label]
+        sha256 = "e1706ab0a39ac88094b6d54a3f5cdba41fe5a901"
+        mediaType = "application/bin"
+        name = "first"
+        size = 123
+        [parcel.conditions]
+        memberOf = ["cli", "utility"]
+
+        [[parcel]]
+        [parcel.label]
+        sha256 = "a1706ab0a39ac88094b6d54a3f5cdba41fe5a901"
+        mediaType = "application/bin"
+        name = "second"
+        size = 123
+        [parcel.conditions]
+        memberOf = ["cli"]
+
+        [[parcel]]
+        [parcel.label]
+        sha256 = "5b992e90b71d5fadab3cd3777230ef370df75f5b"
+        mediaType = "application/x-javascript"
+        name = "third"
+        size = 123
+        [parcel.conditions]
+        memberOf = ["utility"]
+        "#;
+        let inv: crate::Invoice = toml::from_str(toml).expect("test invoice parsed");
+
+        let names = |filter: &mut BindleFilter| -> Vec<String> {
+            filter
+                .filter()
+                .expect("parcels resolved")
+                .into_iter()
+                .map(|p| p.label.name)
+                .collect()
+        };
+
+        // Only one member of cli is needed, and the first is chosen by default
+        assert_eq!(vec!["first"], names(&mut BindleFilter::new(&inv)));
+
+        assert_eq!(
+            vec!["second"],
+            names(BindleFilter::new(&inv).select_parcel("cli", "second"))
+        );
+
+        // Optional groups pulled in by a requirement only include selected parcels
+        assert_eq!(
+            vec!["daemon", "first"],
+            names(BindleFilter::new(&inv).with_group("server"))
+        );
+        assert_eq!(
+            vec!["daemon", "second", "third"],
+            names(
+                BindleFilter::new(&inv)
+                    .with_group("server")
+                    .select_parcel("cli", "second")
+                    .select_parcel("utility", "third")
+            )
+        );
+
+        // Selecting something that isn't in the group is an error
+        assert!(matches!(
+            BindleFilter::new(&inv)
+                .select_parcel("cli", "third")
+                .filter(),
+            Err(FilterError::InvalidSelection(_, _))
+        ));
+        assert!(matches!(
+            BindleFilter::new(&inv)
+                .select_parcel("nonexistent", "third")
+                .filter(),
+            Err(FilterError::UnknownGroup(_))
+        ));
+    }
+
+    #[test]
+    fn test_unsatisfiable_group() {
+        let toml = r#"
+        bindleVersion = "1.0.0"
+
+        [bindle]
+        name = "test/unsatisfiable"
+        version = "0.1.0"
+
+        [[group]]
+        name = "cli"
+        satisfiedBy = "oneOf"
+        required = true
+
+        [[group]]
+        name = "broken"
+        satisfiedBy = "someOf"
+
+        [[parcel]]
+        [parcel.label]
+        name = "only_choice"
+        sha256 = "12345"
+        mediaType = "application/octet-stream"
+        size = 123
+        [parcel.label.feature.testing]
+        disabled = "true"
+        [parcel.conditions]
+        memberOf = ["cli"]
+        "#;
+        let mut inv: crate::Invoice = toml::from_str(toml).expect("test invoice parsed");
+
+        assert!(matches!(
+            BindleFilter::new(&inv).filter(),
+            Err(FilterError::InvalidSatisfiedBy(_, _))
+        ));
+
+        inv.group.as_mut().unwrap().pop();
+        BindleFilter::new(&inv)
+            .filter()
+            .expect("group should be satisfied if the parcel is enabled");
+        assert!(matches!(
+            BindleFilter::new(&inv)
+                .deactivate_feature("testing", "disabled", "true")
+                .filter(),
+            Err(FilterError::Unsatisfiable(_))
+        ));
+    }
 }
@@ -3,7 +3,7 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::invoice::{Invoice, Label};
+use crate::invoice::{Invoice, Label, Signature};
 use crate::search::SearchOptions;
 
 /// A custom type for responding to invoice creation requests. Because invoices can be created
@@ -24,6 +24,14 @@ pub struct MissingParcelsResponse {
     pub missing: Vec<Label>,
 }
 
+/// A request to add signatures to an invoice that has already been created. The signatures use
+/// the same `[[signature]]` format as an invoice so they can be copied from a signed invoice file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct AddSignaturesRequest {
+    pub signature: Vec<Signature>,
+}
+
 /// A string error message returned from the server
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorResponse {
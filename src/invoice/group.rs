@@ -2,6 +2,8 @@
 
 use serde::{Deserialize, Serialize};
 
+use std::str::FromStr;
+
 /// A group is a top-level organization object that may contain zero or more parcels. Every parcel
 /// belongs to at least one group, but may belong to others.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -11,3 +13,41 @@ pub struct Group {
     pub required: Option<bool>,
     pub satisfied_by: Option<String>,
 }
+
+impl Group {
+    /// Parses the `satisfied_by` field of this group, returning the default of
+    /// [`SatisfiedBy::AllOf`] if it is not set
+    pub fn satisfaction(&self) -> Result<SatisfiedBy, &'static str> {
+        match self.satisfied_by.as_deref() {
+            Some(s) => s.parse(),
+            None => Ok(SatisfiedBy::default()),
+        }
+    }
+}
+
+/// The criterion by which a group's requirements are satisfied, as described in the invoice spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SatisfiedBy {
+    /// All of the parcels in the group are required
+    #[default]
+    AllOf,
+    /// The group is satisfied if at least one of its parcels is present
+    OneOf,
+    /// The group is satisfied even if none of its parcels are present
+    Optional,
+}
+
+impl FromStr for SatisfiedBy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let normalized = s.trim().to_lowercase();
+        match normalized.as_str() {
+            "allof" => Ok(Self::AllOf),
+            "oneof" => Ok(Self::OneOf),
+            // The spec lists `anyOf` as an alias for `optional`
+            "optional" | "anyof" => Ok(Self::Optional),
+            _ => Err("Invalid satisfiedBy value, should be one of: allOf, oneOf, optional"),
+        }
+    }
+}
@@ -12,25 +12,29 @@ pub mod signature;
 pub mod verification;
 
 #[doc(inline)]
-pub(crate) use api::{DeviceAuthorizationExtraFields, LoginParams};
+pub use api::{
+    AddSignaturesRequest, ErrorResponse, InvoiceCreateResponse, MissingParcelsResponse,
+    QueryOptions,
+};
 #[doc(inline)]
-pub use api::{ErrorResponse, InvoiceCreateResponse, MissingParcelsResponse, QueryOptions};
+pub(crate) use api::{DeviceAuthorizationExtraFields, LoginParams};
 #[doc(inline)]
 pub use bindle_spec::BindleSpec;
 #[doc(inline)]
 pub use condition::Condition;
 #[doc(inline)]
-pub use group::Group;
+pub use group::{Group, SatisfiedBy};
 #[doc(inline)]
 pub use label::Label;
 #[doc(inline)]
 pub use parcel::Parcel;
 #[doc(inline)]
-pub use signature::{SecretKeyEntry, Signature, SignatureError, SignatureRole};
+pub use signature::{
+    SecretKeyEntry, Signature, SignatureError, SignatureRole, SignatureVersion, SigningKey,
+};
 #[doc(inline)]
 pub use verification::VerificationStrategy;
 
-use ed25519_dalek::{Signature as EdSignature, Signer};
 use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 use tracing::info;
@@ -158,7 +162,21 @@ impl Invoice {
             .collect()
     }
 
-    fn cleartext(&self, by: &str, role: &SignatureRole) -> String {
+    /// Returns the data signed by a signature of the given version made at the given time
+    fn cleartext(
+        &self,
+        by: &str,
+        role: &SignatureRole,
+        at: u64,
+        version: SignatureVersion,
+    ) -> String {
+        match version {
+            SignatureVersion::V1 => self.cleartext_v1(by, role),
+            SignatureVersion::V2 => self.cleartext_v2(by, role, at),
+        }
+    }
+
+    fn cleartext_v1(&self, by: &str, role: &SignatureRole) -> String {
         let mut buf = vec![
             by.to_owned(),
             self.bindle.id.name().to_owned(),
@@ -177,74 +195,113 @@ impl Invoice {
         buf.join("\n")
     }
 
-    /// Sign the parcels on the current package.
+    fn cleartext_v2(&self, by: &str, role: &SignatureRole, at: u64) -> String {
+        [
+            by.to_owned(),
+            role.to_string(),
+            at.to_string(),
+            SignatureVersion::V2.to_string(),
+            '~'.to_string(),
+            self.canonical_body(),
+        ]
+        .join("\n")
+    }
+
+    /// Serializes everything in the invoice other than the signatures and yank fields as compact
+    /// JSON with all object keys sorted and all `null` values removed. These fields are excluded
+    /// because they are allowed to change after the invoice is signed
+    fn canonical_body(&self) -> String {
+        let mut unsigned = self.clone();
+        unsigned.signature = None;
+        unsigned.yanked = None;
+        unsigned.yanked_signature = None;
+        // Invoices only contain strings, integers, booleans, lists and maps with string keys, so
+        // this cannot fail
+        let value =
+            serde_json::to_value(&unsigned).expect("Invoice should always serialize to JSON");
+        let mut out = String::new();
+        write_canonical_json(&value, &mut out);
+        out
+    }
+
+    /// Sign the current invoice.
     ///
-    /// Note that this signature will be invalidated if any parcels are
-    /// added after this signature.
+    /// New signatures always use the latest [`SignatureVersion`], which covers everything in the
+    /// invoice other than the signatures and yank fields. This means the signature will be
+    /// invalidated if anything else (such as a parcel, group, or annotation) is changed after it
+    /// is made.
     ///
-    /// In the current version of the spec, a signature is generated by combining the
-    /// signer's ID, the invoice version, and a list of parcels, and then performing
-    /// a cryptographic signature on those fields. The result is then stored in
-    /// a `[[signature]]` block on the invoice. Multiple signatures can be attached
-    /// to any invoice.
-    pub fn sign(
+    /// The result is stored in a `[[signature]]` block on the invoice. Multiple signatures can be
+    /// attached to any invoice. Any [`SigningKey`] can be used, including a [`SecretKeyEntry`] or
+    /// a key held by a signing agent.
+    pub fn sign<K: SigningKey + ?Sized>(
         &mut self,
         signer_role: SignatureRole,
-        keyfile: &SecretKeyEntry,
+        keyfile: &K,
     ) -> Result<(), SignatureError> {
-        let signer_name = keyfile.label.clone();
-        let key = keyfile.key()?;
-        // The spec says it is illegal for the a single key to sign the same invoice
-        // more than once.
-        let encoded_key = base64::encode(key.public.to_bytes());
-        if let Some(sigs) = self.signature.as_ref() {
-            for s in sigs {
-                if s.key == encoded_key {
-                    return Err(SignatureError::DuplicateSignature);
-                }
-            }
-        }
-
-        let cleartext = self.cleartext(&signer_name, &signer_role);
-        let signature: EdSignature = key.sign(cleartext.as_bytes());
-
-        // Timestamp should be generated at this moment.
-        let ts = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_err(|_| SignatureError::SigningFailed)?;
+        sign_one(self, signer_role, keyfile)
+    }
 
-        let signature_entry = Signature {
-            by: signer_name,
-            key: encoded_key,
-            signature: base64::encode(signature.to_bytes()),
-            role: signer_role,
-            at: ts.as_secs(),
-        };
+    /// Append a signature that was made elsewhere, such as by calling [`sign`](Invoice::sign) on
+    /// another copy of this invoice.
+    ///
+    /// Signatures are append-only, so this returns [`SignatureError::DuplicateSignature`] if the key
+    /// has already signed this invoice. The signature must be valid for this invoice, but this does
+    /// not check whether its key is trusted
+    pub fn append_signature(&mut self, signature: Signature) -> Result<(), SignatureError> {
+        if self
+            .signature
+            .iter()
+            .flatten()
+            .any(|s| s.key == signature.key)
+        {
+            return Err(SignatureError::DuplicateSignature);
+        }
+        let cleartext = self.cleartext(
+            &signature.by,
+            &signature.role,
+            signature.at,
+            signature.version,
+        );
+        verification::verify_signature(&signature, cleartext.as_bytes())?;
+        self.signature.get_or_insert_with(Vec::new).push(signature);
+        Ok(())
+    }
 
-        match self.signature.as_mut() {
-            Some(signatures) => signatures.push(signature_entry),
-            None => self.signature = Some(vec![signature_entry]),
+    /// Mark this invoice as yanked, appending the given yank signature (generated with
+    /// [`sign_yank`]) to the `yanked_signature` list.
+    ///
+    /// Yank signatures are append-only, so existing yank signatures are never removed. If the
+    /// same key has already signed a yank for this invoice, the new signature is discarded
+    pub fn yank(&mut self, signature: Option<Signature>) {
+        self.yanked = Some(true);
+        let signature = match signature {
+            Some(s) => s,
+            None => return,
         };
-
-        Ok(())
+        match self.yanked_signature.as_mut() {
+            Some(signatures) if signatures.iter().any(|s| s.key == signature.key) => {
+                info!(key = %signature.key, "Invoice has already been yanked by this key");
+            }
+            Some(signatures) => signatures.push(signature),
+            None => self.yanked_signature = Some(vec![signature]),
+        }
     }
 }
 
-/// Sign the parcels in the invoice using the given list of roles and keys. This is a list of tuples
-/// containing a [`SignatureRole`] and [`SecretKeyEntry`] in that order. Returns a [`SignedInvoice`]
+/// Sign the invoice using the given list of roles and keys. This is a list of tuples containing a
+/// [`SignatureRole`] and [`SigningKey`] (such as a [`SecretKeyEntry`]) in that order. Returns a
+/// [`SignedInvoice`]
 ///
-/// Note that this signature will be invalidated if any parcels are added after this signature.
-///
-/// In the current version of the spec, a signature is generated by combining the signer's ID, the
-/// invoice version, and a list of parcels, and then performing a cryptographic signature on those
-/// fields. The result is then stored in a `[[signature]]` block on the invoice. Multiple signatures
-/// can be attached to any invoice.
-pub fn sign<I>(
+/// See [`Invoice::sign`] for details on what is signed. Note that the signatures will be
+/// invalidated if anything other than the signatures or yank fields is changed afterwards.
+pub fn sign<I, K>(
     mut invoice: I,
-    sign_with: Vec<(SignatureRole, &SecretKeyEntry)>,
+    sign_with: Vec<(SignatureRole, &K)>,
 ) -> Result<SignedInvoice<I>, SignatureError>
 where
     I: BorrowMut<Invoice> + Into<crate::Invoice>,
+    K: SigningKey + ?Sized,
 {
     let mut inv = invoice.borrow_mut();
     for (role, key) in sign_with {
@@ -254,16 +311,24 @@ where
     Ok(SignedInvoice(invoice))
 }
 
-fn sign_one(
+fn sign_one<K: SigningKey + ?Sized>(
+    inv: &mut Invoice,
+    signer_role: SignatureRole,
+    keyfile: &K,
+) -> Result<(), SignatureError> {
+    sign_one_with_version(inv, signer_role, keyfile, SignatureVersion::V2)
+}
+
+fn sign_one_with_version<K: SigningKey + ?Sized>(
     inv: &mut Invoice,
     signer_role: SignatureRole,
-    keyfile: &SecretKeyEntry,
+    keyfile: &K,
+    version: SignatureVersion,
 ) -> Result<(), SignatureError> {
-    let signer_name = keyfile.label.clone();
-    let key = keyfile.key()?;
+    let signer_name = keyfile.label().to_owned();
     // The spec says it is illegal for the a single key to sign the same invoice
     // more than once.
-    let encoded_key = base64::encode(key.public.to_bytes());
+    let encoded_key = base64::encode(keyfile.public_key()?.to_bytes());
     if let Some(sigs) = inv.signature.as_ref() {
         for s in sigs {
             if s.key == encoded_key {
@@ -272,20 +337,21 @@ fn sign_one(
         }
     }
 
-    let cleartext = inv.cleartext(&signer_name, &signer_role);
-    let signature: EdSignature = key.sign(cleartext.as_bytes());
-
     // Timestamp should be generated at this moment.
     let ts = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .map_err(|_| SignatureError::SigningFailed)?;
 
+    let cleartext = inv.cleartext(&signer_name, &signer_role, ts.as_secs(), version);
+    let signature = keyfile.sign(cleartext.as_bytes())?;
+
     let signature_entry = Signature {
         by: signer_name,
         key: encoded_key,
         signature: base64::encode(signature.to_bytes()),
         role: signer_role,
         at: ts.as_secs(),
+        version,
     };
 
     match inv.signature.as_mut() {
@@ -296,6 +362,89 @@ fn sign_one(
     Ok(())
 }
 
+/// Generate a `yanked_signature` block for the bindle with the given ID using the given host key.
+///
+/// As described in the signing spec, only the `host` role is allowed to sign a yank, so this will
+/// return an error if the key does not have the host role. The signed data is the signer's label,
+/// the bindle name and version, the role, the time of the yank, and the literal word `yanked`. The
+/// returned signature can be attached to an invoice with [`Invoice::yank`]
+pub fn sign_yank<K: SigningKey + ?Sized>(
+    id: &crate::Id,
+    keyfile: &K,
+) -> Result<Signature, SignatureError> {
+    if !keyfile.roles().contains(&SignatureRole::Host) {
+        return Err(SignatureError::NoSuitableKey);
+    }
+
+    // Timestamp should be the time at which the bindle was yanked
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| SignatureError::SigningFailed)?
+        .as_secs();
+
+    let cleartext = yank_cleartext(id, keyfile.label(), ts);
+    let signature = keyfile.sign(cleartext.as_bytes())?;
+
+    Ok(Signature {
+        by: keyfile.label().to_owned(),
+        key: base64::encode(keyfile.public_key()?.to_bytes()),
+        signature: base64::encode(signature.to_bytes()),
+        role: SignatureRole::Host,
+        at: ts,
+        version: SignatureVersion::default(),
+    })
+}
+
+/// Writes the value as compact JSON with the keys of every object sorted and all `null` values in
+/// objects removed, so an unset field serializes the same way as one that is missing. The sorting
+/// is done here rather than relying on serde_json, as its key order depends on which features are
+/// enabled
+fn write_canonical_json(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().filter(|(_, v)| !v.is_null()).collect();
+            entries.sort_by_key(|(k, _)| *k);
+            out.push('{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::Value::String(k.clone()).to_string());
+                out.push(':');
+                write_canonical_json(v, out);
+            }
+            out.push('}');
+        }
+        serde_json::Value::Array(list) => {
+            out.push('[');
+            for (i, v) in list.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(v, out);
+            }
+            out.push(']');
+        }
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+/// The data signed by a `yanked_signature` block. This differs from the normal signature cleartext
+/// in that it includes the timestamp and the word `yanked`, which keeps a normal signature from
+/// being repurposed as a yank signature
+fn yank_cleartext(id: &crate::Id, by: &str, at: u64) -> String {
+    [
+        by.to_owned(),
+        id.name().to_owned(),
+        id.version_string(),
+        SignatureRole::Host.to_string(),
+        at.to_string(),
+        "yanked".to_owned(),
+        '~'.to_string(),
+    ]
+    .join("\n")
+}
+
 /// An invoice that has been signed and can no longer be modified unless converted back into a
 /// normal invoice with the `signed` method
 pub struct SignedInvoice<T: Into<Invoice>>(T);
@@ -496,6 +645,112 @@ mod test {
             .verify(invoice, &keyring)
             .expect_err("missing the creator key, so verification should fail");
     }
+    #[test]
+    fn signature_versions() {
+        let invoice = r#"
+        bindleVersion = "1.0.0"
+
+        [bindle]
+        name = "aricebo"
+        version = "1.2.3"
+
+        [annotations]
+        location = "Puerto Rico"
+
+        [[parcel]]
+        [parcel.label]
+        sha256 = "aaabbbcccdddeeefff"
+        name = "telescope.gif"
+        mediaType = "image/gif"
+        size = 123_456
+        [parcel.conditions]
+        memberOf = ["telescopes"]
+
+        [[group]]
+        name = "telescopes"
+        required = false
+        "#;
+
+        let invoice: crate::Invoice = toml::from_str(invoice).expect("a nice clean parse");
+        let creator = SecretKeyEntry::new("Creator".to_owned(), vec![SignatureRole::Creator]);
+        let host = SecretKeyEntry::new("Host".to_owned(), vec![SignatureRole::Host]);
+        let keyring = KeyRing::new(vec![(&creator).try_into().expect("convert to public key")]);
+
+        let mut v2 = invoice.clone();
+        v2.sign(SignatureRole::Creator, &creator)
+            .expect("Should be able to sign");
+        assert_eq!(
+            SignatureVersion::V2,
+            v2.signature.as_ref().unwrap()[0].version
+        );
+        let v2: Invoice = toml::from_str(&toml::to_string(&v2).unwrap())
+            .expect("Signed invoice should round trip");
+        VerificationStrategy::CreativeIntegrity
+            .verify(v2.clone(), &keyring)
+            .expect("A v2 signature should verify");
+
+        // Signatures and yanks can be added without invalidating the signature
+        let mut appended = v2.clone();
+        appended.sign(SignatureRole::Host, &host).unwrap();
+        appended.yank(Some(sign_yank(&appended.bindle.id, &host).unwrap()));
+        let both = KeyRing::new(vec![
+            (&creator).try_into().expect("convert to public key"),
+            (&host).try_into().expect("convert to public key"),
+        ]);
+        VerificationStrategy::ExhaustiveVerification
+            .verify(appended, &both)
+            .expect("Additional signatures should not invalidate a v2 signature");
+
+        // Anything else in the invoice is covered
+        let tampers: Vec<fn(&mut Invoice)> = vec![
+            |inv| inv.parcel.as_mut().unwrap()[0].label.size += 1,
+            |inv| inv.parcel.as_mut().unwrap()[0].label.media_type = "text/plain".to_owned(),
+            |inv| inv.parcel.as_mut().unwrap()[0].conditions = None,
+            |inv| inv.group.as_mut().unwrap()[0].required = Some(true),
+            |inv| inv.annotations = None,
+            |inv| inv.bindle.description = Some("Not a telescope".to_owned()),
+            // Moving the timestamp could move a signature out of a revoked key's window
+            |inv| inv.signature.as_mut().unwrap()[0].at -= 1,
+        ];
+        for tamper in tampers {
+            let mut tampered = v2.clone();
+            tamper(&mut tampered);
+            VerificationStrategy::CreativeIntegrity
+                .verify(tampered, &keyring)
+                .expect_err("A change to the invoice should invalidate a v2 signature");
+        }
+
+        // Version 1 signatures are still accepted, but only cover the parcel SHAs
+        let mut v1 = invoice;
+        sign_one_with_version(
+            &mut v1,
+            SignatureRole::Creator,
+            &creator,
+            SignatureVersion::V1,
+        )
+        .unwrap();
+        let serialized = toml::to_string(&v1).unwrap();
+        assert!(
+            !serialized.contains("version = \"1\""),
+            "v1 signatures should serialize without a version"
+        );
+        let mut v1: Invoice = toml::from_str(&serialized).unwrap();
+        assert_eq!(
+            SignatureVersion::V1,
+            v1.signature.as_ref().unwrap()[0].version
+        );
+        v1.parcel.as_mut().unwrap()[0].label.size += 1;
+        VerificationStrategy::CreativeIntegrity
+            .verify(v1.clone(), &keyring)
+            .expect("A v1 signature should verify");
+
+        // Claiming a v1 signature is v2 should not verify
+        v1.signature.as_mut().unwrap()[0].version = SignatureVersion::V2;
+        VerificationStrategy::CreativeIntegrity
+            .verify(v1, &keyring)
+            .expect_err("Changing the signature version should invalidate it");
+    }
+
     #[test]
     fn invalid_signatures_should_fail() {
         let invoice = r#"
@@ -755,4 +1010,65 @@ mod test {
         let members = invoice.group_members("telescopes");
         assert_eq!(2, members.len());
     }
+
+    #[test]
+    fn yank_signing_and_verifying() {
+        let invoice = r#"
+        bindleVersion = "1.0.0"
+
+        [bindle]
+        name = "aricebo"
+        version = "1.2.3"
+
+        [[parcel]]
+        [parcel.label]
+        sha256 = "aaabbbcccdddeeefff"
+        name = "telescope.gif"
+        mediaType = "image/gif"
+        size = 123_456
+        "#;
+
+        let mut invoice: crate::Invoice = toml::from_str(invoice).expect("a nice clean parse");
+
+        let host = SecretKeyEntry::new("Host".to_owned(), vec![SignatureRole::Host]);
+        let creator = SecretKeyEntry::new("Creator".to_owned(), vec![SignatureRole::Creator]);
+
+        sign_yank(&invoice.bindle.id, &creator)
+            .expect_err("Only a host key should be able to sign a yank");
+
+        let sig = sign_yank(&invoice.bindle.id, &host).expect("Should be able to sign yank");
+        invoice.yank(Some(sig.clone()));
+        assert!(invoice.yanked.unwrap_or_default());
+
+        // Yanking again with the same key should not add another signature
+        invoice.yank(Some(sig));
+        assert_eq!(1, invoice.yanked_signature.as_ref().unwrap().len());
+
+        let keyring = KeyRing::new(vec![(&host).try_into().expect("convert to public key")]);
+        VerificationStrategy::ExhaustiveVerification
+            .verify(invoice.clone(), &keyring)
+            .expect("A valid yank signature should verify");
+
+        // An unknown yank key is only a failure for exhaustive verification
+        VerificationStrategy::GreedyVerification
+            .verify(invoice.clone(), &KeyRing::default())
+            .expect("Unknown yank key should not fail greedy verification");
+        VerificationStrategy::ExhaustiveVerification
+            .verify(invoice.clone(), &KeyRing::default())
+            .expect_err("Unknown yank key should fail exhaustive verification");
+
+        // Tampering with the timestamp should invalidate the signature
+        let mut tampered = invoice.clone();
+        tampered.yanked_signature.as_mut().unwrap()[0].at += 1;
+        VerificationStrategy::GreedyVerification
+            .verify(tampered, &keyring)
+            .expect_err("Tampered yank signature should fail verification");
+
+        // A yank signature must be made by the host role
+        let mut tampered = invoice;
+        tampered.yanked_signature.as_mut().unwrap()[0].role = SignatureRole::Creator;
+        VerificationStrategy::GreedyVerification
+            .verify(tampered, &keyring)
+            .expect_err("Non-host yank signature should fail verification");
+    }
 }
@@ -1,7 +1,10 @@
 //! Contains the Signature type along with associated types and Roles
 
+mod encryption;
+
 pub use ed25519_dalek::{Keypair, PublicKey, Signature as EdSignature, Signer};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
@@ -12,14 +15,17 @@ use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::path::Path;
 use std::str::FromStr;
 
+use encryption::EncryptedKeys;
+pub use encryption::PassphraseSource;
+
 /// The latest key ring version supported by this library.
 pub const KEY_RING_VERSION: &str = "1.0";
 
-/// A signature describes a cryptographic signature of the parcel list.
+/// A signature describes a cryptographic signature of an invoice.
 ///
-/// In the current implementation, a signature signs the list of parcels that belong on
-/// an invoice. The signature, in the current implementation, is an Ed25519 signature
-/// and is signed by the private counterpart of the given public key.
+/// What is signed depends on the [`SignatureVersion`]. The signature, in the current
+/// implementation, is an Ed25519 signature and is signed by the private counterpart of the given
+/// public key.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct Signature {
@@ -33,6 +39,56 @@ pub struct Signature {
     pub role: SignatureRole,
     // The UNIX timestamp, expressed as an unsigned 64-bit integer
     pub at: u64,
+    // The version of the signed data. Signatures without a version are version 1
+    #[serde(default, skip_serializing_if = "SignatureVersion::is_v1")]
+    pub version: SignatureVersion,
+}
+
+impl Signature {
+    pub(crate) fn public_key(&self) -> Result<PublicKey, SignatureError> {
+        let rawbytes =
+            base64::decode(&self.key).map_err(|_| SignatureError::CorruptKey(self.key.clone()))?;
+        PublicKey::from_bytes(rawbytes.as_slice())
+            .map_err(|_| SignatureError::CorruptKey(self.key.clone()))
+    }
+}
+
+/// The version of the data covered by a signature.
+///
+/// See the signing spec for the exact format of each version
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SignatureVersion {
+    /// Signs the signer, the bindle name and version, the role, and the SHAs of the parcels. This
+    /// does not protect anything else in the invoice, such as groups, conditions, or sizes
+    #[serde(rename = "1")]
+    V1,
+    /// Signs the signer, the role, and a canonical serialization of the whole invoice other than
+    /// its signatures and yank fields
+    #[serde(rename = "2")]
+    V2,
+}
+
+impl SignatureVersion {
+    fn is_v1(&self) -> bool {
+        matches!(self, SignatureVersion::V1)
+    }
+}
+
+/// Signatures that don't specify a version were made before versions existed, so this is
+/// [`SignatureVersion::V1`]. New signatures are always made with the latest version
+impl Default for SignatureVersion {
+    fn default() -> Self {
+        SignatureVersion::V1
+    }
+}
+
+impl Display for SignatureVersion {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::V1 => write!(f, "1"),
+            Self::V2 => write!(f, "2"),
+        }
+    }
 }
 
 /// Wrap errors related to signing
@@ -60,6 +116,10 @@ pub enum SignatureError {
     DuplicateSignature,
     #[error("no suitable key for signing data")]
     NoSuitableKey,
+    #[error("signature made with revoked key {0}")]
+    RevokedKey(String),
+    #[error("signing agent error: {0}")]
+    Agent(String),
 }
 
 /// The role of a signer in a signature block.
@@ -116,6 +176,9 @@ impl FromStr for SignatureRole {
 pub struct KeyRing {
     pub version: String,
     pub key: Vec<KeyEntry>,
+    /// Keys that are no longer trusted, either entirely or outside of a window of time
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub revoked: Vec<Revocation>,
 }
 
 impl Default for KeyRing {
@@ -123,6 +186,7 @@ impl Default for KeyRing {
         Self {
             version: KEY_RING_VERSION.to_owned(),
             key: vec![],
+            revoked: vec![],
         }
     }
 }
@@ -132,24 +196,106 @@ impl KeyRing {
         KeyRing {
             version: KEY_RING_VERSION.to_owned(),
             key: keys,
+            revoked: vec![],
         }
     }
+
+    /// Returns the revocation that applies to a signature made by the given key at the given
+    /// UNIX timestamp, or `None` if the signature is not revoked
+    pub fn revocation(&self, key: &PublicKey, at: u64) -> Option<&Revocation> {
+        self.revoked.iter().find(|r| match r.public_key() {
+            Ok(pk) => pk == *key && r.covers(at),
+            Err(e) => {
+                tracing::warn!(%e, "Error parsing revoked key");
+                false
+            }
+        })
+    }
+
     pub fn contains(&self, key: &PublicKey) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns the entry for the given key, or `None` if it isn't in the keyring
+    pub fn get(&self, key: &PublicKey) -> Option<&KeyEntry> {
         // This could definitely be optimized.
         for k in self.key.iter() {
             // Note that we are skipping malformed keys because they don't matter
-            // when doing a contains(). If they key is malformed, it definitely
+            // when doing a lookup. If they key is malformed, it definitely
             // is not the key we are looking for.
             match k.public_key() {
                 Err(e) => tracing::warn!(%e, "Error parsing key"),
-                Ok(pk) if pk == *key => return true,
+                Ok(pk) if pk == *key => return Some(k),
                 _ => {}
             }
 
             tracing::debug!("No match. Moving on.");
         }
         tracing::debug!("No more keys to check");
-        false
+        None
+    }
+}
+
+/// The reason a key was revoked
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum RevocationReason {
+    /// The private key was (or may have been) exposed
+    Compromised,
+    /// The key was taken out of use, such as after a key rotation
+    Retired,
+}
+
+/// A Revocation marks a key on a keyring as no longer trusted.
+///
+/// A signature made with a revoked key is only trusted if its `at` timestamp falls within the
+/// optional validity window. If neither end of the window is set, no signature made with the key is
+/// trusted. For example, a key that was compromised at a known time can be revoked with
+/// `valid_until` set to that time so that older signatures are still trusted.
+///
+/// Note that the timestamp is chosen by the signer, so anyone holding a compromised key can
+/// backdate a signature into the validity window. Only leave a window open on a compromised key if
+/// the signatures inside of it can be trusted some other way.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Revocation {
+    /// The revoked public key, encoded the same way as in a [`KeyEntry`]
+    pub key: String,
+    /// Why the key was revoked
+    pub reason: RevocationReason,
+    /// Signatures made before this UNIX timestamp are not trusted
+    pub valid_from: Option<u64>,
+    /// Signatures made at or after this UNIX timestamp are not trusted
+    pub valid_until: Option<u64>,
+}
+
+impl Revocation {
+    /// Create a new Revocation for the given public key that applies to all signatures
+    pub fn new(public_key: PublicKey, reason: RevocationReason) -> Self {
+        Revocation {
+            key: base64::encode(public_key.to_bytes()),
+            reason,
+            valid_from: None,
+            valid_until: None,
+        }
+    }
+
+    /// Returns whether a signature made at the given UNIX timestamp falls outside of the validity
+    /// window, meaning it is revoked
+    pub fn covers(&self, at: u64) -> bool {
+        match (self.valid_from, self.valid_until) {
+            (None, None) => true,
+            (from, until) => {
+                from.map(|f| at < f).unwrap_or(false) || until.map(|u| at >= u).unwrap_or(false)
+            }
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> Result<PublicKey, SignatureError> {
+        let rawbytes =
+            base64::decode(&self.key).map_err(|_| SignatureError::CorruptKey(self.key.clone()))?;
+        PublicKey::from_bytes(rawbytes.as_slice())
+            .map_err(|_| SignatureError::CorruptKey(self.key.clone()))
     }
 }
 
@@ -192,6 +338,13 @@ impl KeyEntry {
             label_signature: None,
         }
     }
+    /// Create a KeyEntry for the given signing key, with the label signed by the key
+    pub fn from_signing_key<K: SigningKey + ?Sized>(key: &K) -> Result<Self, SignatureError> {
+        let mut entry = KeyEntry::new(key.label(), key.roles().to_vec(), key.public_key()?);
+        let sig = key.sign(key.label().as_bytes())?;
+        entry.label_signature = Some(base64::encode(sig.to_bytes()));
+        Ok(entry)
+    }
     pub fn sign_label(&mut self, key: Keypair) {
         let sig = key.sign(self.label.as_bytes());
         self.label_signature = Some(base64::encode(sig.to_bytes()));
@@ -210,7 +363,17 @@ impl KeyEntry {
             }
         }
     }
-    pub(crate) fn public_key(&self) -> Result<PublicKey, SignatureError> {
+    /// Returns a fingerprint of the public key that is short enough to compare by eye, in the form
+    /// `SHA256:<unpadded base64 of the SHA-256 hash of the key>`
+    pub fn fingerprint(&self) -> Result<String, SignatureError> {
+        let hash = Sha256::digest(&self.public_key()?.to_bytes());
+        Ok(format!(
+            "SHA256:{}",
+            base64::encode_config(hash, base64::STANDARD_NO_PAD)
+        ))
+    }
+    /// Decodes the public key
+    pub fn public_key(&self) -> Result<PublicKey, SignatureError> {
         let rawbytes = base64::decode(&self.key).map_err(|_e| {
             // We swallow the source error because it could disclose information about
             // the secret key.
@@ -299,18 +462,65 @@ impl SecretKeyEntry {
     }
 }
 
+/// A secret key that can sign data.
+///
+/// Implementations don't have to give access to the key material itself, which means the key can
+/// be held by another process, such as a [signing agent](crate::agent). Anything that signs
+/// invoices or yanks accepts any implementation of this trait
+pub trait SigningKey: Send + Sync {
+    /// The label of the key, which is used as the name of the signer
+    fn label(&self) -> &str;
+    /// The roles this key should be used for
+    fn roles(&self) -> &[SignatureRole];
+    /// The public half of the key
+    fn public_key(&self) -> Result<PublicKey, SignatureError>;
+    /// Signs the given data with the key
+    fn sign(&self, data: &[u8]) -> Result<EdSignature, SignatureError>;
+}
+
+impl SigningKey for SecretKeyEntry {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn roles(&self) -> &[SignatureRole] {
+        &self.roles
+    }
+
+    fn public_key(&self) -> Result<PublicKey, SignatureError> {
+        Ok(self.key()?.public)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<EdSignature, SignatureError> {
+        Ok(Signer::sign(&self.key()?, data))
+    }
+}
+
 /// Storage for secret keys
 ///
 /// Any possible number of key storage systems may be used for key storage, but
 /// all of them must provide a way for the system to fetch a key matching the
-/// desired role.
+/// desired role. The storage doesn't have to hold the keys themselves, only something that can
+/// sign with them.
 pub trait SecretKeyStorage {
+    /// The type of key returned by this storage
+    type Key: SigningKey + ?Sized;
+
     /// Get a key appropriate for signing with the given role.
     ///
     /// If no key is found, this will return a None.
     /// In general, if multiple keys match, the implementation chooses the "best fit"
     /// and returns that key.
-    fn get_first_matching(&self, role: &SignatureRole) -> Option<&SecretKeyEntry>;
+    fn get_first_matching(&self, role: &SignatureRole) -> Option<&Self::Key>;
+
+    /// Get all keys appropriate for signing with the given role.
+    ///
+    /// The default implementation only returns the key from
+    /// [`get_first_matching`](SecretKeyStorage::get_first_matching), so storage that can hold
+    /// more than one key should override it
+    fn get_all_matching(&self, role: &SignatureRole) -> Vec<&Self::Key> {
+        self.get_first_matching(role).into_iter().collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -329,44 +539,125 @@ impl Default for SecretKeyFile {
     }
 }
 
+/// The on disk format of a secret key file encrypted with a passphrase. The encrypted data is a
+/// serialized [`SecretKeyFile`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct EncryptedSecretKeyFile {
+    version: String,
+    encrypted: EncryptedKeys,
+}
+
 impl SecretKeyFile {
+    /// Loads a plaintext secret key file. Returns an error if the file is encrypted
     pub async fn load_file(path: impl AsRef<Path>) -> anyhow::Result<SecretKeyFile> {
+        Self::load_file_with_passphrase(path, None).await
+    }
+
+    /// Loads a secret key file, decrypting it with the given passphrase if it is encrypted.
+    /// Plaintext files are loaded as is, so the passphrase is only required for encrypted files
+    pub async fn load_file_with_passphrase(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<SecretKeyFile> {
         let raw = tokio::fs::read(path).await?;
-        let t = toml::from_slice(&raw)?;
-        Ok(t)
+        if !is_encrypted(&raw)? {
+            return Ok(toml::from_slice(&raw)?);
+        }
+        let passphrase = passphrase.ok_or_else(|| {
+            anyhow::anyhow!("Secret key file is encrypted, so a passphrase is needed to load it")
+        })?;
+        let file: EncryptedSecretKeyFile = toml::from_slice(&raw)?;
+        let decrypted = file.encrypted.decrypt(passphrase.to_owned()).await?;
+        Ok(toml::from_slice(&decrypted)?)
     }
 
-    /// Save the present keyfile to the named path.
-    pub async fn save_file(&self, dest: impl AsRef<Path>) -> anyhow::Result<()> {
-        let out = toml::to_vec(self)?;
-        #[cfg(target_family = "unix")]
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .mode(0o600)
-            .open(dest)
+    /// Loads a secret key file, reading the passphrase from the given source only if the file is
+    /// encrypted
+    pub async fn load_file_from_source(
+        path: impl AsRef<Path>,
+        source: &PassphraseSource,
+    ) -> anyhow::Result<SecretKeyFile> {
+        let path = path.as_ref();
+        if !Self::is_encrypted(path).await? {
+            return Self::load_file(path).await;
+        }
+        let passphrase = source
+            .read(&format!("Passphrase for {}: ", path.display()), false)
             .await?;
+        Self::load_file_with_passphrase(path, Some(&passphrase)).await
+    }
 
-        // TODO(thomastaylor312): Figure out what the proper permissions are on windows (probably
-        // creator/owner with read/write permissions and everything else excluded) and figure out
-        // how to set those
-        #[cfg(target_family = "windows")]
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(dest)
-            .await?;
+    /// Returns whether the secret key file at the given path is encrypted
+    pub async fn is_encrypted(path: impl AsRef<Path>) -> anyhow::Result<bool> {
+        is_encrypted(&tokio::fs::read(path).await?)
+    }
 
-        file.write_all(&out).await?;
-        file.flush().await?;
-        Ok(())
+    /// Save the present keyfile to the named path.
+    pub async fn save_file(&self, dest: impl AsRef<Path>) -> anyhow::Result<()> {
+        write_key_file(dest, &toml::to_vec(self)?).await
+    }
+
+    /// Save the present keyfile to the named path, encrypted with the given passphrase. It can be
+    /// loaded again with [`load_file_with_passphrase`](SecretKeyFile::load_file_with_passphrase)
+    pub async fn save_encrypted_file(
+        &self,
+        dest: impl AsRef<Path>,
+        passphrase: &str,
+    ) -> anyhow::Result<()> {
+        if passphrase.is_empty() {
+            anyhow::bail!("Passphrase cannot be empty");
+        }
+        let plaintext = toml::to_vec(self)?;
+        let file = EncryptedSecretKeyFile {
+            version: self.version.clone(),
+            encrypted: EncryptedKeys::encrypt(
+                plaintext,
+                passphrase.to_owned(),
+                encryption::DEFAULT_KDF_PARAMS,
+            )
+            .await?,
+        };
+        write_key_file(dest, &toml::to_vec(&file)?).await
     }
 }
 
+fn is_encrypted(raw: &[u8]) -> anyhow::Result<bool> {
+    let value: toml::Value = toml::from_slice(raw)?;
+    Ok(value.get("encrypted").is_some())
+}
+
+async fn write_key_file(dest: impl AsRef<Path>, out: &[u8]) -> anyhow::Result<()> {
+    // Write to a temporary file and rename it over the destination so a failed write never leaves
+    // a truncated key file behind. Truncate the temporary file so that nothing is left over from
+    // an earlier failed attempt
+    let dest = dest.as_ref();
+    let tmp = dest.with_extension("toml.tmp");
+    let mut opts = OpenOptions::new();
+    opts.create(true).write(true).truncate(true);
+    // TODO(thomastaylor312): Figure out what the proper permissions are on windows (probably
+    // creator/owner with read/write permissions and everything else excluded) and figure out
+    // how to set those
+    #[cfg(target_family = "unix")]
+    opts.mode(0o600);
+    let mut file = opts.open(&tmp).await?;
+
+    file.write_all(out).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, dest).await?;
+    Ok(())
+}
+
 impl SecretKeyStorage for SecretKeyFile {
+    type Key = SecretKeyEntry;
+
     fn get_first_matching(&self, role: &SignatureRole) -> Option<&SecretKeyEntry> {
         self.key.iter().find(|k| k.roles.contains(role))
     }
+
+    fn get_all_matching(&self, role: &SignatureRole) -> Vec<&SecretKeyEntry> {
+        self.key.iter().filter(|k| k.roles.contains(role)).collect()
+    }
 }
 
 #[cfg(test)]
@@ -449,4 +740,64 @@ mod test {
             .expect("Should load key from file");
         assert_eq!(newfile.key.len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_encrypted_secret_keys() {
+        let mut kr = SecretKeyFile::default();
+        kr.key.push(SecretKeyEntry::new(
+            "test".to_owned(),
+            vec![SignatureRole::Creator],
+        ));
+
+        let outdir = tempfile::tempdir().expect("created a temp dir");
+        let dest = outdir.path().join("testkey.toml");
+        // Start with a plaintext file to make sure nothing is left over when it is encrypted
+        kr.save_file(&dest).await.unwrap();
+        assert!(!SecretKeyFile::is_encrypted(&dest).await.unwrap());
+        kr.save_encrypted_file(&dest, "correct horse")
+            .await
+            .expect("Should write encrypted keys to file");
+        assert!(SecretKeyFile::is_encrypted(&dest).await.unwrap());
+
+        let raw = tokio::fs::read_to_string(&dest).await.unwrap();
+        assert!(
+            !raw.contains(&kr.key[0].keypair),
+            "Secret key should not be stored in plaintext"
+        );
+
+        SecretKeyFile::load_file(&dest)
+            .await
+            .expect_err("Encrypted file should not load without a passphrase");
+        SecretKeyFile::load_file_with_passphrase(&dest, Some("battery staple"))
+            .await
+            .expect_err("Encrypted file should not load with the wrong passphrase");
+        let newfile = SecretKeyFile::load_file_with_passphrase(&dest, Some("correct horse"))
+            .await
+            .expect("Should load encrypted key from file");
+        assert_eq!(newfile.key.len(), 1);
+        assert_eq!(newfile.key[0].keypair, kr.key[0].keypair);
+
+        // Plaintext files still load when given a passphrase
+        kr.save_file(&dest).await.unwrap();
+        SecretKeyFile::load_file_with_passphrase(&dest, Some("correct horse"))
+            .await
+            .expect("Should load plaintext key from file");
+
+        kr.save_encrypted_file(&dest, "correct horse")
+            .await
+            .unwrap();
+        let passphrase_file = outdir.path().join("passphrase");
+        tokio::fs::write(&passphrase_file, "correct horse\n")
+            .await
+            .unwrap();
+        let source = PassphraseSource::detect(Some(passphrase_file), "BINDLE_TEST_PASSPHRASE");
+        let newfile = SecretKeyFile::load_file_from_source(&dest, &source)
+            .await
+            .expect("Should load encrypted key using the passphrase file");
+        assert_eq!(newfile.key[0].keypair, kr.key[0].keypair);
+        assert!(
+            !dest.with_extension("toml.tmp").exists(),
+            "Temporary file should be renamed over the key file"
+        );
+    }
 }
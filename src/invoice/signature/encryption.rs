@@ -0,0 +1,325 @@
+//! Passphrase encryption for secret key files.
+//!
+//! The encryption key is derived from the passphrase with Argon2id and a random salt, and the keys
+//! are encrypted with ChaCha20-Poly1305. The encryption is authenticated, so a wrong passphrase or
+//! a file that has been tampered with fails to decrypt rather than producing garbage. The KDF and
+//! its parameters are stored alongside the ciphertext so they can be strengthened for new files
+//! without breaking existing ones
+
+use std::path::PathBuf;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncBufReadExt;
+
+const KDF_ARGON2ID: &str = "argon2id";
+const CIPHER_CHACHA20_POLY1305: &str = "chacha20-poly1305";
+/// The Argon2id parameters used when encrypting, following the current OWASP recommendation of
+/// 19 MiB of memory, 2 passes and 1 lane
+pub(super) const DEFAULT_KDF_PARAMS: KdfParams = KdfParams {
+    memory_kib: 19 * 1024,
+    time_cost: 2,
+    parallelism: 1,
+};
+/// The largest Argon2id parameters accepted when decrypting. The parameters come from the file, so
+/// without a limit a crafted file could make loading it use all available memory or run for hours
+const MAX_KDF_PARAMS: KdfParams = KdfParams {
+    memory_kib: 1024 * 1024,
+    time_cost: 64,
+    parallelism: 16,
+};
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// The cost parameters for Argon2id
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct KdfParams {
+    /// The amount of memory to use, in KiB
+    memory_kib: u32,
+    /// The number of passes over the memory
+    time_cost: u32,
+    /// The number of lanes to use
+    parallelism: u32,
+}
+
+impl KdfParams {
+    fn check(&self) -> anyhow::Result<()> {
+        for (name, value, max) in [
+            ("memory", self.memory_kib, MAX_KDF_PARAMS.memory_kib),
+            ("time cost", self.time_cost, MAX_KDF_PARAMS.time_cost),
+            ("parallelism", self.parallelism, MAX_KDF_PARAMS.parallelism),
+        ] {
+            if value > max {
+                anyhow::bail!(
+                    "The key derivation {} ({}) is more than the maximum of {}",
+                    name,
+                    value,
+                    max
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The encrypted contents of a secret key file, along with everything needed to decrypt them
+/// other than the passphrase
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct EncryptedKeys {
+    kdf: String,
+    salt: String,
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+    // This needs to go at the bottom as it is serialized as a TOML table
+    kdf_params: KdfParams,
+}
+
+impl EncryptedKeys {
+    /// Encrypts the data with a key derived from the passphrase using the given parameters. Key
+    /// derivation is deliberately slow, so this runs on the blocking thread pool
+    pub(super) async fn encrypt(
+        plaintext: Vec<u8>,
+        passphrase: String,
+        params: KdfParams,
+    ) -> anyhow::Result<Self> {
+        tokio::task::spawn_blocking(move || Self::encrypt_blocking(&plaintext, &passphrase, params))
+            .await?
+    }
+
+    fn encrypt_blocking(
+        plaintext: &[u8],
+        passphrase: &str,
+        params: KdfParams,
+    ) -> anyhow::Result<Self> {
+        let mut rng = rand::rngs::OsRng {};
+        let mut salt = [0u8; SALT_LEN];
+        rng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+
+        let key = derive_key(passphrase, &salt, params)?;
+        let mut in_out = plaintext.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut in_out,
+        )
+        .map_err(|_| anyhow::anyhow!("Unable to encrypt secret keys"))?;
+
+        Ok(EncryptedKeys {
+            kdf: KDF_ARGON2ID.to_owned(),
+            salt: base64::encode(salt),
+            cipher: CIPHER_CHACHA20_POLY1305.to_owned(),
+            nonce: base64::encode(nonce),
+            ciphertext: base64::encode(in_out),
+            kdf_params: params,
+        })
+    }
+
+    /// Decrypts the data with a key derived from the passphrase. Returns an error if the
+    /// passphrase is wrong or the data was modified. Like encryption, this runs on the blocking
+    /// thread pool
+    pub(super) async fn decrypt(self, passphrase: String) -> anyhow::Result<Vec<u8>> {
+        tokio::task::spawn_blocking(move || self.decrypt_blocking(&passphrase)).await?
+    }
+
+    fn decrypt_blocking(&self, passphrase: &str) -> anyhow::Result<Vec<u8>> {
+        if self.kdf != KDF_ARGON2ID {
+            anyhow::bail!("Unsupported key derivation function {}", self.kdf);
+        }
+        if self.cipher != CIPHER_CHACHA20_POLY1305 {
+            anyhow::bail!("Unsupported cipher {}", self.cipher);
+        }
+        self.kdf_params.check()?;
+        let salt = base64::decode(&self.salt)?;
+        let nonce = Nonce::try_assume_unique_for_key(&base64::decode(&self.nonce)?)
+            .map_err(|_| anyhow::anyhow!("Invalid nonce"))?;
+        let mut in_out = base64::decode(&self.ciphertext)?;
+
+        let key = derive_key(passphrase, &salt, self.kdf_params)?;
+        let plaintext = key
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| {
+                // Deliberately vague, as there is no way to tell the two apart
+                anyhow::anyhow!(
+                    "Unable to decrypt secret keys. The passphrase is wrong or the file is corrupt"
+                )
+            })?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: KdfParams) -> anyhow::Result<LessSafeKey> {
+    let params = Params::new(
+        params.memory_kib,
+        params.time_cost,
+        params.parallelism,
+        Some(KEY_LEN),
+    )
+    .map_err(|e| anyhow::anyhow!("Invalid key derivation parameters: {}", e))?;
+    let mut key = [0u8; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Unable to derive encryption key: {}", e))?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| anyhow::anyhow!("Unable to create encryption key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Where to read the passphrase for an encrypted secret key file from
+#[derive(Debug, Clone)]
+pub enum PassphraseSource {
+    /// Prompt for the passphrase on the terminal
+    Prompt,
+    /// Read the passphrase from the environment variable with the given name
+    Env(String),
+    /// Read the passphrase from the first line of the given file
+    File(PathBuf),
+}
+
+impl PassphraseSource {
+    /// Returns the source to use given an optional passphrase file and the name of an environment
+    /// variable. The file is used if it is set, then the environment variable if it is set.
+    /// Otherwise the passphrase is prompted for
+    pub fn detect(file: Option<PathBuf>, env_var: &str) -> Self {
+        match file {
+            Some(path) => PassphraseSource::File(path),
+            None if std::env::var_os(env_var).is_some() => {
+                PassphraseSource::Env(env_var.to_owned())
+            }
+            None => PassphraseSource::Prompt,
+        }
+    }
+
+    /// Reads the passphrase, showing the given prompt if prompting for it. If `confirm` is set,
+    /// the passphrase is prompted for twice to make sure it was typed correctly, which should be
+    /// done whenever a new passphrase is being set. Empty passphrases are not allowed
+    pub async fn read(&self, prompt: &str, confirm: bool) -> anyhow::Result<String> {
+        let passphrase = match self {
+            PassphraseSource::Env(name) => std::env::var(name).map_err(|_| {
+                anyhow::anyhow!(
+                    "Unable to read passphrase from environment variable {}",
+                    name
+                )
+            })?,
+            PassphraseSource::File(path) => {
+                let raw = tokio::fs::read_to_string(path).await.map_err(|e| {
+                    anyhow::anyhow!("Unable to read passphrase file {}: {}", path.display(), e)
+                })?;
+                raw.lines().next().unwrap_or_default().to_owned()
+            }
+            PassphraseSource::Prompt => {
+                let passphrase = prompt_hidden(prompt).await?;
+                if confirm && prompt_hidden("Confirm passphrase: ").await? != passphrase {
+                    anyhow::bail!("Passphrases do not match");
+                }
+                passphrase
+            }
+        };
+        if passphrase.is_empty() {
+            anyhow::bail!("Passphrase cannot be empty");
+        }
+        Ok(passphrase)
+    }
+}
+
+/// Prompts for a line on the terminal without echoing what is typed
+async fn prompt_hidden(prompt: &str) -> anyhow::Result<String> {
+    eprint!("{}", prompt);
+    set_echo(false).await?;
+    let mut line = String::new();
+    let res = tokio::io::BufReader::new(tokio::io::stdin())
+        .read_line(&mut line)
+        .await;
+    // Always turn echo back on, even if reading failed
+    set_echo(true).await?;
+    eprintln!();
+    res?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+#[cfg(target_family = "unix")]
+async fn set_echo(on: bool) -> anyhow::Result<()> {
+    let status = tokio::process::Command::new("stty")
+        .arg(if on { "echo" } else { "-echo" })
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await?;
+    if !status.success() {
+        anyhow::bail!(
+            "Unable to prompt for a passphrase because there is no terminal. Use a passphrase file or environment variable instead"
+        );
+    }
+    Ok(())
+}
+
+// TODO: Figure out how to hide input on windows. Until then, the passphrase is echoed
+#[cfg(not(target_family = "unix"))]
+async fn set_echo(_on: bool) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHEAP_PARAMS: KdfParams = KdfParams {
+        memory_kib: 64,
+        time_cost: 1,
+        parallelism: 1,
+    };
+
+    #[tokio::test]
+    async fn test_max_kdf_params() {
+        let encrypted =
+            EncryptedKeys::encrypt(b"secret".to_vec(), "correct horse".to_owned(), CHEAP_PARAMS)
+                .await
+                .expect("Should encrypt");
+        assert_eq!(KDF_ARGON2ID, encrypted.kdf);
+        assert_eq!(
+            encrypted
+                .clone()
+                .decrypt("correct horse".to_owned())
+                .await
+                .expect("Should decrypt with the right passphrase"),
+            b"secret"
+        );
+
+        let too_expensive = [
+            KdfParams {
+                memory_kib: MAX_KDF_PARAMS.memory_kib + 1,
+                ..CHEAP_PARAMS
+            },
+            KdfParams {
+                time_cost: MAX_KDF_PARAMS.time_cost + 1,
+                ..CHEAP_PARAMS
+            },
+            KdfParams {
+                parallelism: MAX_KDF_PARAMS.parallelism + 1,
+                ..CHEAP_PARAMS
+            },
+        ];
+        for params in too_expensive {
+            let mut encrypted = encrypted.clone();
+            encrypted.kdf_params = params;
+            let err = encrypted
+                .decrypt("correct horse".to_owned())
+                .await
+                .expect_err("Should not decrypt with parameters over the maximum");
+            assert!(err.to_string().contains("maximum"));
+        }
+
+        let mut encrypted = encrypted;
+        encrypted.kdf = "pbkdf2-hmac-sha256".to_owned();
+        encrypted
+            .decrypt("correct horse".to_owned())
+            .await
+            .expect_err("Should not decrypt with an unknown KDF");
+    }
+}
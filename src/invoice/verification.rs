@@ -1,9 +1,10 @@
 use crate::invoice::Signed;
 
-use super::signature::KeyRing;
-use super::{Invoice, Signature, SignatureError, SignatureRole};
+use super::signature::{KeyRing, RevocationReason};
+use super::{Invoice, Signature, SignatureError, SignatureRole, SignatureVersion};
 use ed25519_dalek::{PublicKey, Signature as EdSignature};
-use tracing::{debug, info};
+use serde::Serialize;
+use tracing::{debug, info, warn};
 
 use std::borrow::{Borrow, BorrowMut};
 use std::convert::TryInto;
@@ -134,26 +135,91 @@ fn parse_roles(r: Option<&&str>) -> Result<Vec<SignatureRole>, &'static str> {
         .collect::<Result<Vec<_>, _>>()
 }
 
+/// The checks a strategy makes, in terms of which roles it targets
+struct StrategyRules<'a> {
+    /// The roles the strategy targets
+    roles: &'a [SignatureRole],
+    /// Whether signatures for all roles must be valid, rather than just the target roles
+    all_valid: bool,
+    /// Whether every checked signature must be made by a key in the keyring
+    all_verified: bool,
+    /// Whether there must be a signature for each of the target roles
+    all_roles: bool,
+}
+
 /// A strategy for verifying an invoice.
 impl VerificationStrategy {
-    fn verify_signature(&self, sig: &Signature, cleartext: &[u8]) -> Result<(), SignatureError> {
-        let pk = base64::decode(sig.key.as_bytes())
-            .map_err(|_| SignatureError::CorruptKey(sig.key.clone()))?;
-        let sig_block = base64::decode(sig.signature.as_bytes())
-            .map_err(|_| SignatureError::CorruptSignature(sig.key.clone()))?;
-
-        let pubkey =
-            PublicKey::from_bytes(&pk).map_err(|_| SignatureError::CorruptKey(sig.key.clone()))?;
-        let ed_sig = EdSignature::new(
-            sig_block
-                .as_slice()
-                .try_into()
-                .map_err(|_| SignatureError::CorruptSignature(sig.key.clone()))?,
-        );
-        pubkey
-            .verify_strict(cleartext, &ed_sig)
-            .map_err(|_| SignatureError::Unverified(sig.key.clone()))
+    /// Verify the `yanked_signature` blocks on the invoice.
+    ///
+    /// Every yank signature must be made by the host role and must be valid. A yank signed by a
+    /// key that is not in the keyring or that has been revoked is only an error for
+    /// `ExhaustiveVerification`; for all other strategies a warning is logged so the user can be
+    /// notified.
+    fn verify_yank_signatures(
+        &self,
+        inv: &Invoice,
+        keyring: &KeyRing,
+    ) -> Result<(), SignatureError> {
+        let signatures = match inv.yanked_signature.as_ref() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        for s in signatures {
+            debug!(by = %s.by, "Checking yank signature");
+            if s.role != SignatureRole::Host {
+                return Err(SignatureError::Unverified(format!(
+                    "yank signature for key {} must have the host role",
+                    s.key
+                )));
+            }
+            let cleartext = super::yank_cleartext(&inv.bindle.id, &s.by, s.at);
+            verify_signature(s, cleartext.as_bytes())?;
+            debug!("Yank signature verified");
+
+            let pubkey = base64::decode(&s.key)
+                .map_err(|_| SignatureError::CorruptKey(s.key.to_string()))?;
+            let pko = PublicKey::from_bytes(pubkey.as_slice())
+                .map_err(|_| SignatureError::CorruptKey(s.key.to_string()))?;
+            if let Some(revocation) = keyring.revocation(&pko, s.at) {
+                if matches!(self, VerificationStrategy::ExhaustiveVerification) {
+                    return Err(SignatureError::RevokedKey(s.key.clone()));
+                }
+                warn!(by = %s.by, key = %s.key, reason = ?revocation.reason, "Invoice was yanked by a revoked host key");
+            } else if !keyring.contains(&pko) {
+                if matches!(self, VerificationStrategy::ExhaustiveVerification) {
+                    return Err(SignatureError::Unverified(
+                        "strategy requires that all yank signatures must be verified".to_owned(),
+                    ));
+                }
+                warn!(by = %s.by, key = %s.key, "Invoice was yanked by a host key that is not in the keyring");
+            }
+        }
+        Ok(())
+    }
+
+    fn rules(&self) -> StrategyRules<'_> {
+        let (roles, all_valid, all_verified, all_roles) = match self {
+            VerificationStrategy::GreedyVerification => {
+                (GREEDY_VERIFICATION_ROLES, true, true, true)
+            }
+            VerificationStrategy::CreativeIntegrity => (CREATIVE_INTEGITY_ROLES, false, true, true),
+            VerificationStrategy::AuthoritativeIntegrity => {
+                (AUTHORITATIVE_INTEGRITY_ROLES, false, false, false)
+            }
+            VerificationStrategy::ExhaustiveVerification => {
+                (EXHAUSTIVE_VERIFICATION_ROLES, true, true, false)
+            }
+            VerificationStrategy::MultipleAttestation(a) => (a.as_slice(), false, true, true),
+            VerificationStrategy::MultipleAttestationGreedy(a) => (a.as_slice(), true, true, true),
+        };
+        StrategyRules {
+            roles,
+            all_valid,
+            all_verified,
+            all_roles,
+        }
     }
+
     /// Verify that every signature on this invoice is correct.
     ///
     /// The verification strategy will determine how this verification is performed.
@@ -162,12 +228,19 @@ impl VerificationStrategy {
     ///
     /// - Is the key in the keyring?
     /// - Can the signature be verified?
+    /// - Has the key been revoked for signatures made at the signature's `at` time?
     ///
     /// Note that the purpose of the keyring is to ensure that we know about the
     /// entity that claims to have signed the invoice.
     ///
     /// If no signatures are on the invoice, this will succeed.
     ///
+    /// A signature from a revoked key is rejected with [`SignatureError::RevokedKey`] if it is for
+    /// one of the roles the strategy checks. Otherwise, it is ignored and a warning is logged.
+    ///
+    /// Any `[[yanked_signature]]` blocks are also checked. These must be signed by the host role
+    /// and must be valid, regardless of the strategy.
+    ///
     /// A strategy will determine success or failure based on whether the signature is verified,
     /// whether the keys are known, whether the requisite number/roles are satisfied, and
     /// so on.
@@ -180,20 +253,14 @@ impl VerificationStrategy {
         I: Borrow<Invoice> + Into<Invoice>,
     {
         let inv = invoice.borrow();
-        let (roles, all_valid, all_verified, all_roles) = match self {
-            VerificationStrategy::GreedyVerification => {
-                (GREEDY_VERIFICATION_ROLES, true, true, true)
-            }
-            VerificationStrategy::CreativeIntegrity => (CREATIVE_INTEGITY_ROLES, false, true, true),
-            VerificationStrategy::AuthoritativeIntegrity => {
-                (AUTHORITATIVE_INTEGRITY_ROLES, false, false, false)
-            }
-            VerificationStrategy::ExhaustiveVerification => {
-                (EXHAUSTIVE_VERIFICATION_ROLES, true, true, false)
-            }
-            VerificationStrategy::MultipleAttestation(a) => (a.as_slice(), false, true, true),
-            VerificationStrategy::MultipleAttestationGreedy(a) => (a.as_slice(), true, true, true),
-        };
+        let StrategyRules {
+            roles,
+            all_valid,
+            all_verified,
+            all_roles,
+        } = self.rules();
+
+        self.verify_yank_signatures(inv, keyring)?;
 
         // Either the Creator or an Approver must be in the keyring
         match inv.signature.as_ref() {
@@ -216,16 +283,28 @@ impl VerificationStrategy {
                     }
 
                     let role = s.role.clone();
-                    let cleartext = inv.cleartext(&s.by, &role);
+                    let cleartext = inv.cleartext(&s.by, &role, s.at, s.version);
 
                     // Verify the signature
                     // TODO: This would allow a trivial DOS attack in which an attacker
                     // would only need to attach a known-bad signature, and that would
                     // prevent the module from ever being usable. This is marginally
                     // better if we only verify signatures on known keys.
-                    self.verify_signature(s, cleartext.as_bytes())?;
+                    verify_signature(s, cleartext.as_bytes())?;
                     debug!("Signature verified");
 
+                    let pubkey = base64::decode(&s.key)
+                        .map_err(|_| SignatureError::CorruptKey(s.key.to_string()))?;
+                    let pko = PublicKey::from_bytes(pubkey.as_slice())
+                        .map_err(|_| SignatureError::CorruptKey(s.key.to_string()))?;
+                    if let Some(revocation) = keyring.revocation(&pko, s.at) {
+                        if target_role {
+                            return Err(SignatureError::RevokedKey(s.key.clone()));
+                        }
+                        warn!(by = %s.by, key = %s.key, reason = ?revocation.reason, "Ignoring signature made with a revoked key");
+                        continue;
+                    }
+
                     if !target_role && !all_verified {
                         debug!("Not a target role, not checking for verification");
                         continue;
@@ -233,10 +312,6 @@ impl VerificationStrategy {
                         filled_roles.push(role);
                     }
                     // See if the public key is known to us
-                    let pubkey = base64::decode(&s.key)
-                        .map_err(|_| SignatureError::CorruptKey(s.key.to_string()))?;
-                    let pko = PublicKey::from_bytes(pubkey.as_slice())
-                        .map_err(|_| SignatureError::CorruptKey(s.key.to_string()))?;
 
                     debug!("Looking for key");
                     // If the keyring contains PKO, then we are successful for this round.
@@ -274,6 +349,237 @@ impl VerificationStrategy {
             }
         }
     }
+
+    /// Checks every signature on the invoice and reports on each one, along with which of the
+    /// strategy's rules passed.
+    ///
+    /// Unlike [`verify`](VerificationStrategy::verify), this doesn't stop at the first problem, so
+    /// it is meant for auditing rather than deciding whether to use an invoice. Whether the invoice
+    /// passed is still decided by `verify`, so the two can never disagree
+    pub fn report(&self, invoice: &Invoice, keyring: &KeyRing) -> VerificationReport {
+        let StrategyRules {
+            roles,
+            all_valid,
+            all_verified,
+            all_roles,
+        } = self.rules();
+        let role_list = roles
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let checked_roles = if all_valid {
+            "all roles".to_owned()
+        } else {
+            format!("roles [{}]", role_list)
+        };
+
+        let signatures: Vec<SignatureReport> = invoice
+            .signature
+            .iter()
+            .flatten()
+            .map(|s| {
+                let cleartext = invoice.cleartext(&s.by, &s.role, s.at, s.version);
+                let checked = all_valid || roles.contains(&s.role);
+                SignatureReport::new(s, cleartext.as_bytes(), keyring, checked)
+            })
+            .collect();
+        // Signatures that count towards the strategy, mirroring the checks done by `verify`
+        let counted = || {
+            signatures.iter().filter(|s| {
+                s.checked
+                    && s.valid
+                    && s.revoked.is_none()
+                    && (all_verified || roles.contains(&s.role))
+            })
+        };
+
+        let mut rules = Vec::new();
+        if invoice.signature.is_none() {
+            // `verify` lets unsigned invoices through, so none of the signature rules apply
+            rules.push(RuleReport {
+                rule: "Invoices without any signatures are not checked".to_owned(),
+                passed: true,
+            });
+        } else {
+            rules.push(RuleReport {
+                rule: format!("Signatures for {} must be valid", checked_roles),
+                passed: signatures.iter().filter(|s| s.checked).all(|s| s.valid),
+            });
+            rules.push(RuleReport {
+                rule: format!(
+                    "Signatures for roles [{}] must not be made with revoked keys",
+                    role_list
+                ),
+                passed: !signatures
+                    .iter()
+                    .any(|s| roles.contains(&s.role) && s.revoked.is_some()),
+            });
+            if all_verified {
+                rules.push(RuleReport {
+                    rule: format!(
+                        "Signatures for {} must be made with keys in the keyring",
+                        checked_roles
+                    ),
+                    passed: counted().all(|s| s.in_keyring),
+                });
+            }
+            rules.push(RuleReport {
+                rule: "At least one signature must be made with a key in the keyring".to_owned(),
+                passed: counted().any(|s| s.in_keyring),
+            });
+            if all_roles {
+                rules.push(RuleReport {
+                    rule: format!(
+                        "There must be a signature for each of roles [{}]",
+                        role_list
+                    ),
+                    passed: roles.iter().all(|r| counted().any(|s| &s.role == r)),
+                });
+            }
+        }
+
+        let exhaustive = matches!(self, VerificationStrategy::ExhaustiveVerification);
+        let yank_signatures: Vec<SignatureReport> = invoice
+            .yanked_signature
+            .iter()
+            .flatten()
+            .map(|s| {
+                let cleartext = super::yank_cleartext(&invoice.bindle.id, &s.by, s.at);
+                SignatureReport::new(s, cleartext.as_bytes(), keyring, true)
+            })
+            .collect();
+        if !yank_signatures.is_empty() {
+            rules.push(RuleReport {
+                rule: "Yank signatures must be valid and made with the host role".to_owned(),
+                passed: yank_signatures
+                    .iter()
+                    .all(|s| s.valid && s.role == SignatureRole::Host),
+            });
+            if exhaustive {
+                rules.push(RuleReport {
+                    rule: "Yank signatures must be made with unrevoked keys in the keyring"
+                        .to_owned(),
+                    passed: yank_signatures
+                        .iter()
+                        .all(|s| s.in_keyring && s.revoked.is_none()),
+                });
+            }
+        }
+
+        let error = self.verify(invoice.clone(), keyring).err();
+        VerificationReport {
+            strategy: format!("{:?}", self),
+            verified: error.is_none(),
+            error: error.map(|e| e.to_string()),
+            rules,
+            signatures,
+            yank_signatures,
+        }
+    }
+}
+
+/// A detailed report of how an invoice was checked by a [`VerificationStrategy`]. Created with
+/// [`VerificationStrategy::report`]
+#[derive(Debug, Serialize)]
+pub struct VerificationReport {
+    /// The strategy that was used
+    pub strategy: String,
+    /// Whether the invoice passed verification
+    pub verified: bool,
+    /// Why the invoice failed verification, if it did
+    pub error: Option<String>,
+    /// Each of the rules the strategy checks and whether it passed
+    pub rules: Vec<RuleReport>,
+    /// Details about each signature on the invoice
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<SignatureReport>,
+    /// Details about each signature on the yank of the invoice
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub yank_signatures: Vec<SignatureReport>,
+}
+
+/// One of the rules checked by a strategy
+#[derive(Debug, Serialize)]
+pub struct RuleReport {
+    /// A description of the rule
+    pub rule: String,
+    /// Whether the invoice passed the rule
+    pub passed: bool,
+}
+
+/// Details about a single signature on an invoice
+#[derive(Debug, Serialize)]
+pub struct SignatureReport {
+    /// The name of the signer given in the signature
+    pub by: String,
+    /// The role the signature was made with
+    pub role: SignatureRole,
+    /// The public key that made the signature
+    pub key: String,
+    /// The label of the key in the keyring, if it is there
+    pub key_label: Option<String>,
+    /// Whether the key is in the keyring
+    pub in_keyring: bool,
+    /// Why the key was revoked, if it is revoked for signatures made at the time of this one
+    pub revoked: Option<RevocationReason>,
+    /// Whether the signature is valid for the invoice
+    pub valid: bool,
+    /// Why the signature isn't valid, if it isn't
+    pub problem: Option<String>,
+    /// The UNIX timestamp the signature was made at
+    pub at: u64,
+    /// The version of the signature
+    pub version: SignatureVersion,
+    /// Whether the strategy checks this signature at all
+    pub checked: bool,
+}
+
+impl SignatureReport {
+    fn new(sig: &Signature, cleartext: &[u8], keyring: &KeyRing, checked: bool) -> Self {
+        let problem = verify_signature(sig, cleartext)
+            .err()
+            .map(|e| e.to_string());
+        let pk = sig.public_key().ok();
+        let entry = pk.as_ref().and_then(|pk| keyring.get(pk));
+        SignatureReport {
+            by: sig.by.clone(),
+            role: sig.role.clone(),
+            key: sig.key.clone(),
+            key_label: entry.map(|e| e.label.clone()),
+            in_keyring: entry.is_some(),
+            revoked: pk
+                .as_ref()
+                .and_then(|pk| keyring.revocation(pk, sig.at))
+                .map(|r| r.reason),
+            valid: problem.is_none(),
+            problem,
+            at: sig.at,
+            version: sig.version,
+            checked,
+        }
+    }
+}
+
+/// Checks that the signature is a valid signature of the cleartext by its key. This does not check
+/// whether the key is trusted
+pub(crate) fn verify_signature(sig: &Signature, cleartext: &[u8]) -> Result<(), SignatureError> {
+    let pk = base64::decode(sig.key.as_bytes())
+        .map_err(|_| SignatureError::CorruptKey(sig.key.clone()))?;
+    let sig_block = base64::decode(sig.signature.as_bytes())
+        .map_err(|_| SignatureError::CorruptSignature(sig.key.clone()))?;
+
+    let pubkey =
+        PublicKey::from_bytes(&pk).map_err(|_| SignatureError::CorruptKey(sig.key.clone()))?;
+    let ed_sig = EdSignature::new(
+        sig_block
+            .as_slice()
+            .try_into()
+            .map_err(|_| SignatureError::CorruptSignature(sig.key.clone()))?,
+    );
+    pubkey
+        .verify_strict(cleartext, &ed_sig)
+        .map_err(|_| SignatureError::Unverified(sig.key.clone()))
 }
 
 /// An invoice whose signatures have been verified. Can be converted borrowed as a plain [`Invoice`]
@@ -355,6 +661,7 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::invoice::signature::{Revocation, RevocationReason};
     use crate::invoice::*;
 
     #[test]
@@ -711,4 +1018,131 @@ mod test {
                 .expect_err("inv should not pass: Requires that all signatures must be verified");
         }
     }
+
+    #[test]
+    fn test_revoked_keys() {
+        let invoice = r#"
+        bindleVersion = "1.0.0"
+
+        [bindle]
+        name = "arecebo"
+        version = "1.2.3"
+        "#;
+        let mut invoice: crate::Invoice = toml::from_str(invoice).expect("a nice clean parse");
+
+        let key_creator =
+            SecretKeyEntry::new("Test Creator".to_owned(), vec![SignatureRole::Creator]);
+        let key_proxy = SecretKeyEntry::new("Test Proxy".to_owned(), vec![SignatureRole::Proxy]);
+        invoice
+            .sign(SignatureRole::Creator, &key_creator)
+            .expect("signed as creator");
+        invoice
+            .sign(SignatureRole::Proxy, &key_proxy)
+            .expect("signed as proxy");
+        let at = invoice.signature.as_ref().unwrap()[0].at;
+
+        let keyring = KeyRing::new(vec![
+            key_creator.clone().try_into().expect("convert to pubkey"),
+            key_proxy.clone().try_into().expect("convert to pubkey"),
+        ]);
+        let revoke = |key: &SecretKeyEntry, from: Option<u64>, until: Option<u64>| {
+            let mut keyring = keyring.clone();
+            keyring.revoked.push(Revocation {
+                valid_from: from,
+                valid_until: until,
+                ..Revocation::new(key.key().unwrap().public, RevocationReason::Compromised)
+            });
+            keyring
+        };
+
+        // Revoked entirely
+        let revoked = revoke(&key_creator, None, None);
+        assert!(matches!(
+            VerificationStrategy::CreativeIntegrity.verify(invoice.clone(), &revoked),
+            Err(SignatureError::RevokedKey(_))
+        ));
+
+        // Compromised after the signature was made
+        VerificationStrategy::CreativeIntegrity
+            .verify(invoice.clone(), &revoke(&key_creator, None, Some(at + 1)))
+            .expect("Signatures made inside the window should be trusted");
+
+        // Compromised when or before the signature was made
+        VerificationStrategy::CreativeIntegrity
+            .verify(invoice.clone(), &revoke(&key_creator, None, Some(at)))
+            .expect_err("Signatures made after the window should be rejected");
+        VerificationStrategy::CreativeIntegrity
+            .verify(invoice.clone(), &revoke(&key_creator, Some(at + 1), None))
+            .expect_err("Signatures made before the window should be rejected");
+
+        // A revoked key for a role the strategy doesn't check is only flagged
+        let revoked = revoke(&key_proxy, None, None);
+        VerificationStrategy::CreativeIntegrity
+            .verify(invoice.clone(), &revoked)
+            .expect("Revoked proxy key should not matter for creative integrity");
+        assert!(matches!(
+            VerificationStrategy::ExhaustiveVerification.verify(invoice, &revoked),
+            Err(SignatureError::RevokedKey(_))
+        ));
+
+        // The keyring format should round trip
+        let serialized = toml::to_string(&revoke(&key_creator, None, Some(at))).unwrap();
+        let parsed: KeyRing = toml::from_str(&serialized).expect("keyring should parse");
+        assert_eq!(1, parsed.revoked.len());
+        assert_eq!(RevocationReason::Compromised, parsed.revoked[0].reason);
+        assert_eq!(Some(at), parsed.revoked[0].valid_until);
+    }
+
+    #[test]
+    fn test_verification_report() {
+        let invoice = r#"
+        bindleVersion = "1.0.0"
+
+        [bindle]
+        name = "arecebo"
+        version = "1.2.3"
+        "#;
+        let mut invoice: crate::Invoice = toml::from_str(invoice).expect("a nice clean parse");
+
+        let key_creator =
+            SecretKeyEntry::new("Test Creator".to_owned(), vec![SignatureRole::Creator]);
+        let key_proxy = SecretKeyEntry::new("Test Proxy".to_owned(), vec![SignatureRole::Proxy]);
+        invoice
+            .sign(SignatureRole::Creator, &key_creator)
+            .expect("signed as creator");
+        invoice
+            .sign(SignatureRole::Proxy, &key_proxy)
+            .expect("signed as proxy");
+        let keyring = KeyRing::new(vec![key_creator
+            .clone()
+            .try_into()
+            .expect("convert to pubkey")]);
+
+        let report = VerificationStrategy::CreativeIntegrity.report(&invoice, &keyring);
+        assert!(report.verified);
+        assert!(report.error.is_none());
+        assert!(report.rules.iter().all(|r| r.passed));
+        assert_eq!(2, report.signatures.len());
+        assert_eq!(
+            Some("Test Creator".to_owned()),
+            report.signatures[0].key_label
+        );
+        assert!(report.signatures[0].checked && report.signatures[0].valid);
+        assert!(!report.signatures[1].checked && !report.signatures[1].in_keyring);
+
+        // The unknown proxy key fails exhaustive verification, and the report should say why
+        let report = VerificationStrategy::ExhaustiveVerification.report(&invoice, &keyring);
+        assert!(!report.verified);
+        assert!(report.error.is_some());
+        assert_eq!(1, report.rules.iter().filter(|r| !r.passed).count());
+        assert!(report.signatures[1].checked);
+
+        // A tampered signature is reported without stopping the report
+        invoice.signature.as_mut().unwrap()[0].by = "Someone Else".to_owned();
+        let report = VerificationStrategy::ExhaustiveVerification.report(&invoice, &keyring);
+        assert!(!report.signatures[0].valid);
+        assert!(report.signatures[0].problem.is_some());
+        assert!(report.signatures[1].valid);
+        assert!(!report.rules[0].passed);
+    }
 }
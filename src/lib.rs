@@ -11,6 +11,8 @@
 mod id;
 pub mod invoice;
 
+#[cfg(target_family = "unix")]
+pub mod agent;
 pub mod async_util;
 #[cfg(feature = "caching")]
 pub mod cache;
@@ -0,0 +1,310 @@
+//! Functionality for responding to a compromised signing key across everything in a terminal
+//! provider, following the recommendations in the signing spec.
+//!
+//! Every invoice (including yanked ones) is checked for signatures made by the compromised key,
+//! optionally only within the window of time the key was known to be compromised. What happens to
+//! an affected invoice depends on the role the key signed it with:
+//!
+//! - `creator`: The invoice is yanked, as its contents can no longer be trusted
+//! - `approver` and `proxy`: The signature is removed from the invoice
+//! - `host`: Nothing is changed, but the invoice is flagged for review. The same goes for invoices
+//!   that were yanked by the key, as the yank itself may not have been legitimate
+//!
+//! Removing a signature is the only time signatures are not treated as append-only, so this should
+//! only be run while the provider isn't being used by a running server. Every affected invoice is
+//! listed in the returned report so there is a record of what was changed. Remember to also revoke
+//! the key in any keyrings that trust it
+
+use ed25519_dalek::PublicKey;
+use serde::Serialize;
+use tokio_stream::StreamExt;
+use tracing::{info, instrument, warn};
+
+use crate::provider::migrate::Import;
+use crate::provider::{Provider, ProviderError, Result};
+use crate::{Id, Signature, SignatureError, SignatureRole, SigningKey};
+
+/// Options for responding to a compromised key
+#[derive(Debug, Clone)]
+pub struct CompromiseOptions {
+    /// The compromised public key, base64 encoded the same way as in a keyring
+    pub key: String,
+    /// Only signatures made at or after this UNIX timestamp are affected. All signatures from
+    /// the start are affected if this is `None`
+    pub from: Option<u64>,
+    /// Only signatures made before this UNIX timestamp are affected. All signatures up to now are
+    /// affected if this is `None`
+    pub until: Option<u64>,
+    /// Whether to only report what would be changed without changing anything
+    pub dry_run: bool,
+}
+
+impl CompromiseOptions {
+    /// Returns whether a signature made at the given UNIX timestamp falls inside of the window
+    fn covers(&self, at: u64) -> bool {
+        self.from.map(|f| at >= f).unwrap_or(true) && self.until.map(|u| at < u).unwrap_or(true)
+    }
+}
+
+/// What happened to an invoice that was signed by the compromised key
+#[derive(Debug, Serialize)]
+pub struct AffectedBindle {
+    /// The ID of the bindle
+    pub id: Id,
+    /// Whether the bindle was yanked (or would have been) because the key signed it as the creator.
+    /// This is false for bindles that were already yanked
+    pub yanked: bool,
+    /// The signatures made by the key that were removed (or would have been)
+    pub removed_signatures: Vec<Signature>,
+    /// Whether the bindle has no signatures left after removing the affected ones, meaning it
+    /// should no longer be trusted
+    pub unsigned: bool,
+    /// Whether the key signed or yanked the bindle as the host. This can't be fixed automatically,
+    /// so the bindle should be re-evaluated by hand
+    pub needs_review: bool,
+}
+
+/// The results of responding to a compromised key
+#[derive(Debug, Default, Serialize)]
+pub struct CompromiseReport {
+    /// Whether this was a dry run, in which case nothing was actually changed
+    pub dry_run: bool,
+    /// The number of invoices checked
+    pub invoices_checked: u64,
+    /// The invoices that were signed by the key within the window
+    pub affected: Vec<AffectedBindle>,
+}
+
+/// Checks every invoice in the provider for signatures made by the compromised key and applies
+/// the policy described in the [module level docs](self). The host key is used to sign any yanks.
+///
+/// Any error stops the run. Since invoices that were already handled are yanked or no longer have
+/// the signatures, running it again picks up where it left off
+#[instrument(level = "trace", skip(provider, host_key), fields(key = %options.key))]
+pub async fn respond_to_compromise<P, K>(
+    provider: &P,
+    options: &CompromiseOptions,
+    host_key: &K,
+) -> Result<CompromiseReport>
+where
+    P: Provider + Import + Sync,
+    K: SigningKey + ?Sized,
+{
+    if !host_key.roles().contains(&SignatureRole::Host) {
+        return Err(SignatureError::NoSuitableKey.into());
+    }
+    let compromised = parse_key(&options.key)?;
+    let mut report = CompromiseReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+
+    let mut invoices = provider.list_invoices().await?;
+    while let Some(inv) = invoices.next().await {
+        let mut inv = inv?;
+        report.invoices_checked += 1;
+        let id = inv.bindle.id.clone();
+        let is_affected = |s: &Signature| {
+            s.public_key().map(|pk| pk == compromised).unwrap_or(false) && options.covers(s.at)
+        };
+
+        let mut creator_signed = false;
+        let mut needs_review = inv.yanked_signature.iter().flatten().any(is_affected);
+        let mut removed_signatures = Vec::new();
+        let mut kept = Vec::new();
+        for s in inv.signature.take().unwrap_or_default() {
+            if !is_affected(&s) {
+                kept.push(s);
+                continue;
+            }
+            match s.role {
+                SignatureRole::Creator => {
+                    creator_signed = true;
+                    kept.push(s);
+                }
+                SignatureRole::Host => {
+                    needs_review = true;
+                    kept.push(s);
+                }
+                SignatureRole::Approver | SignatureRole::Proxy => removed_signatures.push(s),
+            }
+        }
+        if !creator_signed && !needs_review && removed_signatures.is_empty() {
+            continue;
+        }
+
+        let unsigned = kept.is_empty();
+        inv.signature = if unsigned { None } else { Some(kept) };
+        let yank = creator_signed && !inv.yanked.unwrap_or(false);
+        warn!(
+            %id,
+            yank,
+            removed = removed_signatures.len(),
+            needs_review,
+            "Invoice was signed by the compromised key"
+        );
+        if !options.dry_run {
+            if !removed_signatures.is_empty() {
+                provider.import_invoice(&inv).await?;
+            }
+            if yank {
+                let signature = crate::sign_yank(&id, host_key)?;
+                provider.yank_invoice(&id, Some(signature)).await?;
+            }
+        }
+        report.affected.push(AffectedBindle {
+            id,
+            yanked: yank,
+            removed_signatures,
+            unsigned,
+            needs_review,
+        });
+    }
+    info!(
+        invoices_checked = report.invoices_checked,
+        affected = report.affected.len(),
+        dry_run = report.dry_run,
+        "Finished responding to compromised key"
+    );
+    Ok(report)
+}
+
+fn parse_key(key: &str) -> Result<PublicKey> {
+    let raw = base64::decode(key.trim())
+        .map_err(|_| ProviderError::FailedSigning(SignatureError::CorruptKey(key.to_owned())))?;
+    PublicKey::from_bytes(&raw)
+        .map_err(|_| ProviderError::FailedSigning(SignatureError::CorruptKey(key.to_owned())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::invoice::signature::KeyRing;
+    use crate::provider::embedded::EmbeddedProvider;
+    use crate::provider::file::FileProvider;
+    use crate::search::NoopEngine;
+    use crate::testing;
+    use crate::{SecretKeyEntry, VerificationStrategy};
+    use std::convert::TryInto;
+    use tempfile::tempdir;
+
+    async fn check_compromise<P>(provider: P)
+    where
+        P: Provider + Import + Sync,
+    {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let creator = SecretKeyEntry::new("Creator".to_owned(), vec![SignatureRole::Creator]);
+        let host = SecretKeyEntry::new("Host".to_owned(), vec![SignatureRole::Host]);
+        let approver = SecretKeyEntry::new(
+            "Approver".to_owned(),
+            vec![SignatureRole::Creator, SignatureRole::Approver],
+        );
+
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(scaffold.invoice.clone(), &KeyRing::default())
+            .unwrap();
+        let signed = crate::invoice::sign(
+            verified,
+            vec![
+                (SignatureRole::Creator, &creator),
+                (SignatureRole::Approver, &approver),
+            ],
+        )
+        .unwrap();
+        let approved = provider.create_invoice(signed).await.unwrap().0.bindle.id;
+
+        // The same key also created another bindle
+        let mut other = scaffold.invoice.clone();
+        other.bindle.id = "another.com/bindle/1.0.0".try_into().unwrap();
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(other, &KeyRing::default())
+            .unwrap();
+        let signed =
+            crate::invoice::sign(verified, vec![(SignatureRole::Creator, &approver)]).unwrap();
+        let created = provider.create_invoice(signed).await.unwrap().0.bindle.id;
+
+        let key: crate::invoice::signature::KeyEntry = (&approver).try_into().unwrap();
+        let mut options = CompromiseOptions {
+            key: key.key.clone(),
+            from: None,
+            until: None,
+            dry_run: true,
+        };
+
+        respond_to_compromise(&provider, &options, &creator)
+            .await
+            .expect_err("Yanks should require a host key");
+
+        let report = respond_to_compromise(&provider, &options, &host)
+            .await
+            .expect("dry run should succeed");
+        assert_eq!(2, report.invoices_checked);
+        assert_eq!(2, report.affected.len());
+        assert_eq!(
+            2,
+            provider
+                .get_invoice(&approved)
+                .await
+                .unwrap()
+                .signature
+                .unwrap()
+                .len(),
+            "A dry run should not change anything"
+        );
+        provider
+            .get_invoice(&created)
+            .await
+            .expect("A dry run should not yank anything");
+
+        // Signatures made before the window are left alone
+        options.dry_run = false;
+        options.from = Some(u64::MAX);
+        let report = respond_to_compromise(&provider, &options, &host)
+            .await
+            .unwrap();
+        assert!(report.affected.is_empty());
+
+        options.from = None;
+        let report = respond_to_compromise(&provider, &options, &host)
+            .await
+            .expect("responding to the compromise should succeed");
+        let approved_report = report.affected.iter().find(|a| a.id == approved).unwrap();
+        assert_eq!(1, approved_report.removed_signatures.len());
+        assert!(!approved_report.yanked && !approved_report.unsigned);
+        let created_report = report.affected.iter().find(|a| a.id == created).unwrap();
+        assert!(created_report.yanked);
+
+        let inv = provider.get_invoice(&approved).await.unwrap();
+        let signatures = inv.signature.unwrap();
+        assert_eq!(1, signatures.len());
+        assert_eq!("Creator", signatures[0].by);
+        assert!(matches!(
+            provider.get_invoice(&created).await,
+            Err(ProviderError::Yanked)
+        ));
+
+        // Running it again finds the yanked bindle, but doesn't change anything else
+        let report = respond_to_compromise(&provider, &options, &host)
+            .await
+            .unwrap();
+        assert_eq!(1, report.affected.len());
+        assert!(!report.affected[0].yanked);
+    }
+
+    #[tokio::test]
+    async fn test_should_respond_to_compromise_file_provider() {
+        let dir = tempdir().unwrap();
+        check_compromise(FileProvider::new(dir.path(), NoopEngine::default()).await).await;
+    }
+
+    #[tokio::test]
+    async fn test_should_respond_to_compromise_embedded_provider() {
+        let dir = tempdir().unwrap();
+        check_compromise(
+            EmbeddedProvider::new(dir.path(), NoopEngine::default())
+                .await
+                .unwrap(),
+        )
+        .await;
+    }
+}
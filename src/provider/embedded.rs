@@ -6,30 +6,61 @@
 //! [CBOR](https://github.com/pyfisch/cbor) format for efficient serialization/deserialization from
 //! the database.
 //!
+//! Small parcels are stored inline in the database. Parcels larger than 1 MiB are streamed to
+//! content-addressed files in a `parcels` directory next to the database files, so they never have
+//! to be held in memory all at once.
+//!
 //! This provider is currently experimental, with the goal of replacing the `FileProvider` as the
 //! default provider in the future.
 
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use sha2::{Digest, Sha256};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use sled::Error as SledError;
+use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::sync::Semaphore;
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt};
 use tokio_util::codec::{BytesCodec, FramedRead};
 use tokio_util::io::StreamReader;
 use tracing::{debug, error, info, instrument, trace, warn};
 use tracing_futures::Instrument;
 
-use crate::provider::{Provider, ProviderError, Result};
+use crate::provider::file::PartFile;
+use crate::provider::gc::{GcOptions, GcReport};
+use crate::provider::migrate::Import;
+use crate::provider::resign::{self, AppendSignature};
+use crate::provider::scrub::{self, Scrub, ScrubIssue, ScrubOptions, ScrubReport};
+use crate::provider::{read_range, Provider, ProviderError, Result, LIST_BUFFER_SIZE};
 use crate::search::Search;
 use crate::verification::Verified;
-use crate::{Id, Signed};
+use crate::{Id, Signature, Signed};
 
 const INVOICE_DB_NAME: &str = "invoices";
 const PARCEL_DB_NAME: &str = "parcels";
+/// Tracks which parcels are stored as files rather than inline. The values are the parcel sizes
+const PARCEL_FILE_DB_NAME: &str = "parcel_files";
+/// The time each inline parcel was created, in seconds since the Unix epoch. This is used for the
+/// garbage collection grace period. Parcels stored as files use the file modification time instead
+const PARCEL_CREATED_DB_NAME: &str = "parcel_created";
+/// The folder name, relative to the storage path, for parcels stored as files
+const PARCEL_DIRECTORY: &str = "parcels";
+const PARCEL_FILE_EXTENSION: &str = "dat";
+/// Parcels larger than this many bytes are stored as files instead of inline in the database
+const INLINE_PARCEL_THRESHOLD: u64 = 1024 * 1024;
+/// A log of invoice changes, used to warm the index incrementally. Keys are the
+/// [high-water mark](Search::high_water_mark) at the time of the change followed by a unique ID,
+/// and values are the keys of the changed invoices
+const CHANGE_DB_NAME: &str = "invoice_changes";
+/// Stored in the default tree. Entries in the change log before this mark have been pruned, so an
+/// index with an older mark needs a full rebuild
+const CHANGES_PRUNED_KEY: &str = "invoice_changes_pruned_before";
 // TODO: This number should be equal to the number of threads configured for blocking. We could
 // expose this value in the constructor, but that feels too much like a low-level detail to expose
 // in the API. But I also can't find a way to fetch this configured value
@@ -43,8 +74,13 @@ const BLOCKING_THREAD_COUNT: usize = 512;
 /// An EmbeddedProvider needs a search engine implementation. When invoices are created or yanked,
 /// the index will be updated.
 pub struct EmbeddedProvider<T> {
+    db: sled::Db,
     invoices: sled::Tree,
     parcels: sled::Tree,
+    parcel_files: sled::Tree,
+    parcel_created: sled::Tree,
+    parcel_dir: PathBuf,
+    changes: sled::Tree,
     index: T,
     semaphore: Arc<Semaphore>,
 }
@@ -52,8 +88,13 @@ pub struct EmbeddedProvider<T> {
 impl<T: Clone> Clone for EmbeddedProvider<T> {
     fn clone(&self) -> Self {
         EmbeddedProvider {
+            db: self.db.clone(),
             invoices: self.invoices.clone(),
             parcels: self.parcels.clone(),
+            parcel_files: self.parcel_files.clone(),
+            parcel_created: self.parcel_created.clone(),
+            parcel_dir: self.parcel_dir.clone(),
+            changes: self.changes.clone(),
             index: self.index.clone(),
             semaphore: self.semaphore.clone(),
         }
@@ -68,10 +109,28 @@ impl<T: Search + Send + Sync> EmbeddedProvider<T> {
         let owned = db.clone();
         let invoices =
             tokio::task::spawn_blocking(move || owned.open_tree(INVOICE_DB_NAME)).await??;
-        let parcels = tokio::task::spawn_blocking(move || db.open_tree(PARCEL_DB_NAME)).await??;
+        let owned = db.clone();
+        let parcels =
+            tokio::task::spawn_blocking(move || owned.open_tree(PARCEL_DB_NAME)).await??;
+        let owned = db.clone();
+        let parcel_files =
+            tokio::task::spawn_blocking(move || owned.open_tree(PARCEL_FILE_DB_NAME)).await??;
+        let owned = db.clone();
+        let parcel_created =
+            tokio::task::spawn_blocking(move || owned.open_tree(PARCEL_CREATED_DB_NAME)).await??;
+        let owned = db.clone();
+        let changes =
+            tokio::task::spawn_blocking(move || owned.open_tree(CHANGE_DB_NAME)).await??;
+        let parcel_dir = storage_path.as_ref().join(PARCEL_DIRECTORY);
+        tokio::fs::create_dir_all(&parcel_dir).await?;
         let emb = EmbeddedProvider {
+            db,
             invoices,
             parcels,
+            parcel_files,
+            parcel_created,
+            parcel_dir,
+            changes,
             index,
             semaphore: Arc::new(Semaphore::new(BLOCKING_THREAD_COUNT)),
         };
@@ -90,16 +149,55 @@ impl<T: Search + Send + Sync> EmbeddedProvider<T> {
     /// in the repository. So it needs to communicate (on startup) what documents it knows
     /// about. The storage engine merely needs to store any non-duplicates. So we can
     /// safely insert, but ignore errors that come back because of duplicate entries.
+    ///
+    /// If the index has a [high-water mark](Search::high_water_mark), only the invoices in the
+    /// change log from that point on are loaded. Once the index is warmed, the change log entries
+    /// before the new mark are no longer needed and are pruned
     #[instrument(level = "trace", skip(self))]
     async fn warm_index(&self) -> anyhow::Result<()> {
         // Read all invoices
         info!("Beginning index warm");
         let mut total_indexed: u64 = 0;
+        let mut had_errors = false;
+        // Grab the next mark before reading anything so nothing that changes while we are warming
+        // gets skipped next time
+        let high_water_mark = crate::search::high_water_mark_at(SystemTime::now());
+        let pruned_before = self
+            .db
+            .get(CHANGES_PRUNED_KEY)
+            .map_err(map_sled_error)?
+            .and_then(|raw| raw.as_ref().try_into().ok().map(u64::from_be_bytes))
+            .unwrap_or_default();
         // NOTE(thomastaylor312): Trying to do this async and spawn blocking is impossible unless we
         // add a clone constraint to T. So technically this could cause a blocking issue depending
         // on the cache size and if there are other IO operations (though it does have the advantage
         // of filling the cache). However, I think this is fine as we only call this on startup
-        for res in self.invoices.iter() {
+        let changed: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>> + Send> =
+            match self.index.high_water_mark().await? {
+                Some(since) if since >= pruned_before => {
+                    debug!(
+                        since,
+                        "Only indexing invoices changed since the high-water mark"
+                    );
+                    // The change log values are the keys of the changed invoices, so look each of
+                    // them up
+                    let invoices = self.invoices.clone();
+                    Box::new(
+                        self.changes
+                            .range(since.to_be_bytes()..)
+                            .values()
+                            .filter_map(move |res| match res {
+                                Ok(key) => invoices
+                                    .get(&key)
+                                    .transpose()
+                                    .map(|r| r.map(|raw| (key, raw))),
+                                Err(e) => Some(Err(e)),
+                            }),
+                    )
+                }
+                _ => Box::new(self.invoices.iter()),
+            };
+        for res in changed {
             let (key, raw) = res.map_err(map_sled_error)?;
             let sha = String::from_utf8_lossy(key.as_ref());
             let invoice: crate::Invoice = serde_cbor::from_slice(raw.as_ref())?;
@@ -115,12 +213,223 @@ impl<T: Search + Send + Sync> EmbeddedProvider<T> {
 
             if let Err(e) = self.index.index(&invoice).await {
                 error!(invoice_id = %invoice.bindle.id, error = %e, "Error indexing invoice");
+                had_errors = true;
             }
             total_indexed += 1;
         }
+        // Don't move the mark past anything we failed to index so it gets retried next time
+        if !had_errors {
+            self.index.set_high_water_mark(high_water_mark).await?;
+            self.prune_changes(high_water_mark).await?;
+        }
         debug!(total_indexed, "Warmed index");
         Ok(())
     }
+
+    /// Removes the change log entries from before the given mark
+    async fn prune_changes(&self, mark: u64) -> anyhow::Result<()> {
+        let (db, changes) = (self.db.clone(), self.changes.clone());
+        let pruned = spawn_lock(self.semaphore.clone(), move || {
+            // Record the mark first, so an index that missed any of the entries will be rebuilt
+            // even if this fails partway through
+            db.insert(CHANGES_PRUNED_KEY, &mark.to_be_bytes())?;
+            let mut batch = sled::Batch::default();
+            let mut pruned: u64 = 0;
+            for key in changes.range(..mark.to_be_bytes()).keys() {
+                batch.remove(key?);
+                pruned += 1;
+            }
+            changes.apply_batch(batch)?;
+            Ok::<_, SledError>(pruned)
+        })
+        .await?
+        .map_err(map_sled_error)?;
+        debug!(pruned, "Pruned change log");
+        Ok(())
+    }
+
+    /// Removes all parcels that are not referenced by any invoice and are older than the grace
+    /// period. See the [`gc`](crate::provider::gc) module for more details
+    #[instrument(level = "trace", skip(self))]
+    pub async fn collect_garbage(&self, options: &GcOptions) -> Result<GcReport> {
+        info!("Beginning garbage collection");
+        let opts = options.clone();
+        let (invoices, parcels, parcel_created) = (
+            self.invoices.clone(),
+            self.parcels.clone(),
+            self.parcel_created.clone(),
+        );
+        let (mut report, live) = spawn_lock(self.semaphore.clone(), move || {
+            let mut report = GcReport {
+                dry_run: opts.dry_run,
+                ..Default::default()
+            };
+
+            // Mark. If any invoice can't be read, we don't know the full live set, so bail out
+            // rather than risk removing a parcel that is in use
+            let mut live = HashSet::new();
+            for raw in invoices.iter().values() {
+                let invoice: crate::Invoice =
+                    serde_cbor::from_slice(raw.map_err(map_sled_error)?.as_ref())?;
+                live.extend(invoice.parcel.into_iter().flatten().map(|p| p.label.sha256));
+            }
+            report.live_parcels = live.len() as u64;
+            debug!(live_parcels = report.live_parcels, "Found live parcels");
+
+            // Sweep the inline parcels
+            for res in parcels.iter() {
+                let (key, data) = res.map_err(map_sled_error)?;
+                let sha = String::from_utf8_lossy(key.as_ref()).into_owned();
+                if live.contains(&sha) {
+                    continue;
+                }
+                let created = parcel_created
+                    .get(&key)
+                    .map_err(map_sled_error)?
+                    .and_then(|raw| raw.as_ref().try_into().ok())
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(secs)));
+                match created {
+                    Some(created) if opts.is_expired(created) => (),
+                    Some(_) => {
+                        trace!(%sha, "Unreferenced parcel is within the grace period");
+                        report.retained.push(sha);
+                        continue;
+                    }
+                    // Parcels stored before we tracked creation times start their grace period now
+                    None => {
+                        if !opts.dry_run {
+                            parcel_created
+                                .insert(&key, &unix_secs(SystemTime::now()).to_be_bytes())
+                                .map_err(map_sled_error)?;
+                        }
+                        report.retained.push(sha);
+                        continue;
+                    }
+                }
+                if !opts.dry_run {
+                    debug!(%sha, "Removing unreferenced parcel");
+                    parcels.remove(&key).map_err(map_sled_error)?;
+                    parcel_created.remove(&key).map_err(map_sled_error)?;
+                }
+                report.removed_bytes += data.len() as u64;
+                report.removed.push(sha);
+            }
+            Ok::<_, ProviderError>((report, live))
+        })
+        .await??;
+
+        // Sweep the parcel files, including any that were abandoned before they were recorded
+        let mut readdir = tokio::fs::read_dir(&self.parcel_dir).await?;
+        while let Some(e) = readdir.next_entry().await? {
+            // Part files have extra extensions, so the SHA is everything before the first one
+            let sha = e
+                .file_name()
+                .to_string_lossy()
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .to_owned();
+            if live.contains(&sha) {
+                continue;
+            }
+            let metadata = e.metadata().await?;
+            if !options.is_expired(metadata.modified()?) {
+                trace!(%sha, "Unreferenced parcel file is within the grace period");
+                report.retained.push(sha);
+                continue;
+            }
+            if !options.dry_run {
+                debug!(%sha, path = %e.path().display(), "Removing unreferenced parcel file");
+                let parcel_files = self.parcel_files.clone();
+                let pid = sha.clone();
+                spawn_lock(self.semaphore.clone(), move || parcel_files.remove(&pid))
+                    .await?
+                    .map_err(map_sled_error)?;
+                tokio::fs::remove_file(e.path()).await?;
+            }
+            report.removed_bytes += metadata.len();
+            report.removed.push(sha);
+        }
+
+        info!(
+            removed = report.removed.len(),
+            removed_bytes = report.removed_bytes,
+            retained = report.retained.len(),
+            dry_run = options.dry_run,
+            "Finished garbage collection"
+        );
+        Ok(report)
+    }
+
+    /// Acquires a blocking thread permit that is held for as long as the returned value is alive.
+    /// This is for long running blocking tasks that outlive the call that started them
+    async fn acquire_permit(&self) -> tokio::sync::OwnedSemaphorePermit {
+        // See `spawn_lock` for why this is safe to expect
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Unable to synchronize threads...aborting")
+    }
+
+    /// Returns the storage directory the provider was created with
+    fn storage_path(&self) -> &Path {
+        self.parcel_dir
+            .parent()
+            .expect("The parcel directory is always inside the storage directory")
+    }
+
+    /// Return the path to the file for a parcel that is too large to be stored inline
+    fn parcel_file_path(&self, parcel_id: &str) -> PathBuf {
+        self.parcel_dir
+            .join(parcel_id)
+            .with_extension(PARCEL_FILE_EXTENSION)
+    }
+
+    /// Checks whether the parcel is stored, either inline or as a file
+    async fn parcel_stored(&self, parcel_id: &str) -> Result<bool> {
+        let parcels = self.parcels.clone();
+        let parcel_files = self.parcel_files.clone();
+        let pid = parcel_id.to_owned();
+        spawn_lock(self.semaphore.clone(), move || {
+            Ok(parcels.contains_key(&pid)? || parcel_files.contains_key(&pid)?)
+        })
+        .await?
+        .map_err(map_sled_error)
+    }
+
+    /// Streams the parcel data to a file, validating the size and digest along the way. The parcel
+    /// is only recorded in the database once the file is complete
+    async fn create_parcel_file<R, B>(&self, parcel_id: &str, size: u64, data: R) -> Result<()>
+    where
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        let path = self.parcel_file_path(parcel_id);
+        debug!(path = %path.display(), "Streaming large parcel to file");
+        let mut part = PartFile::new(path).await?;
+        part.write_parcel(data, parcel_id, size).await?;
+        part.finalize().await?;
+
+        let parcel_files = self.parcel_files.clone();
+        let pid = parcel_id.to_owned();
+        let res = spawn_lock(self.semaphore.clone(), move || {
+            parcel_files.compare_and_swap(
+                &pid,
+                None as Option<&[u8]>,
+                Some(&size.to_be_bytes()[..]),
+            )
+        })
+        .await?;
+
+        match res {
+            Ok(Ok(())) => Ok(()),
+            Err(e) => Err(map_sled_error(e)),
+            // Someone else finished writing the same parcel first. The contents are addressed by
+            // their digest, so the file we renamed into place is identical
+            Ok(Err(_)) => Err(ProviderError::Exists),
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -142,11 +451,13 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         let invoice_id = inv.canonical_name();
 
         let invoices = self.invoices.clone();
+        let (db, changes) = (self.db.clone(), self.changes.clone());
 
         let serialized = serde_cbor::to_vec(&inv)?;
 
         debug!("Inserting invoice into database");
         let res = spawn_lock(self.semaphore.clone(), move || {
+            record_change(&db, &changes, &invoice_id)?;
             invoices.compare_and_swap(&invoice_id, None as Option<&[u8]>, Some(serialized))
         })
         .await?;
@@ -170,28 +481,16 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         }
 
         trace!("Checking for missing parcels listed in newly created invoice");
-        let s = self.semaphore.clone();
-        let parcels = self.parcels.clone();
-        // Loop through the boxes and see what exists
-        let missing = inv
-            .parcel
-            // Need to clone so we can move into the spawn_lock
-            .clone()
-            .unwrap_or_default()
-            .into_iter()
-            .map(|k| (s.clone(), parcels.clone(), k.label))
-            .map(|(s, parcels, label)| async move {
-                // Check if the parcel exists in the database
-                let sha = label.sha256.to_owned();
-                let found = spawn_lock(s, move || parcels.contains_key(&sha).unwrap_or(false))
-                    .await
-                    .unwrap_or(false);
-                if found {
-                    None
-                } else {
-                    Some(label)
-                }
-            });
+        // Loop through the boxes and see what exists. Large parcels are stored as files, so this
+        // checks both places
+        let missing = inv.parcel.iter().flatten().map(|k| async move {
+            let found = self.parcel_stored(&k.label.sha256).await.unwrap_or(false);
+            if found {
+                None
+            } else {
+                Some(k.label.clone())
+            }
+        });
 
         let labels = futures::future::join_all(missing)
             .instrument(tracing::trace_span!("lookup_missing"))
@@ -233,24 +532,43 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         Ok(invoice)
     }
 
-    #[instrument(level = "trace", skip(self, id), fields(id))]
-    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    #[instrument(level = "trace", skip(self, id, signature), fields(id))]
+    async fn yank_invoice<I>(&self, id: I, signature: Option<Signature>) -> Result<()>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>,
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
         tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
-        trace!("Fetching invoice from storage");
-        let mut inv = self.get_yanked_invoice(&parsed_id).await?;
-        inv.yanked = Some(true);
-
-        debug!("Yanking invoice");
+        let invoice_id = parsed_id.sha();
+        let invoices = self.invoices.clone();
+        let (db, changes) = (self.db.clone(), self.changes.clone());
 
-        // NOTE: Using the update_and_fetch method would result in a double deserialization step so
-        // we can re-index. There _is_ a small possibility that someone could fetch the current
-        // value from the DB right before we mutate, but the consequences of this are likely small
-        // or non-existent, so we aren't worrying about wrapping in a transaction
+        debug!("Yanking invoice in database");
+        // Do the whole read-modify-write as a transaction so a yank can't overwrite signatures
+        // appended at the same time, or be overwritten by them
+        let inv = spawn_lock(self.semaphore.clone(), move || {
+            // Recording a change for a yank that then fails only causes an extra re-index
+            record_change(&db, &changes, &invoice_id).map_err(map_sled_error)?;
+            invoices
+                .transaction(|tx| {
+                    let raw = tx.get(&invoice_id)?.ok_or_else(|| {
+                        ConflictableTransactionError::Abort(ProviderError::NotFound)
+                    })?;
+                    let mut inv: crate::Invoice = serde_cbor::from_slice(raw.as_ref())
+                        .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                    inv.yank(signature.clone());
+                    let serialized = serde_cbor::to_vec(&inv)
+                        .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                    tx.insert(invoice_id.as_bytes(), serialized)?;
+                    Ok(inv)
+                })
+                .map_err(|e| match e {
+                    TransactionError::Abort(e) => e,
+                    TransactionError::Storage(e) => map_sled_error(e),
+                })
+        })
+        .await??;
 
         // Attempt to update the index. Right now, we log an error if the index update
         // fails.
@@ -259,18 +577,6 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
             error!(error = %e, "Error indexing yanked invoice");
         }
 
-        // Encode the invoice into a TOML object
-        trace!("Encoding invoice");
-        let serialized = serde_cbor::to_vec(&inv)?;
-        let invoice_id = inv.canonical_name();
-        let invoices = self.invoices.clone();
-        debug!("Writing yanked invoice to database");
-        spawn_lock(self.semaphore.clone(), move || {
-            invoices.insert(&invoice_id, serialized)
-        })
-        .await?
-        .map_err(map_sled_error)?;
-
         Ok(())
     }
 
@@ -287,14 +593,26 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
         let label = self.validate_parcel(parsed_id, parcel_id).await?;
 
+        if self.parcel_stored(parcel_id).await? {
+            debug!("Parcel already exists");
+            return Err(ProviderError::Exists);
+        }
+
+        if label.size > INLINE_PARCEL_THRESHOLD {
+            return self.create_parcel_file(parcel_id, label.size, data).await;
+        }
+
         debug!("Reading data from stream");
 
         // Read the data into memory (it is going to start there anyway in the database before
         // getting flushed to disk)
         let mut parcel_data: Vec<u8> = Vec::with_capacity(label.size as usize);
+        // Never read more than we expect, otherwise a bad client could make us buffer an
+        // arbitrarily large upload
         StreamReader::new(
             data.map(|res| res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))),
         )
+        .take(label.size + 1)
         .read_to_end(&mut parcel_data)
         .await?;
 
@@ -317,8 +635,11 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
 
         debug!("Inserting parcel into database");
         let parcels = self.parcels.clone();
+        let parcel_created = self.parcel_created.clone();
         let pid = parcel_id.to_owned();
         let res = spawn_lock(self.semaphore.clone(), move || {
+            // Record the creation time first so the parcel is never without one
+            parcel_created.insert(&pid, &unix_secs(SystemTime::now()).to_be_bytes())?;
             parcels.compare_and_swap(&pid, None as Option<&[u8]>, Some(parcel_data))
         })
         .await?;
@@ -349,21 +670,86 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         debug!("Getting parcel from storage");
         let parcels = self.parcels.clone();
         let pid = parcel_id.to_owned();
-        let data = match spawn_lock(self.semaphore.clone(), move || parcels.get(&pid))
+        if let Some(d) = spawn_lock(self.semaphore.clone(), move || parcels.get(&pid))
             .await?
             .map_err(map_sled_error)?
         {
             // Wrap the data in a cursor so it implements AsyncRead and can be streamed
-            Some(d) => std::io::Cursor::new(d),
-            None => return Err(ProviderError::NotFound),
-        };
+            return Ok(Box::new(
+                FramedRead::new(std::io::Cursor::new(d), BytesCodec::new())
+                    .map(|res| res.map_err(map_io_error).map(|b| b.freeze())),
+            ));
+        }
 
-        Ok::<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>, _>(Box::new(
-            FramedRead::new(data, BytesCodec::new())
+        // Only open the file if we've recorded it as complete, otherwise it could still be
+        // getting written
+        let parcel_files = self.parcel_files.clone();
+        let pid = parcel_id.to_owned();
+        if !spawn_lock(self.semaphore.clone(), move || {
+            parcel_files.contains_key(&pid)
+        })
+        .await?
+        .map_err(map_sled_error)?
+        {
+            return Err(ProviderError::NotFound);
+        }
+        let path = self.parcel_file_path(parcel_id);
+        trace!(path = %path.display(), "Streaming parcel from file");
+        let reader = File::open(path).await.map_err(map_io_error)?;
+        Ok(Box::new(
+            FramedRead::new(reader, BytesCodec::new())
                 .map(|res| res.map_err(map_io_error).map(|b| b.freeze())),
         ))
     }
 
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        debug!("Getting parcel range from storage");
+        let parcels = self.parcels.clone();
+        let pid = parcel_id.to_owned();
+        if let Some(d) = spawn_lock(self.semaphore.clone(), move || parcels.get(&pid))
+            .await?
+            .map_err(map_sled_error)?
+        {
+            let stream = read_range(std::io::Cursor::new(d), range).await?;
+            return Ok(Box::new(
+                stream.map(|res| res.map_err(map_io_error).map(|b| b.freeze())),
+            ));
+        }
+
+        let parcel_files = self.parcel_files.clone();
+        let pid = parcel_id.to_owned();
+        if !spawn_lock(self.semaphore.clone(), move || {
+            parcel_files.contains_key(&pid)
+        })
+        .await?
+        .map_err(map_sled_error)?
+        {
+            return Err(ProviderError::NotFound);
+        }
+        let path = self.parcel_file_path(parcel_id);
+        trace!(path = %path.display(), "Streaming parcel range from file");
+        let reader = File::open(path).await.map_err(map_io_error)?;
+        let stream = read_range(reader, range).await?;
+        Ok(Box::new(
+            stream.map(|res| res.map_err(map_io_error).map(|b| b.freeze())),
+        ))
+    }
+
     #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
     async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
     where
@@ -376,14 +762,314 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         self.validate_parcel(parsed_id, parcel_id).await?;
 
         debug!("Checking if parcel exists in storage");
-        let pid = parcel_id.to_owned();
+        self.parcel_stored(parcel_id).await
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn list_invoices(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<crate::Invoice>> + Unpin + Send + Sync>> {
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER_SIZE);
+        let permit = self.acquire_permit().await;
+        let invoices = self.invoices.clone();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            for raw in invoices.iter().values() {
+                let res = raw
+                    .map_err(map_sled_error)
+                    .and_then(|raw| Ok(serde_cbor::from_slice(raw.as_ref())?));
+                // If the receiver hung up, there's no point in continuing
+                if tx.blocking_send(res).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Box::new(ReceiverStream::new(rx)))
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn list_parcels(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<String>> + Unpin + Send + Sync>> {
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER_SIZE);
+        let permit = self.acquire_permit().await;
+        let (parcels, parcel_files) = (self.parcels.clone(), self.parcel_files.clone());
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            // Parcel files are only recorded once they are complete, so partial writes are never
+            // included
+            for key in parcels.iter().keys().chain(parcel_files.iter().keys()) {
+                let res = key
+                    .map_err(map_sled_error)
+                    .map(|k| String::from_utf8_lossy(k.as_ref()).into_owned());
+                if tx.blocking_send(res).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Box::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: crate::search::Search + Send + Sync> Import for EmbeddedProvider<T> {
+    #[instrument(level = "trace", skip(self, invoice), fields(invoice_id = %invoice.bindle.id))]
+    async fn import_invoice(&self, invoice: &crate::Invoice) -> Result<()> {
+        let invoice_id = invoice.canonical_name();
+        let invoices = self.invoices.clone();
+        let (db, changes) = (self.db.clone(), self.changes.clone());
+        let serialized = serde_cbor::to_vec(invoice)?;
+
+        debug!("Importing invoice into database");
+        spawn_lock(self.semaphore.clone(), move || {
+            record_change(&db, &changes, &invoice_id)?;
+            invoices.insert(&invoice_id, serialized)
+        })
+        .await?
+        .map_err(map_sled_error)?;
+
+        if let Err(e) = self.index.index(invoice).await {
+            error!(error = %e, "Error indexing imported invoice");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: crate::search::Search + Send + Sync> AppendSignature for EmbeddedProvider<T> {
+    #[instrument(level = "trace", skip(self, signatures), fields(%id))]
+    async fn append_signatures(
+        &self,
+        id: &Id,
+        signatures: Vec<Signature>,
+    ) -> Result<crate::Invoice> {
+        let invoice_id = id.sha();
+        let invoices = self.invoices.clone();
+        let (db, changes) = (self.db.clone(), self.changes.clone());
+
+        // Recording a change for an append that then fails only causes an extra re-index
+        let key = invoice_id.clone();
+        spawn_lock(self.semaphore.clone(), move || {
+            record_change(&db, &changes, &key)
+        })
+        .await?
+        .map_err(map_sled_error)?;
+
+        debug!("Appending signatures to invoice in database");
+        // Do the whole read-modify-write as a transaction so appends running at the same time
+        // can't overwrite each other's signatures
+        let inv = spawn_lock(self.semaphore.clone(), move || {
+            invoices.transaction(|tx| {
+                let raw = tx
+                    .get(&invoice_id)?
+                    .ok_or_else(|| ConflictableTransactionError::Abort(ProviderError::NotFound))?;
+                let mut inv: crate::Invoice = serde_cbor::from_slice(raw.as_ref())
+                    .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                resign::append_to_invoice(&mut inv, signatures.clone())
+                    .map_err(ConflictableTransactionError::Abort)?;
+                let serialized = serde_cbor::to_vec(&inv)
+                    .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                tx.insert(invoice_id.as_bytes(), serialized)?;
+                Ok(inv)
+            })
+        })
+        .await?
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => map_sled_error(e),
+        })?;
+
+        if let Err(e) = self.index.index(&inv).await {
+            error!(error = %e, "Error indexing signed invoice");
+        }
+        Ok(inv)
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: crate::search::Search + Send + Sync> Scrub for EmbeddedProvider<T> {
+    #[instrument(level = "trace", skip(self, options))]
+    async fn scrub(&self, options: &ScrubOptions) -> Result<ScrubReport> {
+        info!(path = %self.storage_path().display(), "Beginning scrub");
+        let mut report = ScrubReport {
+            quarantined: options.quarantine,
+            ..Default::default()
+        };
+
+        // Invoices are small, so check them all in one go. The expected size of each parcel comes
+        // from the labels in the invoices
+        let invoices = self.invoices.clone();
+        let opts = options.clone();
+        let (checked, bad, sizes) = spawn_lock(self.semaphore.clone(), move || {
+            let mut checked = 0u64;
+            let mut bad = Vec::new();
+            let mut sizes = HashMap::new();
+            for res in invoices.iter() {
+                let (key, raw) = res.map_err(map_sled_error)?;
+                let name = String::from_utf8_lossy(key.as_ref()).into_owned();
+                checked += 1;
+                let problem = match serde_cbor::from_slice::<crate::Invoice>(raw.as_ref()) {
+                    Ok(invoice) => {
+                        let problem = scrub::check_invoice(&name, &invoice, &opts);
+                        for label in invoice.parcel.into_iter().flatten().map(|p| p.label) {
+                            sizes.entry(label.sha256).or_insert(label.size);
+                        }
+                        problem
+                    }
+                    Err(e) => Some(format!("Invoice is malformed: {}", e)),
+                };
+                if let Some(problem) = problem {
+                    bad.push((ScrubIssue { name, problem }, raw));
+                }
+            }
+            Ok::<_, ProviderError>((checked, bad, sizes))
+        })
+        .await??;
+        report.invoices_checked = checked;
+        for (issue, raw) in bad {
+            if options.quarantine {
+                let file_name = format!("{}/{}.cbor", INVOICE_DB_NAME, issue.name);
+                scrub::quarantine_data(self.storage_path(), raw.as_ref(), &file_name).await?;
+                let (db, changes, invoices) =
+                    (self.db.clone(), self.changes.clone(), self.invoices.clone());
+                let key = issue.name.clone();
+                spawn_lock(self.semaphore.clone(), move || {
+                    record_change(&db, &changes, &key)?;
+                    invoices.remove(&key)
+                })
+                .await?
+                .map_err(map_sled_error)?;
+                let header = serde_cbor::from_slice(raw.as_ref()).ok();
+                scrub::remove_from_index(&self.index, &issue.name, header).await;
+            }
+            report.bad_invoices.push(issue);
+        }
+
+        let mut throttle = scrub::Throttle::new(options.max_bytes_per_sec);
+
+        // Fetch the inline parcels one at a time so reading them can be throttled
         let parcels = self.parcels.clone();
-        spawn_lock(self.semaphore.clone(), move || parcels.contains_key(&pid))
-            .await?
-            .map_err(map_sled_error)
+        let inline = spawn_lock(self.semaphore.clone(), move || {
+            parcels.iter().keys().collect::<sled::Result<Vec<_>>>()
+        })
+        .await?
+        .map_err(map_sled_error)?;
+        for key in inline {
+            let sha = String::from_utf8_lossy(key.as_ref()).into_owned();
+            let parcels = self.parcels.clone();
+            let data = match spawn_lock(self.semaphore.clone(), move || parcels.get(key))
+                .await?
+                .map_err(map_sled_error)?
+            {
+                Some(d) => d,
+                // Removed since we listed the keys
+                None => continue,
+            };
+            trace!(%sha, "Checking inline parcel");
+            let (actual_sha, size) = scrub::hash_reader(data.as_ref(), &mut throttle).await?;
+            report.parcels_checked += 1;
+            report.bytes_checked += size;
+            if let Some(problem) =
+                scrub::check_parcel(&sha, &actual_sha, size, sizes.get(&sha).copied())
+            {
+                if options.quarantine {
+                    let file_name = format!("{}/{}.{}", PARCEL_DB_NAME, sha, PARCEL_FILE_EXTENSION);
+                    scrub::quarantine_data(self.storage_path(), data.as_ref(), &file_name).await?;
+                    let (parcels, parcel_created) =
+                        (self.parcels.clone(), self.parcel_created.clone());
+                    let pid = sha.clone();
+                    spawn_lock(self.semaphore.clone(), move || {
+                        parcels.remove(&pid)?;
+                        parcel_created.remove(&pid)
+                    })
+                    .await?
+                    .map_err(map_sled_error)?;
+                }
+                report.bad_parcels.push(ScrubIssue { name: sha, problem });
+            }
+        }
+
+        let parcel_files = self.parcel_files.clone();
+        let files = spawn_lock(self.semaphore.clone(), move || {
+            parcel_files.iter().collect::<sled::Result<Vec<_>>>()
+        })
+        .await?
+        .map_err(map_sled_error)?;
+        for (key, raw_size) in files {
+            let sha = String::from_utf8_lossy(key.as_ref()).into_owned();
+            let recorded = raw_size.as_ref().try_into().ok().map(u64::from_be_bytes);
+            let path = self.parcel_file_path(&sha);
+            trace!(%sha, "Checking parcel file");
+            let problem = match File::open(&path).await {
+                Ok(file) => {
+                    let (actual_sha, size) = scrub::hash_reader(file, &mut throttle).await?;
+                    report.parcels_checked += 1;
+                    report.bytes_checked += size;
+                    let expected = sizes.get(&sha).copied().or(recorded);
+                    scrub::check_parcel(&sha, &actual_sha, size, expected).or_else(
+                        || match recorded {
+                            Some(r) if r == size => None,
+                            Some(r) => Some(format!(
+                                "Parcel file is {} bytes, but was recorded as {} bytes",
+                                size, r
+                            )),
+                            None => Some("Recorded parcel size is malformed".to_owned()),
+                        },
+                    )
+                }
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => {
+                    Some("Parcel file is missing".to_owned())
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(problem) = problem {
+                if options.quarantine {
+                    if path.exists() {
+                        scrub::quarantine_file(self.storage_path(), &path).await?;
+                    }
+                    let parcel_files = self.parcel_files.clone();
+                    let pid = sha.clone();
+                    spawn_lock(self.semaphore.clone(), move || parcel_files.remove(&pid))
+                        .await?
+                        .map_err(map_sled_error)?;
+                }
+                report.bad_parcels.push(ScrubIssue { name: sha, problem });
+            }
+        }
+
+        scrub::find_part_files(self.storage_path(), &self.parcel_dir, options, &mut report).await?;
+
+        info!(
+            invoices_checked = report.invoices_checked,
+            parcels_checked = report.parcels_checked,
+            bad_invoices = report.bad_invoices.len(),
+            bad_parcels = report.bad_parcels.len(),
+            part_files = report.part_files.len(),
+            "Finished scrub"
+        );
+        Ok(report)
     }
 }
 
+/// Returns the given time in seconds since the Unix epoch
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Records a change to the invoice with the given key in the change log. This should be called
+/// before writing the invoice so a crash can't leave a change unrecorded
+fn record_change(db: &sled::Db, changes: &sled::Tree, invoice_id: &str) -> sled::Result<()> {
+    let key = [
+        crate::search::high_water_mark_at(SystemTime::now()).to_be_bytes(),
+        db.generate_id()?.to_be_bytes(),
+    ]
+    .concat();
+    changes.insert(key, invoice_id)?;
+    Ok(())
+}
+
 fn map_io_error(e: std::io::Error) -> ProviderError {
     if matches!(e.kind(), std::io::ErrorKind::NotFound) {
         return ProviderError::NotFound;
@@ -444,3 +1130,390 @@ where
         .await
         .map_err(|_| ProviderError::Other("Internal error: unable to lock task".into()))?)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::invoice::signature::{KeyRing, SecretKeyEntry, SignatureRole};
+    use crate::VerificationStrategy;
+    use tempfile::tempdir;
+
+    /// Creates and stores a signed invoice containing a parcel for each of the given blobs
+    async fn store_invoice<T: Search + Send + Sync>(
+        store: &EmbeddedProvider<T>,
+        blobs: &[&[u8]],
+    ) -> crate::Invoice {
+        let mut inv = crate::Invoice::new(crate::BindleSpec {
+            id: "example.com/weather/1.0.0".parse().unwrap(),
+            description: None,
+            authors: None,
+        });
+        inv.parcel = Some(
+            blobs
+                .iter()
+                .enumerate()
+                .map(|(i, data)| crate::Parcel {
+                    label: crate::Label {
+                        sha256: format!("{:x}", Sha256::digest(data)),
+                        name: format!("parcel{}", i),
+                        size: data.len() as u64,
+                        ..Default::default()
+                    },
+                    conditions: None,
+                })
+                .collect(),
+        );
+        let sk = SecretKeyEntry::new("Test Key".to_owned(), vec![SignatureRole::Creator]);
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(inv, &KeyRing::default())
+            .unwrap();
+        let signed = crate::invoice::sign(verified, vec![(SignatureRole::Creator, &sk)]).unwrap();
+        store.create_invoice(signed).await.unwrap().0
+    }
+
+    async fn read_parcel<T: Search + Send + Sync>(
+        store: &EmbeddedProvider<T>,
+        inv: &crate::Invoice,
+        parcel_id: &str,
+    ) -> Vec<u8> {
+        let mut stream = store
+            .get_parcel(&inv.bindle.id, parcel_id)
+            .await
+            .expect("parcel should exist");
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn test_should_store_small_and_large_parcels() {
+        let root = tempdir().unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        let small = b"a small parcel".to_vec();
+        let large = vec![7u8; INLINE_PARCEL_THRESHOLD as usize + 1];
+        let inv = store_invoice(&store, &[&small, &large]).await;
+        let small_sha = format!("{:x}", Sha256::digest(&small));
+        let large_sha = format!("{:x}", Sha256::digest(&large));
+
+        for (sha, data) in [(&small_sha, &small), (&large_sha, &large)] {
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    sha,
+                    FramedRead::new(std::io::Cursor::new(data.clone()), BytesCodec::new()),
+                )
+                .await
+                .expect("parcel should be created");
+            assert!(store.parcel_exists(&inv.bindle.id, sha).await.unwrap());
+            assert_eq!(*data, read_parcel(&store, &inv, sha).await);
+        }
+
+        // Only the large parcel should have gone to disk
+        assert!(!store.parcel_file_path(&small_sha).exists());
+        assert!(store.parcel_file_path(&large_sha).exists());
+
+        let res = store
+            .create_parcel(
+                &inv.bindle.id,
+                &large_sha,
+                FramedRead::new(std::io::Cursor::new(large.clone()), BytesCodec::new()),
+            )
+            .await;
+        assert!(matches!(res, Err(ProviderError::Exists)));
+
+        // Another invoice referencing the stored parcels shouldn't report them as missing
+        let mut other = inv.clone();
+        other.bindle.id = "example.com/weather/2.0.0".parse().unwrap();
+        other.signature = None;
+        let sk = SecretKeyEntry::new("Test Key".to_owned(), vec![SignatureRole::Creator]);
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(other, &KeyRing::default())
+            .unwrap();
+        let signed = crate::invoice::sign(verified, vec![(SignatureRole::Creator, &sk)]).unwrap();
+        let (_, missing) = store.create_invoice(signed).await.unwrap();
+        assert!(missing.is_empty(), "Missing parcels: {:?}", missing);
+    }
+
+    #[tokio::test]
+    async fn test_should_warm_index_incrementally() {
+        use crate::search::{EmbeddedEngine, SearchOptions};
+
+        let root = tempdir().unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        let inv = store_invoice(&store, &[]).await;
+        assert_eq!(1, store.changes.len(), "The change should be logged");
+        drop(store);
+
+        let index_dir = tempdir().unwrap();
+        let index = EmbeddedEngine::new(index_dir.path()).await.unwrap();
+        let search = || {
+            index.query(
+                inv.bindle.id.name(),
+                "",
+                SearchOptions {
+                    strict: true,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // Warming should index the invoice and prune the change log, as the index no longer needs
+        // it
+        let store =
+            crate::testing::reopen(|| EmbeddedProvider::new(root.path(), index.clone())).await;
+        assert_eq!(1, search().await.unwrap().invoices.len());
+        assert!(store.changes.is_empty(), "The change log should be pruned");
+        drop(store);
+
+        // An index with a mark from before the pruned entries can't be warmed incrementally, so it
+        // should be rebuilt
+        index.clear().await.unwrap();
+        index.set_high_water_mark(1).await.unwrap();
+        crate::testing::reopen(|| EmbeddedProvider::new(root.path(), index.clone())).await;
+        assert_eq!(1, search().await.unwrap().invoices.len());
+    }
+
+    #[tokio::test]
+    async fn test_should_list_invoices_and_parcels() {
+        let root = tempdir().unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        let small = b"a small parcel".to_vec();
+        let large = vec![7u8; INLINE_PARCEL_THRESHOLD as usize + 1];
+        let inv = store_invoice(&store, &[&small, &large]).await;
+        let mut expected = Vec::new();
+        for data in [&small, &large] {
+            let sha = format!("{:x}", Sha256::digest(data));
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    &sha,
+                    FramedRead::new(std::io::Cursor::new(data.clone()), BytesCodec::new()),
+                )
+                .await
+                .unwrap();
+            expected.push(sha);
+        }
+
+        let invoices: Vec<crate::Invoice> = store
+            .list_invoices()
+            .await
+            .unwrap()
+            .collect::<Result<_>>()
+            .await
+            .unwrap();
+        assert_eq!(1, invoices.len());
+        assert_eq!(inv.bindle.id, invoices[0].bindle.id);
+
+        let mut parcels: Vec<String> = store
+            .list_parcels()
+            .await
+            .unwrap()
+            .collect::<Result<_>>()
+            .await
+            .unwrap();
+        parcels.sort();
+        expected.sort();
+        assert_eq!(expected, parcels);
+    }
+
+    #[tokio::test]
+    async fn test_should_reject_bad_large_parcel() {
+        let root = tempdir().unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        let large = vec![7u8; INLINE_PARCEL_THRESHOLD as usize + 1];
+        let inv = store_invoice(&store, &[&large]).await;
+        let sha = format!("{:x}", Sha256::digest(&large));
+
+        // Same size, different contents
+        let res = store
+            .create_parcel(
+                &inv.bindle.id,
+                &sha,
+                FramedRead::new(
+                    std::io::Cursor::new(vec![8u8; large.len()]),
+                    BytesCodec::new(),
+                ),
+            )
+            .await;
+        assert!(matches!(res, Err(ProviderError::DigestMismatch)));
+
+        // Too short
+        let res = store
+            .create_parcel(
+                &inv.bindle.id,
+                &sha,
+                FramedRead::new(std::io::Cursor::new(vec![7u8; 10]), BytesCodec::new()),
+            )
+            .await;
+        assert!(matches!(res, Err(ProviderError::SizeMismatch)));
+
+        assert!(!store.parcel_exists(&inv.bindle.id, &sha).await.unwrap());
+        assert!(!store.parcel_file_path(&sha).exists());
+    }
+
+    #[tokio::test]
+    async fn test_should_collect_garbage() {
+        let root = tempdir().unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        let small = b"a small parcel".to_vec();
+        let large = vec![7u8; INLINE_PARCEL_THRESHOLD as usize + 1];
+        let inv = store_invoice(&store, &[&small, &large]).await;
+        let small_sha = format!("{:x}", Sha256::digest(&small));
+        let large_sha = format!("{:x}", Sha256::digest(&large));
+        for (sha, data) in [(&small_sha, &small), (&large_sha, &large)] {
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    sha,
+                    FramedRead::new(std::io::Cursor::new(data.clone()), BytesCodec::new()),
+                )
+                .await
+                .unwrap();
+        }
+
+        let now = GcOptions {
+            grace_period: Duration::from_secs(0),
+            dry_run: false,
+        };
+        let report = store.collect_garbage(&now).await.unwrap();
+        assert_eq!(2, report.live_parcels);
+        assert!(report.removed.is_empty(), "Live parcels should be kept");
+
+        // Orphan the parcels by removing the invoice out of band
+        store.invoices.remove(inv.canonical_name()).unwrap();
+
+        let report = store.collect_garbage(&GcOptions::default()).await.unwrap();
+        assert_eq!(2, report.retained.len());
+        assert!(report.removed.is_empty());
+
+        let report = store
+            .collect_garbage(&GcOptions {
+                dry_run: true,
+                ..now.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(2, report.removed.len());
+        assert_eq!((small.len() + large.len()) as u64, report.removed_bytes);
+        assert!(store.parcels.contains_key(&small_sha).unwrap());
+        assert!(store.parcel_file_path(&large_sha).exists());
+
+        let report = store.collect_garbage(&now).await.unwrap();
+        assert_eq!(2, report.removed.len());
+        assert!(!store.parcels.contains_key(&small_sha).unwrap());
+        assert!(!store.parcel_files.contains_key(&large_sha).unwrap());
+        assert!(!store.parcel_file_path(&large_sha).exists());
+    }
+
+    #[tokio::test]
+    async fn test_should_scrub() {
+        let root = tempdir().unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        let small = b"a small parcel".to_vec();
+        let large = vec![7u8; INLINE_PARCEL_THRESHOLD as usize + 1];
+        let inv = store_invoice(&store, &[&small, &large]).await;
+        let small_sha = format!("{:x}", Sha256::digest(&small));
+        let large_sha = format!("{:x}", Sha256::digest(&large));
+        for (sha, data) in [(&small_sha, &small), (&large_sha, &large)] {
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    sha,
+                    FramedRead::new(std::io::Cursor::new(data.clone()), BytesCodec::new()),
+                )
+                .await
+                .unwrap();
+        }
+
+        let options = ScrubOptions {
+            part_grace_period: Duration::from_secs(0),
+            ..Default::default()
+        };
+        let report = store.scrub(&options).await.unwrap();
+        assert!(report.is_clean(), "Nothing should be wrong yet");
+        assert_eq!(2, report.parcels_checked);
+        assert_eq!((small.len() + large.len()) as u64, report.bytes_checked);
+
+        // Corrupt both kinds of parcel out of band
+        store
+            .parcels
+            .insert(&small_sha, b"bit rot".to_vec())
+            .unwrap();
+        std::fs::write(store.parcel_file_path(&large_sha), vec![8u8; large.len()]).unwrap();
+        let part = store.parcel_dir.join("abandoned.dat.part");
+        std::fs::write(&part, b"partial").unwrap();
+
+        let report = store.scrub(&options).await.unwrap();
+        assert_eq!(2, report.bad_parcels.len());
+        assert_eq!(vec![part.clone()], report.part_files);
+
+        let report = store
+            .scrub(&ScrubOptions {
+                quarantine: true,
+                ..options.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(2, report.bad_parcels.len());
+        assert!(!store
+            .parcel_exists(&inv.bindle.id, &small_sha)
+            .await
+            .unwrap());
+        assert!(!store
+            .parcel_exists(&inv.bindle.id, &large_sha)
+            .await
+            .unwrap());
+        let quarantine = root.path().join("quarantine").join(PARCEL_DIRECTORY);
+        assert_eq!(
+            b"bit rot".to_vec(),
+            std::fs::read(quarantine.join(format!("{}.dat", small_sha))).unwrap()
+        );
+        assert!(quarantine.join(format!("{}.dat", large_sha)).exists());
+        assert!(quarantine.join("abandoned.dat.part").exists());
+
+        assert!(store.scrub(&options).await.unwrap().is_clean());
+
+        // A damaged invoice that still says which bindle it is for is taken out of the index
+        let header = std::collections::BTreeMap::from([("bindle", inv.bindle.clone())]);
+        store
+            .invoices
+            .insert(inv.canonical_name(), serde_cbor::to_vec(&header).unwrap())
+            .unwrap();
+        let query = || {
+            store.index.query(
+                inv.bindle.id.name(),
+                "",
+                crate::search::SearchOptions::default(),
+            )
+        };
+        assert_eq!(1, query().await.unwrap().total);
+        let report = store
+            .scrub(&ScrubOptions {
+                quarantine: true,
+                ..options.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(1, report.bad_invoices.len());
+        assert!(!store.invoices.contains_key(inv.canonical_name()).unwrap());
+        assert_eq!(
+            0,
+            query().await.unwrap().total,
+            "Quarantined invoice should not be searchable"
+        );
+    }
+}
@@ -2,6 +2,7 @@
 //! [documented](https://github.com/deislabs/bindle/blob/master/docs/file-layout.md) in the main
 //! Bindle repo.
 
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
@@ -15,16 +16,21 @@ use sha2::{Digest, Sha256};
 use tokio::fs::{create_dir_all, File, OpenOptions};
 use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::Mutex as TokioMutex;
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt};
 use tokio_util::codec::{BytesCodec, FramedRead};
 use tokio_util::io::StreamReader;
 use tracing::{debug, error, info, instrument, trace, warn};
 use tracing_futures::Instrument;
 
-use crate::provider::{Provider, ProviderError, Result};
+use crate::provider::gc::{GcOptions, GcReport};
+use crate::provider::migrate::Import;
+use crate::provider::resign::{self, AppendSignature};
+use crate::provider::scrub::{self, Scrub, ScrubIssue, ScrubOptions, ScrubReport};
+use crate::provider::{read_range, Provider, ProviderError, Result, LIST_BUFFER_SIZE};
 use crate::search::Search;
 use crate::verification::Verified;
-use crate::{Id, Signed};
+use crate::{Id, Signature, Signed};
 
 /// The folder name for the invoices directory
 const INVOICE_DIRECTORY: &str = "invoices";
@@ -33,7 +39,10 @@ pub const PARCEL_DIRECTORY: &str = "parcels";
 const INVOICE_TOML: &str = "invoice.toml";
 pub const PARCEL_DAT: &str = "parcel.dat";
 const CACHE_SIZE: usize = 50;
-const PART_EXTENSION: &str = "part";
+pub(crate) const PART_EXTENSION: &str = "part";
+/// The name of the lock file in the root directory, which keeps garbage collection from running
+/// while a server is using the same directory
+const LOCK_FILE: &str = "bindle.lock";
 
 /// A file system backend for storing and retrieving bindles and parcles.
 ///
@@ -48,6 +57,12 @@ pub struct FileProvider<T> {
     invoice_cache: Arc<TokioMutex<LruCache<Id, crate::Invoice>>>,
 }
 
+/// A lock on the root directory of a [`FileProvider`], which is released when this is dropped
+#[derive(Debug)]
+pub struct StorageLock {
+    _file: std::fs::File,
+}
+
 impl<T: Clone> Clone for FileProvider<T> {
     fn clone(&self) -> Self {
         FileProvider {
@@ -81,11 +96,16 @@ impl<T: Search + Send + Sync> FileProvider<T> {
     /// in the repository. So it needs to communicate (on startup) what documents it knows
     /// about. The storage engine merely needs to store any non-duplicates. So we can
     /// safely insert, but ignore errors that come back because of duplicate entries.
+    ///
+    /// If the index has a [high-water mark](Search::high_water_mark), any invoices whose files
+    /// haven't been modified since then are skipped
     #[instrument(level = "trace", skip(self))]
     async fn warm_index(&self) -> anyhow::Result<()> {
         // Read all invoices
         info!(path = %self.root.display(), "Beginning index warm");
         let mut total_indexed: u64 = 0;
+        let mut total_skipped: u64 = 0;
+        let mut had_errors = false;
         // Check if the invoice directory exists. If it doesn't, this is likely the first time and
         // we should just return
         let invoice_path = self.invoice_path("");
@@ -94,6 +114,9 @@ impl<T: Search + Send + Sync> FileProvider<T> {
             Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(()),
             Err(e) => return Err(e.into()),
         };
+        let high_water_mark = crate::search::high_water_mark_at(std::time::SystemTime::now());
+        let since = self.index.high_water_mark().await?;
+        debug!(?since, "Fetched high-water mark from index");
         let mut readdir = tokio::fs::read_dir(invoice_path).await?;
         while let Some(e) = readdir.next_entry().await? {
             let p = e.path();
@@ -101,8 +124,17 @@ impl<T: Search + Send + Sync> FileProvider<T> {
                 Some(sha_opt) => sha_opt,
                 None => continue,
             };
-            // Load invoice
             let inv_path = self.invoice_toml_path(&sha);
+            // If we can't tell when the invoice was modified, assume it changed
+            let modified = modified_nanos(&tokio::fs::metadata(&inv_path).await?);
+            if let (Some(since), Some(modified)) = (since, modified) {
+                if modified < since {
+                    trace!(path = %inv_path.display(), "Invoice unchanged since last warm, skipping");
+                    total_skipped += 1;
+                    continue;
+                }
+            }
+            // Load invoice
             info!(path = %inv_path.display(), "Loading invoice into search index");
             // Open file
             let inv_toml = tokio::fs::read(inv_path).await?;
@@ -120,13 +152,136 @@ impl<T: Search + Send + Sync> FileProvider<T> {
 
             if let Err(e) = self.index.index(&invoice).await {
                 error!(invoice_id = %invoice.bindle.id, error = %e, "Error indexing invoice");
+                had_errors = true;
             }
             total_indexed += 1;
         }
-        debug!(total_indexed, "Warmed index");
+        // Don't move the mark past anything we failed to index so it gets retried next time
+        if !had_errors {
+            self.index.set_high_water_mark(high_water_mark).await?;
+        }
+        debug!(total_indexed, total_skipped, "Warmed index");
         Ok(())
     }
 
+    /// Takes a shared lock on the root directory. A server should hold this for as long as it is
+    /// running, as garbage collection takes an exclusive lock and so can't run at the same time.
+    /// Returns an error if garbage is currently being collected
+    pub async fn lock_shared(&self) -> Result<StorageLock> {
+        self.lock(false).await
+    }
+
+    async fn lock(&self, exclusive: bool) -> Result<StorageLock> {
+        create_dir_all(&self.root).await?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.root.join(LOCK_FILE))
+            .await?
+            .into_std()
+            .await;
+        let res = if exclusive {
+            file.try_lock()
+        } else {
+            file.try_lock_shared()
+        };
+        match res {
+            Ok(()) => Ok(StorageLock { _file: file }),
+            Err(std::fs::TryLockError::WouldBlock) if exclusive => Err(ProviderError::Other(
+                format!("{} is in use by a running server", self.root.display()),
+            )),
+            Err(std::fs::TryLockError::WouldBlock) => Err(ProviderError::Other(format!(
+                "Garbage is being collected in {}",
+                self.root.display()
+            ))),
+            Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    /// Removes all parcels that are not referenced by any invoice and are older than the grace
+    /// period. See the [`gc`](crate::provider::gc) module for more details.
+    ///
+    /// Unless this is a dry run, this returns an error if a server holds a
+    /// [shared lock](FileProvider::lock_shared) on the same directory
+    #[instrument(level = "trace", skip(self))]
+    pub async fn collect_garbage(&self, options: &GcOptions) -> Result<GcReport> {
+        let _lock = if options.dry_run {
+            None
+        } else {
+            Some(self.lock(true).await?)
+        };
+        info!(path = %self.root.display(), "Beginning garbage collection");
+        let mut report = GcReport {
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+
+        // Mark. If any invoice can't be read, we don't know the full live set, so bail out rather
+        // than risk removing a parcel that is in use
+        let mut live = HashSet::new();
+        match tokio::fs::read_dir(self.invoice_path("")).await {
+            Ok(mut readdir) => {
+                while let Some(e) = readdir.next_entry().await? {
+                    let sha = e.file_name();
+                    let inv_toml =
+                        match tokio::fs::read(self.invoice_toml_path(&sha.to_string_lossy())).await
+                        {
+                            Ok(data) => data,
+                            // An invoice that was never finished writing can't have any parcels
+                            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                            Err(e) => return Err(e.into()),
+                        };
+                    let invoice: crate::Invoice = toml::from_slice(&inv_toml)?;
+                    live.extend(invoice.parcel.into_iter().flatten().map(|p| p.label.sha256));
+                }
+            }
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => (),
+            Err(e) => return Err(e.into()),
+        }
+        report.live_parcels = live.len() as u64;
+        debug!(live_parcels = report.live_parcels, "Found live parcels");
+
+        // Sweep
+        let mut readdir = match tokio::fs::read_dir(self.root.join(PARCEL_DIRECTORY)).await {
+            Ok(r) => r,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(report),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(e) = readdir.next_entry().await? {
+            let sha = e.file_name().to_string_lossy().into_owned();
+            if live.contains(&sha) {
+                continue;
+            }
+            // The directory is modified whenever a part file is created or renamed in it
+            if !options.is_expired(e.metadata().await?.modified()?) {
+                trace!(%sha, "Unreferenced parcel is within the grace period");
+                report.retained.push(sha);
+                continue;
+            }
+            let size = match tokio::fs::metadata(self.parcel_data_path(&sha)).await {
+                Ok(m) => m.len(),
+                // This is likely an abandoned write, so clean it up anyway
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => 0,
+                Err(e) => return Err(e.into()),
+            };
+            if !options.dry_run {
+                debug!(%sha, "Removing unreferenced parcel");
+                tokio::fs::remove_dir_all(e.path()).await?;
+            }
+            report.removed_bytes += size;
+            report.removed.push(sha);
+        }
+        info!(
+            removed = report.removed.len(),
+            removed_bytes = report.removed_bytes,
+            retained = report.retained.len(),
+            dry_run = options.dry_run,
+            "Finished garbage collection"
+        );
+        Ok(report)
+    }
+
     /// Return the path to the invoice directory for a particular bindle.
     fn invoice_path(&self, invoice_id: &str) -> PathBuf {
         let mut path = self.root.join(INVOICE_DIRECTORY);
@@ -198,7 +353,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         // Create the part file to indicate that we are currently writing
         let mut part = PartFile::new(dest).await?;
         part.write_invoice(&inv).await?;
-        part.finalize().await?;
+        part.finalize_new().await?;
 
         // Attempt to update the index. Right now, we log an error if the index update
         // fails.
@@ -282,19 +437,30 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         Ok(invoice)
     }
 
-    #[instrument(level = "trace", skip(self, id), fields(id))]
-    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    #[instrument(level = "trace", skip(self, id, signature), fields(id))]
+    async fn yank_invoice<I>(&self, id: I, signature: Option<Signature>) -> Result<()>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>,
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
         tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        // Holding the part file keeps signatures from being appended at the same time, so neither
+        // can overwrite the other's changes
+        let mut part = PartFile::new(self.invoice_toml_path(&parsed_id.sha()))
+            .await
+            .map_err(|e| match e {
+                ProviderError::Io(e) => map_io_error(e),
+                e => e,
+            })?;
         trace!("Fetching invoice from storage");
+        self.invoice_cache.lock().await.pop(&parsed_id);
         let mut inv = self.get_yanked_invoice(&parsed_id).await?;
-        inv.yanked = Some(true);
+        inv.yank(signature);
 
-        debug!("Yanking invoice");
+        debug!("Writing yanked invoice to disk");
+        part.write_invoice(&inv).await?;
+        part.finalize().await?;
 
         // Attempt to update the index. Right now, we log an error if the index update
         // fails.
@@ -303,20 +469,6 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
             error!(error = %e, "Error indexing yanked invoice");
         }
 
-        // Open the destination or error out if it already exists.
-        let dest = self.invoice_toml_path(&inv.canonical_name());
-
-        // Encode the invoice into a TOML object
-        trace!("Encoding invoice to TOML");
-        let data = toml::to_vec(&inv)?;
-        // NOTE: Right now, this just force-overwites the existing invoice. We are assuming
-        // that the bindle has already been confirmed to be present. However, we have not
-        // ensured that here. So it is theoretically possible (if get_invoice was not used
-        // to build the invoice) that this could _create_ a new file. We could probably change
-        // this behavior with OpenOptions.
-        debug!(path = %dest.display(), "Writing yanked invoice to disk");
-        tokio::fs::write(dest, data).await?;
-
         // Drop the invoice from the cache (as it is unlikely that someone will want to fetch it
         // right after yanking it)
         trace!("Dropping yanked invoice from cache");
@@ -349,12 +501,24 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         }
         // Create box dir
         trace!(path = %par_path.display(), "Creating parcel directory");
-        create_dir_all(par_path).await?;
+        create_dir_all(&par_path).await?;
 
-        // Write data
+        // Write data. If the part file can't be created, another write is in progress and the
+        // directory belongs to it
         let mut part = PartFile::new(self.parcel_data_path(parcel_id)).await?;
-        part.write_parcel(data, parcel_id, label.size).await?;
-        part.finalize().await
+        let res = async {
+            part.write_parcel(data, parcel_id, label.size).await?;
+            part.finalize_new().await
+        }
+        .await;
+        if matches!(res, Err(ref e) if !matches!(e, ProviderError::Exists)) {
+            // Remove the directory so the parcel can be uploaded again. The part file has already
+            // been cleaned up by this point, so this only removes an empty directory
+            if let Err(e) = tokio::fs::remove_dir(&par_path).await {
+                warn!(error = %e, path = %par_path.display(), "Unable to clean up parcel directory after failed write");
+            }
+        }
+        res
     }
 
     #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
@@ -381,6 +545,31 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         ))
     }
 
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        let name = self.parcel_data_path(parcel_id);
+        debug!(path = %name.display(), "Getting parcel range from storage");
+        let reader = File::open(name).await.map_err(map_io_error)?;
+        let stream = read_range(reader, range).await?;
+        Ok(Box::new(
+            stream.map(|res| res.map_err(map_io_error).map(|b| b.freeze())),
+        ))
+    }
+
     #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
     async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
     where
@@ -400,6 +589,234 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
             Err(e) => Err(e.into()),
         }
     }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn list_invoices(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<crate::Invoice>> + Unpin + Send + Sync>> {
+        let mut readdir = match read_dir_if_exists(&self.invoice_path("")).await? {
+            Some(r) => r,
+            None => return Ok(Box::new(tokio_stream::empty())),
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER_SIZE);
+        tokio::spawn(
+            async move {
+                loop {
+                    let res = match readdir.next_entry().await {
+                        Ok(Some(e)) => match tokio::fs::read(e.path().join(INVOICE_TOML)).await {
+                            Ok(raw) => toml::from_slice(&raw).map_err(ProviderError::from),
+                            // An invoice that was never finished writing doesn't exist yet
+                            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                            Err(e) => Err(e.into()),
+                        },
+                        Ok(None) => break,
+                        Err(e) => Err(e.into()),
+                    };
+                    // If the receiver hung up, there's no point in continuing
+                    if tx.send(res).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            .instrument(tracing::trace_span!("list_invoices")),
+        );
+        Ok(Box::new(ReceiverStream::new(rx)))
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn list_parcels(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<String>> + Unpin + Send + Sync>> {
+        let mut readdir = match read_dir_if_exists(&self.root.join(PARCEL_DIRECTORY)).await? {
+            Some(r) => r,
+            None => return Ok(Box::new(tokio_stream::empty())),
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER_SIZE);
+        tokio::spawn(
+            async move {
+                loop {
+                    let res = match readdir.next_entry().await {
+                        // The parcel directory exists before the data is done being written, so
+                        // only list it once the data file is in place
+                        Ok(Some(e)) => match tokio::fs::metadata(e.path().join(PARCEL_DAT)).await {
+                            Ok(m) if m.is_file() => {
+                                Ok(e.file_name().to_string_lossy().into_owned())
+                            }
+                            Ok(_) => continue,
+                            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                            Err(e) => Err(e.into()),
+                        },
+                        Ok(None) => break,
+                        Err(e) => Err(e.into()),
+                    };
+                    if tx.send(res).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            .instrument(tracing::trace_span!("list_parcels")),
+        );
+        Ok(Box::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: crate::search::Search + Send + Sync> Import for FileProvider<T> {
+    #[instrument(level = "trace", skip(self, invoice), fields(invoice_id = %invoice.bindle.id))]
+    async fn import_invoice(&self, invoice: &crate::Invoice) -> Result<()> {
+        let invoice_id = invoice.canonical_name();
+        create_dir_all(self.invoice_path(&invoice_id)).await?;
+
+        // Go through a part file so an interrupted import never leaves a partial invoice behind.
+        // Finalizing replaces any copy from an earlier import
+        let mut part = PartFile::new(self.invoice_toml_path(&invoice_id)).await?;
+        part.write_invoice(invoice).await?;
+        part.finalize().await?;
+        self.invoice_cache.lock().await.pop(&invoice.bindle.id);
+
+        if let Err(e) = self.index.index(invoice).await {
+            error!(error = %e, "Error indexing imported invoice");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: crate::search::Search + Send + Sync> AppendSignature for FileProvider<T> {
+    #[instrument(level = "trace", skip(self, signatures), fields(%id))]
+    async fn append_signatures(
+        &self,
+        id: &Id,
+        signatures: Vec<Signature>,
+    ) -> Result<crate::Invoice> {
+        // Holding the part file keeps another append from running at the same time, so neither
+        // can overwrite the signatures added by the other
+        let mut part = PartFile::new(self.invoice_toml_path(&id.sha()))
+            .await
+            .map_err(|e| match e {
+                ProviderError::Io(e) => map_io_error(e),
+                e => e,
+            })?;
+        self.invoice_cache.lock().await.pop(id);
+        let mut inv = self.get_yanked_invoice(id).await?;
+        resign::append_to_invoice(&mut inv, signatures)?;
+
+        debug!("Writing signed invoice to disk");
+        part.write_invoice(&inv).await?;
+        part.finalize().await?;
+        self.invoice_cache.lock().await.pop(id);
+
+        if let Err(e) = self.index.index(&inv).await {
+            error!(error = %e, "Error indexing signed invoice");
+        }
+        Ok(inv)
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: crate::search::Search + Send + Sync> Scrub for FileProvider<T> {
+    #[instrument(level = "trace", skip(self, options))]
+    async fn scrub(&self, options: &ScrubOptions) -> Result<ScrubReport> {
+        info!(path = %self.root.display(), "Beginning scrub");
+        let mut report = ScrubReport {
+            quarantined: options.quarantine,
+            ..Default::default()
+        };
+
+        // The expected size of each parcel comes from the labels in the invoices
+        let mut sizes = HashMap::new();
+        if let Some(mut readdir) = read_dir_if_exists(&self.invoice_path("")).await? {
+            while let Some(e) = readdir.next_entry().await? {
+                let name = e.file_name().to_string_lossy().into_owned();
+                scrub::find_part_files(&self.root, &e.path(), options, &mut report).await?;
+                let inv_path = self.invoice_toml_path(&name);
+                let inv_toml = match tokio::fs::read(&inv_path).await {
+                    Ok(data) => data,
+                    Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                report.invoices_checked += 1;
+                let problem = match toml::from_slice::<crate::Invoice>(&inv_toml) {
+                    Ok(invoice) => {
+                        let problem = scrub::check_invoice(&name, &invoice, options);
+                        for label in invoice.parcel.into_iter().flatten().map(|p| p.label) {
+                            sizes.entry(label.sha256).or_insert(label.size);
+                        }
+                        problem
+                    }
+                    Err(e) => Some(format!("Invoice is malformed: {}", e)),
+                };
+                if let Some(problem) = problem {
+                    if options.quarantine {
+                        scrub::quarantine_file(&self.root, &inv_path).await?;
+                        // This only succeeds if nothing else is left in the directory
+                        let _ = tokio::fs::remove_dir(e.path()).await;
+                        let header = toml::from_slice(&inv_toml).ok();
+                        scrub::remove_from_index(&self.index, &name, header).await;
+                    }
+                    report.bad_invoices.push(ScrubIssue { name, problem });
+                }
+            }
+        }
+        if options.quarantine && !report.bad_invoices.is_empty() {
+            self.invoice_cache.lock().await.clear();
+        }
+
+        let mut throttle = scrub::Throttle::new(options.max_bytes_per_sec);
+        if let Some(mut readdir) = read_dir_if_exists(&self.root.join(PARCEL_DIRECTORY)).await? {
+            while let Some(e) = readdir.next_entry().await? {
+                let sha = e.file_name().to_string_lossy().into_owned();
+                scrub::find_part_files(&self.root, &e.path(), options, &mut report).await?;
+                let data_path = self.parcel_data_path(&sha);
+                let file = match File::open(&data_path).await {
+                    Ok(f) => f,
+                    Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                trace!(%sha, "Checking parcel");
+                let (actual_sha, size) = scrub::hash_reader(file, &mut throttle).await?;
+                report.parcels_checked += 1;
+                report.bytes_checked += size;
+                if let Some(problem) =
+                    scrub::check_parcel(&sha, &actual_sha, size, sizes.get(&sha).copied())
+                {
+                    if options.quarantine {
+                        scrub::quarantine_file(&self.root, &data_path).await?;
+                        let _ = tokio::fs::remove_dir(e.path()).await;
+                    }
+                    report.bad_parcels.push(ScrubIssue { name: sha, problem });
+                }
+            }
+        }
+
+        info!(
+            invoices_checked = report.invoices_checked,
+            parcels_checked = report.parcels_checked,
+            bad_invoices = report.bad_invoices.len(),
+            bad_parcels = report.bad_parcels.len(),
+            part_files = report.part_files.len(),
+            "Finished scrub"
+        );
+        Ok(report)
+    }
+}
+
+/// Opens the given directory for reading, returning `None` if it doesn't exist yet
+async fn read_dir_if_exists(path: &Path) -> Result<Option<tokio::fs::ReadDir>> {
+    match tokio::fs::read_dir(path).await {
+        Ok(r) => Ok(Some(r)),
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the modification time of the file in nanoseconds since the Unix epoch, if the platform
+/// supports it
+fn modified_nanos(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata
+        .modified()
+        .ok()
+        .map(crate::search::high_water_mark_at)
 }
 
 fn map_io_error(e: std::io::Error) -> ProviderError {
@@ -492,16 +909,19 @@ async fn validate_sha256(file: &mut File, sha: &str) -> Result<()> {
 /// A helper struct for a part file that will clean up the file on drop if it still exists. Also
 /// contains functionality for writing to the file and finalizing it (i.e moving it to the correct
 /// location)
-struct PartFile {
+pub(crate) struct PartFile {
     path: PathBuf,
     final_location: PathBuf,
     file: File,
+    // Set once the part file has been renamed, as another writer could have created a new part file
+    // at the same path by the time this is dropped
+    finalized: bool,
 }
 
 impl PartFile {
     /// Creates a new PartFile that will eventually be located at the given `final_location`. This
     /// will attempt to create a new part file and return an error if one already exists
-    async fn new(final_location: PathBuf) -> Result<Self> {
+    pub(crate) async fn new(final_location: PathBuf) -> Result<Self> {
         let extension = match final_location.extension() {
             Some(s) => {
                 let mut ext = s.to_owned();
@@ -544,16 +964,25 @@ impl PartFile {
             }
         };
         #[cfg(target_family = "unix")]
-        let file = OpenOptions::new()
+        let file = match OpenOptions::new()
             .create_new(true)
             .write(true)
             .read(true)
             .open(&part)
-            .await?;
+            .await
+        {
+            Ok(f) => f,
+            // Another writer created the part file after the check above
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(ProviderError::WriteInProgress)
+            }
+            Err(e) => return Err(e.into()),
+        };
         Ok(PartFile {
             path: part,
             final_location,
             file,
+            finalized: false,
         })
     }
 
@@ -572,7 +1001,7 @@ impl PartFile {
             .map_err(|e| e.into())
     }
 
-    async fn write_parcel<R, B>(
+    pub(crate) async fn write_parcel<R, B>(
         &mut self,
         data: R,
         parcel_id: &str,
@@ -616,8 +1045,9 @@ impl PartFile {
         Ok(())
     }
 
-    /// Moves the file to the configured final location, consuming the part file
-    async fn finalize(mut self) -> Result<()> {
+    /// Moves the file to the configured final location, consuming the part file. Anything already
+    /// at the final location is replaced
+    pub(crate) async fn finalize(mut self) -> Result<()> {
         debug!(
             renamed_path = %self.final_location.display(),
             "Renaming part file for parcel"
@@ -626,14 +1056,32 @@ impl PartFile {
         // Close the file handle to avoid any problems with unfinished IO operations
         self.file.shutdown().await?;
 
-        tokio::fs::rename(&self.path, &self.final_location)
+        tokio::fs::rename(&self.path, &self.final_location).await?;
+        self.finalized = true;
+        Ok(())
+    }
+
+    /// Moves the file to the configured final location like [`finalize`](PartFile::finalize), but
+    /// returns [`ProviderError::Exists`] if something is already there. Only one part file for a
+    /// location can exist at a time, so another writer can't finalize between the check and the
+    /// rename
+    pub(crate) async fn finalize_new(self) -> Result<()> {
+        if tokio::fs::symlink_metadata(&self.final_location)
             .await
-            .map_err(|e| e.into())
+            .is_ok()
+        {
+            debug!(path = %self.final_location.display(), "Another write already finished");
+            return Err(ProviderError::Exists);
+        }
+        self.finalize().await
     }
 }
 
 impl Drop for PartFile {
     fn drop(&mut self) {
+        if self.finalized {
+            return;
+        }
         // Attempt to delete the file, logging an error if the delete failed
         if let Err(e) = std::fs::remove_file(&self.path) {
             // If it is any other error besides NotFound, log the error
@@ -703,9 +1151,22 @@ mod test {
         // Out-of-band read the invoice
         assert!(store.invoice_toml_path(&inv_name).exists());
 
+        // A yank can't run while another write to the invoice, such as an append, is in progress
+        let yank_sig = crate::invoice::sign_yank(&scaffold.invoice.bindle.id, &sk).unwrap();
+        let part = PartFile::new(store.invoice_toml_path(&inv_name))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store
+                .yank_invoice(&scaffold.invoice.bindle.id, Some(yank_sig.clone()))
+                .await,
+            Err(ProviderError::WriteInProgress)
+        ));
+        drop(part);
+
         // Yank the invoice
         store
-            .yank_invoice(&scaffold.invoice.bindle.id)
+            .yank_invoice(&scaffold.invoice.bindle.id, Some(yank_sig))
             .await
             .unwrap();
 
@@ -715,11 +1176,294 @@ mod test {
             .await
             .unwrap();
         assert!(inv2.yanked.unwrap_or(false));
+        assert_eq!(
+            1,
+            inv2.yanked_signature.unwrap_or_default().len(),
+            "Yanked invoice should have a yank signature"
+        );
 
         // Sanity check that this produces an error
         assert!(store.get_invoice(scaffold.invoice.bindle.id).await.is_err());
     }
 
+    #[tokio::test]
+    async fn test_should_warm_index_incrementally() {
+        use crate::search::{EmbeddedEngine, Search, SearchOptions};
+
+        let root = tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+
+        let sk = mock_secret_key();
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(scaffold.invoice.clone(), &KeyRing::default())
+            .unwrap();
+        let signed = crate::invoice::sign(verified, vec![(SignatureRole::Creator, &sk)]).unwrap();
+        store.create_invoice(signed).await.unwrap();
+
+        let index_dir = tempdir().unwrap();
+        let index = EmbeddedEngine::new(index_dir.path()).await.unwrap();
+        let search = || {
+            index.query(
+                scaffold.invoice.bindle.id.name(),
+                "",
+                SearchOptions {
+                    strict: true,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // Anything older than the mark should be skipped
+        index.set_high_water_mark(u64::MAX).await.unwrap();
+        FileProvider::new(root.path().to_owned(), index.clone()).await;
+        assert!(search().await.unwrap().invoices.is_empty());
+
+        // A full rebuild should pick it up and record the new mark
+        index.clear().await.unwrap();
+        FileProvider::new(root.path().to_owned(), index.clone()).await;
+        assert_eq!(1, search().await.unwrap().invoices.len());
+        let mark = index.high_water_mark().await.unwrap().expect("mark is set");
+        assert!(mark < u64::MAX);
+    }
+
+    #[tokio::test]
+    async fn test_should_collect_garbage() {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+
+        let sk = mock_secret_key();
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(scaffold.invoice.clone(), &KeyRing::default())
+            .unwrap();
+        let signed = crate::invoice::sign(verified, vec![(SignatureRole::Creator, &sk)]).unwrap();
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+
+        let now = GcOptions {
+            grace_period: std::time::Duration::from_secs(0),
+            dry_run: false,
+        };
+        let report = store.collect_garbage(&now).await.unwrap();
+        assert_eq!(1, report.live_parcels);
+        assert!(report.removed.is_empty(), "Live parcels should be kept");
+
+        // Orphan the parcel by removing the invoice out of band
+        std::fs::remove_dir_all(store.invoice_path(&scaffold.invoice.canonical_name())).unwrap();
+
+        let report = store.collect_garbage(&GcOptions::default()).await.unwrap();
+        assert_eq!(vec![parcel.sha.clone()], report.retained);
+        assert!(report.removed.is_empty());
+
+        // Only dry runs are allowed while a server is using the directory
+        let lock = store.lock_shared().await.unwrap();
+        let report = store
+            .collect_garbage(&GcOptions {
+                dry_run: true,
+                ..now.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(vec![parcel.sha.clone()], report.removed);
+        assert_eq!(parcel.data.len() as u64, report.removed_bytes);
+        store
+            .collect_garbage(&now)
+            .await
+            .expect_err("Should not collect garbage while a server holds the lock");
+        assert!(store.parcel_data_path(&parcel.sha).exists());
+        drop(lock);
+
+        let lock = store.lock(true).await.unwrap();
+        store
+            .lock_shared()
+            .await
+            .expect_err("Should not lock while garbage is being collected");
+        drop(lock);
+
+        let report = store.collect_garbage(&now).await.unwrap();
+        assert_eq!(vec![parcel.sha.clone()], report.removed);
+        assert!(!store.parcel_path(&parcel.sha).exists());
+    }
+
+    #[tokio::test]
+    async fn test_should_scrub() {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+
+        let sk = mock_secret_key();
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(scaffold.invoice.clone(), &KeyRing::default())
+            .unwrap();
+        let signed = crate::invoice::sign(verified, vec![(SignatureRole::Creator, &sk)]).unwrap();
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+
+        let options = ScrubOptions {
+            keyring: Some(KeyRing::new(vec![(&sk).try_into().unwrap()])),
+            strategy: VerificationStrategy::CreativeIntegrity,
+            part_grace_period: std::time::Duration::from_secs(0),
+            ..Default::default()
+        };
+        let report = store.scrub(&options).await.unwrap();
+        assert!(report.is_clean(), "Nothing should be wrong yet");
+        assert_eq!(1, report.invoices_checked);
+        assert_eq!(1, report.parcels_checked);
+        assert_eq!(parcel.data.len() as u64, report.bytes_checked);
+
+        let report = store
+            .scrub(&ScrubOptions {
+                keyring: Some(KeyRing::default()),
+                ..options.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(1, report.bad_invoices.len(), "Unknown signer should fail");
+
+        // Flip the data on disk and leave behind an abandoned upload
+        std::fs::write(store.parcel_data_path(&parcel.sha), b"bit rot").unwrap();
+        let part = store.parcel_path("abandoned").join("parcel.dat.part");
+        std::fs::create_dir_all(part.parent().unwrap()).unwrap();
+        std::fs::write(&part, b"partial").unwrap();
+
+        let report = store.scrub(&options).await.unwrap();
+        assert!(report.bad_invoices.is_empty());
+        assert_eq!(1, report.bad_parcels.len());
+        assert_eq!(parcel.sha, report.bad_parcels[0].name);
+        assert_eq!(vec![part.clone()], report.part_files);
+        assert!(store.parcel_data_path(&parcel.sha).exists());
+
+        let report = store
+            .scrub(&ScrubOptions {
+                quarantine: true,
+                ..options.clone()
+            })
+            .await
+            .unwrap();
+        assert!(report.quarantined);
+        assert_eq!(1, report.bad_parcels.len());
+        assert!(!store.parcel_path(&parcel.sha).exists());
+        assert!(!part.exists());
+        let quarantine = root.path().join("quarantine").join(PARCEL_DIRECTORY);
+        assert_eq!(
+            b"bit rot".to_vec(),
+            std::fs::read(quarantine.join(&parcel.sha).join(PARCEL_DAT)).unwrap()
+        );
+        assert!(quarantine.join("abandoned/parcel.dat.part").exists());
+
+        assert!(store.scrub(&options).await.unwrap().is_clean());
+
+        // A damaged invoice that still says which bindle it is for is taken out of the index
+        let id = &scaffold.invoice.bindle.id;
+        let header = format!(
+            "[bindle]\nname = \"{}\"\nversion = \"{}\"\n",
+            id.name(),
+            id.version_string()
+        );
+        std::fs::write(store.invoice_toml_path(&id.sha()), header).unwrap();
+        let query = || {
+            store
+                .index
+                .query(id.name(), "", crate::search::SearchOptions::default())
+        };
+        assert_eq!(1, query().await.unwrap().total);
+        let report = store
+            .scrub(&ScrubOptions {
+                quarantine: true,
+                ..options.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(1, report.bad_invoices.len());
+        assert!(!store.invoice_toml_path(&id.sha()).exists());
+        assert_eq!(
+            0,
+            query().await.unwrap().total,
+            "Quarantined invoice should not be searchable"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_should_list_invoices_and_parcels() {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+
+        // Nothing has been created yet, so the directories don't exist
+        assert!(store.list_invoices().await.unwrap().next().await.is_none());
+        assert!(store.list_parcels().await.unwrap().next().await.is_none());
+
+        let sk = mock_secret_key();
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(scaffold.invoice.clone(), &KeyRing::default())
+            .unwrap();
+        let signed = crate::invoice::sign(verified, vec![(SignatureRole::Creator, &sk)]).unwrap();
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+        // A parcel that is still being written shouldn't be listed
+        create_dir_all(store.parcel_path("abc123")).await.unwrap();
+
+        let invoices: Vec<crate::Invoice> = store
+            .list_invoices()
+            .await
+            .unwrap()
+            .collect::<Result<_>>()
+            .await
+            .unwrap();
+        assert_eq!(1, invoices.len());
+        assert_eq!(scaffold.invoice.bindle.id, invoices[0].bindle.id);
+
+        let parcels: Vec<String> = store
+            .list_parcels()
+            .await
+            .unwrap()
+            .collect::<Result<_>>()
+            .await
+            .unwrap();
+        assert_eq!(vec![parcel.sha.clone()], parcels);
+    }
+
     #[tokio::test]
     async fn test_should_reject_yanked_invoice() {
         // Create a temporary directory
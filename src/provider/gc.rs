@@ -0,0 +1,66 @@
+//! Types for garbage collecting parcels in terminal providers.
+//!
+//! Parcels are stored in a global space addressed by their SHA, so nothing removes a parcel when
+//! the invoices that referenced it go away. Terminal providers implement a mark-and-sweep
+//! collection: every invoice (including yanked ones) is read to build the set of live parcels, and
+//! then every stored parcel that isn't in that set and is older than the grace period is removed.
+//!
+//! The grace period exists because a parcel could be uploaded for an invoice created after the live
+//! set was built. Even so, a collection must only be run while the provider isn't accepting new
+//! invoices, as an invoice created mid-collection could reference a parcel that is about to be
+//! removed. The embedded provider's database can only be opened by one process at a time, so this
+//! is always the case. The file provider keeps a lock file in its root directory that servers hold
+//! a [shared lock](crate::provider::file::FileProvider::lock_shared) on, and a collection fails
+//! rather than running while that lock is held
+
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+
+/// The default grace period of one day
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Options for a garbage collection run
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    /// Unreferenced parcels newer than this are left alone
+    pub grace_period: Duration,
+    /// Whether to only report what would be removed without removing anything
+    pub dry_run: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            grace_period: DEFAULT_GRACE_PERIOD,
+            dry_run: false,
+        }
+    }
+}
+
+impl GcOptions {
+    /// Returns whether something created at the given time is past the grace period. Times in the
+    /// future are never expired
+    pub(crate) fn is_expired(&self, created: SystemTime) -> bool {
+        SystemTime::now()
+            .duration_since(created)
+            .map(|age| age >= self.grace_period)
+            .unwrap_or(false)
+    }
+}
+
+/// The results of a garbage collection run
+#[derive(Debug, Default, Serialize)]
+pub struct GcReport {
+    /// Whether this was a dry run, in which case nothing was actually removed
+    pub dry_run: bool,
+    /// The number of distinct parcels referenced by at least one invoice
+    pub live_parcels: u64,
+    /// The SHAs of the unreferenced parcels that were removed (or would have been)
+    pub removed: Vec<String>,
+    /// The total size in bytes of the removed parcels
+    pub removed_bytes: u64,
+    /// The SHAs of the unreferenced parcels that were kept because they are still within the grace
+    /// period
+    pub retained: Vec<String>,
+}
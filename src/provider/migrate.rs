@@ -0,0 +1,236 @@
+//! Functionality for copying everything from one provider into a terminal provider, such as when
+//! switching from the [`FileProvider`](crate::provider::file::FileProvider) to the
+//! [`EmbeddedProvider`](crate::provider::embedded::EmbeddedProvider).
+//!
+//! Invoices are copied exactly as they are, including their yanked state and all signatures, and
+//! each one is read back from the destination to make sure nothing changed. Parcels are copied
+//! using [`create_parcel`](Provider::create_parcel), so the destination checks the size and digest
+//! of every parcel as it is written. Parcels that already exist in the destination and invoices
+//! that are already stored there exactly as they are in the source are skipped, so an interrupted
+//! migration can be resumed by running it again. An invoice that exists in the destination but
+//! differs from the source (such as one that has since been yanked) is replaced.
+
+use serde::Serialize;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, instrument, warn};
+
+use crate::provider::{Provider, ProviderError, Result};
+use crate::Id;
+
+/// A terminal provider that can store an invoice exactly as given. This is what allows invoices to
+/// be migrated without re-signing them or losing their yanked state
+#[async_trait::async_trait]
+pub trait Import {
+    /// Stores the invoice exactly as given, replacing any existing copy of it. Unlike
+    /// [`create_invoice`](Provider::create_invoice), this does not check the invoice in any way
+    /// (including whether it is yanked), so it should only be given invoices that came from
+    /// another provider
+    async fn import_invoice(&self, invoice: &crate::Invoice) -> Result<()>;
+}
+
+/// The results of a migration
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationReport {
+    /// The number of invoices copied
+    pub invoices_copied: u64,
+    /// The number of invoices that were already stored exactly the same way in the destination
+    pub invoices_skipped: u64,
+    /// The number of parcels copied
+    pub parcels_copied: u64,
+    /// The number of parcels that already existed in the destination
+    pub parcels_skipped: u64,
+    /// The SHAs of parcels that are referenced by an invoice but don't exist in the source, so
+    /// they could not be copied
+    pub parcels_missing: Vec<String>,
+}
+
+/// Copies all invoices and parcels from the source to the destination. Parcels that exist in the
+/// source but are not referenced by any invoice are not copied.
+///
+/// Any error stops the migration. Once the problem is fixed, running the migration again will pick
+/// up where it left off
+#[instrument(level = "trace", skip(source, dest))]
+pub async fn migrate<S, D>(source: &S, dest: &D) -> Result<MigrationReport>
+where
+    S: Provider + Sync,
+    D: Provider + Import + Sync,
+{
+    let mut report = MigrationReport::default();
+    let mut invoices = source.list_invoices().await?;
+    while let Some(inv) = invoices.next().await {
+        let inv = inv?;
+        let id = inv.bindle.id.clone();
+        // Comparing the serialized forms catches any difference in the data, including signatures
+        // and the yanked state
+        let serialized = toml::to_string(&inv)?;
+        let existing = match dest.get_yanked_invoice(&id).await {
+            Ok(existing) => Some(toml::to_string(&existing)?),
+            Err(ProviderError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if existing.as_ref() == Some(&serialized) {
+            debug!(%id, "Invoice already exists in destination, skipping");
+            report.invoices_skipped += 1;
+        } else {
+            info!(%id, "Migrating invoice");
+            dest.import_invoice(&inv).await?;
+
+            // Make sure the invoice wasn't changed on the way in
+            let imported = dest.get_yanked_invoice(&id).await?;
+            if toml::to_string(&imported)? != serialized {
+                return Err(ProviderError::Other(format!(
+                    "Invoice {} was changed when it was stored in the destination",
+                    id
+                )));
+            }
+            report.invoices_copied += 1;
+        }
+
+        for label in inv.parcel.iter().flatten().map(|p| &p.label) {
+            migrate_parcel(source, dest, &id, &label.sha256, &mut report).await?;
+        }
+    }
+    info!(
+        invoices_copied = report.invoices_copied,
+        invoices_skipped = report.invoices_skipped,
+        parcels_copied = report.parcels_copied,
+        parcels_skipped = report.parcels_skipped,
+        parcels_missing = report.parcels_missing.len(),
+        "Finished migration"
+    );
+    Ok(report)
+}
+
+async fn migrate_parcel<S, D>(
+    source: &S,
+    dest: &D,
+    id: &Id,
+    sha: &str,
+    report: &mut MigrationReport,
+) -> Result<()>
+where
+    S: Provider + Sync,
+    D: Provider + Sync,
+{
+    if dest.parcel_exists(id, sha).await? {
+        debug!(%id, sha, "Parcel already exists in destination, skipping");
+        report.parcels_skipped += 1;
+        return Ok(());
+    }
+    if !source.parcel_exists(id, sha).await? {
+        // This can happen if a parcel was never uploaded, which isn't a reason to stop
+        warn!(%id, sha, "Parcel does not exist in source, skipping");
+        if !report.parcels_missing.iter().any(|s| s == sha) {
+            report.parcels_missing.push(sha.to_owned());
+        }
+        return Ok(());
+    }
+    debug!(%id, sha, "Copying parcel");
+    let data = source
+        .get_parcel(id, sha)
+        .await?
+        .map(|res| res.map_err(std::io::Error::other));
+    dest.create_parcel(id, sha, data).await?;
+    report.parcels_copied += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::invoice::signature::{KeyRing, SecretKeyEntry, SignatureRole};
+    use crate::provider::embedded::EmbeddedProvider;
+    use crate::provider::file::FileProvider;
+    use crate::search::NoopEngine;
+    use crate::testing;
+    use crate::VerificationStrategy;
+    use tempfile::tempdir;
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    #[tokio::test]
+    async fn test_should_migrate_and_resume() {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let (source_dir, dest_dir) = (tempdir().unwrap(), tempdir().unwrap());
+        let source = FileProvider::new(source_dir.path(), NoopEngine::default()).await;
+        let dest = EmbeddedProvider::new(dest_dir.path(), NoopEngine::default())
+            .await
+            .unwrap();
+
+        let sk = SecretKeyEntry::new(
+            "Test Key".to_owned(),
+            vec![SignatureRole::Creator, SignatureRole::Host],
+        );
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(scaffold.invoice.clone(), &KeyRing::default())
+            .unwrap();
+        let signed = crate::invoice::sign(verified, vec![(SignatureRole::Creator, &sk)]).unwrap();
+        let id = source.create_invoice(signed).await.unwrap().0.bindle.id;
+        let yank_sig = crate::invoice::sign_yank(&id, &sk).unwrap();
+        source.yank_invoice(&id, Some(yank_sig)).await.unwrap();
+
+        // The parcel hasn't been uploaded, so it can't be copied yet
+        let report = migrate(&source, &dest)
+            .await
+            .expect("migration should succeed");
+        assert_eq!(1, report.invoices_copied);
+        assert_eq!(0, report.parcels_copied);
+        assert_eq!(vec![parcel.sha.clone()], report.parcels_missing);
+
+        let original = source.get_yanked_invoice(&id).await.unwrap();
+        let migrated = dest.get_yanked_invoice(&id).await.unwrap();
+        assert_eq!(
+            toml::to_string(&original).unwrap(),
+            toml::to_string(&migrated).unwrap()
+        );
+        assert!(migrated.yanked.unwrap_or(false));
+        assert_eq!(1, migrated.yanked_signature.unwrap_or_default().len());
+
+        source
+            .create_parcel(
+                &id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .unwrap();
+        let report = migrate(&source, &dest)
+            .await
+            .expect("migration should succeed");
+        assert_eq!(0, report.invoices_copied);
+        assert_eq!(1, report.invoices_skipped);
+        assert_eq!(1, report.parcels_copied);
+        assert!(report.parcels_missing.is_empty());
+        let mut stream = dest.get_parcel(&id, &parcel.sha).await.unwrap();
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(parcel.data, data);
+
+        // Anything already copied should be skipped
+        let report = migrate(&source, &dest)
+            .await
+            .expect("migration should succeed");
+        assert_eq!(0, report.invoices_copied);
+        assert_eq!(1, report.invoices_skipped);
+        assert_eq!(0, report.parcels_copied);
+        assert_eq!(1, report.parcels_skipped);
+
+        // An invoice that changed in the source since it was copied should be copied again
+        let mut changed = original;
+        changed.yanked_signature = None;
+        source.import_invoice(&changed).await.unwrap();
+        let report = migrate(&source, &dest)
+            .await
+            .expect("migration should succeed");
+        assert_eq!(1, report.invoices_copied);
+        assert_eq!(0, report.invoices_skipped);
+        assert!(dest
+            .get_yanked_invoice(&id)
+            .await
+            .unwrap()
+            .yanked_signature
+            .is_none());
+    }
+}
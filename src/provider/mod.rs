@@ -21,17 +21,24 @@
 //! will generally contain another Provider implementation or an HTTP client to talk to another
 //! server upstream
 
+pub mod compromise;
 pub mod embedded;
 pub mod file;
+pub mod gc;
+pub mod migrate;
+pub mod resign;
+pub mod scrub;
 
 use std::convert::TryInto;
+use std::ops::Range;
 
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
 use tokio_stream::Stream;
+use tokio_util::codec::{BytesCodec, FramedRead};
 
 use crate::verification::Verified;
-use crate::SignatureError;
-use crate::{Id, Signed};
+use crate::{Id, Signature, SignatureError, Signed};
 
 /// A custom shorthand result type that always has an error type of [`ProviderError`](ProviderError)
 pub type Result<T> = core::result::Result<T, ProviderError>;
@@ -83,7 +90,12 @@ pub trait Provider {
         I::Error: Into<ProviderError>;
 
     /// Remove an invoice by ID
-    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    ///
+    /// If a yank signature is given (generated with [`sign_yank`](crate::invoice::sign_yank)),
+    /// terminal providers must append it to the `yanked_signature` list of the stored invoice.
+    /// Providers that pass the request on to another server (such as the client) may ignore it,
+    /// as the upstream server is responsible for signing the yank
+    async fn yank_invoice<I>(&self, id: I, signature: Option<Signature>) -> Result<()>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>;
@@ -136,6 +148,27 @@ pub trait Provider {
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>;
 
+    /// Get a range of bytes from a specific parcel using its SHA, such as when resuming an
+    /// interrupted download.
+    ///
+    /// The returned stream ends at the end of the range or the end of the parcel, whichever comes
+    /// first. The default implementation reads the parcel from the start using
+    /// [`get_parcel`](Provider::get_parcel) and throws away anything outside of the range, so
+    /// providers that can seek to the start of the range should override it
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let stream = self.get_parcel(bindle_id, parcel_id).await?;
+        Ok(Box::new(slice_stream(stream, range)))
+    }
+
     /// Checks if the given parcel exists in storage.
     ///
     /// This should not load the full parcel but only indicate if the parcel exists. For some
@@ -144,6 +177,73 @@ pub trait Provider {
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>;
+
+    /// Lists every invoice the provider holds, including yanked invoices.
+    ///
+    /// The invoices are streamed as they are loaded rather than all being loaded up front. No
+    /// ordering is guaranteed. An error for a single invoice is returned as an item in the stream
+    /// so the caller can decide whether to keep going
+    async fn list_invoices(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<crate::Invoice>> + Unpin + Send + Sync>>;
+
+    /// Lists the SHAs of every parcel the provider holds.
+    ///
+    /// Like [`list_invoices`](Provider::list_invoices), the SHAs are streamed in no particular
+    /// order. Parcels that are still being written are not included
+    async fn list_parcels(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<String>> + Unpin + Send + Sync>>;
+}
+
+/// The number of items providers buffer ahead of the consumer when listing invoices or parcels
+pub(crate) const LIST_BUFFER_SIZE: usize = 32;
+
+/// Streams the given range of a seekable reader, stopping at the end of the range or the end of
+/// the data, whichever comes first
+pub(crate) async fn read_range<R>(
+    mut reader: R,
+    range: Range<u64>,
+) -> std::io::Result<FramedRead<tokio::io::Take<R>, BytesCodec>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    reader.seek(std::io::SeekFrom::Start(range.start)).await?;
+    let len = range.end.saturating_sub(range.start);
+    Ok(FramedRead::new(reader.take(len), BytesCodec::new()))
+}
+
+/// Slices the given range out of a stream of bytes that starts at the beginning of a parcel. The
+/// stream is only read up to the end of the range
+pub(crate) fn slice_stream<S, E>(
+    stream: S,
+    range: Range<u64>,
+) -> impl Stream<Item = std::result::Result<bytes::Bytes, E>> + Unpin + Send + Sync
+where
+    S: Stream<Item = std::result::Result<bytes::Bytes, E>> + Unpin + Send + Sync,
+    E: Send + Sync,
+{
+    use futures::StreamExt;
+
+    stream
+        .scan(0u64, move |pos, res| {
+            let item = match res {
+                Err(e) => Some(Some(Err(e))),
+                // Stop reading once we've passed the end of the range
+                Ok(_) if *pos >= range.end => None,
+                Ok(chunk) => {
+                    let chunk_start = *pos;
+                    let len = chunk.len() as u64;
+                    *pos += len;
+                    let start = range.start.saturating_sub(chunk_start).min(len) as usize;
+                    let end = range.end.saturating_sub(chunk_start).min(len) as usize;
+                    // Chunks entirely before the start of the range are skipped
+                    Some((start < end).then(|| Ok(chunk.slice(start..end))))
+                }
+            };
+            futures::future::ready(item)
+        })
+        .filter_map(futures::future::ready)
 }
 
 /// ProviderError describes the possible error states when storing and retrieving bindles.
@@ -0,0 +1,316 @@
+//! Functionality for appending signatures to stored invoices, such as re-signing every bindle after
+//! a host key is rotated.
+//!
+//! Signing is append-only: stored signatures are never modified or removed, and a key can only
+//! sign an invoice once. Because signatures don't cover other signatures, appending one doesn't
+//! invalidate any that are already on the invoice. Re-signing skips invoices the key has already
+//! signed, so an interrupted run can be resumed by running it again.
+
+use serde::Serialize;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, instrument, warn};
+
+use crate::invoice::signature::KeyRing;
+use crate::provider::{Provider, ProviderError, Result};
+use crate::{Id, Signature, SignatureError, SignatureRole, SigningKey, VerificationStrategy};
+
+/// A terminal provider that can add signatures to invoices it has already stored
+#[async_trait::async_trait]
+pub trait AppendSignature {
+    /// Appends the given signatures to the stored invoice (including a yanked one) and returns the
+    /// updated invoice.
+    ///
+    /// Every signature must be valid for the stored invoice and made by a key that hasn't already
+    /// signed it, otherwise a [`ProviderError::FailedSigning`] is returned and nothing is stored.
+    /// This does not check whether the keys are trusted
+    async fn append_signatures(
+        &self,
+        id: &Id,
+        signatures: Vec<Signature>,
+    ) -> Result<crate::Invoice>;
+}
+
+/// The results of re-signing all invoices
+#[derive(Debug, Default, Serialize)]
+pub struct ResignReport {
+    /// The number of invoices that were signed
+    pub signed: u64,
+    /// The number of invoices that were already signed by the key
+    pub already_signed: u64,
+    /// The invoices that were not signed because they failed verification, along with the reason
+    /// they failed
+    pub unverified: Vec<(Id, String)>,
+}
+
+/// Signs every invoice in the provider (including yanked invoices) with the given key as the given
+/// role.
+///
+/// Each invoice is verified with the strategy and keyring before it is signed, so that the key
+/// never vouches for an invoice that can't be trusted. Invoices that fail verification are skipped
+/// and listed in the report. Any other error stops the run
+#[instrument(level = "trace", skip(provider, key, strategy, keyring), fields(key = %key.label()))]
+pub async fn resign_all<P, K>(
+    provider: &P,
+    key: &K,
+    role: SignatureRole,
+    strategy: &VerificationStrategy,
+    keyring: &KeyRing,
+) -> Result<ResignReport>
+where
+    P: Provider + AppendSignature + Sync,
+    K: SigningKey + ?Sized,
+{
+    if !key.roles().contains(&role) {
+        return Err(SignatureError::NoSuitableKey.into());
+    }
+    let mut report = ResignReport::default();
+    let mut invoices = provider.list_invoices().await?;
+    while let Some(inv) = invoices.next().await {
+        let mut inv = inv?;
+        let id = inv.bindle.id.clone();
+        if let Err(e) = strategy.verify(inv.clone(), keyring) {
+            warn!(%id, error = %e, "Invoice failed verification, not signing");
+            report.unverified.push((id, e.to_string()));
+            continue;
+        }
+        match inv.sign(role.clone(), key) {
+            Ok(()) => (),
+            Err(SignatureError::DuplicateSignature) => {
+                debug!(%id, "Invoice is already signed by this key, skipping");
+                report.already_signed += 1;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        // Signing appends the new signature to the end of the list
+        let signature = inv
+            .signature
+            .and_then(|mut s| s.pop())
+            .ok_or_else(|| ProviderError::Other("Signing did not add a signature".to_owned()))?;
+        info!(%id, "Signing invoice");
+        provider.append_signatures(&id, vec![signature]).await?;
+        report.signed += 1;
+    }
+    info!(
+        signed = report.signed,
+        already_signed = report.already_signed,
+        unverified = report.unverified.len(),
+        "Finished re-signing invoices"
+    );
+    Ok(report)
+}
+
+/// Appends the signatures to the invoice, checking each one. This is what terminal providers use
+/// before storing the updated invoice
+pub(crate) fn append_to_invoice(
+    invoice: &mut crate::Invoice,
+    signatures: Vec<Signature>,
+) -> Result<()> {
+    for signature in signatures {
+        invoice.append_signature(signature)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::embedded::EmbeddedProvider;
+    use crate::provider::file::FileProvider;
+    use crate::search::NoopEngine;
+    use crate::testing;
+    use crate::SecretKeyEntry;
+    use std::convert::TryInto;
+    use tempfile::tempdir;
+
+    async fn check_resign<P>(provider: P)
+    where
+        P: Provider + AppendSignature + Sync,
+    {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let creator = SecretKeyEntry::new("Creator".to_owned(), vec![SignatureRole::Creator]);
+        let old_host = SecretKeyEntry::new("Old Host".to_owned(), vec![SignatureRole::Host]);
+        let new_host = SecretKeyEntry::new("New Host".to_owned(), vec![SignatureRole::Host]);
+        // After a rotation, the keyring has both the old and new host keys
+        let keyring = KeyRing::new(vec![
+            (&creator).try_into().unwrap(),
+            (&old_host).try_into().unwrap(),
+            (&new_host).try_into().unwrap(),
+        ]);
+
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(scaffold.invoice.clone(), &KeyRing::default())
+            .unwrap();
+        let signed = crate::invoice::sign(
+            verified,
+            vec![
+                (SignatureRole::Creator, &creator),
+                (SignatureRole::Host, &old_host),
+            ],
+        )
+        .unwrap();
+        let id = provider.create_invoice(signed).await.unwrap().0.bindle.id;
+
+        resign_all(
+            &provider,
+            &creator,
+            SignatureRole::Host,
+            &VerificationStrategy::default(),
+            &keyring,
+        )
+        .await
+        .expect_err("A key without the role should not be able to sign");
+
+        let report = resign_all(
+            &provider,
+            &new_host,
+            SignatureRole::Host,
+            &VerificationStrategy::default(),
+            &keyring,
+        )
+        .await
+        .expect("re-signing should succeed");
+        assert_eq!(1, report.signed);
+
+        let inv = provider.get_yanked_invoice(&id).await.unwrap();
+        let signatures = inv.signature.clone().unwrap();
+        assert_eq!(3, signatures.len());
+        assert_eq!("New Host", signatures[2].by);
+        VerificationStrategy::ExhaustiveVerification
+            .verify(inv, &keyring)
+            .expect("All signatures should still be valid");
+
+        // Running it again should be a no-op
+        let report = resign_all(
+            &provider,
+            &new_host,
+            SignatureRole::Host,
+            &VerificationStrategy::default(),
+            &keyring,
+        )
+        .await
+        .unwrap();
+        assert_eq!(0, report.signed);
+        assert_eq!(1, report.already_signed);
+
+        // A signature that doesn't match the stored invoice should be rejected
+        let mut other = scaffold.invoice.clone();
+        other.bindle.description = Some("Something else".to_owned());
+        let bad_key = SecretKeyEntry::new("Bad".to_owned(), vec![SignatureRole::Approver]);
+        other.sign(SignatureRole::Approver, &bad_key).unwrap();
+        let res = provider
+            .append_signatures(&id, other.signature.unwrap())
+            .await;
+        assert!(matches!(
+            res,
+            Err(ProviderError::FailedSigning(SignatureError::Unverified(_)))
+        ));
+        let inv = provider.get_yanked_invoice(&id).await.unwrap();
+        assert_eq!(3, inv.signature.unwrap().len());
+
+        // An untrusted creator means nothing gets signed
+        let another_host = SecretKeyEntry::new("Another".to_owned(), vec![SignatureRole::Host]);
+        let report = resign_all(
+            &provider,
+            &another_host,
+            SignatureRole::Host,
+            &VerificationStrategy::default(),
+            &KeyRing::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(0, report.signed);
+        assert_eq!(id, report.unverified[0].0);
+    }
+
+    async fn check_concurrent_yank_and_append<P>(provider: P)
+    where
+        P: Provider + AppendSignature + Sync,
+    {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let creator = SecretKeyEntry::new("Creator".to_owned(), vec![SignatureRole::Creator]);
+        let host = SecretKeyEntry::new("Host".to_owned(), vec![SignatureRole::Host]);
+        let approver = SecretKeyEntry::new("Approver".to_owned(), vec![SignatureRole::Approver]);
+
+        for i in 0..20 {
+            let mut inv = scaffold.invoice.clone();
+            inv.bindle.id = format!("{}/1.0.{}", inv.bindle.id.name(), i)
+                .parse()
+                .unwrap();
+            let verified = VerificationStrategy::MultipleAttestation(vec![])
+                .verify(inv, &KeyRing::default())
+                .unwrap();
+            let signed =
+                crate::invoice::sign(verified, vec![(SignatureRole::Creator, &creator)]).unwrap();
+            let id = provider.create_invoice(signed).await.unwrap().0.bindle.id;
+
+            let mut approved = provider.get_invoice(&id).await.unwrap();
+            approved.sign(SignatureRole::Approver, &approver).unwrap();
+            let signature = approved.signature.unwrap().pop().unwrap();
+            let yank = crate::invoice::sign_yank(&id, &host).unwrap();
+
+            let (yanked, appended) = tokio::join!(
+                provider.yank_invoice(&id, Some(yank)),
+                provider.append_signatures(&id, vec![signature]),
+            );
+            // Either can be turned away while the other is writing, but neither can be lost
+            for res in [yanked.as_ref().err(), appended.as_ref().err()]
+                .iter()
+                .flatten()
+            {
+                assert!(
+                    matches!(res, ProviderError::WriteInProgress),
+                    "Unexpected error: {}",
+                    res
+                );
+            }
+            assert!(yanked.is_ok() || appended.is_ok());
+
+            let stored = provider.get_yanked_invoice(&id).await.unwrap();
+            assert_eq!(yanked.is_ok(), stored.yanked.unwrap_or_default());
+            assert_eq!(yanked.is_ok(), stored.yanked_signature.is_some());
+            let signatures = stored.signature.unwrap();
+            assert_eq!(
+                appended.is_ok(),
+                signatures.iter().any(|s| s.by == "Approver")
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_yank_and_append_file_provider() {
+        let dir = tempdir().unwrap();
+        check_concurrent_yank_and_append(
+            FileProvider::new(dir.path(), NoopEngine::default()).await,
+        )
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_yank_and_append_embedded_provider() {
+        let dir = tempdir().unwrap();
+        check_concurrent_yank_and_append(
+            EmbeddedProvider::new(dir.path(), NoopEngine::default())
+                .await
+                .unwrap(),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_should_resign_file_provider() {
+        let dir = tempdir().unwrap();
+        check_resign(FileProvider::new(dir.path(), NoopEngine::default()).await).await;
+    }
+
+    #[tokio::test]
+    async fn test_should_resign_embedded_provider() {
+        let dir = tempdir().unwrap();
+        check_resign(
+            EmbeddedProvider::new(dir.path(), NoopEngine::default())
+                .await
+                .unwrap(),
+        )
+        .await;
+    }
+}
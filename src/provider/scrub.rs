@@ -0,0 +1,360 @@
+//! Types and helpers for checking the integrity of everything stored in a terminal provider.
+//!
+//! Once a parcel is uploaded, nothing reads it back other than to send it to a client, so bit rot
+//! or manual tampering with the underlying storage would otherwise go unnoticed. A scrub re-reads
+//! every stored invoice and parcel and checks that:
+//!
+//! - Each invoice can be parsed, is stored under its own canonical name, and (if a keyring is
+//!   given) still passes signature verification
+//! - Each parcel hashes to its SHA and is the size given by the invoices that reference it
+//! - No part files have been left behind by interrupted writes
+//!
+//! Anything that fails a check is reported and can optionally be moved into a `quarantine`
+//! directory in the provider's storage directory, so it is no longer served but can still be
+//! inspected. Quarantined invoices are also removed from the search index, unless they are too
+//! badly damaged to tell which bindle they were for.
+//!
+//! Scrubs can be run on demand or periodically in the background with
+//! [`run_periodically`]. Since a scrub reads everything in storage, it can be throttled with
+//! [`ScrubOptions::max_bytes_per_sec`] to limit its impact on a running server
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::{error, info, warn};
+
+use crate::invoice::signature::KeyRing;
+use crate::provider::file::PART_EXTENSION;
+use crate::provider::{ProviderError, Result};
+use crate::search::Search;
+use crate::VerificationStrategy;
+
+/// The name of the directory bad items are moved to
+const QUARANTINE_DIRECTORY: &str = "quarantine";
+/// The default grace period for part files of one hour
+const DEFAULT_PART_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Options for a scrub
+#[derive(Debug, Clone)]
+pub struct ScrubOptions {
+    /// The keyring used to re-verify invoice signatures. Signatures are not checked if this is
+    /// `None`
+    pub keyring: Option<KeyRing>,
+    /// The strategy used to re-verify invoice signatures
+    pub strategy: VerificationStrategy,
+    /// Whether to move bad items and leftover part files into quarantine instead of only
+    /// reporting them
+    pub quarantine: bool,
+    /// The maximum number of bytes of parcel data to read per second, or `None` for no limit
+    pub max_bytes_per_sec: Option<u64>,
+    /// Part files that haven't been written to for this long are assumed to be left over from an
+    /// interrupted write rather than being part of a write that is still in progress
+    pub part_grace_period: Duration,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> Self {
+        ScrubOptions {
+            keyring: None,
+            strategy: VerificationStrategy::default(),
+            quarantine: false,
+            max_bytes_per_sec: None,
+            part_grace_period: DEFAULT_PART_GRACE_PERIOD,
+        }
+    }
+}
+
+impl ScrubOptions {
+    /// Returns whether the part file with the given metadata looks like it was left behind by an
+    /// interrupted write
+    pub(crate) fn is_stale_part(&self, metadata: &std::fs::Metadata) -> bool {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|m| SystemTime::now().duration_since(m).ok())
+            .map(|age| age >= self.part_grace_period)
+            .unwrap_or(false)
+    }
+}
+
+/// A problem found during a scrub
+#[derive(Debug, Serialize)]
+pub struct ScrubIssue {
+    /// The name of the bad item. For invoices, this is the name they are stored under. For parcels,
+    /// it is their SHA
+    pub name: String,
+    /// A description of what is wrong with the item
+    pub problem: String,
+}
+
+/// The results of a scrub
+#[derive(Debug, Default, Serialize)]
+pub struct ScrubReport {
+    /// Whether bad items were moved into quarantine
+    pub quarantined: bool,
+    /// The number of invoices checked
+    pub invoices_checked: u64,
+    /// The number of parcels checked
+    pub parcels_checked: u64,
+    /// The total size in bytes of the parcel data that was read
+    pub bytes_checked: u64,
+    /// The invoices that failed a check
+    pub bad_invoices: Vec<ScrubIssue>,
+    /// The parcels that failed a check
+    pub bad_parcels: Vec<ScrubIssue>,
+    /// Part files left behind by interrupted writes
+    pub part_files: Vec<PathBuf>,
+}
+
+impl ScrubReport {
+    /// Returns whether the scrub found any problems
+    pub fn is_clean(&self) -> bool {
+        self.bad_invoices.is_empty() && self.bad_parcels.is_empty() && self.part_files.is_empty()
+    }
+}
+
+/// A terminal provider that can check the integrity of everything it stores
+#[async_trait::async_trait]
+pub trait Scrub {
+    /// Checks every stored invoice and parcel. See the [module level docs](self) for details on
+    /// what is checked. Problems with individual items are returned in the report, so an error is
+    /// only returned if the scrub couldn't be completed
+    async fn scrub(&self, options: &ScrubOptions) -> Result<ScrubReport>;
+}
+
+/// Spawns a task that scrubs the given provider every `interval` until the runtime shuts down.
+/// Problems are logged as warnings
+pub fn run_periodically<S>(
+    provider: S,
+    options: ScrubOptions,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()>
+where
+    S: Scrub + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            info!("Starting background scrub");
+            match provider.scrub(&options).await {
+                Ok(report) => log_report(&report),
+                Err(e) => error!(error = %e, "Background scrub failed"),
+            }
+        }
+    })
+}
+
+fn log_report(report: &ScrubReport) {
+    for issue in report.bad_invoices.iter() {
+        warn!(name = %issue.name, problem = %issue.problem, "Scrub found a bad invoice");
+    }
+    for issue in report.bad_parcels.iter() {
+        warn!(sha = %issue.name, problem = %issue.problem, "Scrub found a bad parcel");
+    }
+    for path in report.part_files.iter() {
+        warn!(path = %path.display(), "Scrub found a leftover part file");
+    }
+    info!(
+        invoices_checked = report.invoices_checked,
+        parcels_checked = report.parcels_checked,
+        bytes_checked = report.bytes_checked,
+        clean = report.is_clean(),
+        "Finished background scrub"
+    );
+}
+
+/// Limits how fast data is read by sleeping whenever reads get ahead of the configured rate
+pub(crate) struct Throttle {
+    max_bytes_per_sec: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub(crate) fn new(max_bytes_per_sec: Option<u64>) -> Self {
+        Throttle {
+            max_bytes_per_sec: max_bytes_per_sec.filter(|rate| *rate > 0),
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Records that the given number of bytes were read, waiting if needed to stay under the rate
+    pub(crate) async fn consume(&mut self, bytes: u64) {
+        let rate = match self.max_bytes_per_sec {
+            Some(r) => r,
+            None => return,
+        };
+        self.bytes += bytes;
+        let target = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        let elapsed = self.start.elapsed();
+        if target > elapsed {
+            tokio::time::sleep(target - elapsed).await;
+        }
+    }
+}
+
+/// Hashes everything in the reader, returning the hex encoded SHA-256 and the number of bytes read
+pub(crate) async fn hash_reader<R: AsyncRead + Unpin>(
+    mut reader: R,
+    throttle: &mut Throttle,
+) -> std::io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    let mut size = 0;
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read as u64;
+        throttle.consume(read as u64).await;
+    }
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+/// Checks a parsed invoice against the name it was stored under and, if configured, re-verifies
+/// its signatures. Returns a description of the problem if there is one
+pub(crate) fn check_invoice(
+    stored_name: &str,
+    invoice: &crate::Invoice,
+    options: &ScrubOptions,
+) -> Option<String> {
+    let name = invoice.canonical_name();
+    if name != stored_name {
+        return Some(format!(
+            "Invoice for {} is stored under the wrong name (expected {})",
+            invoice.bindle.id, name
+        ));
+    }
+    let keyring = options.keyring.as_ref()?;
+    options
+        .strategy
+        .verify(invoice.clone(), keyring)
+        .err()
+        .map(|e| format!("Signature verification failed: {}", e))
+}
+
+/// Just enough of an invoice to tell which bindle it is for, so that a quarantined invoice can be
+/// removed from the search index even if the rest of it is malformed
+#[derive(Deserialize)]
+pub(crate) struct InvoiceHeader {
+    bindle: crate::BindleSpec,
+}
+
+/// Removes a quarantined invoice from the search index. Nothing is removed if the header couldn't
+/// be parsed or if the invoice was stored under the wrong name, since the index entry for that ID
+/// then belongs to a different invoice
+pub(crate) async fn remove_from_index<S: Search + Sync>(
+    index: &S,
+    stored_name: &str,
+    header: Option<InvoiceHeader>,
+) {
+    let id = match header.map(|h| h.bindle.id) {
+        Some(id) if id.sha() == stored_name => id,
+        Some(_) => return,
+        None => {
+            warn!(
+                name = stored_name,
+                "Unable to tell which bindle a quarantined invoice was for, it will stay in the search index until it is rebuilt"
+            );
+            return;
+        }
+    };
+    if let Err(e) = index.remove(&id).await {
+        error!(invoice_id = %id, error = %e, "Error removing quarantined invoice from the index");
+    }
+}
+
+/// Compares the hash and size of a parcel with what was expected. The expected size is `None`
+/// when no invoice references the parcel. Returns a description of the problem if there is one
+pub(crate) fn check_parcel(
+    sha: &str,
+    actual_sha: &str,
+    actual_size: u64,
+    expected_size: Option<u64>,
+) -> Option<String> {
+    if actual_sha != sha {
+        return Some(format!("Parcel data hashes to {}", actual_sha));
+    }
+    match expected_size {
+        Some(expected) if expected != actual_size => Some(format!(
+            "Parcel is {} bytes, but invoices say it should be {} bytes",
+            actual_size, expected
+        )),
+        _ => None,
+    }
+}
+
+/// Looks for leftover part files directly inside the given directory, moving them into quarantine
+/// if requested
+pub(crate) async fn find_part_files(
+    root: &Path,
+    dir: &Path,
+    options: &ScrubOptions,
+    report: &mut ScrubReport,
+) -> Result<()> {
+    let mut readdir = match tokio::fs::read_dir(dir).await {
+        Ok(r) => r,
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(e) = readdir.next_entry().await? {
+        let path = e.path();
+        if path.extension() != Some(PART_EXTENSION.as_ref()) {
+            continue;
+        }
+        let metadata = match e.metadata().await {
+            Ok(m) => m,
+            // The write finished while we were looking
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if !options.is_stale_part(&metadata) {
+            continue;
+        }
+        if options.quarantine {
+            quarantine_file(root, &path).await?;
+        }
+        report.part_files.push(path);
+    }
+    Ok(())
+}
+
+/// Moves the given file from the provider's storage directory into quarantine. The file keeps its
+/// path relative to the storage directory, so the quarantine directory mirrors the storage layout
+pub(crate) async fn quarantine_file(root: &Path, from: &Path) -> Result<()> {
+    let relative = from.strip_prefix(root).map_err(|_| {
+        ProviderError::Other(format!(
+            "Cannot quarantine {} as it is outside of the storage directory",
+            from.display()
+        ))
+    })?;
+    let dest = quarantine_path(root, relative).await?;
+    warn!(from = %from.display(), to = %dest.display(), "Quarantining file");
+    tokio::fs::rename(from, dest).await?;
+    Ok(())
+}
+
+/// Writes the given data into quarantine at the given relative path. This is for items that aren't
+/// stored as files
+pub(crate) async fn quarantine_data(root: &Path, data: &[u8], name: &str) -> Result<()> {
+    let dest = quarantine_path(root, Path::new(name)).await?;
+    warn!(to = %dest.display(), "Quarantining data");
+    tokio::fs::write(dest, data).await?;
+    Ok(())
+}
+
+/// Returns the path in quarantine for the given relative path, creating any missing directories
+async fn quarantine_path(root: &Path, relative: &Path) -> Result<PathBuf> {
+    let dest = root.join(QUARANTINE_DIRECTORY).join(relative);
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    Ok(dest)
+}
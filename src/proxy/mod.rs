@@ -10,40 +10,55 @@ use crate::provider::{Provider, ProviderError, Result};
 use crate::verification::Verified;
 use crate::{
     client::{tokens::TokenManager, Client, ClientError},
-    signature::SignatureRole,
-    SecretKeyEntry,
+    signature::{KeyRing, SignatureRole},
+    SecretKeyEntry, SignatureError, VerificationStrategy,
 };
-use crate::{Id, Signed};
+use crate::{Id, Signature, Signed};
 
 /// A proxy implementation that forwards requests to an upstream server as configured by a
-/// [`Client`](crate::client::Client). The proxy implementation will verify and sign invoice create
-/// operations and sign any fetched invoices
+/// [`Client`](crate::client::Client). The proxy implementation will sign invoice create operations
+/// and verify and sign any fetched invoices
 #[derive(Clone)]
 pub struct Proxy<T> {
     client: Client<T>,
     secret_key: SecretKeyEntry,
+    keyring: KeyRing,
+    strategy: VerificationStrategy,
 }
 
 impl<T> Proxy<T> {
     /// Returns a new proxy configured to connect to an upstream using the given client and verify
-    /// and sign using the given secret key and keyring
-    pub fn new(client: Client<T>, secret_key: SecretKeyEntry) -> Self {
-        Proxy { client, secret_key }
+    /// and sign using the given secret key, keyring, and verification strategy
+    pub fn new(
+        client: Client<T>,
+        secret_key: SecretKeyEntry,
+        keyring: KeyRing,
+        strategy: VerificationStrategy,
+    ) -> Self {
+        Proxy {
+            client,
+            secret_key,
+            keyring,
+            strategy,
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl<T: TokenManager + Send + Sync + 'static> Provider for Proxy<T> {
-    /// Creates the invoice on the upstream server, signing the invoice as a proxy. The role and
-    /// secret key parameters do not matter here
+    /// Creates the invoice on the upstream server, signing the invoice as a proxy
     async fn create_invoice<I>(&self, invoice: I) -> Result<(crate::Invoice, Vec<crate::Label>)>
     where
         I: Signed + Verified + Send + Sync,
     {
-        let res = self.client.create_invoice(invoice.signed()).await?;
+        let inv = sign_as_proxy(invoice.signed(), &self.secret_key)?;
+        let res = self.client.create_invoice(inv).await?;
         Ok((res.invoice, res.missing.unwrap_or_default()))
     }
 
+    /// Fetches the invoice from the upstream server, verifying it with the configured keyring and
+    /// strategy before signing it as a proxy. Invoices that fail verification are rejected with a
+    /// [`ProviderError::FailedSigning`] error rather than being signed
     async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
     where
         I: TryInto<Id> + Send,
@@ -52,15 +67,15 @@ impl<T: TokenManager + Send + Sync + 'static> Provider for Proxy<T> {
         // Parse the ID now because the error type constraint doesn't match that of the client
         let parsed_id = id.try_into().map_err(|e| e.into())?;
         let inv = self.client.get_yanked_invoice(parsed_id).await?;
-        let signed = crate::sign(inv, vec![(SignatureRole::Proxy, &self.secret_key)])?;
-        Ok(signed.signed())
+        verify_and_sign(inv, &self.secret_key, &self.keyring, &self.strategy)
     }
 
-    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    async fn yank_invoice<I>(&self, id: I, _signature: Option<Signature>) -> Result<()>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>,
     {
+        // The upstream server is responsible for signing the yank
         // Parse the ID now because the error type constraint doesn't match that of the client
         let parsed_id = id.try_into().map_err(|e| e.into())?;
         self.client
@@ -99,6 +114,22 @@ impl<T: TokenManager + Send + Sync + 'static> Provider for Proxy<T> {
         Ok(Box::new(stream.map(|res| res.map_err(|e| e.into()))))
     }
 
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Pass the range upstream so only the requested bytes are transferred
+        self.client
+            .get_parcel_range(bindle_id, parcel_id, range)
+            .await
+    }
+
     async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
     where
         I: TryInto<Id> + Send,
@@ -128,4 +159,162 @@ impl<T: TokenManager + Send + Sync + 'static> Provider for Proxy<T> {
             })),
         }
     }
+
+    /// Lists all invoices from the upstream server, verifying and signing each of them as a proxy
+    /// the same way fetched invoices are
+    async fn list_invoices(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<crate::Invoice>> + Unpin + Send + Sync>> {
+        let secret_key = self.secret_key.clone();
+        let keyring = self.keyring.clone();
+        let strategy = self.strategy.clone();
+        let stream = Provider::list_invoices(&self.client).await?;
+        Ok(Box::new(stream.map(move |res| {
+            verify_and_sign(res?, &secret_key, &keyring, &strategy)
+        })))
+    }
+
+    async fn list_parcels(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<String>> + Unpin + Send + Sync>> {
+        Provider::list_parcels(&self.client).await
+    }
+}
+
+/// Verifies an invoice from the upstream server before signing it as a proxy, so the proxy never
+/// attests to an invoice it can't trust
+fn verify_and_sign(
+    inv: crate::Invoice,
+    secret_key: &SecretKeyEntry,
+    keyring: &KeyRing,
+    strategy: &VerificationStrategy,
+) -> Result<crate::Invoice> {
+    let verified = strategy.verify(inv, keyring)?;
+    sign_as_proxy(verified.into(), secret_key)
+}
+
+/// Signs the invoice as a proxy. An invoice that was already signed with the key (such as one that
+/// was created through the proxy) is returned as is
+fn sign_as_proxy(mut inv: crate::Invoice, secret_key: &SecretKeyEntry) -> Result<crate::Invoice> {
+    match inv.sign(SignatureRole::Proxy, secret_key) {
+        Ok(()) | Err(SignatureError::DuplicateSignature) => Ok(inv),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+    use crate::authn::always::AlwaysAuthenticate;
+    use crate::authz::always::AlwaysAuthorize;
+    use crate::client::tokens::NoToken;
+    use crate::signature::SecretKeyStorage;
+    use crate::testing;
+
+    /// Starts a server on a random port in the background, returning its base URL and host key
+    async fn start_upstream(keyring: KeyRing) -> (String, SecretKeyEntry) {
+        let (store, index, ks) = testing::setup().await;
+        let host_key = ks.get_first_matching(&SignatureRole::Host).unwrap().clone();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::spawn(crate::server::server(
+            store,
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            addr,
+            None,
+            ks,
+            VerificationStrategy::default(),
+            keyring,
+        ));
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect(addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        (format!("http://{}/v1/", addr), host_key)
+    }
+
+    #[tokio::test]
+    async fn test_should_verify_and_sign() {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let creator = SecretKeyEntry::new("Creator".to_owned(), vec![SignatureRole::Creator]);
+        let proxy_key = SecretKeyEntry::new("Proxy".to_owned(), vec![SignatureRole::Proxy]);
+        let keyring = KeyRing::new(vec![(&creator).try_into().unwrap()]);
+        // The upstream has to trust the proxy for it to accept invoices the proxy has signed
+        let upstream_keyring = KeyRing::new(vec![
+            (&creator).try_into().unwrap(),
+            (&proxy_key).try_into().unwrap(),
+        ]);
+        let (base_url, host_key) = start_upstream(upstream_keyring).await;
+        let client = Client::new(&base_url, NoToken).unwrap();
+
+        let proxy = Proxy::new(
+            client.clone(),
+            proxy_key,
+            keyring,
+            VerificationStrategy::CreativeIntegrity,
+        );
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(scaffold.invoice.clone(), &KeyRing::default())
+            .unwrap();
+        let signed =
+            crate::invoice::sign(verified, vec![(SignatureRole::Creator, &creator)]).unwrap();
+        let (created, _) = proxy
+            .create_invoice(signed)
+            .await
+            .expect("Proxied create should succeed");
+        let id = created.bindle.id.clone();
+        let signed_by = |inv: &crate::Invoice, role: SignatureRole| {
+            inv.signature
+                .iter()
+                .flatten()
+                .filter(|s| s.role == role)
+                .count()
+        };
+        assert_eq!(
+            1,
+            signed_by(&created, SignatureRole::Proxy),
+            "Created invoice should be signed by the proxy"
+        );
+
+        // The invoice was already signed by the proxy when it was created
+        let inv = proxy
+            .get_invoice(&id)
+            .await
+            .expect("Invoice from a trusted creator should be verified");
+        assert_eq!(1, signed_by(&inv, SignatureRole::Proxy));
+
+        // A proxy that doesn't trust the creator shouldn't sign anything
+        let other_key = SecretKeyEntry::new("Other".to_owned(), vec![SignatureRole::Proxy]);
+        let untrusting = Proxy::new(
+            client.clone(),
+            other_key,
+            KeyRing::default(),
+            VerificationStrategy::CreativeIntegrity,
+        );
+        assert!(matches!(
+            untrusting.get_invoice(&id).await,
+            Err(ProviderError::FailedSigning(_))
+        ));
+
+        // A proxy that trusts the upstream host signs invoices the host has signed
+        let host_keyring = KeyRing::new(vec![(&host_key).try_into().unwrap()]);
+        let other_key = SecretKeyEntry::new("Other".to_owned(), vec![SignatureRole::Proxy]);
+        let host_trusting = Proxy::new(
+            client,
+            other_key,
+            host_keyring,
+            VerificationStrategy::MultipleAttestation(vec![SignatureRole::Host]),
+        );
+        let inv = host_trusting
+            .get_invoice(&id)
+            .await
+            .expect("Invoice signed by a trusted host should be verified");
+        assert_eq!(2, signed_by(&inv, SignatureRole::Proxy));
+    }
 }
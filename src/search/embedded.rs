@@ -0,0 +1,595 @@
+//! An embedded database backed search engine. It uses the same query processing as the
+//! [`StandardEngine`](crate::search::StandardEngine), but persists its index using the [sled
+//! embedded database](https://github.com/spacejam/sled) so it does not need to be rebuilt every
+//! time the server starts
+
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::path::Path;
+
+use sled::IVec;
+use tracing::{debug, info, instrument, trace};
+
+use crate::search::{standard, Matches, Search, SearchOptions};
+
+/// Holds the searchable fields of each invoice
+const DOCUMENT_DB_NAME: &str = "documents";
+/// Holds the full invoices so they can be returned in the matches
+const INVOICE_DB_NAME: &str = "invoices";
+/// Maps each word in the searchable fields to the invoices containing it, for standard mode. Keys
+/// are the word, a zero byte, and the invoice key
+const TERM_DB_NAME: &str = "terms";
+/// Maps each three byte sequence in a bindle name to the invoices with that name, for strict mode.
+/// Keys are the three bytes followed by the invoice key
+const GRAM_DB_NAME: &str = "name_grams";
+const META_DB_NAME: &str = "meta";
+const HIGH_WATER_MARK_KEY: &str = "high_water_mark";
+const FORMAT_VERSION_KEY: &str = "format_version";
+/// The version of the on disk layout. If an existing index has a different version, it is cleared
+/// so the provider rebuilds it
+const FORMAT_VERSION: u64 = 2;
+const GRAM_LEN: usize = 3;
+
+/// A search engine that persists its index to disk.
+///
+/// Queries are processed exactly the same way as the
+/// [`StandardEngine`](crate::search::StandardEngine), including strict mode. Query terms are looked
+/// up in an inverted index, so only the invoices that could match are loaded and scored. Unlike the
+/// in-memory engines, this engine records a [high-water mark](Search::high_water_mark) so providers
+/// only need to index the invoices that changed since the last time they were started.
+///
+/// If the index ever gets out of sync with the provider (for example, if the underlying storage
+/// is restored from a backup), use [`clear`](EmbeddedEngine::clear) before creating the provider
+/// to force a full rebuild of the index.
+#[derive(Clone)]
+pub struct EmbeddedEngine {
+    documents: sled::Tree,
+    invoices: sled::Tree,
+    terms: sled::Tree,
+    grams: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl EmbeddedEngine {
+    /// Opens the index stored in the given directory, creating it if it does not exist. This
+    /// directory must not be the same as the one used by the
+    /// [`EmbeddedProvider`](crate::provider::embedded::EmbeddedProvider)
+    pub async fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        debug!(path = %path.as_ref().display(), "Opening embedded search index");
+        let path = path.as_ref().to_owned();
+        let engine = tokio::task::spawn_blocking(move || {
+            let db = sled::open(path)?;
+            Ok::<_, sled::Error>(EmbeddedEngine {
+                documents: db.open_tree(DOCUMENT_DB_NAME)?,
+                invoices: db.open_tree(INVOICE_DB_NAME)?,
+                terms: db.open_tree(TERM_DB_NAME)?,
+                grams: db.open_tree(GRAM_DB_NAME)?,
+                meta: db.open_tree(META_DB_NAME)?,
+            })
+        })
+        .await??;
+
+        let meta = engine.meta.clone();
+        let version = tokio::task::spawn_blocking(move || meta.get(FORMAT_VERSION_KEY))
+            .await??
+            .and_then(|raw| raw.as_ref().try_into().ok().map(u64::from_be_bytes));
+        if version != Some(FORMAT_VERSION) {
+            info!(
+                ?version,
+                "Search index was created by a different version, so it will be rebuilt"
+            );
+            engine.clear().await?;
+        }
+        Ok(engine)
+    }
+
+    /// Removes everything from the index, including the high-water mark. The next provider created
+    /// with this engine will do a full rebuild of the index
+    #[instrument(level = "trace", skip(self))]
+    pub async fn clear(&self) -> anyhow::Result<()> {
+        debug!("Clearing embedded search index");
+        let engine = self.clone();
+        tokio::task::spawn_blocking(move || {
+            // Clear the mark first so a failure partway through still results in a full rebuild
+            engine.meta.clear()?;
+            engine.documents.clear()?;
+            engine.invoices.clear()?;
+            engine.terms.clear()?;
+            engine.grams.clear()?;
+            engine
+                .meta
+                .insert(FORMAT_VERSION_KEY, &FORMAT_VERSION.to_be_bytes())?;
+            Ok::<_, sled::Error>(())
+        })
+        .await??;
+        self.meta.flush_async().await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Search for EmbeddedEngine {
+    #[instrument(level = "trace", skip(self))]
+    async fn query(
+        &self,
+        term: &str,
+        filter: &str,
+        options: SearchOptions,
+    ) -> anyhow::Result<Matches> {
+        trace!("beginning search");
+        let engine = self.clone();
+        let owned_term = term.to_owned();
+        let strict = options.strict;
+        let entries = tokio::task::spawn_blocking(move || {
+            let candidates = if strict {
+                engine.strict_candidates(&owned_term)?
+            } else {
+                engine.standard_candidates(&owned_term)?
+            };
+            engine.load_entries(candidates)
+        })
+        .await??;
+        let found = standard::find_matches(entries.iter(), term, filter, &options);
+
+        debug!(total_matches = found.len(), "Found matches");
+        let mut matches = Matches::new(&options, term.to_owned());
+        matches.fill_page(found);
+
+        // The matched documents only contain the searchable fields, so swap in the full invoices
+        // for this page of results
+        let invoices = self.invoices.clone();
+        let page = std::mem::take(&mut matches.invoices);
+        matches.invoices = tokio::task::spawn_blocking(move || {
+            page.into_iter()
+                .map(|doc| {
+                    let raw = invoices.get(doc.name())?.ok_or_else(|| {
+                        anyhow::anyhow!("Invoice {} is missing from the index", doc.name())
+                    })?;
+                    Ok(serde_cbor::from_slice(raw.as_ref())?)
+                })
+                .collect::<anyhow::Result<Vec<crate::Invoice>>>()
+        })
+        .await??;
+
+        Ok(matches)
+    }
+
+    #[instrument(level = "trace", skip(self, invoice), fields(invoice_id = %invoice.bindle.id))]
+    async fn index(&self, invoice: &crate::Invoice) -> anyhow::Result<()> {
+        // Only the searchable fields go into the documents so that queries don't have to
+        // deserialize every parcel and annotation
+        let mut doc = crate::Invoice::new(invoice.bindle.clone());
+        doc.yanked = invoice.yanked;
+
+        let key = invoice.name();
+        let words: BTreeSet<String> = standard::IndexEntry::new(doc.clone())
+            .words()
+            .cloned()
+            .collect();
+        let serialized_doc = serde_cbor::to_vec(&doc)?;
+        let serialized = serde_cbor::to_vec(invoice)?;
+        let engine = self.clone();
+        tokio::task::spawn_blocking(move || {
+            // Words that are no longer in the document (which shouldn't normally happen, as the
+            // searchable fields of a bindle never change) need to be removed from the index
+            if let Some(raw) = engine.documents.get(&key)? {
+                let old: crate::Invoice = serde_cbor::from_slice(raw.as_ref())?;
+                for word in standard::IndexEntry::new(old).words() {
+                    if !words.contains(word) {
+                        engine.terms.remove(term_key(word, &key))?;
+                    }
+                }
+            }
+            // Insert everything pointing at the document first, so a document is never stored
+            // without being findable. Leftover entries are harmless as matches are always checked
+            // against the document
+            for word in words.iter() {
+                engine.terms.insert(term_key(word, &key), &[])?;
+            }
+            for gram in doc.bindle.id.name().as_bytes().windows(GRAM_LEN) {
+                engine.grams.insert([gram, key.as_bytes()].concat(), &[])?;
+            }
+            engine.invoices.insert(&key, serialized)?;
+            engine.documents.insert(&key, serialized_doc)?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        let key = id.to_string();
+        let engine = self.clone();
+        tokio::task::spawn_blocking(move || {
+            // Remove the document first so the invoice stops matching queries straight away, then
+            // clean up everything that pointed at it
+            let raw = match engine.documents.remove(&key)? {
+                Some(raw) => raw,
+                None => return Ok(()),
+            };
+            engine.invoices.remove(&key)?;
+            let old: crate::Invoice = serde_cbor::from_slice(raw.as_ref())?;
+            for word in standard::IndexEntry::new(old.clone()).words() {
+                engine.terms.remove(term_key(word, &key))?;
+            }
+            for gram in old.bindle.id.name().as_bytes().windows(GRAM_LEN) {
+                engine.grams.remove([gram, key.as_bytes()].concat())?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+        Ok(())
+    }
+
+    async fn high_water_mark(&self) -> anyhow::Result<Option<u64>> {
+        let meta = self.meta.clone();
+        let raw = tokio::task::spawn_blocking(move || meta.get(HIGH_WATER_MARK_KEY)).await??;
+        raw.map(|r| {
+            let bytes = r
+                .as_ref()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Stored high-water mark is malformed"))?;
+            Ok(u64::from_be_bytes(bytes))
+        })
+        .transpose()
+    }
+
+    async fn set_high_water_mark(&self, mark: u64) -> anyhow::Result<()> {
+        let meta = self.meta.clone();
+        tokio::task::spawn_blocking(move || meta.insert(HIGH_WATER_MARK_KEY, &mark.to_be_bytes()))
+            .await??;
+        // Make sure everything indexed up to this mark is actually on disk before we rely on it
+        self.meta.flush_async().await?;
+        Ok(())
+    }
+}
+
+impl EmbeddedEngine {
+    /// Returns the keys of the invoices that could match the query in standard mode, or `None` if
+    /// every invoice could match. An invoice is a candidate if every query token matches one of its
+    /// words
+    fn standard_candidates(&self, term: &str) -> anyhow::Result<Option<BTreeSet<IVec>>> {
+        let mut candidates: Option<BTreeSet<IVec>> = None;
+        for token in term.split_whitespace().flat_map(standard::tokenize) {
+            let mut found = BTreeSet::new();
+            if standard::allowed_typos(&token) == 0 {
+                // Only exact and prefix matches are allowed, which are all under the token itself
+                for res in self.terms.scan_prefix(token.as_bytes()) {
+                    found.insert(invoice_key(&res?.0));
+                }
+            } else {
+                // Walk each distinct word once, skipping over the entries for the invoices it is
+                // in, and collect the invoices for the words that match
+                let mut next = Vec::new();
+                while let Some(res) = self.terms.range(next.as_slice()..).next() {
+                    let (key, _) = res?;
+                    let word = word(&key);
+                    if standard::match_quality(&token, &String::from_utf8_lossy(word)).is_some() {
+                        for res in self.terms.scan_prefix([word, &[0]].concat()) {
+                            found.insert(invoice_key(&res?.0));
+                        }
+                    }
+                    next = [word, &[1]].concat();
+                }
+            }
+            trace!(%token, candidates = found.len(), "Looked up query token");
+            candidates = Some(match candidates {
+                Some(c) => c.intersection(&found).cloned().collect(),
+                None => found,
+            });
+        }
+        Ok(candidates)
+    }
+
+    /// Returns the keys of the invoices that could match the query in strict mode, or `None` if
+    /// every invoice could match. Every term must be contained in the name, so the name must
+    /// contain every sequence of bytes in each term. Terms that are too short to look up this way
+    /// are checked when the candidates are matched
+    fn strict_candidates(&self, term: &str) -> anyhow::Result<Option<BTreeSet<IVec>>> {
+        let mut candidates: Option<BTreeSet<IVec>> = None;
+        for gram in term
+            .split_whitespace()
+            .flat_map(|t| t.as_bytes().windows(GRAM_LEN))
+        {
+            let found = self
+                .grams
+                .scan_prefix(gram)
+                .map(|res| res.map(|(key, _)| IVec::from(&key[GRAM_LEN..])))
+                .collect::<sled::Result<BTreeSet<_>>>()?;
+            candidates = Some(match candidates {
+                Some(c) => c.intersection(&found).cloned().collect(),
+                None => found,
+            });
+        }
+        Ok(candidates)
+    }
+
+    /// Loads the documents for the given keys in key order, or all of them if `None`
+    fn load_entries(
+        &self,
+        candidates: Option<BTreeSet<IVec>>,
+    ) -> anyhow::Result<Vec<standard::IndexEntry>> {
+        let decode = |raw: IVec| -> anyhow::Result<standard::IndexEntry> {
+            let doc: crate::Invoice = serde_cbor::from_slice(raw.as_ref())?;
+            Ok(standard::IndexEntry::new(doc))
+        };
+        match candidates {
+            None => self
+                .documents
+                .iter()
+                .values()
+                .map(|raw| decode(raw?))
+                .collect(),
+            Some(keys) => {
+                trace!(candidates = keys.len(), "Loading candidate documents");
+                keys.into_iter()
+                    .filter_map(|key| self.documents.get(key).transpose())
+                    .map(|raw| decode(raw?))
+                    .collect()
+            }
+        }
+    }
+}
+
+fn term_key(word: &str, key: &str) -> Vec<u8> {
+    [word.as_bytes(), &[0], key.as_bytes()].concat()
+}
+
+/// Returns the word part of a key in the terms tree. Words never contain a zero byte, as they are
+/// only made up of alphanumeric characters
+fn word(term_key: &[u8]) -> &[u8] {
+    let end = term_key
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(term_key.len());
+    &term_key[..end]
+}
+
+/// Returns the invoice key part of a key in the terms tree
+fn invoice_key(term_key: &[u8]) -> IVec {
+    let word = word(term_key);
+    IVec::from(term_key.get(word.len() + 1..).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Invoice;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn embedded_engine_should_persist_index() {
+        let dir = tempdir().expect("unable to create tempdir");
+        let searcher = EmbeddedEngine::new(dir.path())
+            .await
+            .expect("unable to open index");
+        assert!(searcher.high_water_mark().await.unwrap().is_none());
+
+        let mut inv = invoice_fixture("example.com/weather", "1.0.0", "Weather prediction");
+        inv.annotations = Some(
+            vec![("secret".to_owned(), "narwhal".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        searcher.index(&inv).await.expect("successfully indexed");
+        searcher
+            .index(&invoice_fixture(
+                "example.com/almanac",
+                "1.0.0",
+                "A weather almanac",
+            ))
+            .await
+            .expect("successfully indexed");
+        searcher.set_high_water_mark(42).await.unwrap();
+        drop(searcher);
+
+        let searcher = crate::testing::reopen(|| EmbeddedEngine::new(dir.path())).await;
+        assert_eq!(Some(42), searcher.high_water_mark().await.unwrap());
+
+        let matches = searcher
+            .query("weather", "", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert_eq!(2, matches.total);
+        assert_eq!(
+            inv.annotations, matches.invoices[0].annotations,
+            "Should return the full invoice"
+        );
+        assert_eq!("example.com/almanac", matches.invoices[1].bindle.id.name());
+
+        // Annotations should still not be searchable
+        let matches = searcher
+            .query("narwhal", "", SearchOptions::default())
+            .await
+            .expect("query should succeed");
+        assert!(matches.invoices.is_empty());
+
+        searcher.clear().await.expect("unable to clear index");
+        assert!(searcher.high_water_mark().await.unwrap().is_none());
+        let matches = searcher
+            .query("weather", "", SearchOptions::default())
+            .await
+            .expect("query should succeed");
+        assert!(matches.invoices.is_empty());
+    }
+
+    #[tokio::test]
+    async fn embedded_engine_should_update_and_page() {
+        let dir = tempdir().expect("unable to create tempdir");
+        let searcher = EmbeddedEngine::new(dir.path())
+            .await
+            .expect("unable to open index");
+        for version in &["1.0.0", "1.1.0", "2.0.0"] {
+            searcher
+                .index(&invoice_fixture("example.com/weather", version, "Weather"))
+                .await
+                .expect("successfully indexed");
+        }
+
+        // Yanking is an update to an existing entry
+        let mut yanked = invoice_fixture("example.com/weather", "2.0.0", "Weather");
+        yanked.yanked = Some(true);
+        searcher.index(&yanked).await.expect("successfully indexed");
+
+        let matches = searcher
+            .query(
+                "example.com/weather",
+                "",
+                SearchOptions {
+                    strict: true,
+                    limit: 1,
+                    offset: 1,
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("found some matches");
+        assert_eq!(2, matches.total);
+        assert!(!matches.more);
+        assert_eq!("1.1.0", matches.invoices[0].bindle.id.version_string());
+    }
+
+    #[tokio::test]
+    async fn embedded_engine_should_remove() {
+        let dir = tempdir().expect("unable to create tempdir");
+        let searcher = EmbeddedEngine::new(dir.path())
+            .await
+            .expect("unable to open index");
+        let inv = invoice_fixture("example.com/weather", "1.0.0", "Weather prediction");
+        searcher.index(&inv).await.expect("successfully indexed");
+        searcher
+            .index(&invoice_fixture("example.com/weather", "2.0.0", "Weather"))
+            .await
+            .expect("successfully indexed");
+
+        searcher
+            .remove(&inv.bindle.id)
+            .await
+            .expect("unable to remove invoice");
+        // Removing something that isn't there is fine
+        searcher
+            .remove(&inv.bindle.id)
+            .await
+            .expect("unable to remove missing invoice");
+
+        for strict in [true, false] {
+            let matches = searcher
+                .query(
+                    "weather",
+                    "",
+                    SearchOptions {
+                        strict,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .expect("query should succeed");
+            assert_eq!(1, matches.total);
+            assert_eq!("2.0.0", matches.invoices[0].bindle.id.version_string());
+        }
+        assert_eq!(1, searcher.invoices.len());
+        assert!(!searcher
+            .terms
+            .iter()
+            .keys()
+            .any(|k| invoice_key(&k.unwrap()).as_ref() == b"example.com/weather/1.0.0"));
+        assert!(!searcher
+            .grams
+            .iter()
+            .keys()
+            .any(|k| k.unwrap().ends_with(b"example.com/weather/1.0.0")));
+    }
+
+    #[tokio::test]
+    async fn embedded_engine_should_look_up_terms() {
+        let dir = tempdir().expect("unable to create tempdir");
+        let searcher = EmbeddedEngine::new(dir.path())
+            .await
+            .expect("unable to open index");
+        searcher
+            .index(&invoice_fixture(
+                "example.com/weather",
+                "1.0.0",
+                "Weather prediction",
+            ))
+            .await
+            .expect("successfully indexed");
+        searcher
+            .index(&invoice_fixture("example.com/calendar", "1.0.0", "Dates"))
+            .await
+            .expect("successfully indexed");
+
+        let names = |matches: Matches| -> Vec<String> {
+            matches
+                .invoices
+                .iter()
+                .map(|i| i.bindle.id.name().to_owned())
+                .collect()
+        };
+        let standard = |term: &'static str| {
+            let searcher = searcher.clone();
+            async move {
+                names(
+                    searcher
+                        .query(term, "", SearchOptions::default())
+                        .await
+                        .expect("query should succeed"),
+                )
+            }
+        };
+        let strict = |term: &'static str| {
+            let searcher = searcher.clone();
+            async move {
+                names(
+                    searcher
+                        .query(
+                            term,
+                            "",
+                            SearchOptions {
+                                strict: true,
+                                ..Default::default()
+                            },
+                        )
+                        .await
+                        .expect("query should succeed"),
+                )
+            }
+        };
+
+        assert_eq!(vec!["example.com/weather"], standard("prediction").await);
+        assert_eq!(vec!["example.com/weather"], standard("pred").await);
+        assert_eq!(
+            vec!["example.com/weather"],
+            standard("wether").await,
+            "Typos should still be found"
+        );
+        assert_eq!(
+            vec!["example.com/weather"],
+            standard("weather 1.0").await,
+            "All terms should match the same invoice"
+        );
+        assert!(standard("weather dates").await.is_empty());
+        assert_eq!(2, standard("").await.len());
+
+        assert_eq!(vec!["example.com/calendar"], strict("ple.com/cal").await);
+        assert_eq!(vec!["example.com/weather"], strict("ea th").await);
+        assert!(strict("weather calendar").await.is_empty());
+
+        // Words that are no longer in a document should be removed from the index
+        searcher
+            .index(&invoice_fixture("example.com/calendar", "1.0.0", "Days"))
+            .await
+            .expect("successfully indexed");
+        assert!(standard("dates").await.is_empty());
+        assert!(searcher.terms.scan_prefix(b"dates\0").next().is_none());
+    }
+
+    fn invoice_fixture(name: &str, version: &str, description: &str) -> Invoice {
+        Invoice::new(crate::BindleSpec {
+            id: format!("{}/{}", name, version).parse().unwrap(),
+            description: Some(description.to_owned()),
+            authors: None,
+        })
+    }
+}
@@ -1,11 +1,20 @@
 //! Common types and traits for use in implementing query functionality for a Bindle server. Note
 //! that this functionality is quite likely to change
+use std::convert::TryInto;
+use std::ops::RangeInclusive;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde::{Deserialize, Serialize};
+use tracing::trace;
 
+mod embedded;
 mod noop;
+mod standard;
 mod strict;
 
+pub use embedded::EmbeddedEngine;
 pub use noop::NoopEngine;
+pub use standard::StandardEngine;
 pub use strict::StrictEngine;
 
 #[derive(Debug)]
@@ -75,6 +84,30 @@ impl Matches {
             total: 0,
         }
     }
+
+    /// Sets the total from the full list of found invoices and then fills in the invoices for the
+    /// page of results described by the offset and limit
+    fn fill_page(&mut self, mut found: Vec<crate::Invoice>) {
+        self.total = found.len() as u64;
+
+        if self.offset >= self.total {
+            // We're past the end of the search results. Return an empty matches object.
+            self.more = false;
+            return;
+        }
+
+        // Apply offset and limit
+        let mut last_index = self.offset + self.limit as u64 - 1;
+        if last_index >= self.total {
+            last_index = self.total - 1;
+        }
+
+        self.more = self.total > last_index + 1;
+        trace!(last_index, self.more, "Getting next page of results");
+        let range = RangeInclusive::new(self.offset as usize, last_index as usize);
+        self.invoices = found.drain(range).collect();
+        trace!("Returning {} found invoices", self.invoices.len());
+    }
 }
 
 /// This trait describes the minimal set of features a Bindle provider must implement to provide
@@ -112,4 +145,42 @@ pub trait Search {
     /// as such, following the protocol specification's requirements for yanked
     /// invoices.
     async fn index(&self, document: &crate::Invoice) -> anyhow::Result<()>;
+
+    /// Removes the invoice with the given ID from the index so it is no longer returned by
+    /// queries. Removing an invoice that isn't in the index is not an error.
+    ///
+    /// Providers call this when an invoice is taken out of storage, such as when a scrub
+    /// quarantines it. The default implementation does nothing, which is only appropriate for
+    /// engines that do not keep an index
+    async fn remove(&self, _id: &crate::Id) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the high-water mark last recorded with
+    /// [`set_high_water_mark`](Search::set_high_water_mark), or `None` if there isn't one.
+    ///
+    /// The mark is a time in nanoseconds since the Unix epoch (see
+    /// [`high_water_mark_at`](high_water_mark_at)), and means the same thing no matter which
+    /// provider recorded it: every invoice that was created or changed before that time is in the
+    /// index. Providers use it when warming the index so that only the invoices changed at or after
+    /// the mark need to be indexed, and should take the mark before they start reading so that
+    /// anything changed while warming is indexed again next time. A `None` means the provider must
+    /// index everything. Engines that do not persist their index should always return `None`, which
+    /// is what the default implementation does
+    async fn high_water_mark(&self) -> anyhow::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Records the given high-water mark alongside the index. The default implementation does
+    /// nothing
+    async fn set_high_water_mark(&self, _mark: u64) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the [high-water mark](Search::high_water_mark) for the given time
+pub fn high_water_mark_at(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos().try_into().unwrap_or(u64::MAX))
+        .unwrap_or_default()
 }
@@ -0,0 +1,369 @@
+//! A standard query engine implementation. It matches query terms against multiple fields of the
+//! bindle, ranks the results, and tolerates small typos in the query terms
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use tokio::sync::RwLock;
+use tracing::{debug, instrument, trace};
+
+use crate::search::{strict, Matches, Search, SearchOptions};
+
+// Weights for each of the indexed fields. The spec suggests that the name should be the highest
+// weighted field
+const NAME_WEIGHT: u32 = 8;
+const AUTHORS_WEIGHT: u32 = 4;
+const VERSION_WEIGHT: u32 = 2;
+const DESCRIPTION_WEIGHT: u32 = 1;
+
+// Multipliers for how well a query token matched a field token
+const EXACT_MATCH: u32 = 3;
+const PREFIX_MATCH: u32 = 2;
+const FUZZY_MATCH: u32 = 1;
+
+/// Implements standard query processing.
+///
+/// In standard mode, every term in the query must match at least one of the `name`, `version`,
+/// `authors`, or `description` fields of the bindle. A term matches a field if it is the same as,
+/// a prefix of, or within a small edit distance of one of the words in that field. Results are
+/// ordered by how well they matched, with matches on the name weighted the highest. Annotations
+/// and parcels are never indexed.
+///
+/// If strict mode is requested in the [`SearchOptions`], the query is processed using the same
+/// rules as the [`StrictEngine`](crate::search::StrictEngine)
+#[derive(Clone)]
+pub struct StandardEngine {
+    // Keyed by the invoice name so that ties in ranking are broken in a predictable order
+    index: Arc<RwLock<BTreeMap<String, IndexEntry>>>,
+}
+
+impl Default for StandardEngine {
+    fn default() -> Self {
+        StandardEngine {
+            index: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+}
+
+/// The tokenized fields of an invoice along with the invoice itself
+pub(super) struct IndexEntry {
+    invoice: crate::Invoice,
+    fields: Vec<(u32, Vec<String>)>,
+}
+
+impl IndexEntry {
+    pub(super) fn new(invoice: crate::Invoice) -> Self {
+        let spec = &invoice.bindle;
+        let fields = vec![
+            (NAME_WEIGHT, tokenize(spec.id.name())),
+            (VERSION_WEIGHT, tokenize(&spec.id.version_string())),
+            (
+                AUTHORS_WEIGHT,
+                spec.authors
+                    .iter()
+                    .flatten()
+                    .flat_map(|a| tokenize(a))
+                    .collect(),
+            ),
+            (
+                DESCRIPTION_WEIGHT,
+                spec.description
+                    .as_deref()
+                    .map(tokenize)
+                    .unwrap_or_default(),
+            ),
+        ];
+        IndexEntry { invoice, fields }
+    }
+
+    /// Returns all of the words in the indexed fields
+    pub(super) fn words(&self) -> impl Iterator<Item = &String> {
+        self.fields.iter().flat_map(|(_, words)| words.iter())
+    }
+
+    /// Scores this entry against the given query tokens. Returns `None` if any of the tokens do
+    /// not match, as all terms in a standard query are required
+    fn score(&self, query_tokens: &[String]) -> Option<u32> {
+        query_tokens.iter().try_fold(0, |total, token| {
+            self.fields
+                .iter()
+                .filter_map(|(weight, words)| {
+                    words
+                        .iter()
+                        .filter_map(|word| match_quality(token, word))
+                        .max()
+                        .map(|quality| weight * quality)
+                })
+                .max()
+                .map(|score| total + score)
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Search for StandardEngine {
+    #[instrument(level = "trace", skip(self))]
+    async fn query(
+        &self,
+        term: &str,
+        filter: &str,
+        options: SearchOptions,
+    ) -> anyhow::Result<Matches> {
+        trace!("beginning search");
+        let index = self.index.read().await;
+        let found = find_matches(index.values(), term, filter, &options);
+
+        debug!(total_matches = found.len(), "Found matches");
+        let mut matches = Matches::new(&options, term.to_owned());
+        matches.fill_page(found);
+
+        Ok(matches)
+    }
+
+    async fn index(&self, invoice: &crate::Invoice) -> anyhow::Result<()> {
+        self.index
+            .write()
+            .await
+            .insert(invoice.name(), IndexEntry::new(invoice.clone()));
+        Ok(())
+    }
+
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        self.index.write().await.remove(&id.to_string());
+        Ok(())
+    }
+}
+
+/// Finds all of the entries matching the query and returns their invoices in ranked order. The
+/// entries should be given in name order so that ties are broken predictably
+pub(super) fn find_matches<'a>(
+    entries: impl Iterator<Item = &'a IndexEntry>,
+    term: &str,
+    filter: &str,
+    options: &SearchOptions,
+) -> Vec<crate::Invoice> {
+    let candidates = entries
+        .filter(|e| options.yanked || !e.invoice.yanked.unwrap_or(false))
+        .filter(|e| filter.is_empty() || e.invoice.version_in_range(filter));
+
+    if options.strict {
+        debug!("Using strict mode");
+        let mut found: Vec<crate::Invoice> = candidates
+            .filter(|e| strict::is_match(&e.invoice, term, filter))
+            .map(|e| e.invoice.clone())
+            .collect();
+        strict::sort_matches(&mut found);
+        found
+    } else {
+        let query_tokens: Vec<String> = term.split_whitespace().flat_map(tokenize).collect();
+        debug!(?query_tokens, "Using standard mode");
+        let mut scored: Vec<(u32, &IndexEntry)> = candidates
+            .filter_map(|e| e.score(&query_tokens).map(|score| (score, e)))
+            .collect();
+        // This is a stable sort, so entries with the same score stay in name order
+        scored.sort_by(|(a, _), (b, _)| b.cmp(a));
+        scored.into_iter().map(|(_, e)| e.invoice.clone()).collect()
+    }
+}
+
+/// Splits the given text into lowercased words on any non-alphanumeric character
+pub(super) fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_lowercase())
+        .collect()
+}
+
+/// Returns how well the query token matches the given word, or `None` if it does not match
+pub(super) fn match_quality(token: &str, word: &str) -> Option<u32> {
+    if token == word {
+        Some(EXACT_MATCH)
+    } else if word.starts_with(token) {
+        Some(PREFIX_MATCH)
+    } else if edit_distance(token, word) <= allowed_typos(token) {
+        Some(FUZZY_MATCH)
+    } else {
+        None
+    }
+}
+
+/// The number of typos allowed for a query token. Short tokens must match exactly, otherwise
+/// nearly everything would be a fuzzy match for them
+pub(super) fn allowed_typos(token: &str) -> usize {
+    match token.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Computes the Levenshtein distance between the two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + if ca == *cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(prev[j + 1] + 1).min(current[j] + 1);
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Invoice;
+
+    #[tokio::test]
+    async fn standard_engine_should_rank_and_fuzzy_match() {
+        let searcher = StandardEngine::default();
+        let fixtures = [
+            invoice_fixture("example.com/weather", "1.0.0", "Weather prediction", "Jane"),
+            invoice_fixture("example.com/almanac", "1.0.0", "A weather almanac", "Jane"),
+            invoice_fixture("example.com/calendar", "2.1.0", "Calendar app", "Matt"),
+        ];
+        for inv in fixtures.iter() {
+            searcher.index(inv).await.expect("successfully indexed");
+        }
+
+        // Matches in the name should rank higher than matches in the description
+        let matches = searcher
+            .query("weather", "", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert!(!matches.strict);
+        assert_eq!(2, matches.invoices.len());
+        assert_eq!("example.com/weather", matches.invoices[0].bindle.id.name());
+        assert_eq!("example.com/almanac", matches.invoices[1].bindle.id.name());
+
+        // Typos should still match
+        let matches = searcher
+            .query("wether", "", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert_eq!(2, matches.invoices.len());
+
+        // All terms are required
+        let matches = searcher
+            .query("weather matt", "", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert!(matches.invoices.is_empty());
+        let matches = searcher
+            .query("calendar matt", "", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert_eq!(1, matches.invoices.len());
+
+        // Authors and versions are indexed
+        let matches = searcher
+            .query("jane", "", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert_eq!(2, matches.invoices.len());
+        let matches = searcher
+            .query("2.1.0", "", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert_eq!(1, matches.invoices.len());
+
+        // Version filters still apply
+        let matches = searcher
+            .query("weather", "^2", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert!(matches.invoices.is_empty());
+
+        // Strict mode only matches on the name
+        let matches = searcher
+            .query(
+                "weather",
+                "",
+                SearchOptions {
+                    strict: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("found some matches");
+        assert!(matches.strict);
+        assert_eq!(1, matches.invoices.len());
+    }
+
+    #[tokio::test]
+    async fn standard_engine_should_not_index_parcels_or_annotations() {
+        let searcher = StandardEngine::default();
+        let mut inv = invoice_fixture("example.com/weather", "1.0.0", "Weather", "Jane");
+        inv.annotations = Some(
+            vec![("secret".to_owned(), "narwhal".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        inv.parcel = Some(vec![crate::Parcel {
+            label: crate::Label {
+                sha256: "abcdef1234567890987654321".to_owned(),
+                media_type: "text/toml".to_owned(),
+                name: "unicorn.toml".to_owned(),
+                size: 101,
+                ..Default::default()
+            },
+            conditions: None,
+        }]);
+        searcher.index(&inv).await.expect("successfully indexed");
+
+        for term in &["narwhal", "unicorn", "abcdef1234567890987654321"] {
+            let matches = searcher
+                .query(term, "", SearchOptions::default())
+                .await
+                .expect("query should succeed");
+            assert!(matches.invoices.is_empty(), "{} should not match", term);
+        }
+    }
+
+    #[tokio::test]
+    async fn standard_engine_should_exclude_yanked() {
+        let searcher = StandardEngine::default();
+        let mut inv = invoice_fixture("example.com/weather", "1.0.0", "Weather", "Jane");
+        inv.yanked = Some(true);
+        searcher.index(&inv).await.expect("successfully indexed");
+
+        let matches = searcher
+            .query("weather", "", SearchOptions::default())
+            .await
+            .expect("query should succeed");
+        assert!(matches.invoices.is_empty());
+
+        let matches = searcher
+            .query(
+                "weather",
+                "",
+                SearchOptions {
+                    yanked: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("query should succeed");
+        assert!(matches.yanked);
+        assert_eq!(1, matches.invoices.len());
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(0, edit_distance("weather", "weather"));
+        assert_eq!(1, edit_distance("wether", "weather"));
+        assert_eq!(2, edit_distance("waether", "weather"));
+        assert_eq!(3, edit_distance("", "abc"));
+    }
+
+    fn invoice_fixture(name: &str, version: &str, description: &str, author: &str) -> Invoice {
+        Invoice::new(crate::BindleSpec {
+            id: format!("{}/{}", name, version).parse().unwrap(),
+            description: Some(description.to_owned()),
+            authors: Some(vec![author.to_owned()]),
+        })
+    }
+}
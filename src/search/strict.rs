@@ -1,7 +1,6 @@
 //! A strict query engine implementation. It always expects a strict match of query terms
 
 use std::collections::BTreeMap;
-use std::ops::RangeInclusive;
 use std::sync::Arc;
 
 use tokio::sync::RwLock;
@@ -10,6 +9,10 @@ use tracing::{debug, instrument, trace};
 use crate::search::{Matches, Search, SearchOptions};
 
 /// Implements strict query processing.
+///
+/// Every whitespace separated term in the query must be found in the name of the bindle. Yanked
+/// bindles are only returned if requested in the [`SearchOptions`]. Results are ordered by name
+/// and then by version.
 #[derive(Clone)]
 pub struct StrictEngine {
     // A BTreeMap will keep the records in a predictable order, which makes the
@@ -40,41 +43,18 @@ impl Search for StrictEngine {
             .index
             .read()
             .await
-            .iter()
-            .filter(|(_, i)| {
-                // Per the spec:
-                // - if `term` is present, then it must be contained within the name field of the bindle.
-                // - if a version filter is present, then the version of the bindle must abide by the filter.
-                debug!(term, filter, "comparing term and filter");
-                i.bindle.id.name().contains(term)
-                    && (filter.is_empty() || i.version_in_range(filter))
-            })
-            .map(|(_, v)| (*v).clone())
+            .values()
+            // Per the spec, a query must not match a yanked bindle unless explicitly requested
+            .filter(|i| options.yanked || !i.yanked.unwrap_or(false))
+            .filter(|i| is_match(i, term, filter))
+            .cloned()
             .collect();
+        sort_matches(&mut found);
 
         debug!(total_matches = found.len(), "Found matches");
         let mut matches = Matches::new(&options, term.to_owned());
         matches.strict = true;
-        matches.yanked = false;
-        matches.total = found.len() as u64;
-
-        if matches.offset >= matches.total {
-            // We're past the end of the search results. Return an empty matches object.
-            matches.more = false;
-            return Ok(matches);
-        }
-
-        // Apply offset and limit
-        let mut last_index = matches.offset + matches.limit as u64 - 1;
-        if last_index >= matches.total {
-            last_index = matches.total - 1;
-        }
-
-        matches.more = matches.total > last_index + 1;
-        trace!(last_index, matches.more, "Getting next page of results");
-        let range = RangeInclusive::new(matches.offset as usize, last_index as usize);
-        matches.invoices = found.drain(range).collect();
-        trace!("Returning {} found invoices", matches.invoices.len());
+        matches.fill_page(found);
 
         Ok(matches)
     }
@@ -86,6 +66,36 @@ impl Search for StrictEngine {
             .insert(invoice.name(), invoice.clone());
         Ok(())
     }
+
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        self.index.write().await.remove(&id.to_string());
+        Ok(())
+    }
+}
+
+/// Checks whether the invoice matches the given term and version filter using the strict rules
+/// from the protocol spec
+pub(super) fn is_match(invoice: &crate::Invoice, term: &str, filter: &str) -> bool {
+    // Per the spec:
+    // - every whitespace separated component of `term` must be contained within the name field of
+    //   the bindle. An empty term matches everything.
+    // - if a version filter is present, then the version of the bindle must abide by the filter.
+    debug!(term, filter, "comparing term and filter");
+    let name = invoice.bindle.id.name();
+    term.split_whitespace().all(|t| name.contains(t))
+        && (filter.is_empty() || invoice.version_in_range(filter))
+}
+
+/// Sorts matches by name and then by version (using SemVer precedence rather than string
+/// ordering) so that identical queries always return identical results in an identical order
+pub(super) fn sort_matches(found: &mut [crate::Invoice]) {
+    found.sort_by(|a, b| {
+        a.bindle
+            .id
+            .name()
+            .cmp(b.bindle.id.name())
+            .then_with(|| a.bindle.id.version().cmp(b.bindle.id.version()))
+    });
 }
 
 #[cfg(test)]
@@ -137,8 +147,165 @@ mod test {
             .await
             .expect("found some matches");
         assert!(matches.invoices.is_empty());
+    }
+
+    #[tokio::test]
+    async fn strict_engine_should_match_single_term() {
+        let searcher = searcher_fixture(&[
+            "foo/bar/baz",
+            "hello/foo/bar/baz/goodbye",
+            "foo/hello/bar/baz",
+            "foo-bar-baz",
+        ])
+        .await;
+        // Use a bindle with the query in the description to make sure it isn't matched
+        let mut described = invoice_fixture("hello".to_owned(), "0.1.0".to_owned());
+        described.bindle.description = Some("foo/bar/baz".to_owned());
+        searcher.index(&described).await.unwrap();
+
+        let matches = searcher
+            .query("foo/bar/baz", "", SearchOptions::default())
+            .await
+            .expect("query should succeed");
+        assert!(matches.strict);
+        assert_eq!(
+            vec!["foo/bar/baz", "hello/foo/bar/baz/goodbye"],
+            names(&matches)
+        );
+    }
+
+    #[tokio::test]
+    async fn strict_engine_should_match_all_terms() {
+        let searcher = searcher_fixture(&[
+            "foo/bar/baz",
+            "hello/foo/bar/baz/goodbye",
+            "foo/hello/bar/baz",
+            "foo-bar-baz",
+            "foo/bar",
+        ])
+        .await;
+        let mut described = invoice_fixture("hello".to_owned(), "0.1.0".to_owned());
+        described.bindle.description = Some("foo/bar/baz".to_owned());
+        searcher.index(&described).await.unwrap();
+
+        let matches = searcher
+            .query("foo bar baz", "", SearchOptions::default())
+            .await
+            .expect("query should succeed");
+        assert_eq!(
+            vec![
+                "foo-bar-baz",
+                "foo/bar/baz",
+                "foo/hello/bar/baz",
+                "hello/foo/bar/baz/goodbye"
+            ],
+            names(&matches)
+        );
+    }
+
+    #[tokio::test]
+    async fn strict_engine_should_exclude_yanked() {
+        let searcher = searcher_fixture(&["my/bindle"]).await;
+        let mut yanked = invoice_fixture("my/bindle".to_owned(), "2.0.0".to_owned());
+        yanked.yanked = Some(true);
+        searcher.index(&yanked).await.unwrap();
 
-        // TODO: Need to test yanked bindles
+        let matches = searcher
+            .query("my/bindle", "", SearchOptions::default())
+            .await
+            .expect("query should succeed");
+        assert!(!matches.yanked);
+        assert_eq!(1, matches.total);
+        assert_eq!("1.0.0", matches.invoices[0].bindle.id.version_string());
+
+        let matches = searcher
+            .query(
+                "my/bindle",
+                "",
+                SearchOptions {
+                    yanked: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("query should succeed");
+        assert!(matches.yanked);
+        assert_eq!(2, matches.total);
+
+        // An empty query matches everything that isn't yanked
+        let matches = searcher
+            .query("", "", SearchOptions::default())
+            .await
+            .expect("query should succeed");
+        assert_eq!(1, matches.total);
+    }
+
+    #[tokio::test]
+    async fn strict_engine_should_order_results() {
+        let searcher = StrictEngine::default();
+        for (name, version) in &[
+            ("b/bindle", "1.0.0"),
+            ("a/bindle", "1.10.0"),
+            ("a/bindle", "1.2.0"),
+            ("a/bindle", "1.2.0-beta.1"),
+        ] {
+            searcher
+                .index(&invoice_fixture(name.to_string(), version.to_string()))
+                .await
+                .unwrap();
+        }
+
+        let expected = vec![
+            "a/bindle/1.2.0-beta.1",
+            "a/bindle/1.2.0",
+            "a/bindle/1.10.0",
+            "b/bindle/1.0.0",
+        ];
+        let matches = searcher
+            .query("bindle", "", SearchOptions::default())
+            .await
+            .expect("query should succeed");
+        let found: Vec<String> = matches.invoices.iter().map(|i| i.name()).collect();
+        assert_eq!(expected, found);
+
+        // Paging through the results should give the same ordering
+        let mut paged = Vec::new();
+        for offset in 0..4 {
+            let matches = searcher
+                .query(
+                    "bindle",
+                    "",
+                    SearchOptions {
+                        offset,
+                        limit: 1,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .expect("query should succeed");
+            assert_eq!(offset < 3, matches.more);
+            paged.extend(matches.invoices.into_iter().map(|i| i.name()));
+        }
+        assert_eq!(expected, paged);
+    }
+
+    async fn searcher_fixture(names: &[&str]) -> StrictEngine {
+        let searcher = StrictEngine::default();
+        for name in names {
+            searcher
+                .index(&invoice_fixture(name.to_string(), "1.0.0".to_owned()))
+                .await
+                .expect("successfully indexed");
+        }
+        searcher
+    }
+
+    fn names(matches: &Matches) -> Vec<&str> {
+        matches
+            .invoices
+            .iter()
+            .map(|i| i.bindle.id.name())
+            .collect()
     }
 
     fn invoice_fixture(name: String, version: String) -> Invoice {
@@ -3,6 +3,7 @@ use std::fmt;
 use std::io::Read;
 
 use either::Either;
+use futures::future::{BoxFuture, FutureExt};
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use tracing::{debug, instrument, trace, warn};
@@ -13,9 +14,14 @@ use warp::Filter;
 use super::TOML_MIME_TYPE;
 use crate::authn::Authenticator;
 use crate::authz::always::Anonymous;
-use crate::authz::Authorizer;
+use crate::authz::{Authorizable, Authorizer, Operation, Request};
+use crate::id::ParseError;
+use crate::provider::Provider;
+use crate::{Id, Invoice};
 
 pub(crate) const PARCEL_ID_SEPARATOR: char = '@';
+/// The last path segment of the endpoint for adding signatures to an invoice
+pub(crate) const SIGNATURES_PATH: &str = "signatures";
 
 /// Query string options for the invoice endpoint
 #[derive(Debug, Deserialize)]
@@ -67,6 +73,29 @@ pub fn parcel() -> impl Filter<Extract = ((String, String),), Error = Rejection>
         })
 }
 
+/// A warp filter that returns the invoice ID if the path is for adding signatures to an invoice
+/// (`_i/{id}/signatures`) and rejects it otherwise
+pub fn signatures() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
+    warp::path("_i")
+        .and(warp::path::tail())
+        .and_then(|tail: warp::path::Tail| {
+            async move {
+                // Bindle versions can't contain a slash, so the suffix can't be part of the ID
+                let inv = match tail
+                    .as_str()
+                    .strip_suffix(SIGNATURES_PATH)
+                    .and_then(|s| s.strip_suffix('/'))
+                {
+                    Some(i) if !i.is_empty() && !i.contains(PARCEL_ID_SEPARATOR) => i,
+                    _ => return Err(custom(InvalidRequestPath)),
+                };
+                trace!(bindle_id = %inv, "Matched signatures path");
+                Ok(inv.to_owned())
+            }
+            .instrument(tracing::debug_span!("signatures_filter"))
+        })
+}
+
 #[instrument(level = "trace")]
 fn handle_tail(tail: &str) -> Result<(String, Option<String>), Rejection> {
     let mut split: Vec<String> = tail
@@ -94,8 +123,9 @@ fn handle_tail(tail: &str) -> Result<(String, Option<String>), Rejection> {
     }
 }
 
-/// A warp filter for adding an authenticator
-fn authenticate<Authn: Authenticator + Clone + Send + Sync>(
+/// A warp filter for adding an authenticator. This should come after the filters that match the
+/// path so that requests are only authenticated by the route that handles them
+pub(crate) fn authenticate<Authn: Authenticator + Clone + Send + Sync>(
     authn: Authn,
 ) -> impl Filter<Extract = (Either<Anonymous, Authn::Item>,), Error = Rejection> + Clone {
     // We get the header optionally as anonymous auth could be enabled
@@ -144,37 +174,135 @@ pub(crate) async fn handle_authn_rejection(
     }
 }
 
-/// A warp filter for adding an authorizer
-pub(crate) fn authenticate_and_authorize<
-    Authn: Authenticator + Clone + Send + Sync,
-    Authz: Authorizer + Clone + Send + Sync,
->(
-    authn: Authn,
+/// Something extracted from a request that an operation can be authorized on
+pub(crate) trait AuthzTarget {
+    /// Returns the ID of the bindle the operation is on, and the SHA of the parcel if it is on one
+    fn target(&self) -> Result<(Id, Option<&str>), ParseError>;
+
+    /// Returns the invoice sent with the request, if any
+    fn invoice(&self) -> Option<&Invoice> {
+        None
+    }
+}
+
+impl AuthzTarget for String {
+    fn target(&self) -> Result<(Id, Option<&str>), ParseError> {
+        Ok((self.parse()?, None))
+    }
+}
+
+impl AuthzTarget for warp::path::Tail {
+    fn target(&self) -> Result<(Id, Option<&str>), ParseError> {
+        Ok((self.as_str().parse()?, None))
+    }
+}
+
+impl AuthzTarget for (String, String) {
+    fn target(&self) -> Result<(Id, Option<&str>), ParseError> {
+        Ok((self.0.parse()?, Some(self.1.as_str())))
+    }
+}
+
+impl AuthzTarget for Invoice {
+    fn target(&self) -> Result<(Id, Option<&str>), ParseError> {
+        Ok((self.bindle.id.clone(), None))
+    }
+
+    fn invoice(&self) -> Option<&Invoice> {
+        Some(self)
+    }
+}
+
+/// Returns a function for use with `and_then` after a target (such as a bindle ID or an invoice) and
+/// an authenticated item have been extracted. It authorizes the operation on the target for the
+/// item, passing the target through if it is allowed. The stored invoice is loaded from the store
+/// if the authorizer needs it
+pub(crate) fn authorize<I, T, P, Authz>(
+    operation: Operation,
+    store: P,
     authz: Authz,
-) -> impl Filter<Extract = ((),), Error = Rejection> + Clone {
-    authenticate(authn)
-        .and(warp::path::full())
-        .and(warp::method())
-        .and(warp::any().map(move || authz.clone()))
-        .and_then(
-            |item: Either<Anonymous, Authn::Item>,
-             path: warp::path::FullPath,
-             method: warp::http::Method,
-             authz: Authz| {
-                async move {
-                    trace!(path = path.as_str(), %method, "Authorizing request");
-                    if let Err(e) = item.either(
-                        |anon| authz.authorize(anon, path.as_str(), &method),
-                        |i| authz.authorize(i, path.as_str(), &method),
-                    ) {
-                        debug!(error = %e, "Authorization error");
-                        return Err(warp::reject::custom(AuthzFail));
+) -> impl Fn(T, I) -> BoxFuture<'static, Result<T, Rejection>> + Clone + Send + Sync
+where
+    I: Authorizable + Send + Sync + 'static,
+    T: AuthzTarget + Send + Sync + 'static,
+    P: Provider + Clone + Send + Sync + 'static,
+    Authz: Authorizer + Clone + Send + Sync + 'static,
+{
+    move |target: T, item: I| {
+        let store = store.clone();
+        let authz = authz.clone();
+        async move {
+            // Scoped so that the borrows of the target end before it is passed through
+            {
+                let (id, parcel_sha) = target.target().map_err(|e| custom(InvalidBindleId(e)))?;
+                let stored = match target.invoice() {
+                    None if authz.needs_invoice(operation) => {
+                        // A missing invoice is left for the handler to report
+                        match store.get_yanked_invoice(id.clone()).await {
+                            Ok(inv) => Some(inv),
+                            Err(e) => {
+                                debug!(error = %e, "Unable to load invoice for authorization");
+                                None
+                            }
+                        }
                     }
-                    Ok(())
-                }
-                .instrument(tracing::trace_span!("authorization"))
-            },
-        )
+                    _ => None,
+                };
+                let request = Request {
+                    operation,
+                    bindle_id: Some(&id),
+                    parcel_sha,
+                    invoice: target.invoice().or(stored.as_ref()),
+                };
+                check_authorization(&authz, &item, &request).await?;
+            }
+            Ok(target)
+        }
+        .instrument(tracing::trace_span!("authorization"))
+        .boxed()
+    }
+}
+
+/// Returns a function for use with `and_then` that authorizes an operation that isn't on a single
+/// bindle, such as a query, for the authenticated item
+pub(crate) fn authorize_any<I, Authz>(
+    operation: Operation,
+    authz: Authz,
+) -> impl Fn(I) -> BoxFuture<'static, Result<(), Rejection>> + Clone + Send + Sync
+where
+    I: Authorizable + Send + Sync + 'static,
+    Authz: Authorizer + Clone + Send + Sync + 'static,
+{
+    move |item: I| {
+        let authz = authz.clone();
+        async move {
+            let request = Request {
+                operation,
+                bindle_id: None,
+                parcel_sha: None,
+                invoice: None,
+            };
+            check_authorization(&authz, &item, &request).await
+        }
+        .instrument(tracing::trace_span!("authorization"))
+        .boxed()
+    }
+}
+
+async fn check_authorization<I: Authorizable + Sync, Authz: Authorizer>(
+    authz: &Authz,
+    item: &I,
+    request: &Request<'_>,
+) -> Result<(), Rejection> {
+    trace!(operation = ?request.operation, bindle_id = ?request.bindle_id, "Authorizing request");
+    if let Err(e) = item.check_scope(request) {
+        debug!(error = %e, "Request is out of scope for the credentials");
+        return Err(warp::reject::custom(AuthzFail));
+    }
+    authz.authorize(item, request).await.map_err(|e| {
+        debug!(error = %e, "Authorization error");
+        warp::reject::custom(AuthzFail)
+    })
 }
 
 #[derive(Debug)]
@@ -283,6 +411,12 @@ pub(crate) async fn handle_invalid_request_path(
             "Invalid URL. Missing Bindle ID and/or parcel SHA",
             warp::http::StatusCode::BAD_REQUEST,
         ))
+    } else if let Some(InvalidBindleId(e)) = err.find::<InvalidBindleId>() {
+        debug!("Handling rejection as invalid bindle ID rejection");
+        Ok(crate::server::reply::reply_from_error(
+            e,
+            warp::http::StatusCode::BAD_REQUEST,
+        ))
     } else {
         Err(err)
     }
@@ -292,3 +426,8 @@ pub(crate) async fn handle_invalid_request_path(
 struct InvalidRequestPath;
 
 impl Reject for InvalidRequestPath {}
+
+#[derive(Debug)]
+struct InvalidBindleId(ParseError);
+
+impl Reject for InvalidBindleId {}
@@ -13,10 +13,13 @@ pub mod v1 {
     use super::*;
 
     use crate::{
-        signature::{KeyRing, SecretKeyStorage},
-        LoginParams, QueryOptions, SignatureError,
+        provider::resign::AppendSignature,
+        signature::{KeyEntry, KeyRing, SecretKeyStorage},
+        LoginParams, QueryOptions, Signature, SignatureError,
     };
 
+    use std::convert::TryInto;
+
     use oauth2::reqwest::async_http_client;
     use oauth2::{basic::BasicClient, devicecode::StandardDeviceAuthorizationResponse};
     use oauth2::{AuthUrl, ClientId, DeviceAuthorizationUrl, Scope};
@@ -24,6 +27,20 @@ pub mod v1 {
     use tracing::Instrument;
     use warp::http::StatusCode;
 
+    /// Runs a function that signs something on the blocking thread pool. Keys held by a signing
+    /// agent sign using blocking IO, which would otherwise stall the runtime while waiting on the
+    /// agent
+    async fn sign_blocking<T, F>(f: F) -> Result<T, SignatureError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, SignatureError> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(f).await.map_err(|e| {
+            tracing::error!(error = %e, "Signing task failed");
+            SignatureError::SigningFailed
+        })?
+    }
+
     //////////// Invoice Functions ////////////
     #[instrument(level = "trace", skip(index))]
     pub async fn query_invoices<S: Search>(
@@ -58,14 +75,18 @@ pub mod v1 {
     }
 
     #[instrument(level = "trace", skip(store, secret_store))]
-    pub async fn create_invoice<P: Provider, S: SecretKeyStorage>(
+    pub async fn create_invoice<P, S>(
+        inv: crate::Invoice,
         store: P,
         secret_store: S,
         strategy: VerificationStrategy,
         keyring: std::sync::Arc<KeyRing>,
-        inv: crate::Invoice,
         accept_header: Option<String>,
-    ) -> Result<impl warp::Reply, Infallible> {
+    ) -> Result<impl warp::Reply, Infallible>
+    where
+        P: Provider,
+        S: SecretKeyStorage + Send + 'static,
+    {
         let accept = accept_header.unwrap_or_default();
         trace!("Create invoice request with invoice: {:?}", inv);
 
@@ -74,20 +95,24 @@ pub mod v1 {
         // with my private key, and THEN go on to store.create_invoice()
 
         let role = SignatureRole::Host;
-        let sk = match secret_store.get_first_matching(&role) {
-            None => {
-                return Ok(reply::into_reply(ProviderError::FailedSigning(
-                    SignatureError::NoSuitableKey,
-                )))
-            }
-            Some(k) => k,
-        };
+        if secret_store.get_first_matching(&role).is_none() {
+            return Ok(reply::into_reply(ProviderError::FailedSigning(
+                SignatureError::NoSuitableKey,
+            )));
+        }
 
         let verified = match strategy.verify(inv, &keyring) {
             Ok(v) => v,
             Err(e) => return Ok(reply::into_reply(ProviderError::FailedSigning(e))),
         };
-        let signed = match crate::sign(verified, vec![(role, sk)]) {
+        let signed = match sign_blocking(move || {
+            let sk = secret_store
+                .get_first_matching(&role)
+                .ok_or(SignatureError::NoSuitableKey)?;
+            crate::sign(verified, vec![(role, sk)])
+        })
+        .await
+        {
             Ok(s) => s,
             Err(e) => return Ok(reply::into_reply(ProviderError::FailedSigning(e))),
         };
@@ -162,14 +187,37 @@ pub mod v1 {
         Ok::<Box<dyn warp::Reply>, Infallible>(res)
     }
 
-    #[instrument(level = "trace", skip(store), fields(id = tail.as_str()))]
-    pub async fn yank_invoice<P: Provider>(
+    #[instrument(level = "trace", skip(store, secret_store), fields(id = tail.as_str()))]
+    pub async fn yank_invoice<P, S>(
         tail: warp::path::Tail,
         store: P,
+        secret_store: S,
         accept_header: Option<String>,
-    ) -> Result<impl warp::Reply, Infallible> {
-        let id = tail.as_str();
-        if let Err(e) = store.yank_invoice(id).await {
+    ) -> Result<impl warp::Reply, Infallible>
+    where
+        P: Provider,
+        S: SecretKeyStorage + Send + 'static,
+    {
+        let id: crate::Id = match tail.as_str().try_into() {
+            Ok(i) => i,
+            Err(e) => return Ok(reply::into_reply(ProviderError::from(e))),
+        };
+
+        // The spec requires that a yank be signed by the host key
+        let yank_id = id.clone();
+        let signature = match sign_blocking(move || {
+            let sk = secret_store
+                .get_first_matching(&SignatureRole::Host)
+                .ok_or(SignatureError::NoSuitableKey)?;
+            crate::sign_yank(&yank_id, sk)
+        })
+        .await
+        {
+            Ok(s) => s,
+            Err(e) => return Ok(reply::into_reply(ProviderError::FailedSigning(e))),
+        };
+
+        if let Err(e) = store.yank_invoice(id, Some(signature)).await {
             debug!(error = %e, "Got error during yank invoice request");
             return Ok(reply::into_reply(e));
         }
@@ -182,6 +230,72 @@ pub mod v1 {
         ))
     }
 
+    #[instrument(level = "trace", skip(store, keyring, req), fields(id = %id))]
+    pub async fn add_signatures<P: Provider + AppendSignature + Sync>(
+        id: String,
+        store: P,
+        keyring: std::sync::Arc<KeyRing>,
+        req: crate::AddSignaturesRequest,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id: crate::Id = match id.as_str().try_into() {
+            Ok(i) => i,
+            Err(e) => return Ok(reply::into_reply(ProviderError::from(e))),
+        };
+        if req.signature.is_empty() {
+            return Ok(reply::reply_from_error(
+                "at least one signature is required",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+
+        // A yanked invoice shouldn't be used, so there is no reason to sign it
+        if let Err(e) = store.get_invoice(&id).await {
+            debug!(error = %e, "Got error while fetching invoice to sign");
+            return Ok(reply::into_reply(e));
+        }
+        if let Err(e) = req
+            .signature
+            .iter()
+            .try_for_each(|s| check_added_signature(s, &keyring))
+        {
+            return Ok(reply::into_reply(ProviderError::FailedSigning(e)));
+        }
+
+        // The provider checks that each signature is valid for the stored invoice
+        let inv = match store.append_signatures(&id, req.signature).await {
+            Ok(i) => i,
+            Err(e) => {
+                debug!(error = %e, "Got error while adding signatures");
+                return Ok(reply::into_reply(e));
+            }
+        };
+        Ok(warp::reply::with_status(
+            reply::serialized_data(&inv, accept_header.unwrap_or_default()),
+            StatusCode::OK,
+        ))
+    }
+
+    /// Checks that a signature added to an existing invoice has a role that can sign after creation
+    /// and was made by a trusted key
+    fn check_added_signature(sig: &Signature, keyring: &KeyRing) -> Result<(), SignatureError> {
+        // Creators sign before upload and the host signature is made by the server
+        if !matches!(sig.role, SignatureRole::Approver | SignatureRole::Proxy) {
+            return Err(SignatureError::Unverified(format!(
+                "signature for key {} must have the approver or proxy role",
+                sig.key
+            )));
+        }
+        let pk = sig.public_key()?;
+        if keyring.revocation(&pk, sig.at).is_some() {
+            return Err(SignatureError::RevokedKey(sig.key.clone()));
+        }
+        if !keyring.contains(&pk) {
+            return Err(SignatureError::UnknownSigningKey(sig.key.clone()));
+        }
+        Ok(())
+    }
+
     #[instrument(level = "trace", skip(store))]
     pub async fn head_invoice<P: Provider + Sync>(
         id: String,
@@ -246,6 +360,7 @@ pub mod v1 {
     pub async fn get_parcel<P: Provider + Sync>(
         (bindle_id, id): (String, String),
         store: P,
+        range_header: Option<String>,
     ) -> Result<Box<dyn warp::Reply>, Infallible> {
         // Get parcel label to ascertain content type and length, and validate that it does exist
         let label = match parcel_in_bindle(&store, &bindle_id, &id).await {
@@ -253,7 +368,29 @@ pub mod v1 {
             Err(e) => return Ok::<Box<dyn warp::Reply>, Infallible>(Box::new(e)),
         };
 
-        let data = match store.get_parcel(bindle_id, &id).await {
+        let range = match range_header.as_deref().map(|h| parse_range(h, label.size)) {
+            None | Some(RangeRequest::Full) => None,
+            Some(RangeRequest::Partial(r)) => Some(r),
+            Some(RangeRequest::Unsatisfiable) => {
+                debug!(range = ?range_header, size = label.size, "Requested range is not satisfiable");
+                let resp = warp::http::Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(warp::http::header::ACCEPT_RANGES, "bytes")
+                    .header(
+                        warp::http::header::CONTENT_RANGE,
+                        format!("bytes */{}", label.size),
+                    )
+                    .body(hyper::Body::empty())
+                    .unwrap();
+                return Ok::<Box<dyn warp::Reply>, Infallible>(Box::new(resp));
+            }
+        };
+
+        let data = match &range {
+            Some(r) => store.get_parcel_range(bindle_id, &id, r.clone()).await,
+            None => store.get_parcel(bindle_id, &id).await,
+        };
+        let data = match data {
             Ok(reader) => reader,
             Err(e) => {
                 debug!(error = %e, "Got error while getting parcel from store");
@@ -264,17 +401,25 @@ pub mod v1 {
         // TODO: If we start to use compression on the body, we'll need a new custom header for
         // _actual_ size of the parcel, so the client can reconstruct the label data from headers
         // without needing to read the whole (possibly large) file
-        let resp = warp::http::Response::builder()
+        let builder = warp::http::Response::builder()
             .header(warp::http::header::CONTENT_TYPE, label.media_type)
-            .header(warp::http::header::CONTENT_LENGTH, label.size)
-            .body(hyper::Body::wrap_stream(data))
-            .unwrap();
+            .header(warp::http::header::ACCEPT_RANGES, "bytes");
+        let builder = match range {
+            Some(r) => builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(warp::http::header::CONTENT_LENGTH, r.end - r.start)
+                .header(
+                    warp::http::header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", r.start, r.end - 1, label.size),
+                ),
+            None => builder
+                .status(StatusCode::OK)
+                .header(warp::http::header::CONTENT_LENGTH, label.size),
+        };
+        let resp = builder.body(hyper::Body::wrap_stream(data)).unwrap();
 
         // Gotta box because this is not a toml reply type (which we use for sending error messages to the user)
-        Ok::<Box<dyn warp::Reply>, Infallible>(Box::new(warp::reply::with_status(
-            resp,
-            warp::http::StatusCode::OK,
-        )))
+        Ok::<Box<dyn warp::Reply>, Infallible>(Box::new(resp))
     }
 
     #[instrument(level = "trace", skip(store))]
@@ -283,7 +428,7 @@ pub mod v1 {
         store: P,
     ) -> Result<Box<dyn warp::Reply>, Infallible> {
         trace!("Getting parcel data");
-        let inv = get_parcel((bindle_id, id), store).await?;
+        let inv = get_parcel((bindle_id, id), store, None).await?;
 
         // Consume the response to we can take the headers
         let (parts, _) = inv.into_response().into_parts();
@@ -353,6 +498,33 @@ pub mod v1 {
         ))
     }
 
+    //////////// Key Functions ////////////
+
+    /// Returns the public keys of all of the host keys, with their labels signed, as a keyring
+    #[instrument(level = "trace", skip(secret_store))]
+    pub async fn get_host_keys<S: SecretKeyStorage + Send + 'static>(
+        secret_store: S,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        // The labels are signed when creating the entries
+        let keys = match sign_blocking(move || {
+            secret_store
+                .get_all_matching(&SignatureRole::Host)
+                .into_iter()
+                .map(KeyEntry::from_signing_key)
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        {
+            Ok(k) => k,
+            Err(e) => return Ok(reply::into_reply(ProviderError::FailedSigning(e))),
+        };
+        Ok(warp::reply::with_status(
+            reply::serialized_data(&KeyRing::new(keys), accept_header.unwrap_or_default()),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
     //////////// Login Functions ////////////
 
     /// Redirects to a login request
@@ -441,6 +613,43 @@ pub mod v1 {
 
     //////////// Helper Functions ////////////
 
+    /// How a parcel request should be served based on its `Range` header
+    #[derive(Debug, PartialEq)]
+    enum RangeRequest {
+        /// Send the whole parcel
+        Full,
+        /// Send only the given bytes of the parcel
+        Partial(std::ops::Range<u64>),
+        /// None of the requested bytes exist in the parcel
+        Unsatisfiable,
+    }
+
+    /// Parses a `Range` header for a parcel of the given size. Per RFC 7233, a header that can't
+    /// be parsed is ignored and the full parcel is sent. Requests for multiple ranges are also
+    /// answered with the full parcel, as we don't support multipart responses
+    fn parse_range(header: &str, size: u64) -> RangeRequest {
+        let spec = match header.trim().strip_prefix("bytes=") {
+            Some(s) if !s.contains(',') => s.trim(),
+            _ => return RangeRequest::Full,
+        };
+        let (first, last) = match spec.split_once('-') {
+            Some(parts) => parts,
+            None => return RangeRequest::Full,
+        };
+        let range = match (first.parse::<u64>().ok(), last.parse::<u64>().ok()) {
+            // A suffix range, such as `bytes=-500` for the last 500 bytes
+            (None, Some(suffix)) if first.is_empty() => size.saturating_sub(suffix)..size,
+            (Some(start), None) if last.is_empty() => start..size,
+            (Some(start), Some(end)) if start <= end => start..end.saturating_add(1).min(size),
+            _ => return RangeRequest::Full,
+        };
+        if range.start >= range.end {
+            RangeRequest::Unsatisfiable
+        } else {
+            RangeRequest::Partial(range)
+        }
+    }
+
     /// Fetches an invoice from the given store and checks that the given SHA exists within that
     /// invoice. Returns a result where the Error variant is a warp reply containing the error
     #[instrument(level = "trace", skip(store))]
@@ -473,6 +682,43 @@ pub mod v1 {
             )),
         }
     }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_parse_range() {
+            assert_eq!(RangeRequest::Partial(0..10), parse_range("bytes=0-9", 100));
+            assert_eq!(
+                RangeRequest::Partial(90..100),
+                parse_range("bytes=90-", 100)
+            );
+            assert_eq!(
+                RangeRequest::Partial(70..100),
+                parse_range("bytes=-30", 100)
+            );
+            // Ranges that go past the end are cut off at the end of the parcel
+            assert_eq!(
+                RangeRequest::Partial(90..100),
+                parse_range("bytes=90-200", 100)
+            );
+            assert_eq!(
+                RangeRequest::Partial(0..100),
+                parse_range("bytes=-200", 100)
+            );
+
+            assert_eq!(RangeRequest::Unsatisfiable, parse_range("bytes=100-", 100));
+            assert_eq!(RangeRequest::Unsatisfiable, parse_range("bytes=-0", 100));
+            assert_eq!(RangeRequest::Unsatisfiable, parse_range("bytes=0-", 0));
+
+            // Anything we don't understand is ignored
+            assert_eq!(RangeRequest::Full, parse_range("bytes=10-5", 100));
+            assert_eq!(RangeRequest::Full, parse_range("bytes=0-1,5-9", 100));
+            assert_eq!(RangeRequest::Full, parse_range("items=0-9", 100));
+            assert_eq!(RangeRequest::Full, parse_range("bytes=a-b", 100));
+        }
+    }
 }
 
 // A helper struct for HEAD responses that takes the raw headers from a GET request and puts them
@@ -42,7 +42,7 @@ pub async fn server<P, I, Authn, Authz, S>(
     keyring: KeyRing,
 ) -> anyhow::Result<()>
 where
-    P: Provider + Clone + Send + Sync + 'static,
+    P: Provider + crate::provider::resign::AppendSignature + Clone + Send + Sync + 'static,
     I: Search + Clone + Send + Sync + 'static,
     S: SecretKeyStorage + Clone + Send + Sync + 'static,
     Authn: crate::authn::Authenticator + Clone + Send + Sync + 'static,
@@ -96,13 +96,15 @@ async fn shutdown_signal() {
 mod test {
     use std::convert::TryInto;
 
+    #[cfg(target_family = "unix")]
+    use crate::agent;
     use crate::authn::always::AlwaysAuthenticate;
     use crate::authz::always::AlwaysAuthorize;
     use crate::invoice::{
-        signature::{KeyRing, SecretKeyEntry},
+        signature::{KeyRing, SecretKeyEntry, SecretKeyStorage},
         SignatureRole, VerificationStrategy,
     };
-    use crate::provider::Provider;
+    use crate::provider::{resign::AppendSignature, Provider};
     use crate::search::StrictEngine;
     use crate::testing::{self, MockKeyStore};
 
@@ -117,7 +119,7 @@ mod test {
         #[future]
         provider_setup: (T, StrictEngine, MockKeyStore),
     ) where
-        T: Provider + Clone + Send + Sync + 'static,
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
     {
         let bindles = testing::load_all_files().await;
         let (store, index, ks) = provider_setup.await;
@@ -288,7 +290,7 @@ mod test {
         #[future]
         provider_setup: (T, StrictEngine, MockKeyStore),
     ) where
-        T: Provider + Clone + Send + Sync + 'static,
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
     {
         let (store, index, ks) = provider_setup.await;
 
@@ -352,7 +354,15 @@ mod test {
             "Body: {}",
             String::from_utf8_lossy(res.body())
         );
-        toml::from_slice::<crate::Invoice>(res.body()).expect("should be valid invoice TOML");
+        let inv =
+            toml::from_slice::<crate::Invoice>(res.body()).expect("should be valid invoice TOML");
+        assert!(
+            inv.yanked_signature
+                .unwrap_or_default()
+                .into_iter()
+                .any(|sig| matches!(sig.role, crate::SignatureRole::Host)),
+            "Yanked invoice should have a yank signature from the host"
+        );
     }
 
     #[rstest]
@@ -364,7 +374,7 @@ mod test {
         #[future]
         provider_setup: (T, StrictEngine, MockKeyStore),
     ) where
-        T: Provider + Clone + Send + Sync + 'static,
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
     {
         let bindles = testing::load_all_files().await;
         let (store, index, ks) = provider_setup.await;
@@ -415,7 +425,7 @@ mod test {
         #[future]
         provider_setup: (T, StrictEngine, MockKeyStore),
     ) where
-        T: Provider + Clone + Send + Sync + 'static,
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
     {
         let (store, index, keystore) = provider_setup.await;
 
@@ -503,6 +513,101 @@ mod test {
         );
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn test_parcel_range<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
+    {
+        let (store, index, keystore) = provider_setup.await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            keystore,
+            VerificationStrategy::default(),
+            KeyRing::default(),
+        );
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").expect("Missing parcel");
+        let sk = SecretKeyEntry::new("test".to_owned(), vec![SignatureRole::Host]);
+        let verified = VerificationStrategy::MultipleAttestation(vec![])
+            .verify(scaffold.invoice.clone(), &KeyRing::default())
+            .unwrap();
+        let signed = crate::sign(verified, vec![(SignatureRole::Host, &sk)]).unwrap();
+        store
+            .create_invoice(signed)
+            .await
+            .expect("Unable to insert invoice into store");
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(
+                    std::io::Cursor::new(parcel.data.clone()),
+                    BytesCodec::default(),
+                ),
+            )
+            .await
+            .expect("Unable to create parcel");
+        let path = format!("/v1/_i/{}@{}", scaffold.invoice.bindle.id, &parcel.sha);
+        let size = parcel.data.len();
+
+        let res = warp::test::request().path(&path).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!(res.headers().get("Accept-Ranges").unwrap(), "bytes");
+
+        let res = warp::test::request()
+            .path(&path)
+            .header("Range", "bytes=2-5")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::PARTIAL_CONTENT,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert_eq!(res.body().as_ref(), &parcel.data[2..6]);
+        assert_eq!(
+            res.headers()
+                .get("Content-Range")
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            format!("bytes 2-5/{}", size)
+        );
+        assert_eq!(res.headers().get("Content-Length").unwrap(), "4");
+
+        let res = warp::test::request()
+            .path(&path)
+            .header("Range", "bytes=-3")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.body().as_ref(), &parcel.data[size - 3..]);
+
+        let res = warp::test::request()
+            .path(&path)
+            .header("Range", format!("bytes={}-", size))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            res.headers()
+                .get("Content-Range")
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            format!("bytes */{}", size)
+        );
+    }
+
     #[rstest]
     #[tokio::test]
     // Once again, this isn't meant to exercise all of the query functionality, just that the API
@@ -512,7 +617,7 @@ mod test {
         #[future]
         provider_setup: (T, StrictEngine, MockKeyStore),
     ) where
-        T: Provider + Clone + Send + Sync + 'static,
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
     {
         // Insert data into store
         let (store, index, ks) = provider_setup.await;
@@ -616,6 +721,35 @@ mod test {
         // Test version queries (also broken for the same reason as other tests here)
 
         // Test yank
+        store
+            .yank_invoice("enterprise.com/warpcore/1.0.0", None)
+            .await
+            .expect("Unable to yank invoice");
+        let res = warp::test::request()
+            .path("/v1/_q?q=enterprise.com/warpcore")
+            .reply(&api)
+            .await;
+        let matches: crate::Matches =
+            toml::from_slice(res.body()).expect("Unable to deserialize response");
+        assert_eq!(
+            matches.invoices.len(),
+            1,
+            "Yanked invoices should not be returned by default"
+        );
+        assert!(!matches.yanked);
+
+        let res = warp::test::request()
+            .path("/v1/_q?q=enterprise.com/warpcore&yanked=true")
+            .reply(&api)
+            .await;
+        let matches: crate::Matches =
+            toml::from_slice(res.body()).expect("Unable to deserialize response");
+        assert_eq!(
+            matches.invoices.len(),
+            2,
+            "Yanked invoices should be returned when requested"
+        );
+        assert!(matches.yanked);
 
         // Test limit/offset
     }
@@ -627,7 +761,7 @@ mod test {
         #[future]
         provider_setup: (T, StrictEngine, MockKeyStore),
     ) where
-        T: Provider + Clone + Send + Sync + 'static,
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
     {
         let (store, index, ks) = provider_setup.await;
 
@@ -707,7 +841,7 @@ mod test {
         #[future]
         provider_setup: (T, StrictEngine, MockKeyStore),
     ) where
-        T: Provider + Clone + Send + Sync + 'static,
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
     {
         let (store, index, ks) = provider_setup.await;
 
@@ -751,6 +885,222 @@ mod test {
         );
     }
 
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_should_sign_with_agent() {
+        let dir = crate::testing::private_tempdir();
+        let socket = dir.path().join("agent.sock");
+        let host = SecretKeyEntry::new("Host".to_owned(), vec![SignatureRole::Host]);
+        let keys = crate::signature::SecretKeyFile {
+            key: vec![host],
+            ..Default::default()
+        };
+        tokio::spawn(agent::serve(agent::bind(&socket).await.unwrap(), keys));
+        let store = tokio::task::spawn_blocking(move || agent::AgentKeyStore::connect(&socket))
+            .await
+            .unwrap()
+            .expect("should connect to agent");
+
+        // The agent runs on the same single threaded runtime as the server, so this only works if
+        // the server doesn't block the runtime while signing
+        let (provider, index, _) = testing::setup().await;
+        let api = super::routes::api(
+            provider,
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            store,
+            VerificationStrategy::default(),
+            KeyRing::default(),
+        );
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/_k")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let keyring: KeyRing = toml::from_slice(res.body()).expect("should be a valid keyring");
+        assert_eq!(1, keyring.key.len());
+        assert!(keyring.key[0].label_signature.is_some());
+
+        let scaffold = testing::RawScaffold::load("valid_v1").await;
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(&scaffold.invoice)
+            .reply(&api)
+            .await;
+        assert!(
+            res.status().is_success(),
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_host_keys() {
+        let (store, index, ks) = testing::setup().await;
+        let host_key: crate::invoice::signature::KeyEntry = ks
+            .get_first_matching(&SignatureRole::Host)
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let api = super::routes::api(
+            store,
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            ks,
+            VerificationStrategy::default(),
+            KeyRing::default(),
+        );
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/_k")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let keyring: KeyRing = toml::from_slice(res.body()).expect("should be a valid keyring");
+        assert_eq!(1, keyring.key.len(), "Only the host key should be returned");
+        let entry = keyring.key[0].clone();
+        assert_eq!(host_key.key, entry.key);
+        assert_eq!(vec![SignatureRole::Host], entry.roles);
+        assert!(entry.label_signature.is_some(), "Label should be signed");
+        entry
+            .clone()
+            .verify_label(entry.public_key().unwrap())
+            .expect("Label signature should be valid");
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_add_signatures<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
+    {
+        let (store, index, ks) = provider_setup.await;
+        let approver = SecretKeyEntry::new("Approver".to_owned(), vec![SignatureRole::Approver]);
+        let keyring = KeyRing::new(vec![(&approver).try_into().unwrap()]);
+
+        let api = super::routes::api(
+            store,
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            ks,
+            VerificationStrategy::default(),
+            keyring.clone(),
+        );
+
+        let scaffold = testing::RawScaffold::load("valid_v1").await;
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(&scaffold.invoice)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let inv = toml::from_slice::<crate::InvoiceCreateResponse>(res.body())
+            .expect("should be valid invoice response TOML")
+            .invoice;
+        let sig_path = format!("/v1/_i/{}/signatures", inv.bindle.id);
+
+        // Signs a copy of the stored invoice and returns a request body with just the new signature
+        let sign = |role: SignatureRole, key: &SecretKeyEntry| {
+            let mut signed = inv.clone();
+            signed.sign(role, key).expect("should be able to sign");
+            let signature = signed.signature.unwrap().pop().unwrap();
+            toml::to_vec(&crate::AddSignaturesRequest {
+                signature: vec![signature],
+            })
+            .unwrap()
+        };
+        let approval = sign(SignatureRole::Approver, &approver);
+
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path(&sig_path)
+            .body(&approval)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let updated =
+            toml::from_slice::<crate::Invoice>(res.body()).expect("should be valid invoice TOML");
+        assert_eq!(
+            inv.signature.as_ref().unwrap().len() + 1,
+            updated.signature.as_ref().unwrap().len()
+        );
+        VerificationStrategy::AuthoritativeIntegrity
+            .verify(updated, &keyring)
+            .expect("Approved invoice should pass verification");
+
+        // A key can only sign once, the key must be trusted, and only approvers and proxies can
+        // sign after creation
+        let untrusted = SecretKeyEntry::new("Untrusted".to_owned(), vec![SignatureRole::Approver]);
+        let host = SecretKeyEntry::new("Host".to_owned(), vec![SignatureRole::Host]);
+        for body in [
+            approval,
+            sign(SignatureRole::Approver, &untrusted),
+            sign(SignatureRole::Host, &host),
+        ] {
+            let res = warp::test::request()
+                .method("POST")
+                .header("Content-Type", "application/toml")
+                .path(&sig_path)
+                .body(&body)
+                .reply(&api)
+                .await;
+            assert_eq!(
+                res.status(),
+                warp::http::StatusCode::BAD_REQUEST,
+                "Body: {}",
+                String::from_utf8_lossy(res.body())
+            );
+        }
+
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i/not.found/1.0.0/signatures")
+            .body(sign(SignatureRole::Approver, &approver))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::NOT_FOUND,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+    }
+
     #[rstest]
     #[tokio::test]
     async fn test_anonymous_get<T>(
@@ -758,7 +1108,7 @@ mod test {
         #[future]
         provider_setup: (T, StrictEngine, MockKeyStore),
     ) where
-        T: Provider + Clone + Send + Sync + 'static,
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
     {
         let (store, index, ks) = provider_setup.await;
 
@@ -846,4 +1196,377 @@ mod test {
             String::from_utf8_lossy(res.body())
         );
     }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_policy_authorization<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
+    {
+        let (store, index, ks) = provider_setup.await;
+
+        let policy: crate::authz::policy::Policy = toml::from_str(
+            r#"
+            [[rule]]
+            principals = ["admin"]
+            prefixes = ["enterprise.com/"]
+            permissions = ["read", "create"]
+            "#,
+        )
+        .expect("Unable to parse policy");
+
+        let api = super::routes::api(
+            store,
+            index,
+            crate::authn::http_basic::HttpBasic::from_file("test/data/htpasswd")
+                .await
+                .expect("Unable to load htpasswd file"),
+            crate::authz::policy::PolicyAuthorizer::new(policy),
+            ks,
+            VerificationStrategy::default(),
+            KeyRing::default(),
+        );
+        let auth = format!("Basic {}", base64::encode(b"admin:sw0rdf1sh"));
+
+        let scaffold = testing::RawScaffold::load("valid_v1").await;
+
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .header("Authorization", &auth)
+            .path("/v1/_i")
+            .body(&scaffold.invoice)
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let scaffold: testing::Scaffold = scaffold.into();
+        let path = format!("/v1/_i/{}", scaffold.invoice.bindle.id);
+
+        // The policy grants read, so fetching should work
+        let res = warp::test::request()
+            .method("GET")
+            .header("Authorization", &auth)
+            .path(&path)
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // But not yank
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", &auth)
+            .path(&path)
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::FORBIDDEN,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // And anonymous users get nothing
+        let res = warp::test::request()
+            .method("GET")
+            .path(&path)
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::FORBIDDEN,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+    }
+
+    /// An authorizer that only lets the authors of a bindle yank it
+    #[derive(Clone)]
+    struct AuthorsCanYank;
+
+    #[async_trait::async_trait]
+    impl crate::authz::Authorizer for AuthorsCanYank {
+        async fn authorize<A>(
+            &self,
+            item: &A,
+            request: &crate::authz::Request<'_>,
+        ) -> anyhow::Result<()>
+        where
+            A: crate::authz::Authorizable + Sync,
+        {
+            if request.operation != crate::authz::Operation::YankInvoice {
+                return Ok(());
+            }
+            let inv = request
+                .invoice
+                .ok_or_else(|| anyhow::anyhow!("The invoice was not loaded"))?;
+            if inv
+                .bindle
+                .authors
+                .as_ref()
+                .map(|a| a.contains(&item.principal()))
+                .unwrap_or_default()
+            {
+                Ok(())
+            } else {
+                anyhow::bail!("Only the authors of a bindle can yank it")
+            }
+        }
+
+        fn needs_invoice(&self, operation: crate::authz::Operation) -> bool {
+            operation == crate::authz::Operation::YankInvoice
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_authorize_with_invoice<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
+    {
+        let (store, index, ks) = provider_setup.await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            crate::authn::http_basic::HttpBasic::from_file("test/data/htpasswd")
+                .await
+                .expect("Unable to load htpasswd file"),
+            AuthorsCanYank,
+            ks,
+            VerificationStrategy::default(),
+            KeyRing::default(),
+        );
+        let auth = format!("Basic {}", base64::encode(b"admin:sw0rdf1sh"));
+
+        // Bad credentials should be rejected before the body is looked at
+        for content_type in ["application/toml", "application/json"] {
+            let res = warp::test::request()
+                .method("POST")
+                .header("Content-Type", content_type)
+                .header(
+                    "Authorization",
+                    format!("Basic {}", base64::encode(b"admin:wrong")),
+                )
+                .path("/v1/_i")
+                .body("not an invoice")
+                .reply(&api)
+                .await;
+            assert_eq!(
+                res.status(),
+                warp::http::StatusCode::UNAUTHORIZED,
+                "Body: {}",
+                String::from_utf8_lossy(res.body())
+            );
+            let res = warp::test::request()
+                .method("POST")
+                .header("Content-Type", content_type)
+                .header("Authorization", &auth)
+                .path("/v1/_i")
+                .body("not an invoice")
+                .reply(&api)
+                .await;
+            assert_eq!(
+                res.status(),
+                warp::http::StatusCode::BAD_REQUEST,
+                "Body: {}",
+                String::from_utf8_lossy(res.body())
+            );
+        }
+
+        let sk = SecretKeyEntry::new("test".to_owned(), vec![SignatureRole::Host]);
+        let scaffold = testing::Scaffold::load("incomplete").await;
+        let others = scaffold.invoice.clone();
+        let mut owned = scaffold.invoice;
+        owned.bindle.id = format!("{}/2.0.0", owned.bindle.id.name()).parse().unwrap();
+        owned.bindle.authors = Some(vec!["admin".to_owned()]);
+
+        for inv in [&others, &owned] {
+            let verified = VerificationStrategy::MultipleAttestation(vec![])
+                .verify(inv.clone(), &KeyRing::default())
+                .unwrap();
+            let signed = crate::sign(verified, vec![(SignatureRole::Host, &sk)]).unwrap();
+            store
+                .create_invoice(signed)
+                .await
+                .expect("Should be able to insert invoice");
+        }
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", &auth)
+            .path(&format!("/v1/_i/{}", others.bindle.id))
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::FORBIDDEN,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", &auth)
+            .path(&format!("/v1/_i/{}", owned.bindle.id))
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_api_tokens<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + AppendSignature + Clone + Send + Sync + 'static,
+    {
+        use crate::authn::token::{Scope, TokenAuthenticator, TokenFile};
+
+        let (store, index, ks) = provider_setup.await;
+        let dir = tempfile::tempdir().expect("Unable to create temp dir");
+        let token_file = dir.path().join("tokens.toml");
+
+        let mut tokens = TokenFile::default();
+        let (push, _) = tokens
+            .create(
+                "ci",
+                vec![Scope::Read, Scope::Push],
+                vec!["enterprise.com/".to_owned()],
+                None,
+            )
+            .expect("Unable to create token");
+        let (elsewhere, _) = tokens
+            .create(
+                "ci",
+                vec![Scope::Push],
+                vec!["example.com/".to_owned()],
+                None,
+            )
+            .expect("Unable to create token");
+        let (revoked, entry) = tokens
+            .create("ci", vec![Scope::Read], vec![], None)
+            .expect("Unable to create token");
+        let revoked_id = entry.id.clone();
+        tokens.revoke(&revoked_id).expect("Unable to revoke token");
+        tokens
+            .save_file(&token_file)
+            .await
+            .expect("Unable to save token file");
+
+        let authn = TokenAuthenticator::new(
+            &token_file,
+            crate::authn::http_basic::HttpBasic::from_file("test/data/htpasswd")
+                .await
+                .expect("Unable to load htpasswd file"),
+        )
+        .await
+        .expect("Unable to load token file");
+        let api = super::routes::api(
+            store,
+            index,
+            authn,
+            crate::authz::anonymous_get::AnonymousGet,
+            ks,
+            VerificationStrategy::default(),
+            KeyRing::default(),
+        );
+
+        let scaffold = testing::RawScaffold::load("valid_v1").await;
+        let create = |token: &str| {
+            warp::test::request()
+                .method("POST")
+                .header("Content-Type", "application/toml")
+                .header("Authorization", format!("Bearer {}", token))
+                .path("/v1/_i")
+                .body(&scaffold.invoice)
+        };
+
+        // A token for another prefix can't push here
+        let res = create(&elsewhere).reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::FORBIDDEN,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // A revoked token isn't accepted at all
+        let res = create(&revoked).reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::UNAUTHORIZED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let res = create(&push).reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // The token doesn't have the yank scope, even though the authorizer would allow it
+        let scaffold: testing::Scaffold = scaffold.into();
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", format!("Bearer {}", push))
+            .path(&format!("/v1/_i/{}", scaffold.invoice.bindle.id))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::FORBIDDEN,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // Other credentials still work
+        let res = warp::test::request()
+            .method("DELETE")
+            .header(
+                "Authorization",
+                format!("Basic {}", base64::encode(b"admin:sw0rdf1sh")),
+            )
+            .path(&format!("/v1/_i/{}", scaffold.invoice.bindle.id))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+    }
 }
@@ -16,7 +16,12 @@ pub fn api<P, I, Authn, Authz, S>(
     keyring: KeyRing,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 where
-    P: crate::provider::Provider + Clone + Send + Sync + 'static,
+    P: crate::provider::Provider
+        + crate::provider::resign::AppendSignature
+        + Clone
+        + Send
+        + Sync
+        + 'static,
     I: crate::search::Search + Clone + Send + Sync + 'static,
     S: crate::invoice::signature::SecretKeyStorage + Clone + Send + Sync + 'static,
     Authn: crate::authn::Authenticator + Clone + Send + Sync + 'static,
@@ -27,37 +32,80 @@ where
     // Use an Arc to avoid a possibly expensive clone of the keyring on every API call
     let wrapped_keyring = Arc::new(keyring);
     warp::path("v1")
-        .and(filters::authenticate_and_authorize(authn.clone(), authz))
-        .untuple_one()
         .and(
-            v1::invoice::query(index)
+            v1::invoice::query(index, authn.clone(), authz.clone())
                 .or(v1::invoice::create_toml(
                     store.clone(),
                     secret_store.clone(),
                     verification_strategy.clone(),
                     wrapped_keyring.clone(),
+                    authn.clone(),
+                    authz.clone(),
                 ))
                 .boxed()
                 .or(v1::invoice::create_json(
                     store.clone(),
-                    secret_store,
+                    secret_store.clone(),
                     verification_strategy,
-                    wrapped_keyring,
+                    wrapped_keyring.clone(),
+                    authn.clone(),
+                    authz.clone(),
+                ))
+                .boxed()
+                .or(v1::invoice::get(
+                    store.clone(),
+                    authn.clone(),
+                    authz.clone(),
+                ))
+                .boxed()
+                .or(v1::invoice::head(
+                    store.clone(),
+                    authn.clone(),
+                    authz.clone(),
+                ))
+                .boxed()
+                .or(v1::invoice::yank(
+                    store.clone(),
+                    secret_store.clone(),
+                    authn.clone(),
+                    authz.clone(),
                 ))
                 .boxed()
-                .or(v1::invoice::get(store.clone()))
+                .or(v1::invoice::add_signatures_toml(
+                    store.clone(),
+                    wrapped_keyring.clone(),
+                    authn.clone(),
+                    authz.clone(),
+                ))
                 .boxed()
-                .or(v1::invoice::head(store.clone()))
+                .or(v1::invoice::add_signatures_json(
+                    store.clone(),
+                    wrapped_keyring,
+                    authn.clone(),
+                    authz.clone(),
+                ))
                 .boxed()
-                .or(v1::invoice::yank(store.clone()))
+                .or(v1::parcel::create(
+                    store.clone(),
+                    authn.clone(),
+                    authz.clone(),
+                ))
                 .boxed()
-                .or(v1::parcel::create(store.clone()))
+                .or(v1::parcel::get(store.clone(), authn.clone(), authz.clone()))
                 .boxed()
-                .or(v1::parcel::get(store.clone()))
+                .or(v1::parcel::head(
+                    store.clone(),
+                    authn.clone(),
+                    authz.clone(),
+                ))
                 .boxed()
-                .or(v1::parcel::head(store.clone()))
+                .or(v1::relationships::get_missing_parcels(
+                    store,
+                    authn.clone(),
+                    authz.clone(),
+                ))
                 .boxed()
-                .or(v1::relationships::get_missing_parcels(store))
+                .or(v1::keys::get_host_keys(secret_store, authn.clone(), authz))
                 .boxed()
                 .or(v1::auth::login(
                     authn.client_id().to_owned(),
@@ -68,13 +116,17 @@ where
                 .or(health)
                 .boxed(),
         )
-        .recover(filters::handle_invalid_request_path)
+        // Authentication only happens once a route has matched the path, so its rejections take
+        // precedence over invalid paths from the other routes
         .recover(filters::handle_authn_rejection)
         .recover(filters::handle_authz_rejection)
+        .recover(filters::handle_invalid_request_path)
         .with(warp::trace::request())
 }
 
 pub mod v1 {
+    use crate::authn::Authenticator;
+    use crate::authz::{Authorizer, Operation};
     use crate::provider::Provider;
     use crate::search::Search;
     use crate::server::handlers::v1::*;
@@ -106,6 +158,7 @@ pub mod v1 {
 
     pub mod invoice {
         use crate::{
+            provider::resign::AppendSignature,
             server::routes::with_secret_store,
             signature::{KeyRing, SecretKeyStorage},
         };
@@ -114,104 +167,226 @@ pub mod v1 {
 
         use std::sync::Arc;
 
-        pub fn query<S>(
+        pub fn query<S, Authn, Authz>(
             index: S,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
             S: Search + Clone + Send + Sync,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             warp::path("_q")
                 .and(warp::get())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize_any(Operation::Query, authz))
+                .untuple_one()
                 .and(warp::query::<crate::QueryOptions>())
                 .and(warp::any().map(move || index.clone()))
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(query_invoices)
         }
 
-        pub fn create_toml<P, S>(
+        pub fn create_toml<P, S, Authn, Authz>(
             store: P,
             secret_store: S,
             verification_strategy: crate::VerificationStrategy,
             keyring: Arc<KeyRing>,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
-            S: SecretKeyStorage + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            S: SecretKeyStorage + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             warp::path("_i")
                 .and(warp::path::end())
                 .and(warp::post())
+                // Authenticate before parsing the body so that unauthenticated clients get a 401
+                // rather than an error about the body
+                .and(filters::authenticate(authn))
+                .and(filters::toml())
+                .map(|item, inv| (inv, item))
+                .untuple_one()
+                .and_then(filters::authorize(
+                    Operation::CreateInvoice,
+                    store.clone(),
+                    authz,
+                ))
                 .and(with_store(store))
                 .and(with_secret_store(secret_store))
                 .and(warp::any().map(move || verification_strategy.clone()))
                 .and(warp::any().map(move || keyring.clone()))
-                .and(filters::toml())
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(create_invoice)
                 .recover(filters::handle_deserialize_rejection)
         }
-        pub fn create_json<P, S>(
+        pub fn create_json<P, S, Authn, Authz>(
             store: P,
             secret_store: S,
             verification_strategy: crate::VerificationStrategy,
             keyring: Arc<KeyRing>,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
-            S: SecretKeyStorage + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            S: SecretKeyStorage + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             warp::path("_i")
                 .and(warp::path::end())
                 .and(warp::post())
+                // Authenticate before parsing the body so that unauthenticated clients get a 401
+                // rather than an error about the body
+                .and(filters::authenticate(authn))
+                .and(warp::body::json())
+                .map(|item, inv| (inv, item))
+                .untuple_one()
+                .and_then(filters::authorize(
+                    Operation::CreateInvoice,
+                    store.clone(),
+                    authz,
+                ))
                 .and(with_store(store))
                 .and(with_secret_store(secret_store))
                 .and(warp::any().map(move || verification_strategy.clone()))
                 .and(warp::any().map(move || keyring.clone()))
-                .and(warp::body::json())
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(create_invoice)
                 .recover(filters::handle_deserialize_rejection)
         }
 
         // The GET and HEAD endpoints handle both parcels and invoices through the request router function
-        pub fn get<P>(
+        pub fn get<P, Authn, Authz>(
             store: P,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             filters::invoice()
                 .and(warp::get())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize(
+                    Operation::GetInvoice,
+                    store.clone(),
+                    authz,
+                ))
                 .and(warp::query::<filters::InvoiceQuery>())
                 .and(with_store(store))
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(get_invoice)
         }
 
-        pub fn head<P>(
+        pub fn head<P, Authn, Authz>(
             store: P,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             filters::invoice()
                 .and(warp::head())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize(
+                    Operation::GetInvoice,
+                    store.clone(),
+                    authz,
+                ))
                 .and(warp::query::<filters::InvoiceQuery>())
                 .and(with_store(store))
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(head_invoice)
         }
 
-        pub fn yank<P>(
+        pub fn add_signatures_toml<P, Authn, Authz>(
+            store: P,
+            keyring: Arc<KeyRing>,
+            authn: Authn,
+            authz: Authz,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            P: Provider + AppendSignature + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
+        {
+            filters::signatures()
+                .and(warp::post())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize(
+                    Operation::AddSignatures,
+                    store.clone(),
+                    authz,
+                ))
+                .and(with_store(store))
+                .and(warp::any().map(move || keyring.clone()))
+                .and(filters::toml())
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(add_signatures)
+                .recover(filters::handle_deserialize_rejection)
+        }
+
+        pub fn add_signatures_json<P, Authn, Authz>(
+            store: P,
+            keyring: Arc<KeyRing>,
+            authn: Authn,
+            authz: Authz,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            P: Provider + AppendSignature + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
+        {
+            filters::signatures()
+                .and(warp::post())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize(
+                    Operation::AddSignatures,
+                    store.clone(),
+                    authz,
+                ))
+                .and(with_store(store))
+                .and(warp::any().map(move || keyring.clone()))
+                .and(warp::body::json())
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(add_signatures)
+                .recover(filters::handle_deserialize_rejection)
+        }
+
+        pub fn yank<P, S, Authn, Authz>(
             store: P,
+            secret_store: S,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            S: SecretKeyStorage + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             warp::path("_i")
                 .and(warp::path::tail())
                 .and(warp::delete())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize(
+                    Operation::YankInvoice,
+                    store.clone(),
+                    authz,
+                ))
                 .and(with_store(store))
+                .and(with_secret_store(secret_store))
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(yank_invoice)
         }
@@ -220,59 +395,127 @@ pub mod v1 {
     pub mod parcel {
         use super::*;
 
-        pub fn create<P>(
+        pub fn create<P, Authn, Authz>(
             store: P,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             filters::parcel()
                 .and(warp::post())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize(
+                    Operation::CreateParcel,
+                    store.clone(),
+                    authz,
+                ))
                 .and(warp::body::stream())
                 .and(with_store(store))
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(create_parcel)
         }
 
-        pub fn get<P>(
+        pub fn get<P, Authn, Authz>(
             store: P,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             filters::parcel()
                 .and(warp::get())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize(
+                    Operation::GetParcel,
+                    store.clone(),
+                    authz,
+                ))
                 .and(with_store(store))
+                .and(warp::header::optional::<String>("range"))
                 .and_then(get_parcel)
         }
 
-        pub fn head<P>(
+        pub fn head<P, Authn, Authz>(
             store: P,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             filters::parcel()
                 .and(warp::head())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize(
+                    Operation::GetParcel,
+                    store.clone(),
+                    authz,
+                ))
                 .and(with_store(store))
                 .and_then(head_parcel)
         }
     }
 
+    pub mod keys {
+        use super::*;
+
+        use crate::{server::routes::with_secret_store, signature::SecretKeyStorage};
+
+        pub fn get_host_keys<S, Authn, Authz>(
+            secret_store: S,
+            authn: Authn,
+            authz: Authz,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            S: SecretKeyStorage + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
+        {
+            warp::path("_k")
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize_any(Operation::GetHostKeys, authz))
+                .untuple_one()
+                .and(with_secret_store(secret_store))
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(crate::server::handlers::v1::get_host_keys)
+        }
+    }
+
     pub mod relationships {
         use super::*;
 
-        pub fn get_missing_parcels<P>(
+        pub fn get_missing_parcels<P, Authn, Authz>(
             store: P,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            Authn: Authenticator + Clone + Send + Sync + 'static,
+            Authz: Authorizer + Clone + Send + Sync + 'static,
         {
             // For some reason, using the `path!` macro here was causing matching problems
             warp::path("_r")
                 .and(warp::path("missing"))
                 .and(warp::path::tail())
                 .and(warp::get())
+                .and(filters::authenticate(authn))
+                .and_then(filters::authorize(
+                    Operation::GetMissingParcels,
+                    store.clone(),
+                    authz,
+                ))
                 .and(with_store(store))
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(get_missing)
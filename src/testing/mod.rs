@@ -195,6 +195,39 @@ pub async fn load_all_files() -> HashMap<String, RawScaffold> {
         .collect()
 }
 
+/// Opens something backed by sled, such as an `EmbeddedProvider` or `EmbeddedEngine`, retrying
+/// while the database is still locked. Sled releases its lock from a background thread some time
+/// after the last handle is dropped, so reopening a database right after dropping it can fail
+pub async fn reopen<T, E, F, Fut>(open: F) -> T
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+    E: std::fmt::Debug,
+{
+    let mut attempts = 0;
+    loop {
+        match open().await {
+            Ok(t) => return t,
+            Err(e) if attempts >= 50 => panic!("unable to reopen database: {:?}", e),
+            Err(_) => {
+                attempts += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Creates a temporary directory that only the current user can access, which is where the
+/// signing agent requires its socket to be
+#[cfg(target_family = "unix")]
+pub fn private_tempdir() -> tempfile::TempDir {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempdir().expect("unable to create tempdir");
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))
+        .expect("unable to set tempdir permissions");
+    dir
+}
+
 /// Filters all items in a parcel directory that do not match the proper extensions. Returns None if
 /// there isn't a parcel directory
 async fn filter_files<P: AsRef<Path>>(root_path: P) -> Option<Vec<PathBuf>> {
@@ -263,6 +296,8 @@ impl Default for MockKeyStore {
 }
 
 impl SecretKeyStorage for MockKeyStore {
+    type Key = SecretKeyEntry;
+
     fn get_first_matching(&self, _role: &SignatureRole) -> Option<&SecretKeyEntry> {
         Some(&self.mock_secret_key)
     }
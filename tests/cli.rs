@@ -264,6 +264,120 @@ async fn test_create_key_and_sign_invoice() {
     }
 }
 
+#[cfg(target_family = "unix")]
+#[tokio::test]
+async fn test_sign_invoice_with_agent() {
+    use bindle::signature::{KeyRing, SecretKeyFile};
+    use bindle::{SecretKeyEntry, SignatureRole, VerificationStrategy};
+    use std::convert::TryInto;
+
+    let tempdir = testing::private_tempdir();
+    let key = SecretKeyEntry::new("testkey".to_owned(), vec![SignatureRole::Creator]);
+    let keyring = KeyRing::new(vec![(&key).try_into().unwrap()]);
+    let socket = tempdir.path().join("agent.sock");
+    let listener = bindle::agent::bind(&socket).await.unwrap();
+    tokio::spawn(bindle::agent::serve(
+        listener,
+        SecretKeyFile {
+            key: vec![key],
+            ..Default::default()
+        },
+    ));
+
+    // The secrets file doesn't exist, so this can only work if the agent is used
+    let signed_path = tempdir.path().join("signed-invoice.toml");
+    let cmd = format!(
+        "run --features cli --bin bindle -- sign-invoice ./test/data/simple-invoice.toml -o {} -f {}",
+        signed_path.to_str().unwrap(),
+        tempdir.path().join("nonexistent.toml").to_str().unwrap()
+    );
+    // The agent runs on this thread, so the command can't block it
+    let output = tokio::process::Command::new("cargo")
+        .args(cmd.split(' '))
+        .env(ENV_BINDLE_URL, "localhost:8080")
+        .env(bindle::agent::AGENT_SOCKET_ENV, &socket)
+        .output()
+        .await
+        .expect("Invoice should get signed");
+    assert_status(output, "Invoice should get signed by the agent");
+
+    let signed: bindle::Invoice =
+        toml::from_slice(&tokio::fs::read(signed_path).await.unwrap()).unwrap();
+    VerificationStrategy::CreativeIntegrity
+        .verify(signed, &keyring)
+        .expect("Invoice should be signed with the key held by the agent");
+}
+
+#[tokio::test]
+async fn test_keys() {
+    let controller = TestController::new(BINARY_NAME).await;
+    let tempdir = tempfile::tempdir().expect("Unable to set up tempdir");
+    let keyring_file = tempdir.path().join("keyring.toml");
+    let keys = |args: &str| {
+        let cmd = format!(
+            "run --features cli --bin bindle -- -r {} keys {}",
+            keyring_file.to_str().unwrap(),
+            args
+        );
+        std::process::Command::new("cargo")
+            .args(cmd.split(' '))
+            .env(ENV_BINDLE_URL, &controller.base_url)
+            .output()
+            .expect("Should be able to run command")
+    };
+
+    let key = bindle::SecretKeyEntry::new("Test".to_owned(), vec![]);
+    let entry = bindle::signature::KeyEntry::from_signing_key(&key).unwrap();
+    assert_status(
+        keys(&format!("add Test {} --roles creator,approver", entry.key)),
+        "Key should be added",
+    );
+    assert!(
+        !keys(&format!("add Other {} --roles creator", entry.key))
+            .status
+            .success(),
+        "Adding the same key twice should fail"
+    );
+    assert_status(keys("fetch --yes"), "Server host keys should be fetched");
+
+    let keyring: bindle::signature::KeyRing =
+        toml::from_slice(&tokio::fs::read(&keyring_file).await.unwrap()).unwrap();
+    assert_eq!(2, keyring.key.len());
+    assert_eq!(
+        vec![
+            bindle::SignatureRole::Creator,
+            bindle::SignatureRole::Approver
+        ],
+        keyring.key[0].roles
+    );
+    assert_eq!(vec![bindle::SignatureRole::Host], keyring.key[1].roles);
+
+    let output = keys("list");
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains(&entry.fingerprint().unwrap()),
+        "Fingerprint should be listed"
+    );
+    assert_status(output, "Keys should be listed");
+    assert_status(keys("verify"), "Keys should verify");
+
+    assert_status(keys("remove Test"), "Key should be removed");
+    assert!(!keys("remove Test").status.success());
+    let keyring: bindle::signature::KeyRing =
+        toml::from_slice(&tokio::fs::read(&keyring_file).await.unwrap()).unwrap();
+    assert_eq!(1, keyring.key.len());
+
+    // A label that doesn't match its signature should fail verification
+    let mut tampered = keyring;
+    tampered.key[0].label = "Someone else".to_owned();
+    tokio::fs::write(&keyring_file, toml::to_string(&tampered).unwrap())
+        .await
+        .unwrap();
+    assert!(
+        !keys("verify").status.success(),
+        "Tampered label should fail verification"
+    );
+}
+
 #[tokio::test]
 async fn test_get_parcel() {
     let controller = TestController::new(BINARY_NAME).await;
@@ -324,6 +438,45 @@ async fn test_yank() {
     assert_status(output, "Should be able to yank a bindle");
 }
 
+#[tokio::test]
+async fn test_verify() {
+    let controller = TestController::new(BINARY_NAME).await;
+    let root = std::env::var("CARGO_MANIFEST_DIR").expect("Unable to get project directory");
+    let path = std::path::PathBuf::from(root).join("test/data/standalone");
+    let output = std::process::Command::new("cargo")
+        .args(&[
+            "run",
+            "--features",
+            "cli",
+            "--bin",
+            "bindle",
+            "--",
+            "verify",
+            "-p",
+            path.to_str().unwrap(),
+            "--parcels",
+            "-o",
+            "json",
+            "enterprise.com/warpcore/1.0.0",
+        ])
+        .env(ENV_BINDLE_URL, &controller.base_url)
+        .output()
+        .expect("Should be able to run command");
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert_status(output, "Should be able to verify a standalone bindle");
+    let report: serde_json::Value =
+        serde_json::from_str(&stdout).expect("Output should be valid JSON");
+    assert_eq!(report["report"]["verified"], true);
+    assert!(
+        report["parcels"]
+            .as_array()
+            .expect("Parcels should have been checked")
+            .iter()
+            .all(|p| p["problem"].is_null()),
+        "All parcels should match their digests"
+    );
+}
+
 #[tokio::test]
 async fn test_no_bindles() {
     let controller = TestController::new(BINARY_NAME).await;
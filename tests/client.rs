@@ -6,7 +6,10 @@ use test_util::TestController;
 
 use std::convert::TryInto;
 
+use bindle::client::{tokens::NoToken, ClientBuilder};
+use bindle::signature::{KeyEntry, KeyRing};
 use bindle::testing;
+use bindle::{QueryOptions, SignatureRole, VerificationStrategy};
 
 use tokio_stream::StreamExt;
 
@@ -134,6 +137,18 @@ async fn test_streaming_successful() {
         on_disk_len,
         data.len()
     );
+
+    // Resuming partway through should only return the rest of the parcel
+    let mut stream = controller
+        .client
+        .get_parcel_stream_from(&inv.bindle.id, &parcel_sha, 3)
+        .await
+        .expect("unable to get parcel");
+    let mut rest = Vec::new();
+    while let Some(res) = stream.next().await {
+        rest.extend(res.expect("Shouldn't get an error in stream"));
+    }
+    assert_eq!(&data[3..], rest.as_slice());
 }
 
 #[tokio::test]
@@ -217,6 +232,37 @@ async fn test_missing() {
     }
 }
 
+#[tokio::test]
+async fn test_host_keys() {
+    let controller = TestController::new(BINARY_NAME).await;
+
+    let keyring = controller
+        .client
+        .get_host_keys()
+        .await
+        .expect("Should be able to fetch host keys");
+    assert!(!keyring.key.is_empty(), "Server should have a host key");
+
+    // Anything the server creates should be signed with one of the keys it published
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+    let host_signature = inv
+        .signature
+        .unwrap_or_default()
+        .into_iter()
+        .find(|s| s.role == SignatureRole::Host)
+        .expect("Invoice should be signed by the host");
+    assert!(
+        keyring.key.iter().any(|k| k.key == host_signature.key),
+        "Host signature should be made with a published key"
+    );
+}
+
 #[tokio::test]
 async fn test_charset() {
     let controller = TestController::new(BINARY_NAME).await;
@@ -238,3 +284,106 @@ async fn test_charset() {
         .await
         .expect("Content-Type with charset shouldn't fail");
 }
+
+#[tokio::test]
+async fn test_verification() {
+    let controller = TestController::new(BINARY_NAME).await;
+
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+
+    // The server signs every invoice it stores, so trust the key it signed this one with
+    let host_signature = inv
+        .signature
+        .iter()
+        .flatten()
+        .find(|s| s.role == SignatureRole::Host)
+        .expect("Server should have signed the invoice");
+    let keyring = KeyRing::new(vec![KeyEntry {
+        label: host_signature.by.clone(),
+        roles: vec![SignatureRole::Host],
+        key: host_signature.key.clone(),
+        label_signature: None,
+    }]);
+    let client = ClientBuilder::default()
+        .keyring(keyring)
+        .verification_strategy(VerificationStrategy::MultipleAttestation(vec![
+            SignatureRole::Host,
+        ]))
+        .build(&controller.base_url, NoToken)
+        .expect("Unable to build client");
+    client
+        .get_invoice(&inv.bindle.id)
+        .await
+        .expect("Invoice signed by a trusted key should be verified");
+    client
+        .query_invoices(QueryOptions::default())
+        .await
+        .expect("Queried invoices signed by a trusted key should be verified");
+
+    // A client that doesn't trust any keys should refuse the invoice
+    let client = ClientBuilder::default()
+        .keyring(KeyRing::default())
+        .build(&controller.base_url, NoToken)
+        .expect("Unable to build client");
+    match client.get_invoice(&inv.bindle.id).await {
+        Ok(_) => panic!("getting an unverified invoice should have errored"),
+        Err(e) => {
+            if !matches!(e, bindle::client::ClientError::VerificationFailed(_)) {
+                panic!("Expected a verification failed error, got: {:?}", e)
+            }
+        }
+    }
+    match client.query_invoices(QueryOptions::default()).await {
+        Ok(_) => panic!("querying for an unverified invoice should have errored"),
+        Err(e) => {
+            if !matches!(e, bindle::client::ClientError::VerificationFailed(_)) {
+                panic!("Expected a verification failed error, got: {:?}", e)
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_unexpected_invoice() {
+    use warp::Filter;
+
+    // A server that returns the same yanked invoice no matter which one is asked for
+    let mut inv = testing::Scaffold::load("valid_v1").await.invoice;
+    inv.yanked = Some(true);
+    let body = toml::to_vec(&inv).expect("Unable to serialize invoice");
+    let route = warp::path!("v1" / "_i" / ..).map(move || {
+        warp::http::Response::builder()
+            .header("Content-Type", "application/toml")
+            .body(body.clone())
+    });
+    let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let client = ClientBuilder::default()
+        .build(&format!("http://{}/v1/", addr), NoToken)
+        .expect("Unable to build client");
+    let other: bindle::Id = format!("{}/9.9.9", inv.bindle.id.name()).parse().unwrap();
+    for res in [
+        client.get_yanked_invoice(&other).await,
+        client.get_invoice(&inv.bindle.id).await,
+    ] {
+        match res {
+            Ok(_) => panic!("an unexpected invoice should have errored"),
+            Err(e) => {
+                if !matches!(e, bindle::client::ClientError::UnexpectedInvoice(_)) {
+                    panic!("Expected an unexpected invoice error, got: {:?}", e)
+                }
+            }
+        }
+    }
+    client
+        .get_yanked_invoice(&inv.bindle.id)
+        .await
+        .expect("Yanked invoice should be returned when asked for");
+}